@@ -0,0 +1,54 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use fuser::FileType;
+use rusty_file_system::image::{self, Geometry, FEATURE_DIR_INDEX};
+use rusty_file_system::FSState;
+use std::ffi::OsString;
+use std::fs::File;
+use std::hint::black_box;
+use tempdir::TempDir;
+
+const ROOT_INO: u32 = 1;
+
+// A root directory holding count files, laid out linearly or with a hashed index
+fn populated(count: usize, features: u32) -> (FSState, Vec<OsString>) {
+    let dir = TempDir::new("dir_lookup").unwrap();
+    let image = File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(dir.path().join("fs.img"))
+        .unwrap();
+    let geometry = Geometry::new(64 << 20, 4096, 16384).unwrap();
+    image::format_with_features(&image, geometry, features).unwrap();
+    let mut state = FSState::load(&image).unwrap();
+    let names: Vec<OsString> = (0..count)
+        .map(|i| OsString::from(format!("target/debug/deps/artifact-{i:06}.rlib")))
+        .collect();
+    for name in &names {
+        state
+            .make_node(ROOT_INO, name, FileType::RegularFile, 0o644)
+            .unwrap();
+    }
+    (state, names)
+}
+
+fn lookup(c: &mut Criterion) {
+    let mut group = c.benchmark_group("dir_lookup");
+    for count in [100, 1_000, 10_000] {
+        for (layout, features) in [("linear", 0), ("indexed", FEATURE_DIR_INDEX)] {
+            let (state, names) = populated(count, features);
+            group.bench_with_input(BenchmarkId::new(layout, count), &names, |b, names| {
+                let mut i = 0;
+                b.iter(|| {
+                    i = (i + 7919) % names.len();
+                    black_box(state.lookup_entry(ROOT_INO, &names[i]).unwrap())
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, lookup);
+criterion_main!(benches);
@@ -0,0 +1,463 @@
+use crate::perm::Credentials;
+use crate::{inode_errno, FSState, Inode};
+use fuser::FileType;
+use libc::{c_int, EACCES, EEXIST, EINVAL, ENODATA, XATTR_CREATE, XATTR_REPLACE};
+use std::ffi::OsStr;
+
+pub(crate) const ACL_ACCESS: &str = "system.posix_acl_access";
+pub(crate) const ACL_DEFAULT: &str = "system.posix_acl_default";
+
+// The xattr format Linux uses for ACLs: version u32, then tag u16 | perm u16 | id u32 per entry,
+// sorted by tag and then id
+const ACL_VERSION: u32 = 2;
+const ACL_HEADER_LEN: usize = 4;
+const ACL_ENTRY_LEN: usize = 8;
+const ACL_UNDEFINED_ID: u32 = u32::MAX;
+
+const USER_OBJ: u16 = 0x01;
+const USER: u16 = 0x02;
+const GROUP_OBJ: u16 = 0x04;
+const GROUP: u16 = 0x08;
+const MASK: u16 = 0x10;
+const OTHER: u16 = 0x20;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct AclEntry {
+    tag: u16,
+    perm: u16,
+    id: u32,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Acl(Vec<AclEntry>);
+
+impl Acl {
+    fn parse(data: &[u8]) -> Result<Self, c_int> {
+        if data.len() < ACL_HEADER_LEN
+            || !(data.len() - ACL_HEADER_LEN).is_multiple_of(ACL_ENTRY_LEN)
+        {
+            return Err(EINVAL);
+        }
+        if data[..4] != ACL_VERSION.to_le_bytes() {
+            return Err(EINVAL);
+        }
+        let entries = data[ACL_HEADER_LEN..]
+            .chunks_exact(ACL_ENTRY_LEN)
+            .map(|entry| AclEntry {
+                tag: u16::from_le_bytes([entry[0], entry[1]]),
+                perm: u16::from_le_bytes([entry[2], entry[3]]),
+                id: u32::from_le_bytes(entry[4..8].try_into().unwrap()),
+            })
+            .collect();
+        let acl = Self(entries);
+        acl.validate()?;
+        Ok(acl)
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut data = ACL_VERSION.to_le_bytes().to_vec();
+        for entry in &self.0 {
+            data.extend(entry.tag.to_le_bytes());
+            data.extend(entry.perm.to_le_bytes());
+            data.extend(entry.id.to_le_bytes());
+        }
+        data
+    }
+
+    // One each of the owner, owning group and other entries, a mask whenever there are named
+    // entries, no repeated names and everything in order
+    fn validate(&self) -> Result<(), c_int> {
+        let count = |tag| self.0.iter().filter(|entry| entry.tag == tag).count();
+        let named = count(USER) + count(GROUP);
+        let valid = count(USER_OBJ) == 1
+            && count(GROUP_OBJ) == 1
+            && count(OTHER) == 1
+            && count(MASK) == (named > 0) as usize
+            && self.0.iter().all(|entry| {
+                entry.perm & !0o7 == 0
+                    && [USER_OBJ, USER, GROUP_OBJ, GROUP, MASK, OTHER].contains(&entry.tag)
+                    && (!matches!(entry.tag, USER | GROUP) || entry.id != ACL_UNDEFINED_ID)
+            })
+            && self
+                .0
+                .windows(2)
+                .all(|pair| (pair[0].tag, pair[0].id) < (pair[1].tag, pair[1].id));
+        match valid {
+            true => Ok(()),
+            false => Err(EINVAL),
+        }
+    }
+
+    fn entry_mut(&mut self, tag: u16) -> Option<&mut AclEntry> {
+        self.0.iter_mut().find(|entry| entry.tag == tag)
+    }
+
+    fn perm(&self, tag: u16) -> Option<u16> {
+        self.0
+            .iter()
+            .find(|entry| entry.tag == tag)
+            .map(|entry| entry.perm)
+    }
+
+    // The group class is the mask when there is one, the owning group otherwise
+    fn group_class_tag(&self) -> u16 {
+        match self.perm(MASK) {
+            Some(_) => MASK,
+            None => GROUP_OBJ,
+        }
+    }
+
+    // Permission bits of the mode the ACL implies
+    fn mode(&self) -> u16 {
+        let perm = |tag| self.perm(tag).unwrap_or(0);
+        perm(USER_OBJ) << 6 | perm(self.group_class_tag()) << 3 | perm(OTHER)
+    }
+
+    // Makes the ACL agree with the permission bits of mode, as chmod does
+    fn set_mode(&mut self, mode: u16) {
+        let group_class = self.group_class_tag();
+        for (tag, shift) in [(USER_OBJ, 6), (group_class, 3), (OTHER, 0)] {
+            if let Some(entry) = self.entry_mut(tag) {
+                entry.perm = (mode >> shift) & 0o7;
+            }
+        }
+    }
+
+    // Drops whatever mode does not allow, for the access ACL of a new inode
+    fn restrict_to_mode(&mut self, mode: u16) {
+        let group_class = self.group_class_tag();
+        for (tag, shift) in [(USER_OBJ, 6), (group_class, 3), (OTHER, 0)] {
+            if let Some(entry) = self.entry_mut(tag) {
+                entry.perm &= (mode >> shift) & 0o7;
+            }
+        }
+    }
+
+    // Nothing beyond what the permission bits say
+    fn is_minimal(&self) -> bool {
+        self.0.len() == 3
+    }
+
+    // The POSIX.1e access check: owner, named users, then the group entries that match, then
+    // other. Named entries and the owning group are limited by the mask.
+    pub(crate) fn permits(&self, inode: &Inode, creds: &Credentials, want: u16) -> bool {
+        let mask = self.perm(MASK).unwrap_or(0o7);
+        let grants = |perm: u16| perm & want == want;
+        if creds.uid == inode.uid {
+            return grants(self.perm(USER_OBJ).unwrap_or(0));
+        }
+        if let Some(entry) = self
+            .0
+            .iter()
+            .find(|entry| entry.tag == USER && entry.id == creds.uid)
+        {
+            return grants(entry.perm & mask);
+        }
+        let groups = self.0.iter().filter(|entry| match entry.tag {
+            GROUP_OBJ => creds.in_group(inode.gid),
+            GROUP => creds.in_group(entry.id),
+            _ => false,
+        });
+        // A matching group entry without the bits denies, other is not consulted
+        let mut matched = false;
+        for entry in groups {
+            if grants(entry.perm & mask) {
+                return true;
+            }
+            matched = true;
+        }
+        !matched && grants(self.perm(OTHER).unwrap_or(0))
+    }
+}
+
+impl FSState {
+    fn acl(&self, ino_id: u32, name: &str) -> Result<Option<Acl>, c_int> {
+        match self.get_xattr(ino_id, OsStr::new(name)) {
+            Ok(value) => Acl::parse(&value).map(Some),
+            Err(ENODATA) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub(crate) fn access_acl(&self, ino_id: u32) -> Option<Acl> {
+        self.acl(ino_id, ACL_ACCESS).ok().flatten()
+    }
+
+    fn remove_acl(&mut self, ino_id: u32, name: &str) -> Result<(), c_int> {
+        match self.remove_xattr(ino_id, OsStr::new(name)) {
+            Err(ENODATA) => Ok(()),
+            result => result,
+        }
+    }
+
+    // Setting the access ACL also sets the mode from it, and one the mode can express on its
+    // own is not stored at all. Default ACLs only exist on directories, an empty one removes
+    // it.
+    pub(crate) fn set_acl(
+        &mut self,
+        ino_id: u32,
+        name: &str,
+        value: &[u8],
+        flags: i32,
+    ) -> Result<(), c_int> {
+        let inode = *self.inode(ino_id).map_err(inode_errno)?;
+        if name == ACL_DEFAULT {
+            if inode.kind != FileType::Directory {
+                return Err(EACCES);
+            }
+            if value.is_empty() {
+                return self.remove_acl(ino_id, name);
+            }
+        }
+        let acl = Acl::parse(value)?;
+        if name == ACL_ACCESS && acl.is_minimal() {
+            // Nothing is stored, but the flags still apply to the ACL there was
+            let stored = self.acl(ino_id, name)?.is_some();
+            if stored && flags & XATTR_CREATE != 0 {
+                return Err(EEXIST);
+            }
+            if !stored && flags & XATTR_REPLACE != 0 {
+                return Err(ENODATA);
+            }
+            self.remove_acl(ino_id, name)?;
+        } else {
+            self.store_xattr(ino_id, OsStr::new(name), &acl.to_bytes(), flags)?;
+        }
+        // Only once the ACL is in place, so a failed set leaves the mode alone
+        if name == ACL_ACCESS {
+            self.inode_mut(ino_id).map_err(inode_errno)?.perm = inode.perm & 0o7000 | acl.mode();
+        }
+        Ok(())
+    }
+
+    // Keeps the access ACL in step with a chmod
+    pub(crate) fn chmod_acl(&mut self, ino_id: u32, perm: u16) -> Result<(), c_int> {
+        if let Some(mut acl) = self.acl(ino_id, ACL_ACCESS)? {
+            acl.set_mode(perm);
+            self.store_xattr(ino_id, OsStr::new(ACL_ACCESS), &acl.to_bytes(), 0)?;
+        }
+        Ok(())
+    }
+
+    // Gives a new inode the default ACL of its parent, restricted by the mode it was created
+    // with, and sets the mode from the result. Directories also inherit it as their own
+    // default.
+    pub(crate) fn inherit_acl(&mut self, parent: u32, ino_id: u32) -> Result<(), c_int> {
+        let Some(default) = self.acl(parent, ACL_DEFAULT)? else {
+            return Ok(());
+        };
+        let inode = *self.inode(ino_id).map_err(inode_errno)?;
+        if inode.kind == FileType::Directory {
+            self.store_xattr(ino_id, OsStr::new(ACL_DEFAULT), &default.to_bytes(), 0)?;
+        }
+        let mut acl = default;
+        acl.restrict_to_mode(inode.perm);
+        self.inode_mut(ino_id).map_err(inode_errno)?.perm = inode.perm & 0o7000 | acl.mode();
+        if !acl.is_minimal() {
+            self.store_xattr(ino_id, OsStr::new(ACL_ACCESS), &acl.to_bytes(), 0)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::state_with_node;
+    use crate::{fsck, SetAttr};
+    use libc::{R_OK, W_OK, X_OK};
+
+    const OWNER: u32 = 1000;
+    const GROUP_ID: u32 = 100;
+
+    fn entry(tag: u16, perm: u16, id: u32) -> AclEntry {
+        AclEntry { tag, perm, id }
+    }
+
+    fn base(user: u16, group: u16, other: u16) -> Vec<AclEntry> {
+        vec![
+            entry(USER_OBJ, user, ACL_UNDEFINED_ID),
+            entry(GROUP_OBJ, group, ACL_UNDEFINED_ID),
+            entry(OTHER, other, ACL_UNDEFINED_ID),
+        ]
+    }
+
+    // Owner rw, named user 2000 rw, owning group r, mask r, other nothing
+    fn shared_acl() -> Acl {
+        Acl(vec![
+            entry(USER_OBJ, 6, ACL_UNDEFINED_ID),
+            entry(USER, 6, 2000),
+            entry(GROUP_OBJ, 4, ACL_UNDEFINED_ID),
+            entry(GROUP, 6, 300),
+            entry(MASK, 4, ACL_UNDEFINED_ID),
+            entry(OTHER, 0, ACL_UNDEFINED_ID),
+        ])
+    }
+
+    fn set(state: &mut FSState, ino_id: u32, name: &str, acl: &Acl) -> Result<(), c_int> {
+        state.set_xattr(ino_id, OsStr::new(name), &acl.to_bytes(), 0)
+    }
+
+    #[test]
+    fn test_parse_and_validate() {
+        let acl = shared_acl();
+        assert_eq!(Acl::parse(&acl.to_bytes()), Ok(acl));
+        assert_eq!(Acl::parse(&[2, 0, 0, 0, 1]), Err(EINVAL));
+
+        let mut entries = base(6, 4, 4);
+        entries.insert(1, entry(USER, 6, 5));
+        assert_eq!(Acl(entries.clone()).validate(), Err(EINVAL));
+        entries.insert(3, entry(MASK, 6, ACL_UNDEFINED_ID));
+        assert_eq!(Acl(entries.clone()).validate(), Ok(()));
+        entries.swap(0, 1);
+        assert_eq!(Acl(entries).validate(), Err(EINVAL));
+    }
+
+    #[test]
+    fn test_named_entries_are_limited_by_the_mask() {
+        let (mut state, file) = state_with_node(FileType::RegularFile, 0o600, OWNER, GROUP_ID);
+        set(&mut state, file, ACL_ACCESS, &shared_acl()).unwrap();
+        // The group bits of the mode show the mask
+        assert_eq!(state.inode(file).unwrap().perm, 0o640);
+
+        let named = Credentials::new(2000, 1);
+        assert_eq!(state.check_access(file, &named, R_OK), Ok(()));
+        assert_eq!(state.check_access(file, &named, W_OK), Err(EACCES));
+        let named_group = Credentials::new(3000, 300);
+        assert_eq!(state.check_access(file, &named_group, R_OK), Ok(()));
+        assert_eq!(state.check_access(file, &named_group, W_OK), Err(EACCES));
+        let owner = Credentials::new(OWNER, 1);
+        assert_eq!(state.check_access(file, &owner, R_OK | W_OK), Ok(()));
+        let other = Credentials::new(3000, 3000);
+        assert_eq!(state.check_access(file, &other, R_OK), Err(EACCES));
+    }
+
+    #[test]
+    fn test_matching_group_without_the_bits_does_not_fall_back_to_other() {
+        let (mut state, file) = state_with_node(FileType::RegularFile, 0o604, OWNER, GROUP_ID);
+        let acl = Acl(vec![
+            entry(USER_OBJ, 6, ACL_UNDEFINED_ID),
+            entry(GROUP_OBJ, 0, ACL_UNDEFINED_ID),
+            entry(GROUP, 0, 300),
+            entry(MASK, 7, ACL_UNDEFINED_ID),
+            entry(OTHER, 4, ACL_UNDEFINED_ID),
+        ]);
+        set(&mut state, file, ACL_ACCESS, &acl).unwrap();
+        assert_eq!(
+            state.check_access(file, &Credentials::new(1, 300), R_OK),
+            Err(EACCES)
+        );
+        assert_eq!(
+            state.check_access(file, &Credentials::new(1, 1), R_OK),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_minimal_acl_only_sets_the_mode() {
+        let (mut state, file) = state_with_node(FileType::RegularFile, 0o600, OWNER, GROUP_ID);
+        set(&mut state, file, ACL_ACCESS, &Acl(base(7, 5, 1))).unwrap();
+        assert_eq!(state.inode(file).unwrap().perm, 0o751);
+        assert_eq!(state.get_xattr(file, OsStr::new(ACL_ACCESS)), Err(ENODATA));
+    }
+
+    #[test]
+    fn test_failed_set_leaves_the_mode_alone() {
+        let (mut state, file) = state_with_node(FileType::RegularFile, 0o600, OWNER, GROUP_ID);
+        let bytes = shared_acl().to_bytes();
+        assert_eq!(
+            state.set_xattr(file, OsStr::new(ACL_ACCESS), &bytes, XATTR_REPLACE),
+            Err(ENODATA)
+        );
+        assert_eq!(state.inode(file).unwrap().perm, 0o600);
+        set(&mut state, file, ACL_ACCESS, &shared_acl()).unwrap();
+        let minimal = Acl(base(7, 5, 1)).to_bytes();
+        assert_eq!(
+            state.set_xattr(file, OsStr::new(ACL_ACCESS), &minimal, XATTR_CREATE),
+            Err(EEXIST)
+        );
+        assert_eq!(state.inode(file).unwrap().perm, 0o640);
+        assert!(state.get_xattr(file, OsStr::new(ACL_ACCESS)).is_ok());
+    }
+
+    #[test]
+    fn test_minimal_acl_follows_the_flags() {
+        let (mut state, file) = state_with_node(FileType::RegularFile, 0o600, OWNER, GROUP_ID);
+        let minimal = Acl(base(7, 5, 1)).to_bytes();
+        assert_eq!(
+            state.set_xattr(file, OsStr::new(ACL_ACCESS), &minimal, XATTR_REPLACE),
+            Err(ENODATA)
+        );
+        assert_eq!(state.inode(file).unwrap().perm, 0o600);
+
+        set(&mut state, file, ACL_ACCESS, &shared_acl()).unwrap();
+        state
+            .set_xattr(file, OsStr::new(ACL_ACCESS), &minimal, XATTR_REPLACE)
+            .unwrap();
+        assert_eq!(state.inode(file).unwrap().perm, 0o751);
+        assert_eq!(state.get_xattr(file, OsStr::new(ACL_ACCESS)), Err(ENODATA));
+    }
+
+    #[test]
+    fn test_chmod_updates_the_mask() {
+        let (mut state, file) = state_with_node(FileType::RegularFile, 0o600, OWNER, GROUP_ID);
+        set(&mut state, file, ACL_ACCESS, &shared_acl()).unwrap();
+        let chmod = SetAttr {
+            mode: Some(0o670),
+            ..Default::default()
+        };
+        state.set_attr(file, &chmod).unwrap();
+
+        let acl = state.access_acl(file).unwrap();
+        assert_eq!(acl.perm(MASK), Some(7));
+        assert_eq!(acl.perm(GROUP_OBJ), Some(4));
+        assert_eq!(acl.perm(OTHER), Some(0));
+        let named = Credentials::new(2000, 1);
+        assert_eq!(state.check_access(file, &named, R_OK | W_OK), Ok(()));
+    }
+
+    #[test]
+    fn test_default_acl_is_inherited() {
+        let (mut state, dir) = state_with_node(FileType::Directory, 0o755, OWNER, GROUP_ID);
+        let mut default = shared_acl();
+        default.entry_mut(MASK).unwrap().perm = 7;
+        default.entry_mut(USER_OBJ).unwrap().perm = 7;
+        set(&mut state, dir, ACL_DEFAULT, &default).unwrap();
+
+        let file = state
+            .make_node_as(dir, OsStr::new("file"), FileType::RegularFile, 0o640, 1, 1)
+            .unwrap();
+        // The mode the file was created with caps the owner, mask and other entries
+        assert_eq!(state.inode(file).unwrap().perm, 0o640);
+        let acl = state.access_acl(file).unwrap();
+        assert_eq!(acl.perm(USER_OBJ), Some(6));
+        assert_eq!(acl.perm(MASK), Some(4));
+        assert_eq!(acl.perm(USER), Some(6));
+        assert_eq!(state.get_xattr(file, OsStr::new(ACL_DEFAULT)), Err(ENODATA));
+
+        let sub = state
+            .make_node_as(dir, OsStr::new("sub"), FileType::Directory, 0o777, 1, 1)
+            .unwrap();
+        assert_eq!(state.inode(sub).unwrap().perm, 0o770);
+        assert_eq!(state.acl(sub, ACL_DEFAULT), Ok(Some(default)));
+        let named = Credentials::new(2000, 1);
+        assert_eq!(state.check_access(sub, &named, R_OK | W_OK), Ok(()));
+        assert_eq!(state.check_access(sub, &named, X_OK), Err(EACCES));
+        assert_eq!(fsck::check(&state), vec![]);
+    }
+
+    #[test]
+    fn test_default_acl_only_on_directories() {
+        let (mut state, file) = state_with_node(FileType::RegularFile, 0o600, OWNER, GROUP_ID);
+        assert_eq!(
+            set(&mut state, file, ACL_DEFAULT, &shared_acl()),
+            Err(EACCES)
+        );
+        let (mut state, dir) = state_with_node(FileType::Directory, 0o755, OWNER, GROUP_ID);
+        set(&mut state, dir, ACL_DEFAULT, &shared_acl()).unwrap();
+        state
+            .set_xattr(dir, OsStr::new(ACL_DEFAULT), &[], 0)
+            .unwrap();
+        assert_eq!(state.acl(dir, ACL_DEFAULT), Ok(None));
+    }
+}
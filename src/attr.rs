@@ -0,0 +1,241 @@
+use crate::{inode_errno, FSState, Inode};
+use fuser::{FileAttr, FileType, TimeOrNow};
+use libc::{c_int, EINVAL};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// atime is only bumped on access when it is older than mtime or ctime, or older than this.
+// Same as the relatime mount option.
+const RELATIME_INTERVAL_NS: i64 = 24 * 60 * 60 * 1_000_000_000;
+
+// Nanoseconds since the Unix epoch, negative before it. An i64 covers 1677 to 2262.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Default)]
+pub(crate) struct Timestamp(pub(crate) i64);
+
+impl Timestamp {
+    pub(crate) fn now() -> Self {
+        SystemTime::now().into()
+    }
+}
+
+impl From<SystemTime> for Timestamp {
+    fn from(time: SystemTime) -> Self {
+        let nanos = |d: Duration| d.as_nanos().min(i64::MAX as u128) as i64;
+        match time.duration_since(UNIX_EPOCH) {
+            Ok(since) => Self(nanos(since)),
+            Err(err) => Self(-nanos(err.duration())),
+        }
+    }
+}
+
+impl From<TimeOrNow> for Timestamp {
+    fn from(time: TimeOrNow) -> Self {
+        match time {
+            TimeOrNow::SpecificTime(time) => time.into(),
+            TimeOrNow::Now => Self::now(),
+        }
+    }
+}
+
+impl From<Timestamp> for SystemTime {
+    fn from(time: Timestamp) -> Self {
+        let nanos = Duration::from_nanos(time.0.unsigned_abs());
+        match time.0 >= 0 {
+            true => UNIX_EPOCH + nanos,
+            false => UNIX_EPOCH - nanos,
+        }
+    }
+}
+
+// Changes requested by setattr, None leaves the attribute as it is. ctime is always set to
+// the time of the change.
+#[derive(Default, Debug)]
+pub struct SetAttr {
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub size: Option<u64>,
+    pub atime: Option<TimeOrNow>,
+    pub mtime: Option<TimeOrNow>,
+    pub crtime: Option<SystemTime>,
+}
+
+impl Inode {
+    pub(crate) fn file_attr(&self, blk_size: u64) -> FileAttr {
+        FileAttr {
+            ino: self.ino_id as u64,
+            size: self.size,
+            blocks: self.blocks as u64 * (blk_size / 512),
+            atime: self.atime.into(),
+            mtime: self.mtime.into(),
+            ctime: self.ctime.into(),
+            crtime: self.crtime.into(),
+            kind: self.kind,
+            perm: self.perm,
+            nlink: self.nlink,
+            uid: self.uid,
+            gid: self.gid,
+            rdev: 0,
+            blksize: blk_size as u32,
+            flags: 0,
+        }
+    }
+}
+
+impl FSState {
+    pub fn file_attr(&self, ino_id: u32) -> Result<FileAttr, c_int> {
+        let inode = self.inode(ino_id).map_err(inode_errno)?;
+        let mut attr = inode.file_attr(self.blk_size());
+        // Pages waiting for blocks count as the blocks they will take
+        attr.blocks += self.delayed.of_inode(ino_id).count() as u64 * (self.blk_size() / 512);
+        Ok(attr)
+    }
+
+    // Applies chmod, chown, truncate and utimens style changes
+    pub fn set_attr(&mut self, ino_id: u32, attr: &SetAttr) -> Result<(), c_int> {
+        let kind = self.inode(ino_id).map_err(inode_errno)?.kind;
+        if let Some(size) = attr.size {
+            if kind == FileType::Directory {
+                return Err(libc::EISDIR);
+            }
+            if kind != FileType::RegularFile {
+                return Err(EINVAL);
+            }
+            self.truncate(ino_id, size)?;
+        }
+
+        if let Some(mode) = attr.mode {
+            self.chmod_acl(ino_id, mode as u16)?;
+        }
+        let inode = self.inode_mut(ino_id).map_err(inode_errno)?;
+        if let Some(mode) = attr.mode {
+            inode.perm = (mode & 0o7777) as u16;
+        }
+        inode.uid = attr.uid.unwrap_or(inode.uid);
+        inode.gid = attr.gid.unwrap_or(inode.gid);
+        if let Some(atime) = attr.atime {
+            inode.atime = atime.into();
+        }
+        if let Some(mtime) = attr.mtime {
+            inode.mtime = mtime.into();
+        }
+        if let Some(crtime) = attr.crtime {
+            inode.crtime = crtime.into();
+        }
+        inode.update_ctime();
+        Ok(())
+    }
+
+    // Records a read of the inode's data, following relatime rules
+    pub fn touch_atime(&mut self, ino_id: u32) -> Result<(), c_int> {
+        let inode = self.inode_mut(ino_id).map_err(inode_errno)?;
+        let now = Timestamp::now();
+        if inode.atime <= inode.mtime
+            || inode.atime <= inode.ctime
+            || now.0 - inode.atime.0 >= RELATIME_INTERVAL_NS
+        {
+            inode.atime = now;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::state_with_node;
+    use crate::ROOT_INO;
+
+    #[test]
+    fn test_timestamp_conversions() {
+        let time = UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_789);
+        assert_eq!(Timestamp::from(time), Timestamp(1_700_000_000_123_456_789));
+        assert_eq!(SystemTime::from(Timestamp::from(time)), time);
+
+        let before = UNIX_EPOCH - Duration::new(5, 1);
+        assert_eq!(Timestamp::from(before), Timestamp(-5_000_000_001));
+        assert_eq!(SystemTime::from(Timestamp::from(before)), before);
+    }
+
+    #[test]
+    fn test_new_file_attr() {
+        let (state, ino_id) = state_with_node(FileType::RegularFile, 0o644, 1000, 100);
+        let attr = state.file_attr(ino_id).unwrap();
+        assert_eq!(attr.ino, ino_id as u64);
+        assert_eq!((attr.uid, attr.gid), (1000, 100));
+        assert_eq!(attr.perm, 0o644);
+        assert_eq!(attr.nlink, 1);
+        assert_eq!(attr.kind, FileType::RegularFile);
+        assert_eq!(attr.atime, attr.crtime);
+        assert_eq!(attr.mtime, attr.crtime);
+        assert_eq!(attr.ctime, attr.crtime);
+        assert_eq!(attr.blksize, 4096);
+    }
+
+    #[test]
+    fn test_write_updates_mtime_and_ctime_only() {
+        let (mut state, ino_id) = state_with_node(FileType::RegularFile, 0o644, 1000, 100);
+        let inode = state.inode_mut(ino_id).unwrap();
+        (inode.mtime, inode.ctime) = (Timestamp(1), Timestamp(1));
+        let before = *inode;
+        state.write_at(ino_id, 0, b"data").unwrap();
+        let after = *state.inode(ino_id).unwrap();
+        assert!(after.mtime > before.mtime);
+        assert!(after.ctime > before.ctime);
+        assert_eq!(after.atime, before.atime);
+        assert_eq!(after.crtime, before.crtime);
+        assert_eq!(state.file_attr(ino_id).unwrap().blocks, 8);
+    }
+
+    #[test]
+    fn test_chmod_chown_utimens_truncate() {
+        let (mut state, ino_id) = state_with_node(FileType::RegularFile, 0o644, 1000, 100);
+        state.write_at(ino_id, 0, &[1; 100]).unwrap();
+        state.inode_mut(ino_id).unwrap().ctime = Timestamp(1);
+        let atime = UNIX_EPOCH + Duration::new(1_000, 1);
+        let mtime = UNIX_EPOCH + Duration::new(2_000, 2);
+        let attr = SetAttr {
+            mode: Some(libc::S_IFREG | 0o4750),
+            uid: Some(0),
+            gid: Some(0),
+            size: Some(10),
+            atime: Some(TimeOrNow::SpecificTime(atime)),
+            mtime: Some(TimeOrNow::SpecificTime(mtime)),
+            crtime: None,
+        };
+        state.set_attr(ino_id, &attr).unwrap();
+
+        let attr = state.file_attr(ino_id).unwrap();
+        assert_eq!(attr.perm, 0o4750);
+        assert_eq!((attr.uid, attr.gid), (0, 0));
+        assert_eq!(attr.size, 10);
+        assert_eq!(attr.atime, atime);
+        assert_eq!(attr.mtime, mtime);
+        assert!(state.inode(ino_id).unwrap().ctime > Timestamp(1));
+    }
+
+    #[test]
+    fn test_truncate_rejects_directories() {
+        let (mut state, _) = state_with_node(FileType::RegularFile, 0o644, 1000, 100);
+        let attr = SetAttr {
+            size: Some(0),
+            ..Default::default()
+        };
+        assert_eq!(state.set_attr(ROOT_INO, &attr), Err(libc::EISDIR));
+    }
+
+    #[test]
+    fn test_atime_follows_relatime() {
+        let (mut state, ino_id) = state_with_node(FileType::RegularFile, 0o644, 1000, 100);
+        // Read since the last change, within the interval
+        let atime = Timestamp(Timestamp::now().0 - 1_000_000_000);
+        let inode = state.inode_mut(ino_id).unwrap();
+        (inode.mtime, inode.ctime, inode.atime) = (Timestamp(100), Timestamp(100), atime);
+        state.touch_atime(ino_id).unwrap();
+        assert_eq!(state.inode(ino_id).unwrap().atime, atime);
+
+        // Modified since
+        state.inode_mut(ino_id).unwrap().mtime = atime;
+        state.touch_atime(ino_id).unwrap();
+        assert!(state.inode(ino_id).unwrap().atime > atime);
+    }
+}
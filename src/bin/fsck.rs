@@ -0,0 +1,98 @@
+use rusty_file_system::FSState;
+use rusty_file_system::{fsck, journal};
+use std::env;
+use std::fs::OpenOptions;
+use std::process;
+
+const USAGE: &str = "usage: fsck [-n | -y] <image>
+  -n  only report problems, open the image read-only (default)
+  -y  replay the journal, repair every problem found and write the image back";
+
+// Exit codes follow e2fsck
+const EXIT_CLEAN: i32 = 0;
+const EXIT_CORRECTED: i32 = 1;
+const EXIT_UNCORRECTED: i32 = 4;
+const EXIT_OPERATIONAL: i32 = 8;
+const EXIT_USAGE: i32 = 16;
+
+fn usage_error(msg: &str) -> ! {
+    eprintln!("fsck: {msg}\n{USAGE}");
+    process::exit(EXIT_USAGE);
+}
+
+fn fail(msg: String) -> ! {
+    eprintln!("fsck: {msg}");
+    process::exit(EXIT_OPERATIONAL);
+}
+
+fn main() {
+    env_logger::init();
+    let mut repair = false;
+    let mut path = None;
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "-n" => repair = false,
+            "-y" => repair = true,
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                return;
+            }
+            _ if path.is_none() && !arg.starts_with('-') => path = Some(arg),
+            _ => usage_error(&format!("unexpected argument {arg}")),
+        }
+    }
+    let path = path.unwrap_or_else(|| usage_error("missing image path"));
+
+    let image = OpenOptions::new()
+        .read(true)
+        .write(repair)
+        .open(&path)
+        .unwrap_or_else(|err| fail(format!("cannot open {path}: {err}")));
+    // Like e2fsck, a read-only check looks at the image as it is
+    match (repair, journal::needs_recovery(&image)) {
+        (true, Ok(true)) => match journal::recover(&image) {
+            Ok(_) => println!("{path}: replayed the journal"),
+            Err(err) => fail(format!("{path}: {err}")),
+        },
+        (false, Ok(true)) => println!("{path}: journal needs recovery, checking without it"),
+        (_, Ok(false)) => {}
+        (_, Err(err)) => fail(format!("{path}: {err}")),
+    }
+    let mut state =
+        FSState::load_all_blks(&image).unwrap_or_else(|err| fail(format!("{path}: {err}")));
+
+    let problems = if repair {
+        fsck::repair(&mut state)
+    } else {
+        fsck::check(&state)
+    };
+    for problem in &problems {
+        println!("{path}: {problem}");
+    }
+    if problems.is_empty() {
+        println!("{path}: clean");
+        process::exit(EXIT_CLEAN);
+    }
+    if !repair {
+        println!("{path}: {} problems found", problems.len());
+        process::exit(EXIT_UNCORRECTED);
+    }
+
+    state
+        .save(&image)
+        .unwrap_or_else(|err| fail(format!("{path}: {err}")));
+    let remaining = fsck::check(&state);
+    for problem in &remaining {
+        println!("{path}: not fixed: {problem}");
+    }
+    println!(
+        "{path}: {} problems found, {} fixed",
+        problems.len(),
+        problems.len().saturating_sub(remaining.len())
+    );
+    process::exit(if remaining.is_empty() {
+        EXIT_CORRECTED
+    } else {
+        EXIT_UNCORRECTED
+    });
+}
@@ -0,0 +1,157 @@
+use rusty_file_system::image::{
+    self, Geometry, DEFAULT_FEATURES, FEATURE_DIR_INDEX, FEATURE_EXTENTS,
+};
+use std::env;
+use std::fs::OpenOptions;
+use std::process;
+
+const USAGE: &str =
+    "usage: mkfs [-s size] [-b block_size] [-i inodes] [-g blocks] [-j journal_blocks] [-c]
+            [-O features] <image>
+  -s size        total image size, accepts K/M/G suffixes (default 1G)
+  -b block_size  block size in bytes (default 4096)
+  -i inodes      number of inodes, including the reserved null and root inodes (default 10)
+  -g blocks      blocks per group, a multiple of 8 (default 8 times the block size)
+  -j blocks      journal size in blocks, 0 for none (default 1/32 of the blocks, 32 to 1024,
+                 and at least what the largest request needs)
+  -c             copy-on-write updates instead of a journal
+  -O features    comma separated features to enable, prefix with ^ to disable.
+                 Known features: dir_index (hashed directory indexes, on by default),
+                 extents (map the blocks of new files with extents)";
+
+// Applies a -O list like "^dir_index" to features
+fn parse_features(arg: &str, mut features: u32) -> Option<u32> {
+    for name in arg.split(',') {
+        let (enable, name) = match name.strip_prefix('^') {
+            Some(name) => (false, name),
+            None => (true, name),
+        };
+        let flag = match name {
+            "dir_index" => FEATURE_DIR_INDEX,
+            "extents" => FEATURE_EXTENTS,
+            _ => return None,
+        };
+        features = if enable {
+            features | flag
+        } else {
+            features & !flag
+        };
+    }
+    Some(features)
+}
+
+fn parse_size(arg: &str) -> Option<u64> {
+    let (digits, shift) = match arg.chars().last()?.to_ascii_uppercase() {
+        'K' => (&arg[..arg.len() - 1], 10),
+        'M' => (&arg[..arg.len() - 1], 20),
+        'G' => (&arg[..arg.len() - 1], 30),
+        _ => (arg, 0),
+    };
+    digits.parse::<u64>().ok()?.checked_mul(1 << shift)
+}
+
+fn usage_error(msg: &str) -> ! {
+    eprintln!("mkfs: {msg}\n{USAGE}");
+    process::exit(2);
+}
+
+fn main() {
+    env_logger::init();
+    let defaults = Geometry::default();
+    let mut size = defaults.size_bytes();
+    let mut blk_size = defaults.blk_size;
+    let mut ino_count = defaults.ino_count;
+    let mut blks_per_group = None;
+    let mut journal_blks = None;
+    let mut cow = false;
+    let mut features = DEFAULT_FEATURES;
+    let mut path = None;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| {
+            args.next()
+                .unwrap_or_else(|| usage_error(&format!("{name} needs a value")))
+        };
+        match arg.as_str() {
+            "-s" => size = parse_size(&value("-s")).unwrap_or_else(|| usage_error("invalid size")),
+            "-b" => {
+                blk_size = parse_size(&value("-b"))
+                    .and_then(|size| u32::try_from(size).ok())
+                    .unwrap_or_else(|| usage_error("invalid block size"))
+            }
+            "-i" => {
+                ino_count = value("-i")
+                    .parse()
+                    .unwrap_or_else(|_| usage_error("invalid inode count"))
+            }
+            "-g" => {
+                blks_per_group = Some(
+                    value("-g")
+                        .parse()
+                        .unwrap_or_else(|_| usage_error("invalid blocks per group")),
+                )
+            }
+            "-j" => {
+                journal_blks = Some(
+                    value("-j")
+                        .parse()
+                        .unwrap_or_else(|_| usage_error("invalid journal size")),
+                )
+            }
+            "-c" => cow = true,
+            "-O" => {
+                features = parse_features(&value("-O"), features)
+                    .unwrap_or_else(|| usage_error("unknown feature"))
+            }
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                return;
+            }
+            _ if path.is_none() && !arg.starts_with('-') => path = Some(arg),
+            _ => usage_error(&format!("unexpected argument {arg}")),
+        }
+    }
+    let path = path.unwrap_or_else(|| usage_error("missing image path"));
+
+    let geometry = Geometry::new(size, blk_size, ino_count)
+        .and_then(|geometry| match blks_per_group {
+            Some(blks_per_group) => geometry.with_blks_per_group(blks_per_group),
+            None => Ok(geometry),
+        })
+        .and_then(|geometry| match cow {
+            true => geometry.with_journal(journal_blks.unwrap_or(0))?.with_cow(),
+            false => geometry.with_journal(journal_blks.unwrap_or(geometry.default_journal_blks())),
+        })
+        .unwrap_or_else(|err| {
+            eprintln!("mkfs: {err}");
+            process::exit(1);
+        });
+    let image = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&path)
+        .unwrap_or_else(|err| {
+            eprintln!("mkfs: cannot open {path}: {err}");
+            process::exit(1);
+        });
+    if let Err(err) = image::format_with_features(&image, geometry, features) {
+        eprintln!("mkfs: {err}");
+        process::exit(1);
+    }
+
+    let updates = match geometry.cow {
+        true => "copy-on-write".to_string(),
+        false => format!("{} journal blocks", geometry.journal_blks),
+    };
+    println!(
+        "{path}: {} blocks of {} bytes in {} groups, {} inodes, {updates}, first data block {}",
+        geometry.blk_count,
+        geometry.blk_size,
+        geometry.group_count(),
+        geometry.ino_count,
+        geometry.reserved_blks()
+    );
+}
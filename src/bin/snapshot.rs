@@ -0,0 +1,86 @@
+use rusty_file_system::{journal, FSState};
+use std::env;
+use std::ffi::OsStr;
+use std::fs::OpenOptions;
+use std::io;
+use std::process;
+use std::time::UNIX_EPOCH;
+
+const USAGE: &str = "usage: snapshot [-c name | -d name | -r name] <image>
+  -c name  take a snapshot of the image as it is now
+  -d name  delete a snapshot, freeing the blocks only it uses
+  -r name  roll the image back to a snapshot, losing every change made since
+Without an option, lists the snapshots with their ids and creation times.
+The image must not be mounted. Mounted, snapshots are read under /.snapshots and
+mkdir or rmdir there takes and deletes them.";
+
+enum Op {
+    List,
+    Create(String),
+    Delete(String),
+    Rollback(String),
+}
+
+fn usage_error(msg: &str) -> ! {
+    eprintln!("snapshot: {msg}\n{USAGE}");
+    process::exit(2);
+}
+
+fn fail(msg: String) -> ! {
+    eprintln!("snapshot: {msg}");
+    process::exit(1);
+}
+
+fn main() {
+    env_logger::init();
+    let mut op = Op::List;
+    let mut path = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| {
+            args.next()
+                .unwrap_or_else(|| usage_error(&format!("{name} needs a value")))
+        };
+        match arg.as_str() {
+            "-c" => op = Op::Create(value("-c")),
+            "-d" => op = Op::Delete(value("-d")),
+            "-r" => op = Op::Rollback(value("-r")),
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                return;
+            }
+            _ if path.is_none() && !arg.starts_with('-') => path = Some(arg),
+            _ => usage_error(&format!("unexpected argument {arg}")),
+        }
+    }
+    let path = path.unwrap_or_else(|| usage_error("missing image path"));
+
+    let image = OpenOptions::new()
+        .read(true)
+        .write(!matches!(op, Op::List))
+        .open(&path)
+        .unwrap_or_else(|err| fail(format!("cannot open {path}: {err}")));
+    if !matches!(op, Op::List) {
+        journal::recover(&image).unwrap_or_else(|err| fail(format!("{path}: {err}")));
+    }
+    let mut state = FSState::load(&image).unwrap_or_else(|err| fail(format!("{path}: {err}")));
+
+    let result = match &op {
+        Op::List => {
+            for (id, name, created) in state.list_snapshots() {
+                let secs = created.duration_since(UNIX_EPOCH).unwrap_or_default();
+                println!("{id}\t{}\t{}", name.to_string_lossy(), secs.as_secs());
+            }
+            return;
+        }
+        Op::Create(name) => state.create_snapshot(OsStr::new(name)).map(|_| ()),
+        Op::Delete(name) => state.delete_snapshot(OsStr::new(name)),
+        Op::Rollback(name) => state.rollback_snapshot(OsStr::new(name)),
+    };
+    if let Err(err) = result {
+        fail(format!("{path}: {}", io::Error::from_raw_os_error(err)));
+    }
+    state
+        .commit(&image)
+        .unwrap_or_else(|err| fail(format!("{path}: {err}")));
+}
@@ -0,0 +1,513 @@
+use crate::extent::MAX_EXTENT_LBLKS;
+use crate::{BlockError, FSState, Inode, INODE_FLAG_EXTENTS, INVALID_PTR, NUM_INO_DIRECT_PTR};
+use std::mem;
+
+// Logical blocks are mapped like ext2: the first NUM_INO_DIRECT_PTR through direct_blks, then
+// one, two and three levels of pointer blocks below indirect_blk, dbl_indirect_blk and
+// tri_indirect_blk. A pointer block is blk_size / 4 little-endian u32 block numbers. Inodes
+// with INODE_FLAG_EXTENTS keep an extent tree there instead, see extent.rs.
+const NUM_ROOT_PTRS: usize = NUM_INO_DIRECT_PTR + 3;
+
+// Where a logical block lives: the inode pointer to start from (see root_ptr) and the index to
+// follow in each pointer block below it
+struct BlkPath {
+    root: usize,
+    idxs: Vec<usize>,
+}
+
+// The blocks right below the inode, which sharing it takes a reference to
+pub(crate) fn tree_roots(inode: &Inode) -> Vec<u32> {
+    // Inline data sits where the block pointers would be
+    if inode.has_inline_data() {
+        return Vec::new();
+    }
+    if inode.has_extents() {
+        return inode.extent_root_blks();
+    }
+    let mut blks = inode.direct_blks.to_vec();
+    blks.extend([
+        inode.indirect_blk,
+        inode.dbl_indirect_blk,
+        inode.tri_indirect_blk,
+    ]);
+    blks.retain(|&blk_no| blk_no != INVALID_PTR);
+    blks
+}
+
+fn root_ptr(inode: &mut Inode, root: usize) -> &mut u32 {
+    match root {
+        root if root < NUM_INO_DIRECT_PTR => &mut inode.direct_blks[root],
+        NUM_INO_DIRECT_PTR => &mut inode.indirect_blk,
+        13 => &mut inode.dbl_indirect_blk,
+        _ => &mut inode.tri_indirect_blk,
+    }
+}
+
+impl FSState {
+    fn ptrs_per_blk(&self) -> u64 {
+        self.blk_size() / 4
+    }
+
+    // First logical block and number of pointer levels below each root pointer
+    fn root_range(&self, root: usize) -> (u64, u32) {
+        let n = self.ptrs_per_blk();
+        match root {
+            root if root < NUM_INO_DIRECT_PTR => (root as u64, 0),
+            NUM_INO_DIRECT_PTR => (NUM_INO_DIRECT_PTR as u64, 1),
+            13 => (NUM_INO_DIRECT_PTR as u64 + n, 2),
+            _ => (NUM_INO_DIRECT_PTR as u64 + n + n * n, 3),
+        }
+    }
+
+    // Number of logical blocks inode can map
+    pub(crate) fn max_lblks(&self, inode: &Inode) -> u64 {
+        if inode.has_extents() {
+            return MAX_EXTENT_LBLKS;
+        }
+        let (start, depth) = self.root_range(NUM_ROOT_PTRS - 1);
+        start + self.ptrs_per_blk().pow(depth)
+    }
+
+    fn blk_path(&self, lblk: u64) -> Option<BlkPath> {
+        let n = self.ptrs_per_blk();
+        let root = (0..NUM_ROOT_PTRS)
+            .rev()
+            .find(|&root| self.root_range(root).0 <= lblk)?;
+        let (start, depth) = self.root_range(root);
+        let mut rel = lblk - start;
+        if rel >= n.pow(depth) {
+            return None;
+        }
+        let mut idxs = vec![0; depth as usize];
+        for idx in idxs.iter_mut().rev() {
+            *idx = (rel % n) as usize;
+            rel /= n;
+        }
+        Some(BlkPath { root, idxs })
+    }
+
+    // Missing pointer blocks read as all holes
+    fn ptr_at(&self, blk_no: u32, idx: usize) -> u32 {
+        self.blk_data(blk_no).map_or(INVALID_PTR, |data| {
+            u32::from_le_bytes(data[idx * 4..idx * 4 + 4].try_into().unwrap())
+        })
+    }
+
+    fn set_ptr(&mut self, blk_no: u32, idx: usize, ptr: u32) -> Result<(), BlockError> {
+        self.blk_data_mut(blk_no)?[idx * 4..idx * 4 + 4].copy_from_slice(&ptr.to_le_bytes());
+        Ok(())
+    }
+
+    // Physical block backing logical block lblk of inode, INVALID_PTR for a hole
+    pub(crate) fn bmap(&self, inode: &Inode, lblk: u64) -> Result<u32, BlockError> {
+        if inode.has_extents() {
+            return match lblk < MAX_EXTENT_LBLKS {
+                true => Ok(self.extent_bmap(inode, lblk)),
+                false => Err(BlockError::FileTooLarge),
+            };
+        }
+        let path = self.blk_path(lblk).ok_or(BlockError::FileTooLarge)?;
+        let mut inode = *inode;
+        let mut blk_no = *root_ptr(&mut inode, path.root);
+        for idx in path.idxs {
+            if blk_no == INVALID_PTR {
+                break;
+            }
+            blk_no = self.ptr_at(blk_no, idx);
+        }
+        Ok(blk_no)
+    }
+
+    // Like bmap, but allocates the data block and any pointer blocks missing on the way.
+    // Every block allocated is counted in inode.blocks, even when a later allocation fails.
+    pub(crate) fn bmap_alloc(&mut self, inode: &mut Inode, lblk: u64) -> Result<u32, BlockError> {
+        self.bmap_write(inode, lblk, 1)
+    }
+
+    // Like bmap_alloc, when want blocks from lblk on are about to be written, so they can be
+    // given blocks in one piece
+    pub(crate) fn bmap_alloc_run(
+        &mut self,
+        inode: &mut Inode,
+        lblk: u64,
+        want: u64,
+    ) -> Result<u32, BlockError> {
+        self.bmap_write(inode, lblk, want)
+    }
+
+    // Like bmap, for changing the block in place. Holes stay holes.
+    pub(crate) fn bmap_mut(&mut self, inode: &mut Inode, lblk: u64) -> Result<u32, BlockError> {
+        self.bmap_write(inode, lblk, 0)
+    }
+
+    // want is how many blocks from lblk on are about to be written, 0 leaves holes as they are
+    fn bmap_write(&mut self, inode: &mut Inode, lblk: u64, want: u64) -> Result<u32, BlockError> {
+        if inode.has_extents() {
+            return self.extent_bmap_write(inode, lblk, want);
+        }
+        let path = self.blk_path(lblk).ok_or(BlockError::FileTooLarge)?;
+        self.write_path(inode, path.root, &path.idxs, want)
+    }
+
+    // Follows idxs from the root pointer and returns the block reached. Blocks shared with a
+    // snapshot or another file are copied on the way down, see snapshot.rs.
+    fn write_path(
+        &mut self,
+        inode: &mut Inode,
+        root: usize,
+        idxs: &[usize],
+        want: u64,
+    ) -> Result<u32, BlockError> {
+        let mut depth = self.root_range(root).1;
+        let mut blk_no = self.unshare_blk(*root_ptr(inode, root), depth)?;
+        if blk_no == INVALID_PTR {
+            if want == 0 {
+                return Ok(INVALID_PTR);
+            }
+            blk_no = self.alloc_blk_for(self.inode_goal(inode.ino_id), want)?;
+            inode.blocks += 1;
+        }
+        *root_ptr(inode, root) = blk_no;
+        for &idx in idxs {
+            depth -= 1;
+            let ptr = self.ptr_at(blk_no, idx);
+            let mut next = self.unshare_blk(ptr, depth)?;
+            if next == INVALID_PTR {
+                if want == 0 {
+                    return Ok(INVALID_PTR);
+                }
+                next = self.alloc_blk_for(self.inode_goal(inode.ino_id), want)?;
+                inode.blocks += 1;
+            }
+            if next != ptr {
+                self.set_ptr(blk_no, idx, next)?;
+            }
+            blk_no = next;
+        }
+        Ok(blk_no)
+    }
+
+    // Maps logical block lblk to blk_no, which gains a reference, in place of whatever block
+    // was there before
+    pub(crate) fn bmap_share(
+        &mut self,
+        inode: &mut Inode,
+        lblk: u64,
+        blk_no: u32,
+    ) -> Result<(), BlockError> {
+        let old = match inode.has_extents() {
+            true => self.extent_set(inode, lblk, blk_no)?,
+            false => {
+                let path = self.blk_path(lblk).ok_or(BlockError::FileTooLarge)?;
+                match path.idxs.split_last() {
+                    None => mem::replace(root_ptr(inode, path.root), blk_no),
+                    Some((&idx, above)) => {
+                        let parent = self.write_path(inode, path.root, above, 1)?;
+                        let old = self.ptr_at(parent, idx);
+                        self.set_ptr(parent, idx, blk_no)?;
+                        old
+                    }
+                }
+            }
+        };
+        self.share_blk(blk_no);
+        match old {
+            INVALID_PTR => inode.blocks += 1,
+            old => self.free_blk(old)?,
+        }
+        Ok(())
+    }
+
+    // Points inode, which maps nothing yet, at every block from maps, the same way. Each tree
+    // gains a reference at its root.
+    pub(crate) fn share_tree(
+        &mut self,
+        from: &mut Inode,
+        inode: &mut Inode,
+    ) -> Result<(), BlockError> {
+        if from.has_extents() {
+            self.push_down_extents(from)?;
+        }
+        for blk_no in tree_roots(from) {
+            self.share_blk(blk_no);
+        }
+        inode.set_ptr_area(&from.ptr_area());
+        inode.flags = inode.flags & !INODE_FLAG_EXTENTS | from.flags & INODE_FLAG_EXTENTS;
+        // The xattr block stays with from
+        inode.blocks += from.blocks - (from.xattr_blk != INVALID_PTR) as u32;
+        Ok(())
+    }
+
+    // Frees every data block from logical block first_lblk on, along with the pointer blocks
+    // left with nothing below them
+    pub(crate) fn free_blks_from(
+        &mut self,
+        inode: &mut Inode,
+        first_lblk: u64,
+    ) -> Result<(), BlockError> {
+        if inode.has_extents() {
+            return self.extent_free_from(inode, first_lblk);
+        }
+        for root in 0..NUM_ROOT_PTRS {
+            let (start, depth) = self.root_range(root);
+            let blk_no = *root_ptr(inode, root);
+            if blk_no == INVALID_PTR || first_lblk >= start + self.ptrs_per_blk().pow(depth) {
+                continue;
+            }
+            let from = first_lblk.saturating_sub(start);
+            *root_ptr(inode, root) = self.free_subtree(inode, blk_no, depth, from)?;
+        }
+        Ok(())
+    }
+
+    // Frees the part of the tree under blk_no mapping relative blocks from on. Returns where
+    // blk_no is now: INVALID_PTR once freed, or a copy when it was shared with a snapshot and
+    // had to change.
+    fn free_subtree(
+        &mut self,
+        inode: &mut Inode,
+        blk_no: u32,
+        depth: u32,
+        from: u64,
+    ) -> Result<u32, BlockError> {
+        // Dropping the reference to a shared subtree leaves it to whoever else has one
+        if from == 0 && self.blk_refs(blk_no) > 1 {
+            inode.blocks -= self.subtree_blks(blk_no, depth);
+            self.free_blk(blk_no)?;
+            return Ok(INVALID_PTR);
+        }
+        let blk_no = match from {
+            0 => blk_no,
+            _ => self.unshare_blk(blk_no, depth)?,
+        };
+        if depth > 0 {
+            let n = self.ptrs_per_blk();
+            let span = n.pow(depth - 1);
+            for idx in (from / span) as usize..n as usize {
+                let child = self.ptr_at(blk_no, idx);
+                if child == INVALID_PTR {
+                    continue;
+                }
+                let child_from = from.saturating_sub(idx as u64 * span);
+                let kept = self.free_subtree(inode, child, depth - 1, child_from)?;
+                if kept != child {
+                    self.set_ptr(blk_no, idx, kept)?;
+                }
+            }
+        }
+        let empty = depth > 0
+            && (0..self.ptrs_per_blk() as usize).all(|idx| self.ptr_at(blk_no, idx) == INVALID_PTR);
+        if from == 0 || empty {
+            self.free_blk(blk_no)?;
+            inode.blocks -= 1;
+            return Ok(INVALID_PTR);
+        }
+        Ok(blk_no)
+    }
+
+    // Number of blocks in the tree under blk_no, itself included
+    fn subtree_blks(&self, blk_no: u32, depth: u32) -> u32 {
+        let mut count = 1;
+        if depth > 0 {
+            for idx in 0..self.ptrs_per_blk() as usize {
+                let child = self.ptr_at(blk_no, idx);
+                if child != INVALID_PTR {
+                    count += self.subtree_blks(child, depth - 1);
+                }
+            }
+        }
+        count
+    }
+
+    // Makes every block of inode's tree its own, for changes that find their blocks with bmap
+    pub(crate) fn unshare_tree(&mut self, inode: &mut Inode) -> Result<(), BlockError> {
+        if self.refcounts.is_empty() {
+            return Ok(());
+        }
+        if inode.has_extents() {
+            return self.extent_unshare_tree(inode);
+        }
+        for root in 0..NUM_ROOT_PTRS {
+            let blk_no = *root_ptr(inode, root);
+            if blk_no != INVALID_PTR {
+                *root_ptr(inode, root) = self.unshare_subtree(blk_no, self.root_range(root).1)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Parents first, since the copy of a pointer block shares its children
+    fn unshare_subtree(&mut self, blk_no: u32, depth: u32) -> Result<u32, BlockError> {
+        let blk_no = self.unshare_blk(blk_no, depth)?;
+        if depth > 0 {
+            for idx in 0..self.ptrs_per_blk() as usize {
+                let child = self.ptr_at(blk_no, idx);
+                if child == INVALID_PTR {
+                    continue;
+                }
+                let copy = self.unshare_subtree(child, depth - 1)?;
+                if copy != child {
+                    self.set_ptr(blk_no, idx, copy)?;
+                }
+            }
+        }
+        Ok(blk_no)
+    }
+
+    // Moves every block of inode's tree that must not be written in place, see cow.rs, and
+    // points the blocks above it at the copy. Returns whether a root pointer changed.
+    pub(crate) fn cow_tree(&mut self, inode: &mut Inode) -> Result<bool, BlockError> {
+        if inode.has_extents() {
+            return self.extent_cow_tree(inode);
+        }
+        let mut moved = false;
+        for root in 0..NUM_ROOT_PTRS {
+            let blk_no = *root_ptr(inode, root);
+            if blk_no == INVALID_PTR {
+                continue;
+            }
+            let copy = self.cow_subtree(blk_no, self.root_range(root).1)?;
+            if copy != blk_no {
+                *root_ptr(inode, root) = copy;
+                moved = true;
+            }
+        }
+        Ok(moved)
+    }
+
+    // Children first, since pointing at a copy changes a pointer block. Returns where blk_no
+    // ended up.
+    fn cow_subtree(&mut self, blk_no: u32, depth: u32) -> Result<u32, BlockError> {
+        if depth > 0 {
+            for idx in 0..self.ptrs_per_blk() as usize {
+                let child = self.ptr_at(blk_no, idx);
+                if child == INVALID_PTR {
+                    continue;
+                }
+                let copy = self.cow_subtree(child, depth - 1)?;
+                if copy != child {
+                    self.set_ptr(blk_no, idx, copy)?;
+                }
+            }
+        }
+        self.cow_blk(blk_no)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fsck;
+    use crate::image::Geometry;
+    use crate::test_util::state_with_file;
+
+    // 1 KiB blocks, so each pointer block holds 256 pointers
+    const N: u64 = 256;
+    const IND: u64 = NUM_INO_DIRECT_PTR as u64;
+    const DBL: u64 = IND + N;
+    const TRI: u64 = DBL + N * N;
+
+    fn geometry() -> Geometry {
+        Geometry::new(1 << 20, 1024, 16).unwrap()
+    }
+
+    // Maps lblk and returns how many blocks that took
+    fn alloc(state: &mut FSState, inode: &mut Inode, lblk: u64) -> u32 {
+        let before = inode.blocks;
+        let blk_no = state.bmap_alloc(inode, lblk).unwrap();
+        assert_ne!(blk_no, INVALID_PTR);
+        assert_eq!(state.bmap(inode, lblk).unwrap(), blk_no);
+        inode.blocks - before
+    }
+
+    #[test]
+    fn test_direct_blocks_need_no_pointer_blocks() {
+        let (mut state, ino_id) = state_with_file(geometry());
+        let mut inode = *state.inode(ino_id).unwrap();
+        assert_eq!(alloc(&mut state, &mut inode, 0), 1);
+        assert_eq!(alloc(&mut state, &mut inode, IND - 1), 1);
+        assert_eq!(inode.indirect_blk, INVALID_PTR);
+        assert_eq!(state.bmap(&inode, 1).unwrap(), INVALID_PTR);
+    }
+
+    #[test]
+    fn test_each_level_allocates_its_pointer_blocks() {
+        let (mut state, ino_id) = state_with_file(geometry());
+        let mut inode = *state.inode(ino_id).unwrap();
+        assert_eq!(alloc(&mut state, &mut inode, IND), 2);
+        assert_eq!(alloc(&mut state, &mut inode, DBL - 1), 1);
+        assert_eq!(alloc(&mut state, &mut inode, DBL), 3);
+        assert_eq!(alloc(&mut state, &mut inode, DBL + N), 2);
+        assert_eq!(alloc(&mut state, &mut inode, TRI - 1), 2);
+        assert_eq!(alloc(&mut state, &mut inode, TRI), 4);
+        let last = state.max_lblks(&inode) - 1;
+        assert_eq!(alloc(&mut state, &mut inode, last), 3);
+        assert_ne!(inode.tri_indirect_blk, INVALID_PTR);
+        assert_eq!(state.bmap(&inode, TRI + 1).unwrap(), INVALID_PTR);
+
+        *state.inode_mut(inode.ino_id).unwrap() = inode;
+        assert_eq!(fsck::check(&state), vec![]);
+    }
+
+    #[test]
+    fn test_beyond_triple_indirect_is_too_large() {
+        let (mut state, ino_id) = state_with_file(geometry());
+        let mut inode = *state.inode(ino_id).unwrap();
+        let max = state.max_lblks(&inode);
+        assert_eq!(max, TRI + N * N * N);
+        assert!(matches!(
+            state.bmap_alloc(&mut inode, max),
+            Err(BlockError::FileTooLarge)
+        ));
+        assert!(matches!(
+            state.bmap(&inode, max),
+            Err(BlockError::FileTooLarge)
+        ));
+    }
+
+    #[test]
+    fn test_free_whole_tree() {
+        let (mut state, ino_id) = state_with_file(geometry());
+        let mut inode = *state.inode(ino_id).unwrap();
+        let free_before = state.metadata.free_blk_count;
+        for lblk in [0, IND, DBL + N + 3, TRI + N * N + 7] {
+            alloc(&mut state, &mut inode, lblk);
+        }
+
+        state.free_blks_from(&mut inode, 0).unwrap();
+        assert_eq!(inode.blocks, 0);
+        assert_eq!(state.metadata.free_blk_count, free_before);
+        assert_eq!(inode.direct_blks, [INVALID_PTR; NUM_INO_DIRECT_PTR]);
+        assert_eq!(inode.indirect_blk, INVALID_PTR);
+        assert_eq!(inode.dbl_indirect_blk, INVALID_PTR);
+        assert_eq!(inode.tri_indirect_blk, INVALID_PTR);
+    }
+
+    #[test]
+    fn test_free_from_level_boundaries() {
+        let (mut state, ino_id) = state_with_file(geometry());
+        let mut inode = *state.inode(ino_id).unwrap();
+        for lblk in [IND - 1, IND, IND + 1, DBL, DBL + N] {
+            alloc(&mut state, &mut inode, lblk);
+        }
+
+        // The double indirect tree goes, leaving two indirect blocks with data below them
+        state.free_blks_from(&mut inode, DBL).unwrap();
+        assert_eq!(inode.dbl_indirect_blk, INVALID_PTR);
+        assert_eq!(inode.blocks, 4);
+
+        // Partially emptied pointer blocks stay
+        state.free_blks_from(&mut inode, IND + 1).unwrap();
+        assert_ne!(inode.indirect_blk, INVALID_PTR);
+        assert_eq!(state.bmap(&inode, IND + 1).unwrap(), INVALID_PTR);
+        assert_eq!(inode.blocks, 3);
+
+        state.free_blks_from(&mut inode, IND).unwrap();
+        assert_eq!(inode.indirect_blk, INVALID_PTR);
+        assert_eq!(inode.blocks, 1);
+        assert_ne!(state.bmap(&inode, IND - 1).unwrap(), INVALID_PTR);
+
+        *state.inode_mut(inode.ino_id).unwrap() = inode;
+        assert_eq!(fsck::check(&state), vec![]);
+    }
+}
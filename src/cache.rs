@@ -0,0 +1,397 @@
+use crate::image::Device;
+use log::error;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Arc;
+
+// The blocks past the reserved area, which hold pointers, directories, extents, xattrs and file
+// contents, are kept here by block number. The bitmaps, group descriptors and inode table are
+// kept apart from them and always in memory.
+//
+// A state loaded with FSState::load has every block in here. One opened with FSState::open
+// keeps at most cap of them and reads the others from its image when they are asked for,
+// evicting the block used least recently to make room. Blocks changed since the last commit or
+// save are dirty and stay until it writes them, as the image does not have them yet. Journaled
+// images commit after every request, and fs.rs commits images without a journal once their
+// dirty blocks outnumber cap, so the cap is only exceeded for the length of one request.
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub resident: u64,
+    pub dirty: u64,
+}
+
+struct Entry {
+    data: Arc<[u8]>,
+    used: u64, // When it was last asked for, its key in Lru::order while clean
+}
+
+#[derive(Default)]
+struct Lru {
+    entries: HashMap<u32, Entry>,
+    order: BTreeMap<u64, u32>, // Clean blocks, least recently used first
+    dirty: HashSet<u32>,
+    clock: u64,
+    stats: CacheStats,
+}
+
+impl Lru {
+    fn touch(&mut self, blk_no: u32) -> Option<Arc<[u8]>> {
+        self.clock += 1;
+        let entry = self.entries.get_mut(&blk_no)?;
+        if !self.dirty.contains(&blk_no) {
+            self.order.remove(&entry.used);
+            self.order.insert(self.clock, blk_no);
+        }
+        entry.used = self.clock;
+        Some(entry.data.clone())
+    }
+
+    fn insert(&mut self, blk_no: u32, data: Arc<[u8]>, dirty: bool) {
+        self.remove(blk_no);
+        self.clock += 1;
+        if dirty {
+            self.dirty.insert(blk_no);
+        } else {
+            self.order.insert(self.clock, blk_no);
+        }
+        let used = self.clock;
+        self.entries.insert(blk_no, Entry { data, used });
+    }
+
+    fn remove(&mut self, blk_no: u32) -> Option<Arc<[u8]>> {
+        let entry = self.entries.remove(&blk_no)?;
+        if !self.dirty.remove(&blk_no) {
+            self.order.remove(&entry.used);
+        }
+        Some(entry.data)
+    }
+
+    fn set_dirty(&mut self, blk_no: u32) {
+        if let Some(entry) = self.entries.get(&blk_no) {
+            if self.dirty.insert(blk_no) {
+                self.order.remove(&entry.used);
+            }
+        }
+    }
+
+    fn evict(&mut self, cap: usize) {
+        while self.entries.len() > cap {
+            let Some((_, blk_no)) = self.order.pop_first() else {
+                return;
+            };
+            self.entries.remove(&blk_no);
+            self.stats.evictions += 1;
+        }
+    }
+}
+
+pub(crate) struct BlockCache {
+    lru: RefCell<Lru>,
+    device: Option<Box<dyn Device + Send>>, // Where blocks not in memory are read from
+    blk_size: usize,
+    cap: usize,
+}
+
+impl BlockCache {
+    // Keeps every block it is given, with nothing to read the others from
+    pub(crate) fn new(blk_size: usize) -> Self {
+        Self {
+            lru: RefCell::default(),
+            device: None,
+            blk_size,
+            cap: usize::MAX,
+        }
+    }
+
+    // Keeps up to cap blocks of device in memory
+    pub(crate) fn with_device(blk_size: usize, device: Box<dyn Device + Send>, cap: usize) -> Self {
+        Self {
+            device: Some(device),
+            cap: cap.max(1),
+            ..Self::new(blk_size)
+        }
+    }
+
+    fn read(&self, blk_no: u32) -> Option<Arc<[u8]>> {
+        let device = self.device.as_ref()?;
+        let mut data = vec![0; self.blk_size];
+        match device.read_bytes(&mut data, blk_no as u64 * self.blk_size as u64) {
+            Ok(()) => Some(data.into()),
+            Err(err) => {
+                error!("Failed to read block {blk_no}: {err}");
+                None
+            }
+        }
+    }
+
+    // Block blk_no, read from the device on a miss when on_disk says the device has it. None
+    // when it is neither in memory nor on disk.
+    pub(crate) fn get(&self, blk_no: u32, on_disk: bool) -> Option<Arc<[u8]>> {
+        let mut lru = self.lru.borrow_mut();
+        if let Some(data) = lru.touch(blk_no) {
+            lru.stats.hits += 1;
+            return Some(data);
+        }
+        if !on_disk || self.device.is_none() {
+            return None;
+        }
+        lru.stats.misses += 1;
+        let data = self.read(blk_no)?;
+        lru.insert(blk_no, data.clone(), false);
+        lru.evict(self.cap);
+        Some(data)
+    }
+
+    // Like get, but a block read from the device is not kept. For file contents, which the
+    // kernel caches itself, so reading a large file does not push out the metadata.
+    pub(crate) fn get_uncached(&self, blk_no: u32, on_disk: bool) -> Option<Arc<[u8]>> {
+        let mut lru = self.lru.borrow_mut();
+        if let Some(data) = lru.touch(blk_no) {
+            lru.stats.hits += 1;
+            return Some(data);
+        }
+        if !on_disk || self.device.is_none() {
+            return None;
+        }
+        lru.stats.misses += 1;
+        drop(lru);
+        self.read(blk_no)
+    }
+
+    // Block blk_no for changing, which makes it dirty. A block that is not on disk starts out as
+    // zeros. None when it is on disk but cannot be read, zeros in its place would be written
+    // back over it.
+    pub(crate) fn get_mut(&mut self, blk_no: u32, on_disk: bool) -> Option<&mut [u8]> {
+        if !self.lru.get_mut().entries.contains_key(&blk_no) {
+            let data = match on_disk && self.device.is_some() {
+                true => {
+                    self.lru.get_mut().stats.misses += 1;
+                    self.read(blk_no)?
+                }
+                false => vec![0; self.blk_size].into(),
+            };
+            self.lru.get_mut().insert(blk_no, data, true);
+        }
+        let lru = self.lru.get_mut();
+        lru.set_dirty(blk_no);
+        lru.evict(self.cap);
+        Some(Arc::make_mut(
+            &mut lru.entries.get_mut(&blk_no).unwrap().data,
+        ))
+    }
+
+    // A newly allocated block, all zeros until written
+    pub(crate) fn insert_zeroed(&mut self, blk_no: u32) {
+        let data = vec![0; self.blk_size].into();
+        self.lru.get_mut().insert(blk_no, data, true);
+    }
+
+    // Puts data in place of blk_no. Clean data must be what the device has.
+    pub(crate) fn insert(&mut self, blk_no: u32, data: Arc<[u8]>, dirty: bool) {
+        let lru = self.lru.get_mut();
+        lru.insert(blk_no, data, dirty);
+        lru.evict(self.cap);
+    }
+
+    // Forgets blk_no, for a block that was freed
+    pub(crate) fn remove(&mut self, blk_no: u32) -> Option<Arc<[u8]>> {
+        self.lru.get_mut().remove(blk_no)
+    }
+
+    // Everything was written to the device, so any block can be evicted now
+    pub(crate) fn mark_clean(&mut self) {
+        let lru = self.lru.get_mut();
+        for blk_no in std::mem::take(&mut lru.dirty) {
+            lru.clock += 1;
+            lru.entries.get_mut(&blk_no).unwrap().used = lru.clock;
+            lru.order.insert(lru.clock, blk_no);
+        }
+        lru.evict(self.cap);
+    }
+
+    // More blocks are dirty than the cache is meant to hold
+    pub(crate) fn over_cap(&self) -> bool {
+        self.lru.borrow().dirty.len() > self.cap
+    }
+
+    pub(crate) fn stats(&self) -> CacheStats {
+        let lru = self.lru.borrow();
+        CacheStats {
+            resident: lru.entries.len() as u64,
+            dirty: lru.dirty.len() as u64,
+            ..lru.stats
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crash::CrashDevice;
+    use crate::image::{self, Geometry};
+    use crate::{fsck, FSState, ROOT_INO};
+    use fuser::FileType;
+    use libc::EIO;
+    use std::ffi::OsStr;
+    use std::fs::OpenOptions;
+    use tempdir::TempDir;
+
+    const BLK_SIZE: usize = 1024;
+
+    fn device(blks: u32) -> Box<CrashDevice> {
+        let data = (0..blks as usize * BLK_SIZE)
+            .map(|i| (i / BLK_SIZE) as u8)
+            .collect();
+        Box::new(CrashDevice::new(data, None, false))
+    }
+
+    #[test]
+    fn test_least_recently_used_is_evicted() {
+        let cache = BlockCache::with_device(BLK_SIZE, device(8), 2);
+        assert_eq!(cache.get(1, true).unwrap()[0], 1);
+        cache.get(2, true);
+        cache.get(1, true);
+        cache.get(3, true);
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.evictions), (1, 3, 1));
+
+        // 2 went, 1 was used after it
+        cache.get(1, true);
+        assert_eq!(cache.stats().misses, 3);
+        cache.get(2, true);
+        assert_eq!(cache.stats().misses, 4);
+        assert_eq!(cache.stats().resident, 2);
+    }
+
+    #[test]
+    fn test_dirty_blocks_stay_until_clean() {
+        let mut cache = BlockCache::with_device(BLK_SIZE, device(8), 1);
+        cache.get_mut(1, true).unwrap()[0] = 0xaa;
+        cache.get_mut(2, false).unwrap()[0] = 0xbb;
+        cache.get(3, true);
+        let stats = cache.stats();
+        assert_eq!((stats.resident, stats.dirty), (2, 2));
+        assert_eq!(cache.get(1, true).unwrap()[..2], [0xaa, 1]);
+
+        cache.mark_clean();
+        assert_eq!(cache.stats().resident, 1);
+        assert_eq!(cache.stats().dirty, 0);
+    }
+
+    #[test]
+    fn test_missing_blocks_are_not_read() {
+        let cache = BlockCache::with_device(BLK_SIZE, device(8), 4);
+        assert!(cache.get(1, false).is_none());
+        assert!(cache.get_uncached(1, true).is_some());
+        assert_eq!(cache.stats().resident, 0);
+        assert!(BlockCache::new(BLK_SIZE).get(1, true).is_none());
+    }
+
+    #[test]
+    fn test_unreadable_blocks_cannot_be_changed() {
+        let mut cache = BlockCache::with_device(BLK_SIZE, device(2), 4);
+        assert!(cache.get_mut(5, true).is_none());
+        assert_eq!(cache.stats().resident, 0);
+        assert!(cache.get_mut(5, false).is_some());
+    }
+
+    #[test]
+    fn test_open_reads_blocks_on_demand() {
+        let dir = TempDir::new("rustyfs").unwrap();
+        let path = dir.path().join("image");
+        let image = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        let geometry = Geometry::new(4 << 20, 1024, 64)
+            .unwrap()
+            .with_journal(64)
+            .unwrap();
+        image::format(&image, geometry).unwrap();
+        let mut state = FSState::load(&image).unwrap();
+        let mut files = Vec::new();
+        for i in 0..16u8 {
+            let name = format!("file{i}");
+            let ino_id = state
+                .make_node(ROOT_INO, OsStr::new(&name), FileType::RegularFile, 0o644)
+                .unwrap();
+            state.write_at(ino_id, 0, &[i; 20 * 1024]).unwrap();
+            files.push(ino_id);
+        }
+        state.save(&image).unwrap();
+
+        let mut state = FSState::open(image.try_clone().unwrap(), 8 * 1024).unwrap();
+        assert!(state.cache_stats().resident <= 8);
+        for (i, &ino_id) in files.iter().enumerate() {
+            let mut buf = vec![0; 20 * 1024];
+            assert_eq!(state.read_at(ino_id, 0, &mut buf), Ok(buf.len()));
+            assert!(buf.iter().all(|&byte| byte == i as u8));
+        }
+        assert!(state.cache_stats().resident <= 8);
+        assert!(state.cache_stats().misses > 0);
+        assert_eq!(fsck::check(&state), vec![]);
+
+        // Changes stay in memory until committed, which the cap calls for once they outgrow it
+        let ino_id = files[0];
+        state.write_at(ino_id, 1024, &[0xee; 1024]).unwrap();
+        assert!(!state.cache_over_cap());
+        state.write_at(ino_id, 0, &[0xee; 20 * 1024]).unwrap();
+        assert!(state.cache_over_cap());
+        state.commit(&image).unwrap();
+        assert!(!state.cache_over_cap());
+        assert_eq!(state.cache_stats().dirty, 0);
+        assert!(state.cache_stats().resident <= 8);
+        let mut buf = vec![0; 20 * 1024];
+        state.read_at(ino_id, 0, &mut buf).unwrap();
+        assert!(buf.iter().all(|&byte| byte == 0xee));
+
+        let state = FSState::load(&image).unwrap();
+        assert_eq!(fsck::check(&state), vec![]);
+
+        // Once the blocks past the metadata cannot be read, changing them fails with EIO
+        let mut state = FSState::open(image.try_clone().unwrap(), 8 * 1024).unwrap();
+        image
+            .set_len(geometry.reserved_blks() as u64 * 1024)
+            .unwrap();
+        assert_eq!(state.write_at(files[1], 0, &[0xee; 10]), Err(EIO));
+        assert!(state.dirty_data.is_empty());
+    }
+
+    #[test]
+    fn test_commit_without_a_journal_only_writes_what_changed() {
+        let dir = TempDir::new("rustyfs").unwrap();
+        let image = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(dir.path().join("image"))
+            .unwrap();
+        image::format(&image, Geometry::new(4 << 20, 1024, 64).unwrap()).unwrap();
+        let mut state = FSState::load(&image).unwrap();
+        let ino_id = state
+            .make_node(ROOT_INO, OsStr::new("file"), FileType::RegularFile, 0o644)
+            .unwrap();
+        state.write_at(ino_id, 0, &[1; 200 * 1024]).unwrap();
+        state.save(&image).unwrap();
+
+        let mut state = FSState::open(image.try_clone().unwrap(), 8 * 1024).unwrap();
+        state.write_at(ino_id, 1024, &[2; 10]).unwrap();
+        let misses = state.cache_stats().misses;
+        state.commit(&image).unwrap();
+        assert_eq!(state.cache_stats().misses, misses);
+        assert_eq!(state.cache_stats().dirty, 0);
+
+        let state = FSState::load(&image).unwrap();
+        let mut buf = [0; 12];
+        state.read_at(ino_id, 1023, &mut buf).unwrap();
+        assert_eq!(buf, [1, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 1]);
+        assert_eq!(fsck::check(&state), vec![]);
+    }
+}
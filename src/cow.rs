@@ -0,0 +1,356 @@
+use crate::image::{write_blk, Device, ImageError};
+use crate::{BlockError, FSMetadata, FSState, INODE_SIZE_BYTES, INVALID_PTR, SUPER_BLK_NO};
+use log::{debug, warn};
+
+// Copy-on-write images never overwrite a block the committed image uses. Blocks before the
+// journal (the superblock, bitmaps and inode table) have two copies, the second one in the
+// shadow region, see image::Geometry. The superblock records which copy of each is current in
+// FSMetadata.shadow, and a commit writes the changed ones to the other copy. Data blocks, which
+// includes pointer, directory and xattr blocks, are moved to a block the committed image does
+// not use instead, and whatever points at them is updated in turn up to the inode. Finally the
+// superblock goes to the slot it was not read from, with a higher generation, and that single
+// write switches the image over. On load the newest intact slot wins.
+impl FSMetadata {
+    // Where the current copy of blk_no is on disk
+    pub(crate) fn current_blk_no(&self, blk_no: u32) -> u32 {
+        let geometry = self.geometry();
+        match self.cow && blk_no < geometry.journal_blk_no() && self.shadow[blk_no as usize] {
+            true => geometry.shadow_blk_no() + blk_no,
+            false => blk_no,
+        }
+    }
+}
+
+impl FSState {
+    // Blocks freed since the last commit may still be in use on disk, so allocation skips them
+    // as well as the ones set in the bitmap
+    pub(crate) fn taken_on_disk(&self) -> Vec<&[u8]> {
+        match self.metadata.cow {
+            true => vec![self.committed.as_raw_slice()],
+            false => Vec::new(),
+        }
+    }
+
+    // Whether blk_no changed since the last commit while the image on disk still uses it
+    fn must_copy(&self, blk_no: u32) -> bool {
+        self.dirty.contains(&blk_no)
+            && self
+                .committed
+                .get(blk_no as usize)
+                .is_some_and(|committed| *committed)
+    }
+
+    // Moves blk_no to a block the image on disk does not use when it must not be written in
+    // place, returns where it is now
+    pub(crate) fn cow_blk(&mut self, blk_no: u32) -> Result<u32, BlockError> {
+        if !self.must_copy(blk_no) {
+            return Ok(blk_no);
+        }
+        let copy = self.alloc_blk()?;
+        if let Some(data) = self.blk_data(blk_no) {
+            self.blks.insert(copy, data, true);
+        }
+        self.free_blk(blk_no)?;
+        Ok(copy)
+    }
+
+    fn any_must_copy(&self) -> bool {
+        let reserved = self.geometry().reserved_blks();
+        self.dirty
+            .range(reserved..)
+            .any(|&blk_no| self.must_copy(blk_no))
+    }
+
+    // Inodes in the inode table blocks changed since the last commit
+    fn dirty_inodes(&self) -> Vec<u32> {
+        let geometry = self.geometry();
+        let table = geometry.inode_table_blk_no();
+        let per_blk = geometry.blk_size / INODE_SIZE_BYTES as u32;
+        self.dirty
+            .range(table..geometry.journal_blk_no())
+            .flat_map(|&blk_no| {
+                let first = (blk_no - table) * per_blk;
+                first..(first + per_blk).min(geometry.ino_count)
+            })
+            .collect()
+    }
+
+    fn cow_inodes(&mut self, ino_ids: impl IntoIterator<Item = u32>) -> Result<(), BlockError> {
+        for ino_id in ino_ids {
+            let Some(mut inode) = self.inodes[ino_id as usize] else {
+                continue;
+            };
+            // Inline data sits where the block pointers would be
+            let mut moved = !inode.has_inline_data() && self.cow_tree(&mut inode)?;
+            if inode.xattr_blk != INVALID_PTR {
+                let copy = self.cow_blk(inode.xattr_blk)?;
+                moved |= copy != inode.xattr_blk;
+                inode.xattr_blk = copy;
+            }
+            if moved {
+                *self
+                    .inode_mut(ino_id)
+                    .map_err(|_| BlockError::InvalidBlkNo)? = inode;
+            }
+        }
+        Ok(())
+    }
+
+    // Moves every changed data block the image on disk uses. Whatever changes a file's blocks
+    // touches its inode as well, so only the inodes changed since the last commit are walked,
+    // unless that missed something.
+    pub(crate) fn copy_on_write(&mut self) -> Result<(), BlockError> {
+        if !self.any_must_copy() {
+            return Ok(());
+        }
+        self.cow_inodes(self.dirty_inodes())?;
+        if self.any_must_copy() {
+            warn!("Changed blocks outside the changed inodes, walking every inode");
+            self.cow_inodes(0..self.geometry().ino_count)?;
+        }
+        Ok(())
+    }
+
+    // Writes every block changed since the last commit without touching anything the image on
+    // disk uses, then switches the image over to them with the superblock. A crash at any point
+    // leaves the image as of one commit or the other.
+    pub(crate) fn commit_cow<D: Device>(&mut self, image: &D) -> Result<(), ImageError> {
+        self.flush_snapshots().map_err(|_| ImageError::NoSpace)?;
+        if self.dirty.is_empty() {
+            return Ok(());
+        }
+        self.copy_on_write().map_err(|_| ImageError::NoSpace)?;
+        let shadow = self.metadata.shadow.clone();
+        let super_blk_no = self.metadata.super_blk_no;
+        if let Err(err) = self.write_cow(image) {
+            self.metadata.shadow = shadow;
+            self.metadata.super_blk_no = super_blk_no;
+            self.metadata.generation -= 1;
+            return Err(err);
+        }
+        debug!(
+            "Committed generation {} of {} blocks",
+            self.metadata.generation,
+            self.dirty.len()
+        );
+        self.committed = self.blk_bitmap.map.clone();
+        self.dirty.clear();
+        self.dirty_data.clear();
+        self.alloced.clear();
+        self.freed.clear();
+        self.blks.mark_clean();
+        Ok(())
+    }
+
+    fn write_cow<D: Device>(&mut self, image: &D) -> Result<(), ImageError> {
+        let geometry = self.geometry();
+        let metadata = &mut self.metadata;
+        for &blk_no in self
+            .dirty
+            .range(SUPER_BLK_NO + 1..geometry.journal_blk_no())
+        {
+            let current = metadata.shadow[blk_no as usize];
+            metadata.shadow.set(blk_no as usize, !current);
+        }
+        metadata.generation += 1;
+        metadata.super_blk_no = match metadata.super_blk_no {
+            SUPER_BLK_NO => geometry.shadow_blk_no(),
+            _ => SUPER_BLK_NO,
+        };
+
+        for &blk_no in self.dirty.range(SUPER_BLK_NO + 1..) {
+            let home = self.metadata.current_blk_no(blk_no);
+            write_blk(image, geometry, home, &self.blk_image(blk_no))?;
+        }
+        // The superblock must not reach the disk before what it points at
+        image.sync()?;
+        let slot = self.metadata.super_blk_no;
+        write_blk(image, geometry, slot, &self.blk_image(SUPER_BLK_NO))?;
+        image.sync()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crash::{self, CrashDevice};
+    use crate::image::Geometry;
+    use crate::test_util::{self, make_file};
+    use crate::{fsck, ROOT_INO};
+    use fuser::FileType;
+    use std::collections::BTreeSet;
+    use std::ffi::OsStr;
+
+    // 256 blocks of 1 KiB: 12 blocks before the shadow copies, data from block 24
+    fn geometry() -> Geometry {
+        Geometry::new(256 << 10, 1024, 32)
+            .unwrap()
+            .with_cow()
+            .unwrap()
+    }
+
+    fn image_with(setup: impl Fn(&mut FSState)) -> Vec<u8> {
+        crash::image_with(geometry(), setup)
+    }
+
+    fn file_contents(state: &FSState, name: &str) -> Vec<u8> {
+        let ino_id = state.lookup_entry(ROOT_INO, OsStr::new(name)).unwrap();
+        test_util::contents(state, ino_id)
+    }
+
+    // Data blocks the file's tree uses, pointer blocks included
+    fn file_blks(state: &FSState, name: &str) -> BTreeSet<u32> {
+        let ino_id = state.lookup_entry(ROOT_INO, OsStr::new(name)).unwrap();
+        let inode = state.inode(ino_id).unwrap();
+        let mut blks: BTreeSet<u32> = inode.direct_blks.into_iter().collect();
+        blks.insert(inode.indirect_blk);
+        blks.remove(&INVALID_PTR);
+        blks
+    }
+
+    #[test]
+    fn test_geometry_layout() {
+        let geometry = geometry();
+        assert_eq!(geometry.journal_blk_no(), 12);
+        assert_eq!(geometry.shadow_blk_no(), 12);
+        assert_eq!(geometry.reserved_blks(), 24);
+        assert!(matches!(
+            geometry.with_journal(16),
+            Err(ImageError::InvalidGeometry(_))
+        ));
+    }
+
+    #[test]
+    fn test_mkdir_is_atomic() {
+        crash::crash_outcomes(image_with(|_| {}), |state| {
+            state
+                .make_node(ROOT_INO, OsStr::new("dir"), FileType::Directory, 0o755)
+                .unwrap();
+        });
+    }
+
+    #[test]
+    fn test_unlink_is_atomic() {
+        let before = image_with(|state| {
+            make_file(state, "file", &vec![0xab; 3000]);
+        });
+        crash::crash_outcomes(before, |state| {
+            state
+                .remove_node(ROOT_INO, OsStr::new("file"), false)
+                .unwrap();
+        });
+    }
+
+    #[test]
+    fn test_overwrite_is_atomic() {
+        // 14 blocks, so the file has an indirect block to copy as well
+        let before = image_with(|state| {
+            make_file(state, "file", &vec![0xab; 14 * 1024]);
+        });
+        let outcomes = crash::crash_outcomes(before, |state| {
+            let ino_id = state.lookup_entry(ROOT_INO, OsStr::new("file")).unwrap();
+            state.write_at(ino_id, 0, &[0xcd; 15 * 1024]).unwrap();
+        });
+        for state in outcomes {
+            let contents = file_contents(&state, "file");
+            assert!(
+                contents == [0xab; 14 * 1024] || contents == [0xcd; 15 * 1024],
+                "torn file contents"
+            );
+        }
+    }
+
+    #[test]
+    fn test_changed_blocks_move() {
+        let device = CrashDevice::new(
+            image_with(|state| {
+                make_file(state, "file", &vec![0xab; 14 * 1024]);
+            }),
+            None,
+            false,
+        );
+        let mut state = FSState::load(&device).unwrap();
+        let before = file_blks(&state, "file");
+        let free = state.metadata.free_blk_count;
+        let ino_id = state.lookup_entry(ROOT_INO, OsStr::new("file")).unwrap();
+        let last = state.bmap(state.inode(ino_id).unwrap(), 13).unwrap();
+        state.write_at(ino_id, 13 * 1024, &[0xcd; 10]).unwrap();
+        state.commit(&device).unwrap();
+
+        // The last block and the indirect block pointing at it are copied, the old ones freed
+        let after = file_blks(&state, "file");
+        assert_eq!(before.difference(&after).count(), 1);
+        assert_eq!(after.difference(&before).count(), 1);
+        assert_ne!(state.bmap(state.inode(ino_id).unwrap(), 13).unwrap(), last);
+        assert_eq!(state.metadata.free_blk_count, free);
+
+        let state = FSState::load(&device).unwrap();
+        assert_eq!(file_blks(&state, "file"), after);
+        assert_eq!(
+            &file_contents(&state, "file")[13 * 1024..][..10],
+            [0xcd; 10]
+        );
+        assert_eq!(fsck::check(&state), vec![]);
+    }
+
+    #[test]
+    fn test_freed_blocks_wait_for_commit() {
+        let device = CrashDevice::new(
+            image_with(|state| {
+                make_file(state, "old", &vec![0xab; 3000]);
+            }),
+            None,
+            false,
+        );
+        let mut state = FSState::load(&device).unwrap();
+        let old = file_blks(&state, "old");
+        state
+            .remove_node(ROOT_INO, OsStr::new("old"), false)
+            .unwrap();
+        make_file(&mut state, "new", &vec![0xcd; 3000]);
+        assert!(old.is_disjoint(&file_blks(&state, "new")));
+
+        state.commit(&device).unwrap();
+        make_file(&mut state, "newer", &vec![0xef; 3000]);
+        assert!(!old.is_disjoint(&file_blks(&state, "newer")));
+    }
+
+    #[test]
+    fn test_superblock_slots_alternate() {
+        let device = CrashDevice::new(image_with(|_| {}), None, false);
+        let mut state = FSState::load(&device).unwrap();
+        let slot = state.metadata.super_blk_no;
+        let generation = state.metadata.generation;
+        for (idx, name) in ["a", "b", "c"].into_iter().enumerate() {
+            make_file(&mut state, name, &[0xab; 10]);
+            state.commit(&device).unwrap();
+            let loaded = FSState::load(&device).unwrap();
+            assert_eq!(loaded.metadata.generation, generation + idx as u64 + 1);
+            assert_eq!(loaded.metadata.super_blk_no, state.metadata.super_blk_no);
+            assert_eq!(slot == state.metadata.super_blk_no, idx % 2 == 1);
+            assert!(loaded.lookup_entry(ROOT_INO, OsStr::new(name)).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_corrupt_superblock_falls_back_to_other_slot() {
+        let device = CrashDevice::new(image_with(|_| {}), None, false);
+        let mut state = FSState::load(&device).unwrap();
+        make_file(&mut state, "file", &[0xab; 10]);
+        state.commit(&device).unwrap();
+
+        let offset = state.metadata.super_blk_no as usize * 1024;
+        device.data.borrow_mut()[offset + 100] ^= 1;
+        let state = FSState::load(&device).unwrap();
+        assert!(state.lookup_entry(ROOT_INO, OsStr::new("file")).is_err());
+        assert_eq!(fsck::check(&state), vec![]);
+
+        let other = state.metadata.super_blk_no as usize * 1024;
+        device.data.borrow_mut()[other + 100] ^= 1;
+        assert!(matches!(
+            FSState::load(&device),
+            Err(ImageError::CorruptSuperblock)
+        ));
+    }
+}
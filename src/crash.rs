@@ -0,0 +1,141 @@
+// Crash simulation shared by the journal and copy-on-write tests
+use crate::image::{self, Device, Geometry};
+use crate::journal::recover;
+use crate::{fsck, FSState, ROOT_INO};
+use std::cell::{Cell, RefCell};
+use std::ffi::OsString;
+use std::io;
+
+// An in-memory image that loses power at write number crash_at: that write and every one
+// after it fail, except that a torn crash lets the first half of it through. What is left
+// is either everything written before the crash or, if the drive cache went with it, only
+// what was there at the last sync.
+pub(crate) struct CrashDevice {
+    pub(crate) data: RefCell<Vec<u8>>,
+    pub(crate) synced: RefCell<Vec<u8>>,
+    pub(crate) writes: Cell<usize>,
+    crash_at: Option<usize>,
+    torn: bool,
+}
+
+impl CrashDevice {
+    pub(crate) fn new(data: Vec<u8>, crash_at: Option<usize>, torn: bool) -> Self {
+        Self {
+            synced: RefCell::new(data.clone()),
+            data: RefCell::new(data),
+            writes: Cell::new(0),
+            crash_at,
+            torn,
+        }
+    }
+
+    fn crashed(&self) -> bool {
+        self.crash_at.is_some_and(|at| self.writes.get() > at)
+    }
+}
+
+impl Device for CrashDevice {
+    fn read_bytes(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let data = self.data.borrow();
+        let src = data
+            .get(offset as usize..offset as usize + buf.len())
+            .ok_or(io::ErrorKind::UnexpectedEof)?;
+        buf.copy_from_slice(src);
+        Ok(())
+    }
+
+    fn write_bytes(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        let n = self.writes.get();
+        self.writes.set(n + 1);
+        let len = match self.crash_at {
+            Some(at) if n == at && self.torn => buf.len() / 2,
+            Some(at) if n >= at => 0,
+            _ => buf.len(),
+        };
+        let offset = offset as usize;
+        self.data.borrow_mut()[offset..offset + len].copy_from_slice(&buf[..len]);
+        match len == buf.len() {
+            true => Ok(()),
+            false => Err(io::Error::other("crashed")),
+        }
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.data.borrow().len() as u64)
+    }
+
+    fn resize(&self, size: u64) -> io::Result<()> {
+        self.data.borrow_mut().resize(size as usize, 0);
+        Ok(())
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        if self.crashed() {
+            return Err(io::Error::other("crashed"));
+        }
+        *self.synced.borrow_mut() = self.data.borrow().clone();
+        Ok(())
+    }
+}
+
+// A formatted image with setup applied and committed
+pub(crate) fn image_with(geometry: Geometry, setup: impl Fn(&mut FSState)) -> Vec<u8> {
+    let device = CrashDevice::new(Vec::new(), None, false);
+    image::format(&device, geometry).unwrap();
+    let mut state = FSState::load(&device).unwrap();
+    setup(&mut state);
+    state.commit(&device).unwrap();
+    device.data.into_inner()
+}
+
+// What an operation is visible as: the root directory and the free counts
+fn summary(state: &FSState) -> (Vec<OsString>, u32, u32) {
+    let mut names: Vec<_> = state
+        .read_dir(ROOT_INO)
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect();
+    names.sort();
+    let metadata = &state.metadata;
+    (names, metadata.free_blk_count, metadata.free_ino_count)
+}
+
+// Crashes op's commit at each of its writes in turn and checks every image left behind
+// recovers to a clean filesystem as it was either before op or after it. Returns what each
+// of them recovered to.
+pub(crate) fn crash_outcomes(before: Vec<u8>, op: impl Fn(&mut FSState)) -> Vec<FSState> {
+    let run = |crash_at, torn| {
+        let device = CrashDevice::new(before.clone(), crash_at, torn);
+        let mut state = FSState::load(&device).unwrap();
+        op(&mut state);
+        let result = state.commit(&device);
+        (device, state, result)
+    };
+    let (device, state, result) = run(None, false);
+    result.unwrap();
+    let old = summary(&FSState::load(&CrashDevice::new(before.clone(), None, false)).unwrap());
+    let new = summary(&state);
+    assert_ne!(old, new);
+
+    let mut outcomes = Vec::new();
+    for crash_at in 0..device.writes.get() {
+        for torn in [false, true] {
+            let (device, _, result) = run(Some(crash_at), torn);
+            assert!(result.is_err());
+            for image in [device.data.into_inner(), device.synced.into_inner()] {
+                let device = CrashDevice::new(image, None, false);
+                recover(&device).unwrap();
+                let state = FSState::load_all_blks(&device).unwrap();
+                assert_eq!(fsck::check(&state), vec![], "crash at write {crash_at}");
+                let got = summary(&state);
+                assert!(
+                    got == old || got == new,
+                    "crash at write {crash_at}: {got:?}"
+                );
+                outcomes.push(state);
+            }
+        }
+    }
+    outcomes
+}
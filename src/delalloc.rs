@@ -0,0 +1,384 @@
+use crate::{blk_errno, inode_errno, FSState, Inode, INVALID_PTR};
+use fuser::FileType;
+use libc::c_int;
+use std::collections::BTreeMap;
+
+// With delayed allocation, writes to parts of a regular file that have no block yet are held in
+// memory as whole pages instead. Blocks are only picked when the pages are written back, on
+// fsync, once the pages held outgrow the cap, or when the flusher in fs.rs gets to them. By then
+// the runs a file was written in are known, so each is given blocks in one piece. Blocks already
+// mapped are still written in place.
+//
+// The size of the file changes with the write, so a crash before write-back leaves a hole where
+// the pages were.
+#[derive(Default)]
+pub(crate) struct DelayedPages {
+    pages: BTreeMap<(u32, u64), Box<[u8]>>, // Keyed by inode number and logical block
+    cap: u64,                               // In bytes, 0 while delayed allocation is off
+}
+
+impl DelayedPages {
+    pub(crate) fn page(&self, ino_id: u32, lblk: u64) -> Option<&[u8]> {
+        self.pages.get(&(ino_id, lblk)).map(|page| &page[..])
+    }
+
+    fn count(&self) -> u64 {
+        self.pages.len() as u64
+    }
+
+    pub(crate) fn of_inode(&self, ino_id: u32) -> impl Iterator<Item = u64> + '_ {
+        self.pages
+            .range((ino_id, 0)..=(ino_id, u64::MAX))
+            .map(|(&(_, lblk), _)| lblk)
+    }
+
+    // Removes the pages of ino_id from lblk on
+    fn drop_from(&mut self, ino_id: u32, lblk: u64) {
+        let dropped: Vec<u64> = self.of_inode(ino_id).filter(|&l| l >= lblk).collect();
+        for lblk in dropped {
+            self.pages.remove(&(ino_id, lblk));
+        }
+    }
+
+    pub(crate) fn drop_all(&mut self) {
+        self.pages.clear();
+    }
+
+    pub(crate) fn take_all(&mut self) -> BTreeMap<(u32, u64), Box<[u8]>> {
+        std::mem::take(&mut self.pages)
+    }
+
+    pub(crate) fn put_all(&mut self, pages: BTreeMap<(u32, u64), Box<[u8]>>) {
+        self.pages = pages;
+    }
+}
+
+impl FSState {
+    // Holds writes to blocks files do not have yet in memory, up to cap bytes of them. 0 turns
+    // delayed allocation off, which is the default. Pages already held stay until the next
+    // flush_delayed.
+    pub fn set_delalloc(&mut self, cap: u64) {
+        self.delayed.cap = cap;
+    }
+
+    pub fn delalloc(&self) -> bool {
+        self.delayed.cap > 0
+    }
+
+    // Blocks the pages held will need, which statfs leaves out of the free count
+    pub fn delayed_blks(&self) -> u64 {
+        self.delayed.count()
+    }
+
+    // Each page held keeps back its own block and up to three pointer blocks on its way down,
+    // so write-back cannot run out of space. Writes that do not fit next to them are not
+    // delayed.
+    fn room_to_delay(&self, blks: u64) -> bool {
+        (self.delayed.count() + blks) * 4 <= self.metadata.free_blk_count as u64
+    }
+
+    // Whether a write to inode covering blks blocks can wait in memory. When it would not fit
+    // next to the pages already held, those are written back first.
+    pub(crate) fn can_delay(&mut self, inode: &Inode, blks: u64) -> Result<bool, c_int> {
+        if !self.delalloc() || inode.kind != FileType::RegularFile {
+            return Ok(false);
+        }
+        if !self.room_to_delay(blks) {
+            self.flush_delayed()?;
+        }
+        Ok(self.room_to_delay(blks))
+    }
+
+    // Copies src to off within the page for lblk of inode. A page is only started for a block
+    // that is not mapped, false means the write has to go to the block instead.
+    pub(crate) fn delay_write(&mut self, inode: &Inode, lblk: u64, off: usize, src: &[u8]) -> bool {
+        let key = (inode.ino_id, lblk);
+        if !self.delayed.pages.contains_key(&key) {
+            if !matches!(self.bmap(inode, lblk), Ok(INVALID_PTR)) {
+                return false;
+            }
+            let page = vec![0; self.blk_size() as usize].into_boxed_slice();
+            self.delayed.pages.insert(key, page);
+        }
+        let page = self.delayed.pages.get_mut(&key).unwrap();
+        page[off..off + src.len()].copy_from_slice(src);
+        true
+    }
+
+    pub(crate) fn over_delalloc_cap(&self) -> bool {
+        self.delayed.count() * self.blk_size() > self.delayed.cap
+    }
+
+    // Drops the pages past a file truncated to size and zeros the tail of the last one kept
+    pub(crate) fn truncate_delayed(&mut self, ino_id: u32, size: u64) {
+        let blk_size = self.blk_size();
+        self.delayed.drop_from(ino_id, size.div_ceil(blk_size));
+        if let Some(page) = self.delayed.pages.get_mut(&(ino_id, size / blk_size)) {
+            page[(size % blk_size) as usize..].fill(0);
+        }
+    }
+
+    // Whether the pages held are due to be written back ahead of a write of len bytes: they
+    // outgrew the cap, or they would leave the write too little room to be delayed
+    pub(crate) fn write_back_due(&self, len: u64) -> bool {
+        let blks = len.div_ceil(self.blk_size()) + 1;
+        self.delayed.count() > 0 && (self.over_delalloc_cap() || !self.room_to_delay(blks))
+    }
+
+    fn delayed_inodes(&self) -> Vec<u32> {
+        let mut ino_ids: Vec<u32> = self
+            .delayed
+            .pages
+            .keys()
+            .map(|&(ino_id, _)| ino_id)
+            .collect();
+        ino_ids.dedup();
+        ino_ids
+    }
+
+    // Gives every page held a block and copies it there
+    pub fn flush_delayed(&mut self) -> Result<(), c_int> {
+        for ino_id in self.delayed_inodes() {
+            self.flush_delayed_inode(ino_id)?;
+        }
+        Ok(())
+    }
+
+    // Like flush_delayed, file by file until the transaction is full, see transaction_full.
+    // Returns whether pages are left, a commit then makes room for the rest.
+    pub fn flush_delayed_some(&mut self) -> Result<bool, c_int> {
+        for ino_id in self.delayed_inodes() {
+            if self.transaction_full() {
+                return Ok(true);
+            }
+            self.flush_delayed_inode(ino_id)?;
+        }
+        Ok(false)
+    }
+
+    // Like flush_delayed, for the pages of one file. Each run of consecutive pages asks for
+    // that many blocks at once. Pages that did not get a block stay held.
+    pub(crate) fn flush_delayed_inode(&mut self, ino_id: u32) -> Result<(), c_int> {
+        let lblks: Vec<u64> = self.delayed.of_inode(ino_id).collect();
+        if lblks.is_empty() {
+            return Ok(());
+        }
+        let mut inode = *self.inode(ino_id).map_err(inode_errno)?;
+        let mut result = Ok(());
+        let mut run_end = 0; // Index just past the run of consecutive pages lblk is in
+        for (idx, &lblk) in lblks.iter().enumerate() {
+            if idx == run_end {
+                run_end += lblks[idx..]
+                    .iter()
+                    .zip(lblk..)
+                    .take_while(|&(&l, next)| l == next)
+                    .count();
+            }
+            let want = (run_end - idx) as u64;
+            let page = self.delayed.pages.remove(&(ino_id, lblk)).unwrap();
+            let blk = self
+                .bmap_alloc_run(&mut inode, lblk, want)
+                .and_then(|blk_no| self.file_blk_mut(blk_no));
+            match blk {
+                Ok(blk) => blk.copy_from_slice(&page),
+                Err(err) => {
+                    self.delayed.pages.insert((ino_id, lblk), page);
+                    result = Err(blk_errno(err));
+                    break;
+                }
+            }
+        }
+        // Pointer blocks allocated before running out of space still belong to the file
+        *self.inode_mut(ino_id).map_err(inode_errno)? = inode;
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crash::{self, CrashDevice};
+    use crate::fsck;
+    use crate::image::{self, Geometry};
+    use crate::journal::min_journal_blks;
+    use crate::test_util::{contents, make_file, state_with_root};
+    use std::ffi::OsStr;
+
+    fn geometry() -> Geometry {
+        Geometry::new(4 << 20, 1024, 64).unwrap()
+    }
+
+    fn state() -> FSState {
+        let mut state = state_with_root(geometry());
+        state.set_delalloc(64 << 10);
+        state
+    }
+
+    fn pattern(len: usize, seed: u8) -> Vec<u8> {
+        (0..len).map(|i| (i as u8).wrapping_mul(7) ^ seed).collect()
+    }
+
+    #[test]
+    fn test_writes_wait_for_blocks_until_flushed() {
+        let mut state = state();
+        let ino_id = make_file(&mut state, "file", &[]);
+        let free = state.metadata.free_blk_count;
+
+        let data = pattern(3000, 1);
+        assert_eq!(state.write_at(ino_id, 0, &data), Ok(data.len()));
+        assert_eq!(state.metadata.free_blk_count, free);
+        assert_eq!(state.inode(ino_id).unwrap().blocks, 0);
+        assert_eq!(state.delayed_blks(), 3);
+        assert_eq!(contents(&state, ino_id), data);
+
+        state.flush_delayed().unwrap();
+        assert_eq!(state.delayed_blks(), 0);
+        assert_eq!(state.metadata.free_blk_count, free - 3);
+        assert_eq!(contents(&state, ino_id), data);
+    }
+
+    #[test]
+    fn test_small_writes_coalesce_into_one_run() {
+        let mut state = state();
+        let a = make_file(&mut state, "a", &[]);
+        let b = make_file(&mut state, "b", &[]);
+        // Interleaved appends would leave both files in pieces if each got blocks right away
+        for i in 0..8 {
+            let off = i * 1024;
+            state.write_at(a, off, &pattern(1024, 2)).unwrap();
+            state.write_at(b, off, &pattern(1024, 3)).unwrap();
+        }
+        state.flush_delayed().unwrap();
+
+        for ino_id in [a, b] {
+            let inode = *state.inode(ino_id).unwrap();
+            let blks: Vec<u32> = (0..8).map(|l| state.bmap(&inode, l).unwrap()).collect();
+            assert!(blks.windows(2).all(|w| w[1] == w[0] + 1), "{blks:?}");
+        }
+    }
+
+    #[test]
+    fn test_mapped_blocks_are_written_in_place() {
+        let mut state = state();
+        let ino_id = make_file(&mut state, "file", &[]);
+        state.write_at(ino_id, 0, &pattern(2048, 4)).unwrap();
+        state.flush_delayed().unwrap();
+
+        state.write_at(ino_id, 100, &[9; 10]).unwrap();
+        assert_eq!(state.delayed_blks(), 0);
+        state.write_at(ino_id, 2048, &[9; 10]).unwrap();
+        assert_eq!(state.delayed_blks(), 1);
+    }
+
+    #[test]
+    fn test_truncate_drops_and_zeros_pages() {
+        let mut state = state();
+        let ino_id = make_file(&mut state, "file", &[]);
+        state.write_at(ino_id, 0, &[5; 4096]).unwrap();
+        state.truncate(ino_id, 1500).unwrap();
+        assert_eq!(state.delayed_blks(), 2);
+
+        state.truncate(ino_id, 2048).unwrap();
+        let data = contents(&state, ino_id);
+        assert_eq!(data[..1500], [5; 1500]);
+        assert_eq!(data[1500..], [0; 548]);
+
+        state.truncate(ino_id, 0).unwrap();
+        assert_eq!(state.delayed_blks(), 0);
+    }
+
+    #[test]
+    fn test_pages_over_the_cap_are_written_back() {
+        let mut state = state();
+        state.set_delalloc(4096);
+        let ino_id = make_file(&mut state, "file", &[]);
+        state.write_at(ino_id, 0, &[1; 4096]).unwrap();
+        assert_eq!(state.delayed_blks(), 4);
+        state.write_at(ino_id, 4096, &[1; 1]).unwrap();
+        assert_eq!(state.delayed_blks(), 0);
+        assert_eq!(state.inode(ino_id).unwrap().blocks, 5);
+    }
+
+    #[test]
+    fn test_nearly_full_filesystem_writes_directly() {
+        let mut state = state();
+        let ino_id = make_file(&mut state, "file", &[]);
+        let free = state.metadata.free_blk_count as u64;
+        // Leaves less room than four blocks per page asks for
+        let len = (free / 2) * 1024;
+        assert_eq!(
+            state.write_at(ino_id, 0, &vec![1; len as usize]),
+            Ok(len as usize)
+        );
+        assert_eq!(state.delayed_blks(), 0);
+        assert_eq!(contents(&state, ino_id), vec![1; len as usize]);
+    }
+
+    #[test]
+    fn test_save_writes_back_pages() {
+        let device = CrashDevice::new(Vec::new(), None, false);
+        image::format(&device, geometry()).unwrap();
+        let mut state = FSState::load(&device).unwrap();
+        state.set_delalloc(64 << 10);
+        let ino_id = make_file(&mut state, "file", &[]);
+        let data = pattern(5000, 6);
+        state.write_at(ino_id, 0, &data).unwrap();
+
+        state.save(&device).unwrap();
+        assert_eq!(state.delayed_blks(), 0);
+        let state = FSState::load(&device).unwrap();
+        assert_eq!(contents(&state, ino_id), data);
+        assert_eq!(fsck::check(&state), vec![]);
+    }
+
+    #[test]
+    fn test_snapshots_do_not_see_later_pages() {
+        let mut state = state();
+        let ino_id = make_file(&mut state, "file", &[]);
+        state.write_at(ino_id, 0, &[1; 1024]).unwrap();
+        let id = state.create_snapshot(OsStr::new("snap")).unwrap();
+        assert_eq!(state.delayed_blks(), 0);
+
+        state.write_at(ino_id, 1024, &[2; 1024]).unwrap();
+        let snapshot = state.with_snapshot(id, |state| contents(state, ino_id));
+        assert_eq!(snapshot, Ok(vec![1; 1024]));
+    }
+
+    #[test]
+    fn test_write_back_of_many_files_fits_the_journal() {
+        let geometry = Geometry::new(16 << 20, 1024, 256).unwrap();
+        let geometry = geometry
+            .with_journal(min_journal_blks(&geometry) as u32)
+            .unwrap();
+        let device = CrashDevice::new(crash::image_with(geometry, |_| {}), None, false);
+        let mut state = FSState::load(&device).unwrap();
+        state.set_delalloc(4 << 20);
+        let mut files = Vec::new();
+        for idx in 0..200 {
+            let ino_id = make_file(&mut state, &format!("file{idx}"), &[]);
+            state
+                .write_at(ino_id, 0, &pattern(14 * 1024, idx as u8))
+                .unwrap();
+            state.commit(&device).unwrap();
+            files.push(ino_id);
+        }
+        assert_eq!(state.delayed_blks(), 200 * 14);
+
+        let mut pieces = 1;
+        while state.flush_delayed_some().unwrap() {
+            state.commit(&device).unwrap();
+            pieces += 1;
+        }
+        state.commit(&device).unwrap();
+        assert!(pieces > 1);
+        assert_eq!(state.delayed_blks(), 0);
+        make_file(&mut state, "new", &[1; 10]);
+        state.commit(&device).unwrap();
+
+        let state = FSState::load(&device).unwrap();
+        for (idx, &ino_id) in files.iter().enumerate() {
+            assert_eq!(contents(&state, ino_id), pattern(14 * 1024, idx as u8));
+        }
+        assert_eq!(fsck::check(&state), vec![]);
+    }
+}
@@ -0,0 +1,667 @@
+use crate::image::{file_type_from_code, file_type_to_code, FEATURE_DIR_INDEX};
+use crate::{
+    blk_errno, inode_errno, BlockError, FSState, Inode, INODE_FLAG_INDEX, INVALID_PTR, MAX_LINKS,
+};
+use fuser::FileType;
+use libc::{
+    c_int, EEXIST, EINVAL, EISDIR, EMLINK, ENAMETOOLONG, ENOENT, ENOTDIR, ENOTEMPTY, EPERM,
+};
+use log::error;
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::sync::Arc;
+
+const S_ISGID: u16 = libc::S_ISGID as u16;
+
+pub(crate) const MAX_NAME_LEN: usize = 255;
+
+// Directory data is a whole number of blocks, each one a chain of records filling it exactly:
+// ino_id (u32 LE) | rec_len (u16 LE) | name_len (u8) | file type (u8) | name, padded to 4 bytes.
+// rec_len includes any free space left after the record and records never span blocks. A
+// record with ino_id 0 is unused. "." and ".." are the first records of every new directory.
+pub(crate) const DIRENT_HEADER_LEN: usize = 8;
+
+pub(crate) struct DirEntry {
+    pub(crate) ino_id: u32,
+    pub(crate) kind: FileType,
+    pub(crate) name: OsString,
+}
+
+// A record as found in a directory block
+pub(crate) struct Record {
+    pub(crate) offset: usize,
+    pub(crate) ino_id: u32,
+    pub(crate) rec_len: usize,
+    pub(crate) name_len: usize,
+    pub(crate) kind: u8,
+}
+
+impl Record {
+    pub(crate) fn name<'a>(&self, data: &'a [u8]) -> &'a [u8] {
+        let start = self.offset + DIRENT_HEADER_LEN;
+        &data[start..start + self.name_len]
+    }
+
+    // Space the record itself needs, the rest of rec_len can hold new records
+    fn used_len(&self) -> usize {
+        match self.ino_id {
+            INVALID_PTR => 0,
+            _ => rec_len_for(self.name_len),
+        }
+    }
+}
+
+fn rec_len_for(name_len: usize) -> usize {
+    (DIRENT_HEADER_LEN + name_len).next_multiple_of(4)
+}
+
+fn is_dot(name: &OsStr) -> bool {
+    name == "." || name == ".."
+}
+
+pub(crate) fn decode_records(data: &[u8]) -> Vec<Record> {
+    let mut records = Vec::new();
+    let mut offset = 0;
+    while offset + DIRENT_HEADER_LEN <= data.len() {
+        let header = &data[offset..offset + DIRENT_HEADER_LEN];
+        let record = Record {
+            offset,
+            ino_id: u32::from_le_bytes(header[0..4].try_into().unwrap()),
+            rec_len: u16::from_le_bytes(header[4..6].try_into().unwrap()) as usize,
+            name_len: header[6] as usize,
+            kind: header[7],
+        };
+        let end = offset + record.rec_len;
+        if record.rec_len < DIRENT_HEADER_LEN + record.name_len || end > data.len() {
+            error!("Corrupt directory record at offset {offset}");
+            break;
+        }
+        records.push(record);
+        offset = end;
+    }
+    records
+}
+
+pub(crate) fn encode_record(
+    data: &mut [u8],
+    offset: usize,
+    rec_len: usize,
+    entry: (u32, FileType, &[u8]),
+) {
+    let (ino_id, kind, name) = entry;
+    let record = &mut data[offset..offset + rec_len];
+    record[0..4].copy_from_slice(&ino_id.to_le_bytes());
+    record[4..6].copy_from_slice(&(rec_len as u16).to_le_bytes());
+    record[6] = name.len() as u8;
+    record[7] = file_type_to_code(kind);
+    record[DIRENT_HEADER_LEN..DIRENT_HEADER_LEN + name.len()].copy_from_slice(name);
+}
+
+impl FSState {
+    pub(crate) fn dir_inode(&self, ino_id: u32) -> Result<&Inode, c_int> {
+        let inode = self.inode(ino_id).map_err(inode_errno)?;
+        match inode.kind {
+            FileType::Directory => Ok(inode),
+            _ => Err(ENOTDIR),
+        }
+    }
+
+    // Physical block numbers backing the directory, skipping holes
+    pub(crate) fn dir_blks(&self, inode: &Inode) -> Vec<u32> {
+        (0..inode.size / self.blk_size())
+            .map(|lblk| self.bmap(inode, lblk).unwrap_or(INVALID_PTR))
+            .filter(|&blk_no| self.blk_data(blk_no).is_some())
+            .collect()
+    }
+
+    // Whether the image holds blk_no, which the block cache can read back when it is not in
+    // memory. Free blocks are never read.
+    pub(crate) fn blk_on_disk(&self, blk_no: u32) -> bool {
+        self.blk_bitmap
+            .map
+            .get(blk_no as usize)
+            .is_some_and(|alloced| *alloced)
+    }
+
+    pub(crate) fn blk_data(&self, blk_no: u32) -> Option<Arc<[u8]>> {
+        match blk_no {
+            INVALID_PTR => None,
+            _ => self.blks.get(blk_no, self.blk_on_disk(blk_no)),
+        }
+    }
+
+    // Every change to a block goes through here so the next commit writes it out
+    pub(crate) fn blk_data_mut(&mut self, blk_no: u32) -> Result<&mut [u8], BlockError> {
+        let on_disk = self.blk_on_disk(blk_no);
+        let data = self
+            .blks
+            .get_mut(blk_no, on_disk)
+            .ok_or(BlockError::ReadFailed)?;
+        self.dirty.insert(blk_no);
+        Ok(data)
+    }
+
+    // Finds the block and record holding name, along with the record before it in that block
+    fn find_record(&self, inode: &Inode, name: &OsStr) -> Option<(u32, Option<Record>, Record)> {
+        let blks = match inode.flags & INODE_FLAG_INDEX {
+            0 => self.dir_blks(inode),
+            _ => self.dx_lookup_blks(inode, name),
+        };
+        for blk_no in blks {
+            let data = &self.blk_data(blk_no)?;
+            let mut prev = None;
+            for record in decode_records(data) {
+                if record.ino_id != INVALID_PTR && record.name(data) == name.as_bytes() {
+                    return Some((blk_no, prev, record));
+                }
+                prev = Some(record);
+            }
+        }
+        None
+    }
+
+    pub(crate) fn read_dir(&self, ino_id: u32) -> Result<Vec<DirEntry>, c_int> {
+        Ok(self.dir_entries(self.dir_inode(ino_id)?))
+    }
+
+    // Decodes the entries of a directory without checking its kind
+    pub(crate) fn dir_entries(&self, inode: &Inode) -> Vec<DirEntry> {
+        let mut entries = Vec::new();
+        for blk_no in self.dir_blks(inode) {
+            let Some(data) = &self.blk_data(blk_no) else {
+                continue;
+            };
+            for record in decode_records(data) {
+                if record.ino_id == INVALID_PTR {
+                    continue;
+                }
+                let Some(kind) = file_type_from_code(record.kind) else {
+                    error!(
+                        "Unknown file type {} in directory {}",
+                        record.kind, inode.ino_id
+                    );
+                    continue;
+                };
+                entries.push(DirEntry {
+                    ino_id: record.ino_id,
+                    kind,
+                    name: OsString::from_vec(record.name(data).to_vec()),
+                });
+            }
+        }
+        entries
+    }
+
+    pub fn lookup_entry(&self, parent: u32, name: &OsStr) -> Result<u32, c_int> {
+        let inode = self.dir_inode(parent)?;
+        self.find_record(inode, name)
+            .map(|(_, _, record)| record.ino_id)
+            .ok_or(ENOENT)
+    }
+
+    // Adds an entry to dir, reusing free space in an existing block before growing the directory
+    pub(crate) fn add_entry(
+        &mut self,
+        dir: u32,
+        name: &OsStr,
+        ino_id: u32,
+        kind: FileType,
+    ) -> Result<(), c_int> {
+        if name.len() > MAX_NAME_LEN {
+            return Err(ENAMETOOLONG);
+        }
+        if self.find_record(self.dir_inode(dir)?, name).is_some() {
+            return Err(EEXIST);
+        }
+        let inode = self.unshare_dir(dir)?;
+        let entry = (ino_id, kind, name.as_bytes());
+        if inode.flags & INODE_FLAG_INDEX != 0 {
+            return self.dx_add_entry(dir, entry);
+        }
+
+        let blks = self.dir_blks(&inode);
+        let mut inserted = false;
+        for &blk_no in &blks {
+            if self.insert_record(blk_no, entry)? {
+                inserted = true;
+                break;
+            }
+        }
+        if !inserted {
+            // Growing past one block is when a directory gets an index, if enabled
+            if blks.len() == 1 && self.metadata.features & FEATURE_DIR_INDEX != 0 {
+                self.dx_convert(dir)?;
+                return self.dx_add_entry(dir, entry);
+            }
+            let (_, blk_no) = self.append_dir_blk(dir)?;
+            let data = self.blk_data_mut(blk_no).map_err(blk_errno)?;
+            encode_record(data, 0, data.len(), entry);
+        }
+        self.inode_mut(dir).map_err(inode_errno)?.update_mtime();
+        Ok(())
+    }
+
+    // Puts entry into free space in the block if there is enough, returns whether it did
+    pub(crate) fn insert_record(
+        &mut self,
+        blk_no: u32,
+        entry: (u32, FileType, &[u8]),
+    ) -> Result<bool, c_int> {
+        let needed = rec_len_for(entry.2.len());
+        let Some(data) = self.blk_data(blk_no) else {
+            return Ok(false);
+        };
+        let Some(record) = decode_records(&data)
+            .into_iter()
+            .find(|record| record.rec_len - record.used_len() >= needed)
+        else {
+            return Ok(false);
+        };
+        let used = record.used_len();
+        let data = self.blk_data_mut(blk_no).map_err(blk_errno)?;
+        if used > 0 {
+            data[record.offset + 4..record.offset + 6]
+                .copy_from_slice(&(used as u16).to_le_bytes());
+        }
+        encode_record(data, record.offset + used, record.rec_len - used, entry);
+        Ok(true)
+    }
+
+    // Directory blocks are changed in place, so a directory sharing them with a snapshot gets
+    // its own copies first
+    fn unshare_dir(&mut self, dir: u32) -> Result<Inode, c_int> {
+        let mut inode = *self.dir_inode(dir)?;
+        let before = inode;
+        let result = self.unshare_tree(&mut inode);
+        if inode != before {
+            *self.inode_mut(dir).map_err(inode_errno)? = inode;
+        }
+        result.map_err(blk_errno)?;
+        Ok(inode)
+    }
+
+    // Allocates a zeroed block at the end of dir and returns its logical and physical number
+    pub(crate) fn append_dir_blk(&mut self, dir: u32) -> Result<(u64, u32), c_int> {
+        let mut inode = *self.dir_inode(dir)?;
+        let blk_size = self.blk_size();
+        let lblk = inode.size / blk_size;
+        let result = self.bmap_alloc(&mut inode, lblk);
+        if result.is_ok() {
+            inode.size += blk_size;
+        }
+        inode.update_mtime();
+        // Pointer blocks allocated before running out of space still belong to dir
+        *self.inode_mut(dir).map_err(inode_errno)? = inode;
+        result.map(|blk_no| (lblk, blk_no)).map_err(blk_errno)
+    }
+
+    // Removes name from dir and returns the inode it pointed at. The record's space is merged
+    // into the one before it, or marked unused when it is first in its block.
+    pub(crate) fn remove_entry(&mut self, dir: u32, name: &OsStr) -> Result<u32, c_int> {
+        let inode = self.unshare_dir(dir)?;
+        let (blk_no, prev, record) = self.find_record(&inode, name).ok_or(ENOENT)?;
+        let data = self.blk_data_mut(blk_no).map_err(blk_errno)?;
+        match prev {
+            Some(prev) => {
+                let rec_len = (prev.rec_len + record.rec_len) as u16;
+                data[prev.offset + 4..prev.offset + 6].copy_from_slice(&rec_len.to_le_bytes());
+            }
+            None => data[record.offset..record.offset + 4].fill(0),
+        }
+        self.inode_mut(dir).map_err(inode_errno)?.update_mtime();
+        Ok(record.ino_id)
+    }
+
+    // Points an existing entry at another inode of the same kind
+    pub(crate) fn set_entry(&mut self, dir: u32, name: &OsStr, ino_id: u32) -> Result<(), c_int> {
+        let inode = self.unshare_dir(dir)?;
+        let (blk_no, _, record) = self.find_record(&inode, name).ok_or(ENOENT)?;
+        let data = self.blk_data_mut(blk_no).map_err(blk_errno)?;
+        data[record.offset..record.offset + 4].copy_from_slice(&ino_id.to_le_bytes());
+        Ok(())
+    }
+
+    // Writes the "." and ".." entries of a new directory
+    pub(crate) fn init_dir(&mut self, ino_id: u32, parent: u32) -> Result<(), c_int> {
+        self.add_entry(ino_id, OsStr::new("."), ino_id, FileType::Directory)?;
+        self.add_entry(ino_id, OsStr::new(".."), parent, FileType::Directory)
+    }
+
+    // Allocates an inode owned by root and links it into parent under name
+    pub fn make_node(
+        &mut self,
+        parent: u32,
+        name: &OsStr,
+        kind: FileType,
+        perm: u16,
+    ) -> Result<u32, c_int> {
+        self.make_node_as(parent, name, kind, perm, 0, 0)
+    }
+
+    // Like make_node, owned by uid and gid. Under a setgid directory the group comes from the
+    // directory instead, and new subdirectories inherit the setgid bit.
+    pub fn make_node_as(
+        &mut self,
+        parent: u32,
+        name: &OsStr,
+        kind: FileType,
+        mut perm: u16,
+        uid: u32,
+        mut gid: u32,
+    ) -> Result<u32, c_int> {
+        if name.len() > MAX_NAME_LEN {
+            return Err(ENAMETOOLONG);
+        }
+        let dir = self.dir_inode(parent)?;
+        if self.find_record(dir, name).is_some() {
+            return Err(EEXIST);
+        }
+        // A new directory's ".." links back to the parent
+        if kind == FileType::Directory && dir.nlink >= MAX_LINKS {
+            return Err(EMLINK);
+        }
+
+        if dir.perm & S_ISGID != 0 {
+            gid = dir.gid;
+            if kind == FileType::Directory {
+                perm |= S_ISGID;
+            }
+        }
+
+        let ino_id = self.alloc_inode(parent, kind, perm).map_err(inode_errno)?;
+        let inode = self.inode_mut(ino_id).map_err(inode_errno)?;
+        (inode.uid, inode.gid) = (uid, gid);
+        let mut result = self.inherit_acl(parent, ino_id);
+        if kind == FileType::Directory {
+            result = result.and_then(|()| self.init_dir(ino_id, parent));
+        }
+        if let Err(err) = result.and_then(|()| self.add_entry(parent, name, ino_id, kind)) {
+            let _ = self.free_node(ino_id);
+            return Err(err);
+        }
+        if kind == FileType::Directory {
+            self.inode_mut(parent).map_err(inode_errno)?.nlink += 1;
+        }
+        Ok(ino_id)
+    }
+
+    // Adds another entry for an existing inode. Directories cannot be hard linked.
+    pub fn link_node(&mut self, ino_id: u32, parent: u32, name: &OsStr) -> Result<(), c_int> {
+        let inode = *self.inode(ino_id).map_err(inode_errno)?;
+        if inode.kind == FileType::Directory {
+            return Err(EPERM);
+        }
+        if inode.nlink >= MAX_LINKS {
+            return Err(EMLINK);
+        }
+        self.add_entry(parent, name, ino_id, inode.kind)?;
+        let inode = self.inode_mut(ino_id).map_err(inode_errno)?;
+        inode.nlink += 1;
+        inode.update_ctime();
+        Ok(())
+    }
+
+    // Unlinks name from parent. The inode and its data are freed with the last link, or later
+    // by release_inode if a handle still has it open.
+    pub(crate) fn remove_node(
+        &mut self,
+        parent: u32,
+        name: &OsStr,
+        dir: bool,
+    ) -> Result<(), c_int> {
+        if is_dot(name) {
+            return Err(EINVAL);
+        }
+        let ino_id = self.lookup_entry(parent, name)?;
+        let kind = self.inode(ino_id).map_err(inode_errno)?.kind;
+        match (dir, kind == FileType::Directory) {
+            (true, false) => return Err(ENOTDIR),
+            (false, true) => return Err(EISDIR),
+            (true, true) if !self.dir_is_empty(ino_id)? => return Err(ENOTEMPTY),
+            _ => {}
+        }
+
+        self.remove_entry(parent, name)?;
+        if kind == FileType::Directory {
+            // Its "." goes with it, and its ".." no longer links to parent
+            self.inode_mut(parent).map_err(inode_errno)?.nlink -= 1;
+            self.inode_mut(ino_id).map_err(inode_errno)?.nlink = 0;
+        } else {
+            let inode = self.inode_mut(ino_id).map_err(inode_errno)?;
+            inode.nlink -= 1;
+            inode.update_ctime();
+        }
+        self.free_if_unused(ino_id)
+    }
+
+    pub(crate) fn dir_is_empty(&self, ino_id: u32) -> Result<bool, c_int> {
+        Ok(self
+            .read_dir(ino_id)?
+            .iter()
+            .all(|entry| is_dot(&entry.name)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::Geometry;
+    use crate::test_util::state_with_root;
+    use crate::ROOT_INO;
+
+    fn names(state: &FSState, dir: u32) -> Vec<OsString> {
+        let entries = state.read_dir(dir).unwrap();
+        entries.into_iter().map(|entry| entry.name).collect()
+    }
+
+    #[test]
+    fn test_record_layout() {
+        let mut data = vec![0; 64];
+        encode_record(&mut data, 0, 16, (5, FileType::Symlink, b"abc"));
+        encode_record(&mut data, 16, 48, (0, FileType::RegularFile, b""));
+        assert_eq!(data[..12], [5, 0, 0, 0, 16, 0, 3, 7, b'a', b'b', b'c', 0]);
+
+        let records = decode_records(&data);
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].name(&data), b"abc");
+        assert_eq!(records[0].used_len(), 12);
+        assert_eq!(records[1].offset, 16);
+        assert_eq!(records[1].used_len(), 0);
+    }
+
+    #[test]
+    fn test_root_has_dot_entries() {
+        let state = state_with_root(Geometry::default());
+        let entries = state.read_dir(ROOT_INO).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].name, ".");
+        assert_eq!(entries[1].name, "..");
+        assert!(entries.iter().all(|entry| entry.ino_id == ROOT_INO));
+        assert!(entries
+            .iter()
+            .all(|entry| entry.kind == FileType::Directory));
+        assert_eq!(state.inode(ROOT_INO).unwrap().size, state.blk_size());
+    }
+
+    #[test]
+    fn test_create_lookup_and_unlink_file() {
+        let state = &mut state_with_root(Geometry::default());
+        let ino_id = state
+            .make_node(ROOT_INO, OsStr::new("file"), FileType::RegularFile, 0o644)
+            .unwrap();
+        assert_eq!(state.lookup_entry(ROOT_INO, OsStr::new("file")), Ok(ino_id));
+        let entry = state.read_dir(ROOT_INO).unwrap().pop().unwrap();
+        assert_eq!(entry.kind, FileType::RegularFile);
+
+        let dup = state.make_node(ROOT_INO, OsStr::new("file"), FileType::RegularFile, 0o644);
+        assert!(matches!(dup, Err(EEXIST)));
+
+        state
+            .remove_node(ROOT_INO, OsStr::new("file"), false)
+            .unwrap();
+        assert_eq!(
+            state.lookup_entry(ROOT_INO, OsStr::new("file")),
+            Err(ENOENT)
+        );
+        assert!(state.inode(ino_id).is_err());
+    }
+
+    #[test]
+    fn test_subdirectory_dots_point_at_parent() {
+        let state = &mut state_with_root(Geometry::default());
+        let dir = state
+            .make_node(ROOT_INO, OsStr::new("dir"), FileType::Directory, 0o755)
+            .unwrap();
+        assert_eq!(state.lookup_entry(dir, OsStr::new(".")), Ok(dir));
+        assert_eq!(state.lookup_entry(dir, OsStr::new("..")), Ok(ROOT_INO));
+        assert_eq!(state.remove_node(dir, OsStr::new(".."), true), Err(EINVAL));
+    }
+
+    #[test]
+    fn test_removed_space_is_reused() {
+        let state = &mut state_with_root(Geometry::default());
+        for name in ["a", "b", "c"] {
+            state
+                .make_node(ROOT_INO, OsStr::new(name), FileType::RegularFile, 0o644)
+                .unwrap();
+        }
+        state.remove_node(ROOT_INO, OsStr::new("b"), false).unwrap();
+        assert_eq!(names(state, ROOT_INO), [".", "..", "a", "c"]);
+
+        state
+            .make_node(ROOT_INO, OsStr::new("d"), FileType::RegularFile, 0o644)
+            .unwrap();
+        assert_eq!(names(state, ROOT_INO), [".", "..", "a", "d", "c"]);
+    }
+
+    #[test]
+    fn test_directory_grows_by_whole_blocks() {
+        let mut state = FSState::new(Geometry::new(1 << 20, 1024, 128).unwrap());
+        state.set_features(0);
+        state.init_root();
+        // 12 + 244 bytes per record, so each 1 KiB block holds 4 of them
+        let names: Vec<String> = (0..10).map(|i| format!("{i:0>244}")).collect();
+        for name in &names {
+            state
+                .make_node(ROOT_INO, OsStr::new(name), FileType::RegularFile, 0o644)
+                .unwrap();
+        }
+        let root = *state.inode(ROOT_INO).unwrap();
+        assert_eq!(root.size, 3 * 1024);
+        assert_eq!(root.blocks, 3);
+
+        for name in &names {
+            assert!(state.lookup_entry(ROOT_INO, OsStr::new(name)).is_ok());
+        }
+        // Removing the first record of a block leaves an unused record behind
+        state
+            .remove_node(ROOT_INO, OsStr::new(&names[3]), false)
+            .unwrap();
+        assert_eq!(state.read_dir(ROOT_INO).unwrap().len(), 11);
+        assert!(state.lookup_entry(ROOT_INO, OsStr::new(&names[4])).is_ok());
+    }
+
+    #[test]
+    fn test_rmdir_requires_empty_directory() {
+        let state = &mut state_with_root(Geometry::default());
+        let dir = state
+            .make_node(ROOT_INO, OsStr::new("dir"), FileType::Directory, 0o755)
+            .unwrap();
+        state
+            .make_node(dir, OsStr::new("file"), FileType::RegularFile, 0o644)
+            .unwrap();
+
+        assert_eq!(
+            state.remove_node(ROOT_INO, OsStr::new("dir"), false),
+            Err(EISDIR)
+        );
+        assert_eq!(
+            state.remove_node(ROOT_INO, OsStr::new("dir"), true),
+            Err(ENOTEMPTY)
+        );
+        assert_eq!(
+            state.remove_node(dir, OsStr::new("file"), true),
+            Err(ENOTDIR)
+        );
+
+        state.remove_node(dir, OsStr::new("file"), false).unwrap();
+        state
+            .remove_node(ROOT_INO, OsStr::new("dir"), true)
+            .unwrap();
+        assert_eq!(names(state, ROOT_INO), [".", ".."]);
+    }
+
+    #[test]
+    fn test_hard_links_share_the_inode() {
+        let state = &mut state_with_root(Geometry::default());
+        let ino_id = state
+            .make_node(ROOT_INO, OsStr::new("a"), FileType::RegularFile, 0o644)
+            .unwrap();
+        state.write_at(ino_id, 0, b"data").unwrap();
+        state.link_node(ino_id, ROOT_INO, OsStr::new("b")).unwrap();
+        assert_eq!(state.inode(ino_id).unwrap().nlink, 2);
+        assert_eq!(state.lookup_entry(ROOT_INO, OsStr::new("b")), Ok(ino_id));
+        assert_eq!(
+            state.link_node(ino_id, ROOT_INO, OsStr::new("a")),
+            Err(EEXIST)
+        );
+
+        state.remove_node(ROOT_INO, OsStr::new("a"), false).unwrap();
+        assert_eq!(state.inode(ino_id).unwrap().nlink, 1);
+        let mut buf = [0; 4];
+        assert_eq!(state.read_at(ino_id, 0, &mut buf), Ok(4));
+        assert_eq!(&buf, b"data");
+
+        state.remove_node(ROOT_INO, OsStr::new("b"), false).unwrap();
+        assert!(state.inode(ino_id).is_err());
+    }
+
+    #[test]
+    fn test_directory_link_counts() {
+        let state = &mut state_with_root(Geometry::default());
+        assert_eq!(state.inode(ROOT_INO).unwrap().nlink, 2);
+        let dir = state
+            .make_node(ROOT_INO, OsStr::new("dir"), FileType::Directory, 0o755)
+            .unwrap();
+        state
+            .make_node(dir, OsStr::new("sub"), FileType::Directory, 0o755)
+            .unwrap();
+        state
+            .make_node(dir, OsStr::new("file"), FileType::RegularFile, 0o644)
+            .unwrap();
+        assert_eq!(state.inode(ROOT_INO).unwrap().nlink, 3);
+        assert_eq!(state.inode(dir).unwrap().nlink, 3);
+        assert_eq!(
+            state.link_node(dir, ROOT_INO, OsStr::new("alias")),
+            Err(EPERM)
+        );
+
+        state.remove_node(dir, OsStr::new("sub"), true).unwrap();
+        assert_eq!(state.inode(dir).unwrap().nlink, 2);
+    }
+
+    #[test]
+    fn test_setgid_directory_passes_on_its_group() {
+        let state = &mut state_with_root(Geometry::default());
+        let kinds = [FileType::Directory, FileType::RegularFile];
+        let [dir, file] = kinds.map(|kind| {
+            let name = format!("{kind:?}");
+            state
+                .make_node_as(ROOT_INO, OsStr::new(&name), kind, 0o2755, 1000, 100)
+                .unwrap()
+        });
+        assert_eq!(state.inode(file).unwrap().gid, 100);
+
+        let sub = state
+            .make_node_as(dir, OsStr::new("sub"), FileType::Directory, 0o755, 0, 0)
+            .unwrap();
+        let inode = state.inode(sub).unwrap();
+        assert_eq!((inode.uid, inode.gid), (0, 100));
+        assert_eq!(inode.perm, 0o2755);
+        let file = state
+            .make_node_as(dir, OsStr::new("file"), FileType::RegularFile, 0o644, 0, 0)
+            .unwrap();
+        let inode = state.inode(file).unwrap();
+        assert_eq!((inode.gid, inode.perm), (100, 0o644));
+    }
+}
@@ -0,0 +1,940 @@
+use crate::symlink::INLINE_DATA_LEN;
+use crate::{BlockError, FSState, Inode, INODE_FLAG_EXTENTS, INVALID_PTR};
+use std::mem;
+
+// Like ext4, an inode with INODE_FLAG_EXTENTS maps its blocks with a tree of extents instead of
+// a pointer per block. An extent is a run of logical blocks kept in consecutive physical blocks,
+// so a file written in one go needs a handful of them however large it is.
+//
+// The root node sits in the inode where the block pointers would be, the nodes below it fill a
+// block each. A node is
+// | entry count (u16) | depth (u16) | entries of ENTRY_LEN bytes
+// sorted by logical block. Leaves, at depth 0, hold extents:
+// | first logical block | first physical block | length |
+// Index nodes hold | first logical block | child node | 0 |. Each child takes the logical blocks
+// from its own first one up to the next child's, and the first child everything below as well.
+// An all-zero pointer area is an empty leaf, which is how every inode starts out.
+const HEADER_LEN: usize = 4;
+const ENTRY_LEN: usize = 12;
+const ROOT_ENTRIES: usize = (INLINE_DATA_LEN - HEADER_LEN) / ENTRY_LEN;
+
+// Logical blocks are u32, one is kept free so the end of an extent always fits
+pub(crate) const MAX_EXTENT_LBLKS: u64 = u32::MAX as u64;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) struct Extent {
+    pub(crate) lblk: u32,
+    // The child node in an index node
+    pub(crate) start: u32,
+    pub(crate) len: u32,
+}
+
+impl Extent {
+    fn end(&self) -> u64 {
+        self.lblk as u64 + self.len as u64
+    }
+
+    fn blk_at(&self, lblk: u64) -> u32 {
+        self.start + (lblk - self.lblk as u64) as u32
+    }
+
+    pub(crate) fn blks(&self) -> std::ops::Range<u32> {
+        self.start..self.start + self.len
+    }
+}
+
+#[derive(Clone, PartialEq, Debug, Default)]
+pub(crate) struct ExtentNode {
+    pub(crate) depth: u16,
+    pub(crate) entries: Vec<Extent>,
+}
+
+impl ExtentNode {
+    // None when the entries would run past the end of data
+    pub(crate) fn parse(data: &[u8]) -> Option<Self> {
+        let count = u16::from_le_bytes([data[0], data[1]]) as usize;
+        let depth = u16::from_le_bytes([data[2], data[3]]);
+        let entries = data
+            .get(HEADER_LEN..HEADER_LEN + count * ENTRY_LEN)?
+            .chunks_exact(ENTRY_LEN)
+            .map(|entry| {
+                let word = |idx: usize| u32::from_le_bytes(entry[idx..idx + 4].try_into().unwrap());
+                Extent {
+                    lblk: word(0),
+                    start: word(4),
+                    len: word(8),
+                }
+            })
+            .collect();
+        Some(Self { depth, entries })
+    }
+
+    pub(crate) fn write(&self, data: &mut [u8]) {
+        data.fill(0);
+        data[0..2].copy_from_slice(&(self.entries.len() as u16).to_le_bytes());
+        data[2..4].copy_from_slice(&self.depth.to_le_bytes());
+        for (entry, chunk) in self
+            .entries
+            .iter()
+            .zip(data[HEADER_LEN..].chunks_exact_mut(ENTRY_LEN))
+        {
+            chunk[0..4].copy_from_slice(&entry.lblk.to_le_bytes());
+            chunk[4..8].copy_from_slice(&entry.start.to_le_bytes());
+            chunk[8..12].copy_from_slice(&entry.len.to_le_bytes());
+        }
+    }
+
+    // The entry of an index node whose child covers lblk
+    fn child_idx(&self, lblk: u64) -> usize {
+        self.entries
+            .partition_point(|entry| entry.lblk as u64 <= lblk)
+            .saturating_sub(1)
+    }
+
+    // The extent of a leaf holding lblk
+    fn find(&self, lblk: u64) -> Option<&Extent> {
+        let idx = self
+            .entries
+            .partition_point(|extent| extent.lblk as u64 <= lblk);
+        self.entries[..idx]
+            .last()
+            .filter(|extent| lblk < extent.end())
+    }
+
+    // Where to put lblk so it continues the extent before it
+    fn goal(&self, lblk: u64) -> u32 {
+        let idx = self
+            .entries
+            .partition_point(|extent| extent.lblk as u64 <= lblk);
+        self.entries[..idx].last().map_or(0, |extent| {
+            u32::try_from(extent.start as u64 + (lblk - extent.lblk as u64)).unwrap_or(0)
+        })
+    }
+
+    // Maps lblk of a leaf to blk_no, or unmaps it for INVALID_PTR, splitting the extent it was
+    // in and merging with the extents around it where the blocks line up
+    fn map(&mut self, lblk: u32, blk_no: u32) {
+        if let Some(idx) = self
+            .entries
+            .iter()
+            .position(|extent| extent.lblk <= lblk && (lblk as u64) < extent.end())
+        {
+            let extent = self.entries[idx];
+            let before = Extent {
+                len: lblk - extent.lblk,
+                ..extent
+            };
+            let after = Extent {
+                lblk: lblk + 1,
+                start: extent.blk_at(lblk as u64) + 1,
+                len: extent.len - before.len - 1,
+            };
+            let parts = [before, after].into_iter().filter(|part| part.len > 0);
+            self.entries.splice(idx..=idx, parts);
+        }
+        if blk_no == INVALID_PTR {
+            return;
+        }
+        let idx = self.entries.partition_point(|extent| extent.lblk < lblk);
+        let joins_before = idx > 0 && {
+            let before = &self.entries[idx - 1];
+            before.end() == lblk as u64 && before.start + before.len == blk_no
+        };
+        let joins_after = self.entries.get(idx).is_some_and(|after| {
+            after.lblk as u64 == lblk as u64 + 1 && after.start as u64 == blk_no as u64 + 1
+        });
+        match (joins_before, joins_after) {
+            (true, true) => {
+                let after = self.entries.remove(idx);
+                self.entries[idx - 1].len += 1 + after.len;
+            }
+            (true, false) => self.entries[idx - 1].len += 1,
+            (false, true) => {
+                let after = &mut self.entries[idx];
+                after.lblk -= 1;
+                after.start -= 1;
+                after.len += 1;
+            }
+            (false, false) => self.entries.insert(
+                idx,
+                Extent {
+                    lblk,
+                    start: blk_no,
+                    len: 1,
+                },
+            ),
+        }
+    }
+}
+
+impl Inode {
+    pub(crate) fn has_extents(&self) -> bool {
+        self.flags & INODE_FLAG_EXTENTS != 0
+    }
+
+    // A root that does not parse reads as empty, fsck reports it
+    pub(crate) fn extent_root(&self) -> ExtentNode {
+        ExtentNode::parse(&self.ptr_area()).unwrap_or_default()
+    }
+
+    pub(crate) fn set_extent_root(&mut self, root: &ExtentNode) {
+        let mut data = [0; INLINE_DATA_LEN];
+        root.write(&mut data);
+        self.set_ptr_area(&data);
+    }
+
+    // The blocks the root points at: its children, or the data blocks when it is a leaf
+    pub(crate) fn extent_root_blks(&self) -> Vec<u32> {
+        let root = self.extent_root();
+        match root.depth {
+            0 => root.entries.iter().flat_map(Extent::blks).collect(),
+            _ => root.entries.iter().map(|entry| entry.start).collect(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum NodeAt {
+    Root,
+    Blk(u32),
+}
+
+// A node on the way down to a leaf, with the entry followed from it
+struct Step {
+    at: NodeAt,
+    node: ExtentNode,
+    idx: usize,
+}
+
+impl FSState {
+    pub(crate) fn extent_node_capacity(&self) -> usize {
+        (self.blk_size() as usize - HEADER_LEN) / ENTRY_LEN
+    }
+
+    fn capacity_at(&self, at: NodeAt) -> usize {
+        match at {
+            NodeAt::Root => ROOT_ENTRIES,
+            NodeAt::Blk(_) => self.extent_node_capacity(),
+        }
+    }
+
+    fn extent_blk(&self, blk_no: u32) -> ExtentNode {
+        self.blk_data(blk_no)
+            .and_then(|data| ExtentNode::parse(&data))
+            .unwrap_or_default()
+    }
+
+    fn write_extent_node(
+        &mut self,
+        inode: &mut Inode,
+        at: NodeAt,
+        node: &ExtentNode,
+    ) -> Result<(), BlockError> {
+        match at {
+            NodeAt::Root => inode.set_extent_root(node),
+            NodeAt::Blk(blk_no) => node.write(self.blk_data_mut(blk_no)?),
+        }
+        Ok(())
+    }
+
+    // Physical block backing logical block lblk, INVALID_PTR for a hole
+    pub(crate) fn extent_bmap(&self, inode: &Inode, lblk: u64) -> u32 {
+        let mut node = inode.extent_root();
+        while node.depth > 0 {
+            let Some(entry) = node.entries.get(node.child_idx(lblk)) else {
+                return INVALID_PTR;
+            };
+            let child = self.extent_blk(entry.start);
+            if child.depth + 1 != node.depth {
+                return INVALID_PTR;
+            }
+            node = child;
+        }
+        node.find(lblk)
+            .map_or(INVALID_PTR, |extent| extent.blk_at(lblk))
+    }
+
+    // Every extent of inode in logical order
+    pub(crate) fn extents(&self, inode: &Inode) -> Vec<Extent> {
+        let mut extents = Vec::new();
+        self.collect_extents(&inode.extent_root(), &mut extents);
+        extents
+    }
+
+    fn collect_extents(&self, node: &ExtentNode, extents: &mut Vec<Extent>) {
+        match node.depth {
+            0 => extents.extend(&node.entries),
+            depth => {
+                for entry in &node.entries {
+                    let child = self.extent_blk(entry.start);
+                    if child.depth + 1 == depth {
+                        self.collect_extents(&child, extents);
+                    }
+                }
+            }
+        }
+    }
+
+    // One past the last logical block mapped under node
+    fn extents_end(&self, node: &ExtentNode) -> u64 {
+        match (node.depth, node.entries.last()) {
+            (_, None) => 0,
+            (0, Some(extent)) => extent.end(),
+            (_, Some(entry)) => self.extents_end(&self.extent_blk(entry.start)),
+        }
+    }
+
+    // Returns a node the caller can change in place of blk_no, like unshare_blk. The copy of a
+    // shared node takes a reference to every block it points at, data blocks included.
+    fn unshare_extent_node(&mut self, blk_no: u32, depth: u16) -> Result<u32, BlockError> {
+        if self.blk_refs(blk_no) < 2 {
+            return Ok(blk_no);
+        }
+        let node = self.extent_blk(blk_no);
+        let copy = self.alloc_blk_near(blk_no)?;
+        node.write(self.blk_data_mut(copy)?);
+        for entry in &node.entries {
+            match depth {
+                0 => entry.blks().for_each(|blk_no| self.share_blk(blk_no)),
+                _ => self.share_blk(entry.start),
+            }
+        }
+        self.free_blk(blk_no)?;
+        Ok(copy)
+    }
+
+    // The nodes from the root down to the leaf holding lblk, copying the shared ones on the
+    // way so the caller can change any of them
+    fn extent_path(&mut self, inode: &mut Inode, lblk: u64) -> Result<Vec<Step>, BlockError> {
+        let root = inode.extent_root();
+        let mut path = vec![Step {
+            at: NodeAt::Root,
+            node: root,
+            idx: 0,
+        }];
+        loop {
+            let step = path.last_mut().unwrap();
+            if step.node.depth == 0 {
+                break;
+            }
+            if step.node.entries.is_empty() {
+                step.node.depth = 0;
+                break;
+            }
+            step.idx = step.node.child_idx(lblk);
+            let depth = step.node.depth - 1;
+            let child = step.node.entries[step.idx].start;
+            let copy = self.unshare_extent_node(child, depth)?;
+            if copy != child {
+                step.node.entries[step.idx].start = copy;
+                let (at, node) = (step.at, step.node.clone());
+                self.write_extent_node(inode, at, &node)?;
+            }
+            let node = self.extent_blk(copy);
+            if node.depth != depth {
+                return Err(BlockError::InvalidBlkNo);
+            }
+            path.push(Step {
+                at: NodeAt::Blk(copy),
+                node,
+                idx: 0,
+            });
+        }
+        Ok(path)
+    }
+
+    // Maps lblk to blk_no, or unmaps it for INVALID_PTR, and returns the block mapped before.
+    // The old block keeps its reference, the new one gets none.
+    pub(crate) fn extent_set(
+        &mut self,
+        inode: &mut Inode,
+        lblk: u64,
+        blk_no: u32,
+    ) -> Result<u32, BlockError> {
+        if lblk >= MAX_EXTENT_LBLKS {
+            return Err(BlockError::FileTooLarge);
+        }
+        let mut path = self.extent_path(inode, lblk)?;
+        let leaf = path.len() - 1;
+        let old = path[leaf]
+            .node
+            .find(lblk)
+            .map_or(INVALID_PTR, |extent| extent.blk_at(lblk));
+        path[leaf].node.map(lblk as u32, blk_no);
+        // The nodes the splits need are allocated first, so running out of space leaves the
+        // tree as it was
+        let mut spare = Vec::new();
+        for _ in 0..self.extent_splits(&path) {
+            match self.alloc_blk() {
+                Ok(blk_no) => spare.push(blk_no),
+                Err(err) => {
+                    for blk_no in spare {
+                        self.free_blk(blk_no)?;
+                    }
+                    return Err(err);
+                }
+            }
+        }
+        self.store_extent_path(inode, &mut path, leaf, &mut spare)?;
+        Ok(old)
+    }
+
+    // Number of nodes that overflow when path is stored: the leaf, then each parent that is
+    // already full when the split below adds an entry to it
+    fn extent_splits(&self, path: &[Step]) -> usize {
+        let mut splits = 0;
+        for step in path.iter().rev() {
+            if step.node.entries.len() + splits.min(1) <= self.capacity_at(step.at) {
+                break;
+            }
+            splits += 1;
+        }
+        splits
+    }
+
+    // Writes path[level] back, splitting it when it overflowed and dropping it when it was
+    // left empty, then does the same for its parent when that changed too
+    fn store_extent_path(
+        &mut self,
+        inode: &mut Inode,
+        path: &mut Vec<Step>,
+        level: usize,
+        spare: &mut Vec<u32>,
+    ) -> Result<(), BlockError> {
+        let at = path[level].at;
+        let len = path[level].node.entries.len();
+        if len > self.capacity_at(at) {
+            let blk_no = spare.pop().ok_or(BlockError::NoFreeBlksOnAlloc)?;
+            inode.blocks += 1;
+            if at == NodeAt::Root {
+                // The root moves down into the new block, leaving an index pointing at it
+                let node = mem::take(&mut path[level].node);
+                let root = ExtentNode {
+                    depth: node.depth + 1,
+                    entries: vec![Extent {
+                        lblk: node.entries[0].lblk,
+                        start: blk_no,
+                        len: 0,
+                    }],
+                };
+                inode.set_extent_root(&root);
+                let idx = mem::replace(&mut path[level].idx, 0);
+                path[level].node = root;
+                let below = Step {
+                    at: NodeAt::Blk(blk_no),
+                    node,
+                    idx,
+                };
+                path.insert(level + 1, below);
+                return self.store_extent_path(inode, path, level + 1, spare);
+            }
+            // The upper half goes to the new block, which the parent points at next
+            let upper = ExtentNode {
+                depth: path[level].node.depth,
+                entries: path[level].node.entries.split_off(len / 2),
+            };
+            let lblk = upper.entries[0].lblk;
+            self.write_extent_node(inode, NodeAt::Blk(blk_no), &upper)?;
+            let lower = path[level].node.clone();
+            self.write_extent_node(inode, at, &lower)?;
+            let parent = &mut path[level - 1];
+            let entry = Extent {
+                lblk,
+                start: blk_no,
+                len: 0,
+            };
+            parent.node.entries.insert(parent.idx + 1, entry);
+            return self.store_extent_path(inode, path, level - 1, spare);
+        }
+        if let (0, NodeAt::Blk(blk_no)) = (len, at) {
+            self.free_blk(blk_no)?;
+            inode.blocks -= 1;
+            let parent = &mut path[level - 1];
+            parent.node.entries.remove(parent.idx);
+            return self.store_extent_path(inode, path, level - 1, spare);
+        }
+        if len == 0 {
+            path[level].node.depth = 0;
+        }
+        let node = path[level].node.clone();
+        self.write_extent_node(inode, at, &node)?;
+        Ok(())
+    }
+
+    // bmap_alloc and bmap_mut for extents. New blocks go right after the block before them
+    // where that is free, which keeps extents long.
+    pub(crate) fn extent_bmap_write(
+        &mut self,
+        inode: &mut Inode,
+        lblk: u64,
+        want: u64,
+    ) -> Result<u32, BlockError> {
+        if lblk >= MAX_EXTENT_LBLKS {
+            return Err(BlockError::FileTooLarge);
+        }
+        let path = self.extent_path(inode, lblk)?;
+        let leaf = &path[path.len() - 1].node;
+        let (blk_no, goal) = match leaf.find(lblk) {
+            Some(extent) => (extent.blk_at(lblk), extent.blk_at(lblk)),
+            None => match leaf.goal(lblk) {
+                INVALID_PTR => (INVALID_PTR, self.inode_goal(inode.ino_id)),
+                goal => (INVALID_PTR, goal),
+            },
+        };
+        if blk_no != INVALID_PTR && self.blk_refs(blk_no) < 2 {
+            return Ok(blk_no);
+        }
+        if blk_no == INVALID_PTR && want == 0 {
+            return Ok(INVALID_PTR);
+        }
+        // A shared block is replaced by a copy, see snapshot.rs
+        let new = self.alloc_blk_for(goal, want.max(1))?;
+        if let Some(data) = self.blk_data(blk_no) {
+            self.blk_data_mut(new)?.copy_from_slice(&data);
+        }
+        if let Err(err) = self.extent_set(inode, lblk, new) {
+            self.free_blk(new)?;
+            return Err(err);
+        }
+        match blk_no {
+            INVALID_PTR => inode.blocks += 1,
+            blk_no => self.free_blk(blk_no)?,
+        }
+        Ok(new)
+    }
+
+    // Frees every data block from logical block first on, along with the nodes left empty
+    pub(crate) fn extent_free_from(
+        &mut self,
+        inode: &mut Inode,
+        first: u64,
+    ) -> Result<(), BlockError> {
+        let mut root = inode.extent_root();
+        if self.extents_end(&root) <= first {
+            return Ok(());
+        }
+        self.free_extents_from(inode, &mut root, first)?;
+        if root.entries.is_empty() {
+            root.depth = 0;
+        }
+        inode.set_extent_root(&root);
+        Ok(())
+    }
+
+    fn free_extents_from(
+        &mut self,
+        inode: &mut Inode,
+        node: &mut ExtentNode,
+        first: u64,
+    ) -> Result<(), BlockError> {
+        if node.depth == 0 {
+            for extent in mem::take(&mut node.entries) {
+                let keep = first
+                    .saturating_sub(extent.lblk as u64)
+                    .min(extent.len as u64) as u32;
+                for blk_no in extent.start + keep..extent.start + extent.len {
+                    self.free_blk(blk_no)?;
+                    inode.blocks -= 1;
+                }
+                if keep > 0 {
+                    node.entries.push(Extent {
+                        len: keep,
+                        ..extent
+                    });
+                }
+            }
+            return Ok(());
+        }
+        let depth = node.depth - 1;
+        while let Some(&entry) = node.entries.last() {
+            let idx = node.entries.len() - 1;
+            // The first child also takes whatever is below its first logical block
+            let from = if idx == 0 { 0 } else { entry.lblk as u64 };
+            if from >= first {
+                // Dropping the reference to a shared node leaves it to whoever else has one
+                if self.blk_refs(entry.start) > 1 {
+                    inode.blocks -= self.extent_subtree_blks(entry.start, depth);
+                } else {
+                    let mut child = self.extent_blk(entry.start);
+                    self.free_extents_from(inode, &mut child, 0)?;
+                    inode.blocks -= 1;
+                }
+                self.free_blk(entry.start)?;
+                node.entries.pop();
+                continue;
+            }
+            // Every child before this one ends below first
+            if self.extents_end(&self.extent_blk(entry.start)) > first {
+                let blk_no = self.unshare_extent_node(entry.start, depth)?;
+                node.entries[idx].start = blk_no;
+                let mut child = self.extent_blk(blk_no);
+                self.free_extents_from(inode, &mut child, first)?;
+                if child.entries.is_empty() {
+                    self.free_blk(blk_no)?;
+                    inode.blocks -= 1;
+                    node.entries.pop();
+                } else {
+                    child.write(self.blk_data_mut(blk_no)?);
+                }
+            }
+            break;
+        }
+        Ok(())
+    }
+
+    // Number of blocks under the node blk_no, itself included
+    fn extent_subtree_blks(&self, blk_no: u32, depth: u16) -> u32 {
+        let node = self.extent_blk(blk_no);
+        let below: u32 = node
+            .entries
+            .iter()
+            .map(|entry| match depth {
+                0 => entry.len,
+                _ => self.extent_subtree_blks(entry.start, depth - 1),
+            })
+            .sum();
+        1 + below
+    }
+
+    // Makes every block of inode's tree its own, like unshare_tree
+    pub(crate) fn extent_unshare_tree(&mut self, inode: &mut Inode) -> Result<(), BlockError> {
+        for extent in self.extents(inode) {
+            for lblk in extent.lblk as u64..extent.end() {
+                self.extent_bmap_write(inode, lblk, 0)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Moves the extents the root holds into a node of their own, so that sharing the inode's
+    // blocks takes a single reference to it rather than one to every data block
+    pub(crate) fn push_down_extents(&mut self, inode: &mut Inode) -> Result<(), BlockError> {
+        let root = inode.extent_root();
+        if root.depth > 0 || root.entries.is_empty() {
+            return Ok(());
+        }
+        let blk_no = self.alloc_blk()?;
+        inode.blocks += 1;
+        root.write(self.blk_data_mut(blk_no)?);
+        inode.set_extent_root(&ExtentNode {
+            depth: 1,
+            entries: vec![Extent {
+                lblk: root.entries[0].lblk,
+                start: blk_no,
+                len: 0,
+            }],
+        });
+        Ok(())
+    }
+
+    // Like cow_tree: moves the data blocks that must not be written in place, remapping each,
+    // then the nodes, children first
+    pub(crate) fn extent_cow_tree(&mut self, inode: &mut Inode) -> Result<bool, BlockError> {
+        let before = inode.ptr_area();
+        for extent in self.extents(inode) {
+            for lblk in extent.lblk as u64..extent.end() {
+                let blk_no = extent.blk_at(lblk);
+                let copy = self.cow_blk(blk_no)?;
+                if copy != blk_no {
+                    self.extent_set(inode, lblk, copy)?;
+                }
+            }
+        }
+        let mut root = inode.extent_root();
+        if root.depth > 0 && self.cow_extent_entries(&mut root)? {
+            inode.set_extent_root(&root);
+        }
+        Ok(inode.ptr_area() != before)
+    }
+
+    // Moves the children of an index node, returns whether any of them moved
+    fn cow_extent_entries(&mut self, node: &mut ExtentNode) -> Result<bool, BlockError> {
+        let mut moved = false;
+        for idx in 0..node.entries.len() {
+            let child = node.entries[idx].start;
+            let mut below = self.extent_blk(child);
+            if below.depth > 0 && self.cow_extent_entries(&mut below)? {
+                below.write(self.blk_data_mut(child)?);
+            }
+            let copy = self.cow_blk(child)?;
+            moved |= copy != child;
+            node.entries[idx].start = copy;
+        }
+        Ok(moved)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crash::{self, CrashDevice};
+    use crate::image::{self, Geometry, FEATURE_EXTENTS};
+    use crate::test_util::{contents, make_file, make_node, state_with_features};
+    use crate::{fsck, ROOT_INO};
+    use fuser::FileType;
+    use std::ffi::OsStr;
+
+    const BLK: usize = 1024;
+
+    fn geometry() -> Geometry {
+        Geometry::new(4 << 20, 1024, 32).unwrap()
+    }
+
+    fn pattern(len: usize, seed: u8) -> Vec<u8> {
+        (0..len).map(|idx| (idx % 251) as u8 ^ seed).collect()
+    }
+
+    fn extent(lblk: u32, start: u32, len: u32) -> Extent {
+        Extent { lblk, start, len }
+    }
+
+    #[test]
+    fn test_map_splits_and_merges() {
+        let mut leaf = ExtentNode::default();
+        for lblk in 0..10 {
+            leaf.map(lblk, 100 + lblk);
+        }
+        assert_eq!(leaf.entries, [extent(0, 100, 10)]);
+        leaf.map(4, 500);
+        assert_eq!(
+            leaf.entries,
+            [extent(0, 100, 4), extent(4, 500, 1), extent(5, 105, 5)]
+        );
+        leaf.map(4, 104);
+        assert_eq!(leaf.entries, [extent(0, 100, 10)]);
+        leaf.map(0, INVALID_PTR);
+        leaf.map(9, INVALID_PTR);
+        assert_eq!(leaf.entries, [extent(1, 101, 8)]);
+        leaf.map(0, 100);
+        assert_eq!(leaf.entries, [extent(0, 100, 9)]);
+    }
+
+    #[test]
+    fn test_sequential_writes_make_one_extent() {
+        let mut state = state_with_features(geometry(), FEATURE_EXTENTS);
+        let ino_id = make_file(&mut state, "file", &[]);
+        let data = pattern(500 * BLK, 0);
+        for chunk in 0..50 {
+            let part = &data[chunk * 10 * BLK..][..10 * BLK];
+            state
+                .write_at(ino_id, (chunk * 10 * BLK) as u64, part)
+                .unwrap();
+        }
+        let inode = *state.inode(ino_id).unwrap();
+        assert_eq!(state.extents(&inode).len(), 1);
+        assert_eq!(inode.blocks, 500);
+        assert_eq!(contents(&state, ino_id), data);
+        assert_eq!(fsck::check(&state), vec![]);
+    }
+
+    #[test]
+    fn test_fragmented_file_grows_the_tree() {
+        let mut state = state_with_features(geometry(), FEATURE_EXTENTS);
+        let (a, b) = (
+            make_file(&mut state, "a", &[]),
+            make_file(&mut state, "b", &[]),
+        );
+        // Interleaved writes to every other block keep the extents of each file apart
+        let (data_a, data_b) = (pattern(600 * BLK, 1), pattern(600 * BLK, 2));
+        for lblk in (0..600).rev() {
+            let off = lblk * BLK;
+            state
+                .write_at(a, off as u64, &data_a[off..off + BLK])
+                .unwrap();
+            state
+                .write_at(b, off as u64, &data_b[off..off + BLK])
+                .unwrap();
+        }
+        let inode = *state.inode(a).unwrap();
+        assert!(inode.extent_root().depth >= 1);
+        assert_eq!(contents(&state, a), data_a);
+        assert_eq!(contents(&state, b), data_b);
+        assert_eq!(fsck::check(&state), vec![]);
+
+        // Cutting it back frees the nodes along with the data
+        let free = state.metadata.free_blk_count;
+        state.truncate(a, 10 * BLK as u64).unwrap();
+        let inode = *state.inode(a).unwrap();
+        // A single node is left on each level
+        assert_eq!(inode.blocks, 10 + inode.extent_root().depth as u32);
+        assert_eq!(contents(&state, a), data_a[..10 * BLK]);
+        assert!(state.metadata.free_blk_count >= free + 590);
+        state.truncate(a, 0).unwrap();
+        assert_eq!(state.inode(a).unwrap().extent_root(), ExtentNode::default());
+        assert_eq!(state.inode(a).unwrap().blocks, 0);
+        assert_eq!(fsck::check(&state), vec![]);
+    }
+
+    #[test]
+    fn test_holes_and_overwrites() {
+        let mut state = state_with_features(geometry(), FEATURE_EXTENTS);
+        let ino_id = make_file(&mut state, "file", &[]);
+        state.write_at(ino_id, 0, &[1; 3 * BLK]).unwrap();
+        state.write_at(ino_id, 100 * BLK as u64, &[2; BLK]).unwrap();
+        state.write_at(ino_id, BLK as u64 + 10, &[3; 20]).unwrap();
+        let inode = *state.inode(ino_id).unwrap();
+        assert_eq!(inode.blocks, 4);
+        assert_eq!(state.extents(&inode).len(), 2);
+        assert_eq!(state.extent_bmap(&inode, 50), INVALID_PTR);
+        let got = contents(&state, ino_id);
+        assert_eq!(got[BLK + 10..BLK + 30], [3; 20]);
+        assert_eq!(got[50 * BLK], 0);
+        assert_eq!(got[100 * BLK..], [2; BLK]);
+    }
+
+    #[test]
+    fn test_snapshot_shares_the_tree() {
+        let mut state = state_with_features(geometry(), FEATURE_EXTENTS);
+        let ino_id = make_file(&mut state, "file", &[]);
+        let data = pattern(200 * BLK, 3);
+        state.write_at(ino_id, 0, &data).unwrap();
+        let free = state.metadata.free_blk_count;
+
+        state.create_snapshot(OsStr::new("snap")).unwrap();
+        // The root moved down into a leaf, which is all the snapshot shares
+        assert_eq!(state.metadata.free_blk_count, free - 1);
+        // Along with the root directory's block
+        assert_eq!(state.refcounts.len(), 2);
+        state.write_at(ino_id, 50 * BLK as u64, &[9; 10]).unwrap();
+        assert_eq!(fsck::check(&state), vec![]);
+
+        let id = state.snapshot_id(OsStr::new("snap")).unwrap();
+        let old = state
+            .with_snapshot(id, |state| contents(state, ino_id))
+            .unwrap();
+        assert_eq!(old, data);
+        assert_eq!(contents(&state, ino_id)[50 * BLK..][..10], [9; 10]);
+
+        state.delete_snapshot(OsStr::new("snap")).unwrap();
+        state.truncate(ino_id, 0).unwrap();
+        assert!(state.refcounts.is_empty());
+        assert_eq!(fsck::check(&state), vec![]);
+    }
+
+    #[test]
+    fn test_pointer_files_stay_as_they_are() {
+        let mut state = state_with_features(geometry(), FEATURE_EXTENTS);
+        let ptrs = {
+            state.set_features(0);
+            make_file(&mut state, "ptrs", &[])
+        };
+        state.set_features(FEATURE_EXTENTS);
+        let extents = make_file(&mut state, "extents", &[]);
+        assert!(!state.inode(ptrs).unwrap().has_extents());
+        assert!(state.inode(extents).unwrap().has_extents());
+
+        let data = pattern(20 * BLK, 4);
+        state.write_at(ptrs, 0, &data).unwrap();
+        state.clone_file(ptrs, extents).unwrap();
+        assert!(!state.inode(extents).unwrap().has_extents());
+        assert_eq!(contents(&state, extents), data);
+        assert_eq!(fsck::check(&state), vec![]);
+    }
+
+    #[test]
+    fn test_clone_shares_the_tree() {
+        let mut state = state_with_features(geometry(), FEATURE_EXTENTS);
+        let (src, dst) = (
+            make_file(&mut state, "src", &[]),
+            make_file(&mut state, "dst", &[]),
+        );
+        let data = pattern(100 * BLK, 5);
+        state.write_at(src, 0, &data).unwrap();
+        let free = state.metadata.free_blk_count;
+
+        state.clone_file(src, dst).unwrap();
+        assert_eq!(state.metadata.free_blk_count, free - 1);
+        state.write_at(dst, 0, &[6; 10]).unwrap();
+        assert_eq!(contents(&state, src), data);
+        assert_eq!(contents(&state, dst)[..10], [6; 10]);
+        assert_eq!(contents(&state, dst)[10..], data[10..]);
+        assert_eq!(fsck::check(&state), vec![]);
+    }
+
+    #[test]
+    fn test_directories_use_extents() {
+        let mut state = state_with_features(geometry(), FEATURE_EXTENTS);
+        let dir = state
+            .make_node(ROOT_INO, OsStr::new("dir"), FileType::Directory, 0o755)
+            .unwrap();
+        for idx in 0..24 {
+            make_node(
+                &mut state,
+                dir,
+                &format!("file-with-a-long-name-{idx:0>40}"),
+                FileType::RegularFile,
+            );
+        }
+        for idx in (0..24).step_by(2) {
+            let name = format!("file-with-a-long-name-{idx:0>40}");
+            state.remove_node(dir, OsStr::new(&name), false).unwrap();
+        }
+        let inode = *state.inode(dir).unwrap();
+        assert!(inode.has_extents());
+        assert!(inode.blocks > 1);
+        assert_eq!(state.read_dir(dir).unwrap().len(), 14);
+        assert!(state
+            .lookup_entry(
+                dir,
+                OsStr::new(&format!("file-with-a-long-name-{:0>40}", 23))
+            )
+            .is_ok());
+        assert_eq!(fsck::check(&state), vec![]);
+    }
+
+    #[test]
+    fn test_extents_survive_commit_and_load() {
+        let geometry = Geometry::new(512 << 10, 1024, 32).unwrap();
+        for geometry in [
+            geometry.with_journal(64).unwrap(),
+            geometry.with_cow().unwrap(),
+        ] {
+            let device = CrashDevice::new(Vec::new(), None, false);
+            image::format_with_features(&device, geometry, FEATURE_EXTENTS).unwrap();
+            let mut state = FSState::load(&device).unwrap();
+            let ino_id = make_file(&mut state, "file", &[]);
+            let mut data = pattern(100 * BLK, 7);
+            state.write_at(ino_id, 0, &data).unwrap();
+            state.commit(&device).unwrap();
+
+            let mut state = FSState::load(&device).unwrap();
+            state.write_at(ino_id, 40 * BLK as u64, &[8; BLK]).unwrap();
+            data[40 * BLK..41 * BLK].fill(8);
+            state.commit(&device).unwrap();
+
+            let state = FSState::load(&device).unwrap();
+            assert!(state.inode(ino_id).unwrap().has_extents());
+            assert_eq!(contents(&state, ino_id), data);
+            assert_eq!(fsck::check(&state), vec![]);
+        }
+    }
+
+    #[test]
+    fn test_cow_overwrite_is_atomic() {
+        let geometry = Geometry::new(256 << 10, 1024, 32)
+            .unwrap()
+            .with_cow()
+            .unwrap();
+        let before = crash::image_with(geometry, |state| {
+            state.set_features(FEATURE_EXTENTS);
+            let ino_id = make_file(state, "file", &[]);
+            state.write_at(ino_id, 0, &[0xab; 20 * BLK]).unwrap();
+        });
+        let outcomes = crash::crash_outcomes(before, |state| {
+            let ino_id = state.lookup_entry(ROOT_INO, OsStr::new("file")).unwrap();
+            state
+                .write_at(ino_id, 5 * BLK as u64, &[0xcd; 2 * BLK])
+                .unwrap();
+            make_file(state, "done", &[]);
+        });
+        for state in outcomes {
+            let ino_id = state.lookup_entry(ROOT_INO, OsStr::new("file")).unwrap();
+            assert!(state.inode(ino_id).unwrap().has_extents());
+            let mut expected = vec![0xab; 20 * BLK];
+            if state.lookup_entry(ROOT_INO, OsStr::new("done")).is_ok() {
+                expected[5 * BLK..7 * BLK].fill(0xcd);
+            }
+            assert_eq!(contents(&state, ino_id), expected);
+        }
+    }
+}
@@ -0,0 +1,393 @@
+use crate::{
+    blk_errno, inode_errno, BlockError, FSState, Inode, INODE_FLAG_INLINE_DATA, INVALID_PTR,
+    ROOT_INO,
+};
+use libc::{c_int, EBADF, EFBIG, EINVAL};
+use log::error;
+use std::cmp::min;
+use std::sync::Arc;
+
+impl FSState {
+    // For the contents of regular files, which the data mode decides how to commit
+    pub(crate) fn file_blk_mut(&mut self, blk_no: u32) -> Result<&mut [u8], BlockError> {
+        // Only once it is read in, so a block that could not be is not written back as zeros
+        self.blk_data_mut(blk_no)?;
+        self.dirty_data.insert(blk_no);
+        self.blk_data_mut(blk_no)
+    }
+
+    // Contents are read past the block cache, see cache.rs
+    fn file_blk_data(&self, blk_no: u32) -> Option<Arc<[u8]>> {
+        match blk_no {
+            INVALID_PTR => None,
+            _ => self.blks.get_uncached(blk_no, self.blk_on_disk(blk_no)),
+        }
+    }
+
+    pub(crate) fn max_file_size(&self, inode: &Inode) -> u64 {
+        self.max_lblks(inode) * self.blk_size()
+    }
+
+    // Reads up to buf.len() bytes at offset and returns how many were read, which is short only
+    // at the end of the file. Holes read back as zeros.
+    pub fn read_at(&self, ino_id: u32, offset: u64, buf: &mut [u8]) -> Result<usize, c_int> {
+        let inode = self.inode(ino_id).map_err(inode_errno)?;
+        Ok(self.read_inode(inode, offset, buf))
+    }
+
+    pub(crate) fn read_inode(&self, inode: &Inode, offset: u64, buf: &mut [u8]) -> usize {
+        if offset >= inode.size {
+            return 0;
+        }
+        let end = min(inode.size, offset + buf.len() as u64);
+        if inode.has_inline_data() {
+            let data = inode.inline_data();
+            buf[..(end - offset) as usize].copy_from_slice(&data[offset as usize..end as usize]);
+            return (end - offset) as usize;
+        }
+        let blk_size = self.blk_size();
+        let mut pos = offset;
+        while pos < end {
+            let blk_off = (pos % blk_size) as usize;
+            let len = min(blk_size - blk_off as u64, end - pos) as usize;
+            let dst = &mut buf[(pos - offset) as usize..][..len];
+            if let Some(page) = self.delayed.page(inode.ino_id, pos / blk_size) {
+                dst.copy_from_slice(&page[blk_off..blk_off + len]);
+                pos += len as u64;
+                continue;
+            }
+            let blk_no = self.bmap(inode, pos / blk_size).unwrap_or(INVALID_PTR);
+            match self.file_blk_data(blk_no) {
+                Some(data) => dst.copy_from_slice(&data[blk_off..blk_off + len]),
+                None => dst.fill(0),
+            }
+            pos += len as u64;
+        }
+        (end - offset) as usize
+    }
+
+    // Writes data at offset, allocating blocks only for the ranges written so anything skipped
+    // over stays a hole. Returns how many bytes were written, which is short when the
+    // filesystem fills up part way; ENOSPC is only returned when nothing could be written.
+    // With delayed allocation, new blocks are held in memory instead, see delalloc.rs.
+    pub fn write_at(&mut self, ino_id: u32, offset: u64, data: &[u8]) -> Result<usize, c_int> {
+        let blk_size = self.blk_size();
+        let end = offset + data.len() as u64;
+        let inode = *self.inode(ino_id).map_err(inode_errno)?;
+        if end > self.max_file_size(&inode) {
+            return Err(EFBIG);
+        }
+        // Only fast symlinks keep their data inline, and they are never written after creation
+        if inode.has_inline_data() {
+            return Err(EINVAL);
+        }
+        let delay = self.can_delay(&inode, end.div_ceil(blk_size) - offset / blk_size)?;
+        // Making room may have written back pages of this file
+        let mut inode = *self.inode(ino_id).map_err(inode_errno)?;
+        let mut pos = offset;
+        let mut result = Ok(());
+        while pos < end {
+            let blk_off = (pos % blk_size) as usize;
+            let len = min(blk_size - blk_off as u64, end - pos) as usize;
+            let lblk = pos / blk_size;
+            let src = &data[(pos - offset) as usize..][..len];
+            if delay && self.delay_write(&inode, lblk, blk_off, src) {
+                pos += len as u64;
+                continue;
+            }
+            let want = (end - 1) / blk_size - lblk + 1;
+            let blk = self
+                .bmap_alloc_run(&mut inode, lblk, want)
+                .and_then(|blk_no| self.file_blk_mut(blk_no));
+            match blk {
+                Ok(blk) => blk[blk_off..blk_off + len].copy_from_slice(src),
+                Err(err) => {
+                    result = Err(blk_errno(err));
+                    break;
+                }
+            }
+            pos += len as u64;
+        }
+        // Keep whatever made it to disk before running out of space
+        inode.size = inode.size.max(pos);
+        inode.update_mtime();
+        *self.inode_mut(ino_id).map_err(inode_errno)? = inode;
+        // The write itself is done, a failed write-back keeps the pages for fsync to report. No
+        // more than a transaction's worth is written back here, see write_back in fs.rs.
+        if delay && self.over_delalloc_cap() {
+            if let Err(err) = self.flush_delayed_some() {
+                error!("Failed to write back delayed pages: errno {err}");
+            }
+        }
+        match result {
+            Err(err) if pos == offset && !data.is_empty() => Err(err),
+            _ => Ok((pos - offset) as usize),
+        }
+    }
+
+    pub(crate) fn truncate(&mut self, ino_id: u32, size: u64) -> Result<(), c_int> {
+        let blk_size = self.blk_size();
+        let mut inode = *self.inode(ino_id).map_err(inode_errno)?;
+        if size > self.max_file_size(&inode) {
+            return Err(EFBIG);
+        }
+        if inode.has_inline_data() {
+            return self.truncate_inline(inode, size);
+        }
+        let keep_blks = size.div_ceil(blk_size);
+        self.truncate_delayed(ino_id, size);
+        self.free_blks_from(&mut inode, keep_blks)
+            .map_err(blk_errno)?;
+        // Zero the tail of the last block so growing the file again reads back zeros
+        let tail = (size % blk_size) as usize;
+        if tail != 0 && size < inode.size {
+            let blk_no = self
+                .bmap_mut(&mut inode, keep_blks - 1)
+                .map_err(blk_errno)?;
+            if self.blk_data(blk_no).is_some() {
+                self.file_blk_mut(blk_no).map_err(blk_errno)?[tail..].fill(0);
+            }
+        }
+        inode.size = size;
+        inode.update_mtime();
+        *self.inode_mut(ino_id).map_err(inode_errno)? = inode;
+        Ok(())
+    }
+
+    // Inline data can shrink but not grow, emptying it frees the pointer area for blocks again
+    fn truncate_inline(&mut self, mut inode: Inode, size: u64) -> Result<(), c_int> {
+        if size > inode.size {
+            return Err(EINVAL);
+        }
+        let mut data = inode.inline_data();
+        data[size as usize..].fill(0);
+        inode.set_inline_data(&data);
+        if size == 0 {
+            inode.flags &= !INODE_FLAG_INLINE_DATA;
+        }
+        inode.size = size;
+        inode.update_mtime();
+        *self.inode_mut(inode.ino_id).map_err(inode_errno)? = inode;
+        Ok(())
+    }
+
+    pub fn open_inode(&mut self, ino_id: u32) -> Result<(), c_int> {
+        self.inode(ino_id).map_err(inode_errno)?;
+        *self.open_handles.entry(ino_id).or_insert(0) += 1;
+        Ok(())
+    }
+
+    // Drops a handle taken by open_inode, freeing the inode if it was the last thing keeping an
+    // unlinked inode around
+    pub fn release_inode(&mut self, ino_id: u32) -> Result<(), c_int> {
+        let Some(count) = self.open_handles.get_mut(&ino_id) else {
+            return Err(EBADF);
+        };
+        *count -= 1;
+        if *count == 0 {
+            self.open_handles.remove(&ino_id);
+        }
+        self.free_if_unused(ino_id)
+    }
+
+    // Frees the inode and its blocks once no entry links to it and no handle has it open
+    pub(crate) fn free_if_unused(&mut self, ino_id: u32) -> Result<(), c_int> {
+        let inode = self.inode(ino_id).map_err(inode_errno)?;
+        if inode.nlink > 0 || self.open_handles.contains_key(&ino_id) {
+            return Ok(());
+        }
+        self.free_node(ino_id)
+    }
+
+    // Frees the inode along with its data and xattr blocks
+    pub(crate) fn free_node(&mut self, ino_id: u32) -> Result<(), c_int> {
+        self.truncate(ino_id, 0)?;
+        let mut inode = *self.inode(ino_id).map_err(inode_errno)?;
+        self.free_xattr_blk(&mut inode)?;
+        self.free_inode(ino_id).map_err(inode_errno)
+    }
+
+    // Frees inodes left unlinked but open when the image was last saved. Only safe before any
+    // handle has been opened, at mount time.
+    pub(crate) fn reclaim_unlinked(&mut self) {
+        let unlinked: Vec<u32> = self
+            .inodes
+            .iter()
+            .flatten()
+            .filter(|inode| inode.nlink == 0 && inode.ino_id != ROOT_INO)
+            .map(|inode| inode.ino_id)
+            .collect();
+        for ino_id in unlinked {
+            if let Err(err) = self.free_if_unused(ino_id) {
+                error!("Failed to free unlinked inode {ino_id}: errno {err}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::Geometry;
+    use crate::test_util::state_with_file;
+    use crate::{fsck, NUM_INO_DIRECT_PTR};
+    use libc::ENOSPC;
+    use std::ffi::OsStr;
+
+    #[test]
+    fn test_write_then_read_across_blocks() {
+        let (mut state, ino_id) = state_with_file(Geometry::default());
+        let free_before = state.metadata.free_blk_count;
+
+        let data: Vec<u8> = (0..(state.blk_size() as usize + 100))
+            .map(|i| i as u8)
+            .collect();
+        assert_eq!(state.write_at(ino_id, 10, &data), Ok(data.len()));
+
+        let inode = *state.inode(ino_id).unwrap();
+        assert_eq!(inode.size, 10 + data.len() as u64);
+        assert_eq!(inode.blocks, 2);
+        assert_eq!(state.metadata.free_blk_count, free_before - 2);
+        assert_eq!(read(&state, ino_id, 10, data.len()), data);
+        assert_eq!(read(&state, ino_id, 0, 10), vec![0; 10]);
+    }
+
+    #[test]
+    fn test_write_past_direct_blocks_uses_indirect() {
+        let (mut state, ino_id) = state_with_file(Geometry::default());
+        let offset = NUM_INO_DIRECT_PTR as u64 * state.blk_size();
+        state.write_at(ino_id, offset, &[1, 2, 3]).unwrap();
+
+        let inode = *state.inode(ino_id).unwrap();
+        assert_ne!(inode.indirect_blk, INVALID_PTR);
+        assert_eq!(inode.blocks, 2);
+        assert_eq!(read(&state, ino_id, offset - 1, 4), [0, 1, 2, 3]);
+
+        state.truncate(ino_id, offset).unwrap();
+        let inode = *state.inode(ino_id).unwrap();
+        assert_eq!(inode.indirect_blk, INVALID_PTR);
+        assert_eq!(inode.blocks, 0);
+    }
+
+    #[test]
+    fn test_write_past_max_file_size_is_too_big() {
+        let (mut state, ino_id) = state_with_file(Geometry::default());
+        let max = state.max_file_size(state.inode(ino_id).unwrap());
+        assert_eq!(state.write_at(ino_id, max, &[1]), Err(EFBIG));
+    }
+
+    #[test]
+    fn test_truncate_frees_blocks_and_zeroes_tail() {
+        let (mut state, ino_id) = state_with_file(Geometry::default());
+        let free_before = state.metadata.free_blk_count;
+        let len = 3 * state.blk_size() as usize;
+        state.write_at(ino_id, 0, &vec![0xff; len]).unwrap();
+
+        state.truncate(ino_id, 10).unwrap();
+        assert_eq!(state.metadata.free_blk_count, free_before - 1);
+
+        state.truncate(ino_id, 20).unwrap();
+        assert_eq!(state.inode(ino_id).unwrap().blocks, 1);
+        assert_eq!(read(&state, ino_id, 0, 20), [[0xff; 10], [0; 10]].concat());
+    }
+
+    #[test]
+    fn test_read_stops_at_end_of_file() {
+        let (mut state, ino_id) = state_with_file(Geometry::default());
+        state.write_at(ino_id, 0, b"hello").unwrap();
+
+        let mut buf = [0xaa; 8];
+        assert_eq!(state.read_at(ino_id, 2, &mut buf), Ok(3));
+        assert_eq!(&buf[..3], b"llo");
+        assert_eq!(state.read_at(ino_id, 5, &mut buf), Ok(0));
+        assert_eq!(state.read_at(ino_id, 100, &mut buf), Ok(0));
+    }
+
+    #[test]
+    fn test_skipped_ranges_are_holes() {
+        let (mut state, ino_id) = state_with_file(Geometry::default());
+        let blk_size = state.blk_size();
+        let offset = 5 * blk_size + 7;
+        state.write_at(ino_id, offset, &[9; 4]).unwrap();
+
+        let inode = *state.inode(ino_id).unwrap();
+        assert_eq!(inode.size, offset + 4);
+        assert_eq!(inode.blocks, 1);
+        assert_eq!(inode.direct_blks[..5], [INVALID_PTR; 5]);
+        assert_eq!(
+            read(&state, ino_id, 0, offset as usize + 4),
+            [vec![0; offset as usize], vec![9; 4]].concat()
+        );
+
+        // Filling part of a hole only allocates the block written to
+        state.write_at(ino_id, 2 * blk_size, &[1]).unwrap();
+        assert_eq!(state.inode(ino_id).unwrap().blocks, 2);
+        assert_eq!(read(&state, ino_id, 2 * blk_size - 1, 3), [0, 1, 0]);
+    }
+
+    #[test]
+    fn test_write_reports_enospc_when_full() {
+        let geometry = Geometry::new(64 << 10, 1024, 16).unwrap();
+        let (mut state, ino_id) = state_with_file(geometry);
+        let free = state.metadata.free_blk_count as usize;
+
+        // One block goes to the indirect pointer block, the rest hold data
+        let data = vec![1; (free + 10) * 1024];
+        assert_eq!(state.write_at(ino_id, 0, &data), Ok((free - 1) * 1024));
+        assert_eq!(state.metadata.free_blk_count, 0);
+        assert_eq!(state.inode(ino_id).unwrap().size, (free as u64 - 1) * 1024);
+
+        assert_eq!(
+            state.write_at(ino_id, (free as u64 + 5) * 1024, &[1]),
+            Err(ENOSPC)
+        );
+        assert_eq!(fsck::check(&state), vec![]);
+    }
+
+    #[test]
+    fn test_unlinked_file_lives_until_released() {
+        let (mut state, ino_id) = state_with_file(Geometry::default());
+        state.write_at(ino_id, 0, &[1; 5000]).unwrap();
+        let free = state.metadata.free_blk_count;
+        state.open_inode(ino_id).unwrap();
+        state.open_inode(ino_id).unwrap();
+
+        state
+            .remove_node(ROOT_INO, OsStr::new("file"), false)
+            .unwrap();
+        assert_eq!(state.inode(ino_id).unwrap().nlink, 0);
+        assert_eq!(read(&state, ino_id, 4999, 10), [1]);
+        assert_eq!(state.write_at(ino_id, 5000, &[2]), Ok(1));
+
+        state.release_inode(ino_id).unwrap();
+        assert!(state.inode(ino_id).is_ok());
+        state.release_inode(ino_id).unwrap();
+        assert!(state.inode(ino_id).is_err());
+        assert_eq!(state.metadata.free_blk_count, free + 2);
+        assert_eq!(state.release_inode(ino_id), Err(EBADF));
+        assert_eq!(fsck::check(&state), vec![]);
+    }
+
+    #[test]
+    fn test_unlinked_inodes_are_reclaimed() {
+        let (mut state, ino_id) = state_with_file(Geometry::default());
+        state.write_at(ino_id, 0, &[1; 10]).unwrap();
+        state.open_inode(ino_id).unwrap();
+        state
+            .remove_node(ROOT_INO, OsStr::new("file"), false)
+            .unwrap();
+
+        // As after remounting an image saved with the file still open
+        state.open_handles.clear();
+        state.reclaim_unlinked();
+        assert!(state.inode(ino_id).is_err());
+        assert_eq!(fsck::check(&state), vec![]);
+    }
+
+    fn read(state: &FSState, ino_id: u32, offset: u64, len: usize) -> Vec<u8> {
+        let mut buf = vec![0; len];
+        let read = state.read_at(ino_id, offset, &mut buf).unwrap();
+        buf.truncate(read);
+        buf
+    }
+}
@@ -0,0 +1,896 @@
+use crate::dir::MAX_NAME_LEN;
+use crate::journal::DataMode;
+use crate::{inode_errno, Credentials, FSState, SetAttr, ROOT_INO};
+use fuser::{
+    FileAttr, FileType, Filesystem, KernelConfig, ReplyAttr, ReplyCreate, ReplyData,
+    ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyOpen, ReplyStatfs, ReplyWrite, ReplyXattr,
+    Request, TimeOrNow,
+};
+use libc::{
+    c_int, EEXIST, EINVAL, EIO, EISDIR, EPERM, ERANGE, EROFS, EXDEV, O_ACCMODE, O_RDONLY, O_TRUNC,
+    O_WRONLY, R_OK, W_OK, X_OK,
+};
+use log::{debug, error, info};
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, Weak};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+const TTL: Duration = Duration::from_secs(1);
+
+// How often the flusher writes back pages held by delayed allocation, see delalloc.rs
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+// Snapshots are found under /.snapshots, which no directory listing shows. Their inodes get
+// numbers of their own: the snapshot id in the upper 32 bits and the inode number within the
+// snapshot below. Inode 0 is never used, so the .snapshots directory takes that one.
+const SNAPSHOTS_DIR: &str = ".snapshots";
+const SNAPSHOTS_INO: u64 = 1 << 32;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Node {
+    Live(u32),
+    Snapshots,
+    Snapshot(u32, u32),
+}
+
+impl Node {
+    fn of(ino: u64) -> Self {
+        match ((ino >> 32) as u32, ino as u32) {
+            (0, ino_id) => Node::Live(ino_id),
+            (_, 0) => Node::Snapshots,
+            (id, ino_id) => Node::Snapshot(id, ino_id),
+        }
+    }
+
+    fn ino(self) -> u64 {
+        match self {
+            Node::Live(ino_id) => ino_id as u64,
+            Node::Snapshots => SNAPSHOTS_INO,
+            Node::Snapshot(id, ino_id) => (id as u64) << 32 | ino_id as u64,
+        }
+    }
+
+    // Another inode of the same snapshot, or of the live filesystem
+    fn at(self, ino_id: u32) -> Self {
+        match self {
+            Node::Snapshot(id, _) => Node::Snapshot(id, ino_id),
+            _ => Node::Live(ino_id),
+        }
+    }
+
+    // Snapshots are read-only
+    fn live(self) -> Result<u32, c_int> {
+        match self {
+            Node::Live(ino_id) => Ok(ino_id),
+            _ => Err(EROFS),
+        }
+    }
+}
+
+// Where a new entry named name can go: live directories only, and .snapshots is taken in the root
+fn new_entry_parent(parent: u64, name: &OsStr) -> Result<u32, c_int> {
+    match Node::of(parent).live()? {
+        ROOT_INO if name == SNAPSHOTS_DIR => Err(EEXIST),
+        parent => Ok(parent),
+    }
+}
+
+// What open flags ask of the file, as an access(2) mask
+fn open_mask(flags: i32) -> c_int {
+    let mask = match flags & O_ACCMODE {
+        O_RDONLY => R_OK,
+        O_WRONLY => W_OK,
+        _ => R_OK | W_OK,
+    };
+    match flags & O_TRUNC != 0 {
+        true => mask | W_OK,
+        false => mask,
+    }
+}
+
+// A size of 0 asks how big the value is, anything else is the size of the caller's buffer
+fn reply_xattr(reply: ReplyXattr, size: u32, value: &[u8]) {
+    if size == 0 {
+        reply.size(value.len() as u32);
+    } else if value.len() > size as usize {
+        reply.error(ERANGE);
+    } else {
+        reply.data(value);
+    }
+}
+
+// A panic part way through a request does not stop the next one from using the state
+fn lock(state: &Mutex<FSState>) -> MutexGuard<'_, FSState> {
+    state.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn journaled(state: &FSState, image: Option<&File>) -> bool {
+    let geometry = state.geometry();
+    image.is_some() && (geometry.journal_blks > 0 || geometry.cow)
+}
+
+// Without a journal or copy-on-write, changes only reach the image on sync, or once the blocks
+// they changed no longer fit in the block cache. Those are then written in place.
+fn commit(state: &mut FSState, image: Option<&File>) -> Result<(), c_int> {
+    let Some(image) = image else {
+        return Ok(());
+    };
+    if !journaled(state, Some(image)) && !state.cache_over_cap() {
+        return Ok(());
+    }
+    state.commit(image).map_err(|err| {
+        error!("Failed to commit: {err}");
+        EIO
+    })
+}
+
+// Gives every page delayed allocation holds its block, committing a transaction's worth at a
+// time so that none outgrows the journal
+fn write_back(state: &mut FSState, image: Option<&File>) -> Result<(), c_int> {
+    while state.flush_delayed_some()? {
+        commit(state, image)?;
+    }
+    commit(state, image)
+}
+
+// Gives the pages held by delayed allocation their blocks every FLUSH_INTERVAL, and commits them
+// when the image has a journal. Stops once the filesystem is gone.
+fn spawn_flusher(shared: Weak<Mutex<FSState>>, image: Option<Arc<File>>) {
+    thread::spawn(move || loop {
+        thread::sleep(FLUSH_INTERVAL);
+        let Some(shared) = shared.upgrade() else {
+            return;
+        };
+        let mut state = lock(&shared);
+        if state.delayed_blks() == 0 {
+            continue;
+        }
+        if let Err(err) = write_back(&mut state, image.as_deref()) {
+            error!("Background write-back failed: errno {err}");
+        }
+    });
+}
+
+pub struct RustyFS {
+    // Shared with the flusher thread when delayed allocation is on
+    state: Arc<Mutex<FSState>>,
+    image: Option<Arc<File>>,
+    // Permissions are checked by the kernel instead, see with_default_permissions
+    kernel_permissions: bool,
+}
+
+impl RustyFS {
+    pub fn new(state: FSState) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(state)),
+            image: None,
+            kernel_permissions: false,
+        }
+    }
+
+    // Persist the state to this image on fsync and unmount, or after every request that changes
+    // it when the image has a journal or is copy-on-write
+    pub fn with_image(mut self, image: File) -> Self {
+        self.image = Some(Arc::new(image));
+        self
+    }
+
+    // How file contents are committed on an image with a journal
+    pub fn with_data_mode(self, data_mode: DataMode) -> Self {
+        self.lock().set_data_mode(data_mode);
+        self
+    }
+
+    // Holds up to cap bytes of writes to new blocks in memory, see delalloc.rs. A flusher thread
+    // started at mount writes them back.
+    pub fn with_delalloc(self, cap: u64) -> Self {
+        self.lock().set_delalloc(cap);
+        self
+    }
+
+    // For mounts with the default_permissions option, where the kernel checks the mode and
+    // ownership from getattr before any request gets here
+    pub fn with_default_permissions(mut self) -> Self {
+        self.kernel_permissions = true;
+        self
+    }
+
+    // Never held across a call to commit or sync, which take the lock themselves
+    fn lock(&self) -> MutexGuard<'_, FSState> {
+        lock(&self.state)
+    }
+
+    // None when the kernel does the checking
+    fn creds(&self, req: &Request<'_>) -> Option<Credentials> {
+        match self.kernel_permissions {
+            true => None,
+            false => Some(Credentials::of_process(req.uid(), req.gid(), req.pid())),
+        }
+    }
+
+    // Runs f on the state node is in, with its inode number there. The .snapshots directory
+    // stands in for the root, whose attributes and permissions it shares.
+    fn view<R>(
+        &mut self,
+        node: Node,
+        f: impl FnOnce(&FSState, u32) -> Result<R, c_int>,
+    ) -> Result<R, c_int> {
+        let mut state = self.lock();
+        match node {
+            Node::Live(ino_id) => f(&state, ino_id),
+            Node::Snapshots => f(&state, ROOT_INO),
+            Node::Snapshot(id, ino_id) => state.with_snapshot(id, |state| f(state, ino_id))?,
+        }
+    }
+
+    fn node_attr(&mut self, node: Node) -> Result<FileAttr, c_int> {
+        let mut attr = self.view(node, |state, ino_id| state.file_attr(ino_id))?;
+        attr.ino = node.ino();
+        Ok(attr)
+    }
+
+    fn check_access(&mut self, req: &Request<'_>, node: Node, mask: c_int) -> Result<(), c_int> {
+        match self.creds(req) {
+            Some(creds) => self.view(node, |state, ino_id| {
+                state.check_access(ino_id, &creds, mask)
+            }),
+            None => Ok(()),
+        }
+    }
+
+    fn check_xattr(
+        &mut self,
+        req: &Request<'_>,
+        node: Node,
+        name: &OsStr,
+        write: bool,
+    ) -> Result<(), c_int> {
+        match self.creds(req) {
+            Some(creds) => self.view(node, |state, ino_id| {
+                state.check_xattr(ino_id, &creds, name, write)
+            }),
+            None => Ok(()),
+        }
+    }
+
+    fn lookup_node(&mut self, parent: Node, name: &OsStr) -> Result<Node, c_int> {
+        match parent {
+            Node::Live(ROOT_INO) if name == SNAPSHOTS_DIR => Ok(Node::Snapshots),
+            Node::Snapshots => Ok(Node::Snapshot(self.lock().snapshot_id(name)?, ROOT_INO)),
+            _ => {
+                let ino_id = self.view(parent, |state, ino_id| state.lookup_entry(ino_id, name))?;
+                Ok(parent.at(ino_id))
+            }
+        }
+    }
+
+    // Entries with the inode numbers the kernel knows them by. .snapshots holds the root
+    // directory of each snapshot.
+    fn read_node_dir(&mut self, node: Node) -> Result<Vec<(u64, FileType, OsString)>, c_int> {
+        if node == Node::Snapshots {
+            let mut entries = vec![
+                (SNAPSHOTS_INO, FileType::Directory, OsString::from(".")),
+                (ROOT_INO as u64, FileType::Directory, OsString::from("..")),
+            ];
+            let snapshots = self.lock().list_snapshots();
+            for (id, name, _) in snapshots {
+                let ino = Node::Snapshot(id, ROOT_INO).ino();
+                entries.push((ino, FileType::Directory, name));
+            }
+            return Ok(entries);
+        }
+        let entries = self.view(node, |state, ino_id| state.read_dir(ino_id))?;
+        Ok(entries
+            .into_iter()
+            .map(|entry| (node.at(entry.ino_id).ino(), entry.kind, entry.name))
+            .collect())
+    }
+
+    // Only snapshots are opened for reading alone, their inodes are never freed while open
+    fn open_node(&mut self, node: Node, flags: i32) -> Result<(), c_int> {
+        match node {
+            Node::Live(ino_id) => self.lock().open_inode(ino_id),
+            _ if open_mask(flags) & W_OK != 0 => Err(EROFS),
+            _ => Ok(()),
+        }
+    }
+
+    // mkdir and rmdir in .snapshots take and delete snapshots, which only root and the owner
+    // of the root directory can do
+    fn check_snapshot_owner(&self, req: &Request<'_>) -> Result<(), c_int> {
+        let root = self.lock().file_attr(ROOT_INO)?;
+        match req.uid() == 0 || req.uid() == root.uid {
+            true => Ok(()),
+            false => Err(EPERM),
+        }
+    }
+
+    fn make_snapshot(&mut self, req: &Request<'_>, name: &OsStr) -> Result<FileAttr, c_int> {
+        self.check_snapshot_owner(req)?;
+        write_back(&mut self.lock(), self.image.as_deref())?;
+        // On a large filesystem the inodes take several transactions to get ready
+        while self.lock().prepare_snapshot()? {
+            self.commit()?;
+        }
+        let id = self.lock().create_snapshot(name)?;
+        self.commit()?;
+        self.node_attr(Node::Snapshot(id, ROOT_INO))
+    }
+
+    fn remove_snapshot(&mut self, req: &Request<'_>, name: &OsStr) -> Result<(), c_int> {
+        self.check_snapshot_owner(req)?;
+        self.lock().delete_snapshot(name)?;
+        self.commit()
+    }
+
+    fn remove_node(
+        &mut self,
+        req: &Request<'_>,
+        parent: u32,
+        name: &OsStr,
+        dir: bool,
+    ) -> Result<(), c_int> {
+        let creds = self.creds(req);
+        let mut state = self.lock();
+        if let Some(creds) = creds {
+            state.check_access(parent, &creds, X_OK)?;
+            let ino_id = state.lookup_entry(parent, name)?;
+            state.check_remove(parent, ino_id, &creds)?;
+        }
+        state.remove_node(parent, name, dir)?;
+        drop(state);
+        self.commit()
+    }
+
+    fn commit(&mut self) -> Result<(), c_int> {
+        commit(&mut self.lock(), self.image.as_deref())
+    }
+
+    // Writes back the pages delayed allocation holds too. Only the blocks changed since the
+    // last commit are written, also without a journal.
+    fn sync(&mut self) -> Result<(), c_int> {
+        let mut state = self.lock();
+        write_back(&mut state, self.image.as_deref())?;
+        let Some(image) = self.image.as_deref() else {
+            return Ok(());
+        };
+        state.commit(image).map_err(|err| {
+            error!("Failed to commit: {err}");
+            EIO
+        })
+    }
+
+    // The pages delayed allocation holds are written back first when they are in the way, in
+    // pieces rather than all in the write's own transaction
+    fn write_file(&mut self, ino_id: u32, offset: u64, data: &[u8]) -> Result<usize, c_int> {
+        if self.lock().write_back_due(data.len() as u64) {
+            write_back(&mut self.lock(), self.image.as_deref())?;
+        }
+        let written = self.lock().write_at(ino_id, offset, data)?;
+        self.commit()?;
+        Ok(written)
+    }
+
+    fn make_node(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        kind: FileType,
+        perm: u16,
+    ) -> Result<FileAttr, c_int> {
+        let parent = new_entry_parent(parent, name)?;
+        self.check_access(req, Node::Live(parent), W_OK | X_OK)?;
+        let ino_id = self
+            .lock()
+            .make_node_as(parent, name, kind, perm, req.uid(), req.gid())?;
+        self.commit()?;
+        self.lock().file_attr(ino_id)
+    }
+}
+
+impl Filesystem for RustyFS {
+    fn init(&mut self, _req: &Request<'_>, _config: &mut KernelConfig) -> Result<(), c_int> {
+        let mut state = self.lock();
+        state.init_root();
+        state.reclaim_unlinked();
+        if state.delalloc() {
+            spawn_flusher(Arc::downgrade(&self.state), self.image.clone());
+        }
+        drop(state);
+        self.commit()
+    }
+
+    fn destroy(&mut self) {
+        if self.sync().is_ok() && self.image.is_some() {
+            info!("Image saved on unmount");
+        }
+        info!("Block cache: {:?}", self.lock().cache_stats());
+    }
+
+    fn lookup(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        debug!("lookup(parent: {parent}, name: {name:?})");
+        let parent = Node::of(parent);
+        match self
+            .check_access(req, parent, X_OK)
+            .and_then(|()| self.lookup_node(parent, name))
+            .and_then(|node| self.node_attr(node))
+        {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+        match self.node_attr(Node::of(ino)) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn setattr(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        _fh: Option<u64>,
+        crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        debug!("setattr(ino: {ino}, mode: {mode:?}, uid: {uid:?}, gid: {gid:?}, size: {size:?})");
+        // ctime is not taken from the request, it is always the time of the change
+        let attr = SetAttr {
+            mode,
+            uid,
+            gid,
+            size,
+            atime,
+            mtime,
+            crtime,
+        };
+        let checked = Node::of(ino)
+            .live()
+            .and_then(|ino_id| match self.creds(req) {
+                Some(creds) => self.lock().check_set_attr(ino_id, &creds, attr),
+                None => Ok(attr),
+            });
+        match checked
+            .and_then(|attr| self.lock().set_attr(ino as u32, &attr))
+            .and_then(|()| self.commit())
+            .and_then(|()| self.lock().file_attr(ino as u32))
+        {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn mkdir(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        reply: ReplyEntry,
+    ) {
+        debug!("mkdir(parent: {parent}, name: {name:?}, mode: {mode:o})");
+        let perm = (mode & !umask & 0o7777) as u16;
+        let made = match Node::of(parent) {
+            Node::Snapshots => self.make_snapshot(req, name),
+            _ => self.make_node(req, parent, name, FileType::Directory, perm),
+        };
+        match made {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn unlink(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        debug!("unlink(parent: {parent}, name: {name:?})");
+        match Node::of(parent)
+            .live()
+            .and_then(|parent| self.remove_node(req, parent, name, false))
+        {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn rmdir(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        debug!("rmdir(parent: {parent}, name: {name:?})");
+        let removed = match Node::of(parent) {
+            Node::Snapshots => self.remove_snapshot(req, name),
+            node => node
+                .live()
+                .and_then(|parent| self.remove_node(req, parent, name, true)),
+        };
+        match removed {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn link(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        newparent: u64,
+        newname: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        debug!("link(ino: {ino}, newparent: {newparent}, newname: {newname:?})");
+        match Node::of(ino)
+            .live()
+            .and_then(|_| new_entry_parent(newparent, newname))
+            .and_then(|newparent| self.check_access(req, Node::Live(newparent), W_OK | X_OK))
+            .and_then(|()| self.lock().link_node(ino as u32, newparent as u32, newname))
+            .and_then(|()| self.commit())
+            .and_then(|()| self.lock().file_attr(ino as u32))
+        {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn symlink(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        link_name: &OsStr,
+        target: &Path,
+        reply: ReplyEntry,
+    ) {
+        debug!("symlink(parent: {parent}, link_name: {link_name:?}, target: {target:?})");
+        match new_entry_parent(parent, link_name)
+            .and_then(|parent| self.check_access(req, Node::Live(parent), W_OK | X_OK))
+            .and_then(|()| {
+                self.lock().make_symlink(
+                    parent as u32,
+                    link_name,
+                    target.as_os_str(),
+                    req.uid(),
+                    req.gid(),
+                )
+            })
+            .and_then(|ino_id| self.commit().map(|()| ino_id))
+            .and_then(|ino_id| self.lock().file_attr(ino_id))
+        {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    // The kernel follows the target itself and enforces the ELOOP limit, lookup never does
+    fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
+        match self.view(Node::of(ino), |state, ino_id| state.read_link(ino_id)) {
+            Ok(target) => reply.data(&target),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn open(&mut self, req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        let node = Node::of(ino);
+        match self.view(node, |state, ino_id| {
+            state
+                .inode(ino_id)
+                .map(|inode| inode.kind)
+                .map_err(inode_errno)
+        }) {
+            Ok(FileType::Directory) => reply.error(EISDIR),
+            Ok(_) => match self
+                .check_access(req, node, open_mask(flags))
+                .and_then(|()| self.open_node(node, flags))
+            {
+                Ok(()) => reply.opened(0, 0),
+                Err(err) => reply.error(err),
+            },
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        let released = match Node::of(ino) {
+            Node::Live(ino_id) => {
+                let released = self.lock().release_inode(ino_id);
+                released.and_then(|()| self.commit())
+            }
+            _ => Ok(()),
+        };
+        match released {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        if offset < 0 {
+            reply.error(EINVAL);
+            return;
+        }
+        let node = Node::of(ino);
+        let mut buf = vec![0; size as usize];
+        match self.view(node, |state, ino_id| {
+            match state.inode(ino_id).map_err(inode_errno)?.kind {
+                FileType::Directory => Err(EISDIR),
+                _ => state.read_at(ino_id, offset as u64, &mut buf),
+            }
+        }) {
+            Ok(read) => {
+                // Snapshots keep the access times they were taken with
+                if let Node::Live(ino_id) = node {
+                    let _ = self.lock().touch_atime(ino_id);
+                }
+                reply.data(&buf[..read])
+            }
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn write(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        if offset < 0 {
+            reply.error(EINVAL);
+            return;
+        }
+        match Node::of(ino)
+            .live()
+            .and_then(|ino_id| self.write_file(ino_id, offset as u64, data))
+        {
+            Ok(written) => reply.written(written as u32),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    // Aligned blocks are shared with the source rather than copied, see reflink.rs
+    fn copy_file_range(
+        &mut self,
+        _req: &Request<'_>,
+        ino_in: u64,
+        _fh_in: u64,
+        offset_in: i64,
+        ino_out: u64,
+        _fh_out: u64,
+        offset_out: i64,
+        len: u64,
+        _flags: u32,
+        reply: ReplyWrite,
+    ) {
+        if offset_in < 0 || offset_out < 0 {
+            reply.error(EINVAL);
+            return;
+        }
+        // Snapshot blocks are left to the kernel to copy by reading them
+        let src = match Node::of(ino_in) {
+            Node::Live(ino_id) => Ok(ino_id),
+            _ => Err(EXDEV),
+        };
+        match src
+            .and_then(|src| Node::of(ino_out).live().map(|dst| (src, dst)))
+            .and_then(|(src, dst)| {
+                self.lock()
+                    .copy_range(src, offset_in as u64, dst, offset_out as u64, len)
+            })
+            .and_then(|copied| self.commit().map(|()| copied))
+        {
+            Ok(copied) => reply.written(copied as u32),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn fsync(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        _fh: u64,
+        _datasync: bool,
+        reply: ReplyEmpty,
+    ) {
+        match self.sync() {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn opendir(&mut self, req: &Request<'_>, ino: u64, _flags: i32, reply: ReplyOpen) {
+        match self.check_access(req, Node::of(ino), R_OK) {
+            Ok(()) => reply.opened(0, 0),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let node = Node::of(ino);
+        let entries = match self.read_node_dir(node) {
+            Ok(entries) => entries,
+            Err(err) => {
+                reply.error(err);
+                return;
+            }
+        };
+        if let Node::Live(ino_id) = node {
+            let _ = self.lock().touch_atime(ino_id);
+        }
+
+        for (idx, (ino, kind, name)) in entries.into_iter().enumerate().skip(offset as usize) {
+            // The offset handed back is the index of the next entry to return
+            if reply.add(ino, (idx + 1) as i64, kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    fn fsyncdir(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        _fh: u64,
+        _datasync: bool,
+        reply: ReplyEmpty,
+    ) {
+        match self.sync() {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn setxattr(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: i32,
+        _position: u32,
+        reply: ReplyEmpty,
+    ) {
+        debug!("setxattr(ino: {ino}, name: {name:?}, len: {})", value.len());
+        match Node::of(ino)
+            .live()
+            .and_then(|ino_id| self.check_xattr(req, Node::Live(ino_id), name, true))
+            .and_then(|()| self.lock().set_xattr(ino as u32, name, value, flags))
+            .and_then(|()| self.commit())
+        {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn getxattr(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        size: u32,
+        reply: ReplyXattr,
+    ) {
+        let node = Node::of(ino);
+        match self
+            .check_xattr(req, node, name, false)
+            .and_then(|()| self.view(node, |state, ino_id| state.get_xattr(ino_id, name)))
+        {
+            Ok(value) => reply_xattr(reply, size, &value),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn listxattr(&mut self, _req: &Request<'_>, ino: u64, size: u32, reply: ReplyXattr) {
+        match self.view(Node::of(ino), |state, ino_id| state.list_xattr(ino_id)) {
+            Ok(names) => reply_xattr(reply, size, &names),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn removexattr(&mut self, req: &Request<'_>, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        debug!("removexattr(ino: {ino}, name: {name:?})");
+        match Node::of(ino)
+            .live()
+            .and_then(|ino_id| self.check_xattr(req, Node::Live(ino_id), name, true))
+            .and_then(|()| self.lock().remove_xattr(ino as u32, name))
+            .and_then(|()| self.commit())
+        {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    // Not called with default_permissions, the kernel answers access(2) itself then
+    fn access(&mut self, req: &Request<'_>, ino: u64, mask: i32, reply: ReplyEmpty) {
+        let node = Node::of(ino);
+        let writable = match mask & W_OK {
+            0 => Ok(()),
+            _ => node.live().map(|_| ()),
+        };
+        match writable.and_then(|()| self.check_access(req, node, mask)) {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn statfs(&mut self, _req: &Request<'_>, _ino: u64, reply: ReplyStatfs) {
+        let state = self.lock();
+        let metadata = &state.metadata;
+        // Blocks promised to pages held by delayed allocation are as good as taken
+        let free_blks = (metadata.free_blk_count as u64).saturating_sub(state.delayed_blks());
+        reply.statfs(
+            metadata.blk_count as u64,
+            free_blks,
+            free_blks,
+            metadata.ino_count as u64,
+            metadata.free_ino_count as u64,
+            metadata.blk_size,
+            MAX_NAME_LEN as u32,
+            metadata.blk_size,
+        );
+    }
+
+    fn create(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        _flags: i32,
+        reply: ReplyCreate,
+    ) {
+        debug!("create(parent: {parent}, name: {name:?}, mode: {mode:o})");
+        let perm = (mode & !umask & 0o7777) as u16;
+        // The handle create returns is released like one from open
+        match self
+            .make_node(req, parent, name, FileType::RegularFile, perm)
+            .and_then(|attr| self.lock().open_inode(attr.ino as u32).map(|()| attr))
+        {
+            Ok(attr) => reply.created(&TTL, &attr, 0, 0, 0),
+            Err(err) => reply.error(err),
+        }
+    }
+}
@@ -0,0 +1,1214 @@
+use crate::extent::{Extent, ExtentNode, MAX_EXTENT_LBLKS};
+use crate::{inode_errno, FSState, Inode, INVALID_PTR, RESERVED_INODES, ROOT_INO};
+use fuser::FileType;
+use log::error;
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::mem;
+
+const LOST_FOUND: &str = "lost+found";
+
+// A single problem found while checking an image. repair() fixes every kind listed here.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Inconsistency {
+    FreeInodeCount {
+        recorded: u32,
+        actual: u32,
+    },
+    FreeBlockCount {
+        recorded: u32,
+        actual: u32,
+    },
+    // A group descriptor's free counts or directory count disagree with the bitmaps and the
+    // inode table
+    GroupCounts {
+        group: u32,
+    },
+    // An inode slot is in use but free in the bitmap, or the other way around
+    InodeBitmap {
+        ino_id: u32,
+        in_use: bool,
+    },
+    InodeIdMismatch {
+        slot: u32,
+        ino_id: u32,
+    },
+    BadRoot,
+    BadBlockPointer {
+        ino_id: u32,
+        blk_no: u32,
+    },
+    DuplicateBlock {
+        ino_id: u32,
+        blk_no: u32,
+        owner: u32,
+    },
+    // An extent tree node that does not parse or sits at the wrong depth, blk_no 0 for the root
+    // in the inode
+    BadExtentNode {
+        ino_id: u32,
+        blk_no: u32,
+    },
+    UnmarkedBlock {
+        ino_id: u32,
+        blk_no: u32,
+    },
+    LeakedBlock {
+        blk_no: u32,
+    },
+    BlockCount {
+        ino_id: u32,
+        recorded: u32,
+        actual: u32,
+    },
+    DanglingEntry {
+        dir: u32,
+        name: OsString,
+        ino_id: u32,
+    },
+    BadDotEntries {
+        dir: u32,
+        parent: u32,
+    },
+    OrphanInode {
+        ino_id: u32,
+    },
+    // Unlinked while open and never released, nothing is left to recover
+    UnlinkedInode {
+        ino_id: u32,
+    },
+    LinkCount {
+        ino_id: u32,
+        recorded: u32,
+        actual: u32,
+    },
+    // A block shared with a snapshot records a different number of references than it has
+    RefCount {
+        blk_no: u32,
+        recorded: u32,
+        actual: u32,
+    },
+}
+
+impl fmt::Display for Inconsistency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Inconsistency::FreeInodeCount { recorded, actual } => {
+                write!(f, "free inode count is {recorded}, bitmap has {actual}")
+            }
+            Inconsistency::FreeBlockCount { recorded, actual } => {
+                write!(f, "free block count is {recorded}, bitmap has {actual}")
+            }
+            Inconsistency::GroupCounts { group } => {
+                write!(f, "group {group} descriptor has the wrong counts")
+            }
+            Inconsistency::InodeBitmap { ino_id, in_use } => match in_use {
+                true => write!(f, "inode {ino_id} is in use but marked free"),
+                false => write!(f, "inode {ino_id} is free but marked in use"),
+            },
+            Inconsistency::InodeIdMismatch { slot, ino_id } => {
+                write!(f, "inode in slot {slot} has id {ino_id}")
+            }
+            Inconsistency::BadRoot => write!(f, "root inode is missing or not a directory"),
+            Inconsistency::BadBlockPointer { ino_id, blk_no } => {
+                write!(f, "inode {ino_id} points at invalid block {blk_no}")
+            }
+            Inconsistency::DuplicateBlock {
+                ino_id,
+                blk_no,
+                owner,
+            } => write!(
+                f,
+                "inode {ino_id} points at block {blk_no}, already used by inode {owner}"
+            ),
+            Inconsistency::BadExtentNode { ino_id, blk_no } => match *blk_no {
+                INVALID_PTR => write!(f, "inode {ino_id} has an invalid extent tree root"),
+                blk_no => write!(
+                    f,
+                    "inode {ino_id} has an invalid extent tree node in block {blk_no}"
+                ),
+            },
+            Inconsistency::UnmarkedBlock { ino_id, blk_no } => {
+                write!(
+                    f,
+                    "block {blk_no} is used by inode {ino_id} but marked free"
+                )
+            }
+            Inconsistency::LeakedBlock { blk_no } => {
+                write!(f, "block {blk_no} is marked in use but not referenced")
+            }
+            Inconsistency::BlockCount {
+                ino_id,
+                recorded,
+                actual,
+            } => write!(
+                f,
+                "inode {ino_id} records {recorded} blocks, references {actual}"
+            ),
+            Inconsistency::DanglingEntry { dir, name, ino_id } => write!(
+                f,
+                "entry {name:?} in directory {dir} points at free inode {ino_id}"
+            ),
+            Inconsistency::BadDotEntries { dir, parent } => write!(
+                f,
+                "directory {dir} does not have \".\" and \"..\" pointing at itself and {parent}"
+            ),
+            Inconsistency::OrphanInode { ino_id } => {
+                write!(f, "inode {ino_id} is not reachable from the root")
+            }
+            Inconsistency::UnlinkedInode { ino_id } => {
+                write!(f, "inode {ino_id} has no links left but was not freed")
+            }
+            Inconsistency::LinkCount {
+                ino_id,
+                recorded,
+                actual,
+            } => write!(f, "inode {ino_id} records {recorded} links, has {actual}"),
+            Inconsistency::RefCount {
+                blk_no,
+                recorded,
+                actual,
+            } => write!(
+                f,
+                "block {blk_no} records {recorded} references, has {actual}"
+            ),
+        }
+    }
+}
+
+// What a pass over the state found, along with everything repair() needs to fix it
+struct Scan<'a> {
+    state: &'a FSState,
+    problems: Vec<Inconsistency>,
+    // The inode table with ids, block pointers and block counts corrected
+    inodes: Vec<Option<Inode>>,
+    // (pointer block, index) slots that point at bad or duplicate blocks
+    cleared_ptrs: Vec<(u32, usize)>,
+    // Extent tree nodes with the entries pointing at bad or duplicate blocks dropped
+    fixed_extent_nodes: Vec<(u32, ExtentNode)>,
+    // The inode each block is referenced by, the first reference wins
+    owners: Vec<Option<u32>>,
+    // References to each block, more than one only for blocks shared with a snapshot
+    refs: Vec<u32>,
+    // Snapshots whose inodes needed the same corrections, by index
+    snapshots: Vec<(usize, Box<[Option<Inode>]>)>,
+    children: Vec<Vec<u32>>,
+    referenced: HashSet<u32>,
+    dangling: Vec<(u32, OsString, u32)>,
+    // Directories whose "." or ".." is wrong, with the parent ".." should point at
+    bad_dots: Vec<(u32, u32)>,
+    orphans: Vec<u32>,
+    unlinked: Vec<u32>,
+}
+
+impl<'a> Scan<'a> {
+    fn new(state: &'a FSState) -> Self {
+        let mut scan = Self {
+            state,
+            problems: Vec::new(),
+            inodes: state.inodes.to_vec(),
+            cleared_ptrs: Vec::new(),
+            fixed_extent_nodes: Vec::new(),
+            owners: vec![None; state.metadata.blk_count as usize],
+            refs: vec![0; state.metadata.blk_count as usize],
+            snapshots: Vec::new(),
+            children: vec![Vec::new(); state.inodes.len()],
+            referenced: HashSet::new(),
+            dangling: Vec::new(),
+            bad_dots: Vec::new(),
+            orphans: Vec::new(),
+            unlinked: Vec::new(),
+        };
+        scan.check_root();
+        scan.check_inodes();
+        scan.check_blks();
+        scan.check_counters();
+        scan.check_tree();
+        scan
+    }
+
+    fn check_root(&mut self) {
+        let root = &mut self.inodes[ROOT_INO as usize];
+        if !matches!(root, Some(inode) if inode.kind == FileType::Directory) {
+            self.problems.push(Inconsistency::BadRoot);
+            *root = Some(Inode::new(ROOT_INO, FileType::Directory, 0o755));
+        }
+    }
+
+    fn check_inodes(&mut self) {
+        // Slot 0 is the null inode and never holds anything
+        self.inodes[0] = None;
+        for slot in RESERVED_INODES as usize..self.inodes.len() {
+            let in_use = self.inodes[slot].is_some();
+            if self.state.inode_bitmap.map[slot] != in_use {
+                self.problems.push(Inconsistency::InodeBitmap {
+                    ino_id: slot as u32,
+                    in_use,
+                });
+            }
+        }
+        for (slot, inode) in self.inodes.iter_mut().enumerate() {
+            if let Some(inode) = inode.as_mut().filter(|inode| inode.ino_id != slot as u32) {
+                self.problems.push(Inconsistency::InodeIdMismatch {
+                    slot: slot as u32,
+                    ino_id: inode.ino_id,
+                });
+                inode.ino_id = slot as u32;
+            }
+        }
+    }
+
+    fn check_blks(&mut self) {
+        for slot in 0..self.inodes.len() {
+            let Some(mut inode) = self.inodes[slot] else {
+                continue;
+            };
+            self.walk_inode(&mut inode);
+            self.inodes[slot] = Some(inode);
+        }
+        // Snapshots come after the live inodes, whose blocks they share
+        let state = self.state;
+        for (idx, snapshot) in state.snapshots.iter().enumerate() {
+            let mut inodes = snapshot.inodes.clone();
+            for inode in inodes.iter_mut().flatten() {
+                self.walk_inode(inode);
+            }
+            if inodes != snapshot.inodes {
+                self.snapshots.push((idx, inodes));
+            }
+        }
+        for mut blk_no in state.snapshot_meta_blks() {
+            self.walk(0, &mut blk_no, 0);
+        }
+        self.check_refcounts();
+
+        let reserved = self.state.geometry().reserved_blks() as usize;
+        for blk_no in reserved..self.owners.len() {
+            if self.state.blk_bitmap.map[blk_no] && self.owners[blk_no].is_none() {
+                self.problems.push(Inconsistency::LeakedBlock {
+                    blk_no: blk_no as u32,
+                });
+            }
+        }
+    }
+
+    fn walk_inode(&mut self, inode: &mut Inode) {
+        let ino_id = inode.ino_id;
+        let mut count = 0;
+        // Inline data sits where the block pointers would be
+        if inode.has_extents() && !inode.has_inline_data() {
+            count += self.walk_extent_root(inode);
+        } else if !inode.has_inline_data() {
+            for ptr in inode.direct_blks.iter_mut() {
+                count += self.walk(ino_id, ptr, 0);
+            }
+            count += self.walk(ino_id, &mut inode.indirect_blk, 1);
+            count += self.walk(ino_id, &mut inode.dbl_indirect_blk, 2);
+            count += self.walk(ino_id, &mut inode.tri_indirect_blk, 3);
+        }
+        count += self.walk(ino_id, &mut inode.xattr_blk, 0);
+        if inode.blocks != count {
+            self.problems.push(Inconsistency::BlockCount {
+                ino_id,
+                recorded: inode.blocks,
+                actual: count,
+            });
+            inode.blocks = count;
+        }
+    }
+
+    fn check_refcounts(&mut self) {
+        for (&blk_no, &recorded) in &self.state.refcounts {
+            let actual = self.refs.get(blk_no as usize).copied().unwrap_or(0);
+            if recorded != actual {
+                self.problems.push(Inconsistency::RefCount {
+                    blk_no,
+                    recorded,
+                    actual,
+                });
+            }
+        }
+    }
+
+    // Claims the block ptr refers to and, for pointer blocks, everything below it. Returns the
+    // number of blocks claimed and clears ptr when it cannot be kept.
+    fn walk(&mut self, ino_id: u32, ptr: &mut u32, level: u32) -> u32 {
+        match self.claim(ino_id, ptr) {
+            None => 0,
+            Some(false) => self.count_blks(*ptr, level),
+            Some(true) => {
+                let blk_no = *ptr;
+                let mut count = 1;
+                if level > 0 {
+                    for (idx, child) in self.ptrs(blk_no).into_iter().enumerate() {
+                        let mut kept = child;
+                        count += self.walk(ino_id, &mut kept, level - 1);
+                        if kept != child {
+                            self.cleared_ptrs.push((blk_no, idx));
+                        }
+                    }
+                }
+                count
+            }
+        }
+    }
+
+    // Takes the block ptr refers to for ino_id. Returns true the first time, false for a shared
+    // block walked before and None when ptr is cleared.
+    fn claim(&mut self, ino_id: u32, ptr: &mut u32) -> Option<bool> {
+        let blk_no = *ptr;
+        if blk_no == INVALID_PTR {
+            return None;
+        }
+        let reserved = self.state.geometry().reserved_blks();
+        if blk_no < reserved || blk_no as usize >= self.owners.len() {
+            self.problems
+                .push(Inconsistency::BadBlockPointer { ino_id, blk_no });
+            *ptr = INVALID_PTR;
+            return None;
+        }
+        if let Some(owner) = self.owners[blk_no as usize] {
+            // A shared block is claimed once, each further reference only counts its blocks
+            if self.state.refcounts.contains_key(&blk_no) {
+                self.refs[blk_no as usize] += 1;
+                return Some(false);
+            }
+            self.problems.push(Inconsistency::DuplicateBlock {
+                ino_id,
+                blk_no,
+                owner,
+            });
+            *ptr = INVALID_PTR;
+            return None;
+        }
+        self.owners[blk_no as usize] = Some(ino_id);
+        self.refs[blk_no as usize] = 1;
+        if !self.state.blk_bitmap.map[blk_no as usize] {
+            self.problems
+                .push(Inconsistency::UnmarkedBlock { ino_id, blk_no });
+        }
+        Some(true)
+    }
+
+    // Claims the blocks of an extent tree, see walk. Entries that cannot be kept are dropped.
+    fn walk_extent_root(&mut self, inode: &mut Inode) -> u32 {
+        let Some(mut root) = ExtentNode::parse(&inode.ptr_area()) else {
+            self.problems.push(Inconsistency::BadExtentNode {
+                ino_id: inode.ino_id,
+                blk_no: INVALID_PTR,
+            });
+            inode.set_extent_root(&ExtentNode::default());
+            return 0;
+        };
+        let before = root.clone();
+        let count = self.walk_extents(inode.ino_id, &mut root);
+        // A tree that lost all of its entries goes back to an empty leaf
+        if root.entries.is_empty() {
+            root.depth = 0;
+        }
+        if root != before {
+            inode.set_extent_root(&root);
+        }
+        count
+    }
+
+    fn walk_extents(&mut self, ino_id: u32, node: &mut ExtentNode) -> u32 {
+        let mut count = 0;
+        for mut entry in mem::take(&mut node.entries) {
+            let claimed = match node.depth {
+                0 => self.walk_extent(ino_id, &entry),
+                depth => self.walk_extent_node(ino_id, &mut entry.start, depth - 1),
+            };
+            if let Some(claimed) = claimed {
+                count += claimed;
+                node.entries.push(entry);
+            }
+        }
+        count
+    }
+
+    fn walk_extent_node(&mut self, ino_id: u32, ptr: &mut u32, depth: u16) -> Option<u32> {
+        let blk_no = *ptr;
+        match self.claim(ino_id, ptr)? {
+            false => Some(self.count_extent_blks(blk_no, depth)),
+            true => {
+                let capacity = self.state.extent_node_capacity();
+                let node = self
+                    .state
+                    .blk_data(blk_no)
+                    .and_then(|data| ExtentNode::parse(&data))
+                    .filter(|node| node.depth == depth && node.entries.len() <= capacity);
+                let Some(mut node) = node else {
+                    self.problems
+                        .push(Inconsistency::BadExtentNode { ino_id, blk_no });
+                    self.owners[blk_no as usize] = None;
+                    self.refs[blk_no as usize] = 0;
+                    *ptr = INVALID_PTR;
+                    return None;
+                };
+                let before = node.clone();
+                let count = 1 + self.walk_extents(ino_id, &mut node);
+                if node != before {
+                    self.fixed_extent_nodes.push((blk_no, node));
+                }
+                Some(count)
+            }
+        }
+    }
+
+    // An extent is kept or dropped whole, so its blocks are all checked before any is claimed
+    fn walk_extent(&mut self, ino_id: u32, extent: &Extent) -> Option<u32> {
+        let reserved = self.state.geometry().reserved_blks();
+        let end = extent.start as u64 + extent.len as u64;
+        if extent.len == 0
+            || extent.start < reserved
+            || end > self.owners.len() as u64
+            || extent.lblk as u64 + extent.len as u64 > MAX_EXTENT_LBLKS
+        {
+            self.problems.push(Inconsistency::BadBlockPointer {
+                ino_id,
+                blk_no: extent.start,
+            });
+            return None;
+        }
+        for blk_no in extent.blks() {
+            if let Some(owner) = self.owners[blk_no as usize] {
+                if !self.state.refcounts.contains_key(&blk_no) {
+                    self.problems.push(Inconsistency::DuplicateBlock {
+                        ino_id,
+                        blk_no,
+                        owner,
+                    });
+                    return None;
+                }
+            }
+        }
+        for mut blk_no in extent.blks() {
+            self.walk(ino_id, &mut blk_no, 0);
+        }
+        Some(extent.len)
+    }
+
+    // Blocks under the extent node blk_no, which has been walked before
+    fn count_extent_blks(&self, blk_no: u32, depth: u16) -> u32 {
+        let owned = |blk_no: u32| {
+            self.owners
+                .get(blk_no as usize)
+                .is_some_and(Option::is_some)
+        };
+        let Some(node) = self
+            .state
+            .blk_data(blk_no)
+            .and_then(|data| ExtentNode::parse(&data))
+        else {
+            return 1;
+        };
+        let below: u32 = node
+            .entries
+            .iter()
+            .map(|entry| match depth {
+                0 => {
+                    let end = (entry.start as u64 + entry.len as u64).min(self.owners.len() as u64);
+                    (entry.start..end as u32)
+                        .filter(|&blk_no| owned(blk_no))
+                        .count() as u32
+                }
+                _ if owned(entry.start) => self.count_extent_blks(entry.start, depth - 1),
+                _ => 0,
+            })
+            .sum();
+        1 + below
+    }
+
+    // Blocks in the tree under blk_no, which has been walked before
+    fn count_blks(&self, blk_no: u32, level: u32) -> u32 {
+        let mut count = 1;
+        if level > 0 {
+            for child in self.ptrs(blk_no) {
+                if self.owners.get(child as usize).is_some_and(Option::is_some) {
+                    count += self.count_blks(child, level - 1);
+                }
+            }
+        }
+        count
+    }
+
+    fn ptrs(&self, blk_no: u32) -> Vec<u32> {
+        match self.state.blk_data(blk_no) {
+            Some(data) => data
+                .chunks_exact(4)
+                .map(|ptr| u32::from_le_bytes(ptr.try_into().unwrap()))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    fn check_counters(&mut self) {
+        let metadata = &self.state.metadata;
+        let used_inos = self.state.inode_bitmap.map.count_ones() as u32;
+        let actual = metadata.ino_count - used_inos;
+        if metadata.free_ino_count != actual {
+            self.problems.push(Inconsistency::FreeInodeCount {
+                recorded: metadata.free_ino_count,
+                actual,
+            });
+        }
+        let used_blks = self.state.blk_bitmap.map.count_ones() as u32;
+        let actual = metadata.blk_count - used_blks;
+        if metadata.free_blk_count != actual {
+            self.problems.push(Inconsistency::FreeBlockCount {
+                recorded: metadata.free_blk_count,
+                actual,
+            });
+        }
+        let actual = self.state.count_groups();
+        for (group, (recorded, actual)) in self.state.groups.iter().zip(&actual).enumerate() {
+            if recorded != actual {
+                let group = group as u32;
+                self.problems.push(Inconsistency::GroupCounts { group });
+            }
+        }
+    }
+
+    fn check_tree(&mut self) {
+        for slot in 0..self.inodes.len() {
+            let dir = match &self.inodes[slot] {
+                Some(inode) if inode.kind == FileType::Directory => *inode,
+                _ => continue,
+            };
+            for entry in self.state.dir_entries(&dir) {
+                if is_dot(&entry.name) {
+                    continue;
+                }
+                let ino_id = entry.ino_id;
+                let live = ino_id != INVALID_PTR
+                    && matches!(self.inodes.get(ino_id as usize), Some(Some(_)));
+                if live {
+                    self.children[slot].push(ino_id);
+                    self.referenced.insert(ino_id);
+                } else {
+                    self.problems.push(Inconsistency::DanglingEntry {
+                        dir: slot as u32,
+                        name: entry.name.clone(),
+                        ino_id,
+                    });
+                    self.dangling.push((slot as u32, entry.name, ino_id));
+                }
+            }
+        }
+
+        let mut reachable = HashSet::new();
+        mark_reachable(ROOT_INO, &self.children, &mut reachable);
+        for slot in RESERVED_INODES..self.inodes.len() as u32 {
+            let Some(inode) = &self.inodes[slot as usize] else {
+                continue;
+            };
+            if reachable.contains(&slot) {
+                continue;
+            }
+            if inode.nlink == 0 && !self.referenced.contains(&slot) {
+                self.problems
+                    .push(Inconsistency::UnlinkedInode { ino_id: slot });
+                self.unlinked.push(slot);
+            } else {
+                self.problems
+                    .push(Inconsistency::OrphanInode { ino_id: slot });
+                self.orphans.push(slot);
+            }
+        }
+        self.check_dots(&reachable);
+        self.check_links(&reachable);
+    }
+
+    // Orphans are left out, their counts change when they are linked into lost+found
+    fn check_links(&mut self, reachable: &HashSet<u32>) {
+        let links = count_links(self.state, &self.inodes);
+        for (slot, inode) in self.inodes.iter().enumerate() {
+            let ino_id = slot as u32;
+            let Some(inode) = inode.filter(|_| reachable.contains(&ino_id)) else {
+                continue;
+            };
+            let actual = links[slot];
+            if inode.nlink != actual {
+                self.problems.push(Inconsistency::LinkCount {
+                    ino_id,
+                    recorded: inode.nlink,
+                    actual,
+                });
+            }
+        }
+    }
+
+    // "." has to point at the directory itself and ".." at the first directory found linking
+    // to it. Unreachable directories get theirs fixed when they are moved to lost+found.
+    fn check_dots(&mut self, reachable: &HashSet<u32>) {
+        let mut parents = vec![None; self.inodes.len()];
+        parents[ROOT_INO as usize] = Some(ROOT_INO);
+        for (dir, children) in self.children.iter().enumerate() {
+            for &child in children {
+                parents[child as usize].get_or_insert(dir as u32);
+            }
+        }
+        for slot in 0..self.inodes.len() as u32 {
+            let dir = match &self.inodes[slot as usize] {
+                Some(inode) if inode.kind == FileType::Directory => *inode,
+                _ => continue,
+            };
+            let Some(parent) = parents[slot as usize].filter(|_| reachable.contains(&slot)) else {
+                continue;
+            };
+            let entries = self.state.dir_entries(&dir);
+            let points_at = |name: &str, ino_id: u32| {
+                entries
+                    .iter()
+                    .any(|entry| entry.name == name && entry.ino_id == ino_id)
+            };
+            if !points_at(".", slot) || !points_at("..", parent) {
+                self.problems
+                    .push(Inconsistency::BadDotEntries { dir: slot, parent });
+                self.bad_dots.push((slot, parent));
+            }
+        }
+    }
+}
+
+fn is_dot(name: &OsStr) -> bool {
+    name == "." || name == ".."
+}
+
+// Entries pointing at each inode, "." and ".." included
+fn count_links(state: &FSState, inodes: &[Option<Inode>]) -> Vec<u32> {
+    let mut links = vec![0; inodes.len()];
+    for dir in inodes.iter().flatten() {
+        if dir.kind != FileType::Directory {
+            continue;
+        }
+        for entry in state.dir_entries(dir) {
+            if matches!(inodes.get(entry.ino_id as usize), Some(Some(_))) {
+                links[entry.ino_id as usize] += 1;
+            }
+        }
+    }
+    links
+}
+
+fn mark_reachable(from: u32, children: &[Vec<u32>], reachable: &mut HashSet<u32>) {
+    let mut queue = VecDeque::from([from]);
+    while let Some(ino_id) = queue.pop_front() {
+        if reachable.insert(ino_id) {
+            queue.extend(&children[ino_id as usize]);
+        }
+    }
+}
+
+// Reports every inconsistency in state without changing anything
+pub fn check(state: &FSState) -> Vec<Inconsistency> {
+    Scan::new(state).problems
+}
+
+// Fixes everything check() would report and returns what was found. The inode table is taken
+// as the source of truth: both bitmaps and the free counters are rebuilt from the inodes and
+// the blocks they reference, and inodes that cannot be reached are linked into /lost+found.
+pub fn repair(state: &mut FSState) -> Vec<Inconsistency> {
+    let Scan {
+        problems,
+        inodes,
+        cleared_ptrs,
+        fixed_extent_nodes,
+        owners,
+        refs,
+        snapshots,
+        children,
+        referenced,
+        dangling,
+        bad_dots,
+        orphans,
+        unlinked,
+        ..
+    } = Scan::new(state);
+
+    // Blocks that cannot be read are left as they are
+    for (blk_no, idx) in cleared_ptrs {
+        if state.blk_data(blk_no).is_none() {
+            continue;
+        }
+        if let Ok(data) = state.blk_data_mut(blk_no) {
+            data[idx * 4..idx * 4 + 4].fill(0);
+        }
+    }
+    for (blk_no, node) in fixed_extent_nodes {
+        if state.blk_data(blk_no).is_none() {
+            continue;
+        }
+        if let Ok(data) = state.blk_data_mut(blk_no) {
+            node.write(data);
+        }
+    }
+    state.inodes = inodes.into_boxed_slice();
+    rebuild_bitmaps(state, &owners);
+    rebuild_refcounts(state, &refs, snapshots);
+
+    for (dir, name, ino_id) in dangling {
+        if let Err(err) = state.remove_entry(dir, &name) {
+            error!("Failed to remove {name:?} -> {ino_id} from directory {dir}: errno {err}");
+        }
+    }
+    for (dir, parent) in bad_dots {
+        if let Err(err) = fix_dots(state, dir, parent) {
+            error!("Failed to fix the dot entries of directory {dir}: errno {err}");
+        }
+    }
+
+    for ino_id in unlinked {
+        if let Err(err) = state.free_node(ino_id) {
+            error!("Failed to free unlinked inode {ino_id}: errno {err}");
+        }
+    }
+
+    // Orphans nothing points at are the tops of lost subtrees, linking them first brings the
+    // rest back with them. Whatever is left is only referenced from a lost cycle.
+    let (tops, rest): (Vec<u32>, Vec<u32>) = orphans
+        .into_iter()
+        .partition(|ino_id| !referenced.contains(ino_id));
+    let mut placed = HashSet::new();
+    for ino_id in tops.into_iter().chain(rest) {
+        if placed.contains(&ino_id) {
+            continue;
+        }
+        if let Err(err) = link_lost(state, ino_id) {
+            error!("Failed to move inode {ino_id} to {LOST_FOUND}: errno {err}");
+        }
+        mark_reachable(ino_id, &children, &mut placed);
+    }
+
+    // Counted last, once every entry above is in place
+    let links = count_links(state, &state.inodes);
+    for inode in state.inodes.iter_mut().flatten() {
+        inode.nlink = links[inode.ino_id as usize];
+    }
+    problems
+}
+
+fn rebuild_bitmaps(state: &mut FSState, owners: &[Option<u32>]) {
+    for slot in RESERVED_INODES as usize..state.inodes.len() {
+        let in_use = state.inodes[slot].is_some();
+        state.inode_bitmap.map.set(slot, in_use);
+    }
+    let reserved = state.geometry().reserved_blks() as usize;
+    state.blk_bitmap.map[..reserved].fill(true);
+    for (blk_no, owner) in owners.iter().enumerate().skip(reserved) {
+        let in_use = owner.is_some();
+        state.blk_bitmap.map.set(blk_no, in_use);
+        if !in_use {
+            state.blks.remove(blk_no as u32);
+        }
+    }
+
+    let metadata = &mut state.metadata;
+    metadata.free_ino_count = metadata.ino_count - state.inode_bitmap.map.count_ones() as u32;
+    metadata.free_blk_count = metadata.blk_count - state.blk_bitmap.map.count_ones() as u32;
+    state.recount_groups();
+}
+
+// Counts are taken from the references found. Snapshots with corrected inodes get their inode
+// table written out again.
+fn rebuild_refcounts(
+    state: &mut FSState,
+    refs: &[u32],
+    snapshots: Vec<(usize, Box<[Option<Inode>]>)>,
+) {
+    let refcounts: BTreeMap<u32, u32> = refs
+        .iter()
+        .enumerate()
+        .filter(|(_, &refs)| refs > 1)
+        .map(|(blk_no, &refs)| (blk_no as u32, refs))
+        .collect();
+    if refcounts != state.refcounts {
+        state.refcounts = refcounts;
+        state.snap_dirty = true;
+    }
+    for (idx, inodes) in snapshots {
+        state.snapshots[idx].inodes = inodes;
+        for blk_no in mem::take(&mut state.snapshots[idx].table_blks) {
+            if let Err(err) = state.free_blk(blk_no) {
+                error!("Failed to free snapshot table block {blk_no}: {err:?}");
+            }
+        }
+        state.snap_dirty = true;
+    }
+}
+
+fn fix_dots(state: &mut FSState, dir: u32, parent: u32) -> Result<(), libc::c_int> {
+    for (name, ino_id) in [(".", dir), ("..", parent)] {
+        match state.set_entry(dir, OsStr::new(name), ino_id) {
+            Err(libc::ENOENT) => {
+                state.add_entry(dir, OsStr::new(name), ino_id, FileType::Directory)?
+            }
+            result => result?,
+        }
+    }
+    Ok(())
+}
+
+fn link_lost(state: &mut FSState, ino_id: u32) -> Result<(), libc::c_int> {
+    let lost_found = match state.lookup_entry(ROOT_INO, OsStr::new(LOST_FOUND)) {
+        Ok(lost_found) => lost_found,
+        Err(libc::ENOENT) => {
+            state.make_node(ROOT_INO, OsStr::new(LOST_FOUND), FileType::Directory, 0o700)?
+        }
+        Err(err) => return Err(err),
+    };
+    let kind = state.inode(ino_id).map_err(inode_errno)?.kind;
+    let name = OsString::from(format!("#{ino_id}"));
+    state.add_entry(lost_found, &name, ino_id, kind)?;
+    if kind == FileType::Directory {
+        fix_dots(state, ino_id, lost_found)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::Geometry;
+    use crate::test_util::{make_node, state_with_root};
+
+    fn state_with_files() -> (FSState, u32, u32) {
+        let mut state = state_with_root(Geometry::new(1 << 20, 1024, 32).unwrap());
+        let dir = make_node(&mut state, ROOT_INO, "dir", FileType::Directory);
+        let file = make_node(&mut state, dir, "file", FileType::RegularFile);
+        state.write_at(file, 0, &[7; 3000]).unwrap();
+        (state, dir, file)
+    }
+
+    #[test]
+    fn test_clean_state_has_no_problems() {
+        let (state, _, _) = state_with_files();
+        assert_eq!(check(&state), vec![]);
+    }
+
+    #[test]
+    fn test_counters_are_checked_and_repaired() {
+        let (mut state, _, _) = state_with_files();
+        let free_blks = state.metadata.free_blk_count;
+        state.metadata.free_blk_count += 5;
+        state.metadata.free_ino_count -= 1;
+
+        let problems = check(&state);
+        assert!(problems.contains(&Inconsistency::FreeBlockCount {
+            recorded: free_blks + 5,
+            actual: free_blks,
+        }));
+        assert_eq!(problems.len(), 2);
+
+        assert_eq!(repair(&mut state), problems);
+        assert_eq!(state.metadata.free_blk_count, free_blks);
+        assert_eq!(check(&state), vec![]);
+    }
+
+    #[test]
+    fn test_block_used_but_marked_free() {
+        let (mut state, _, file) = state_with_files();
+        let blk_no = state.inode(file).unwrap().direct_blks[1];
+        state.blk_bitmap.map.set(blk_no as usize, false);
+        state.metadata.free_blk_count += 1;
+        state.groups[0].free_blks += 1;
+
+        assert_eq!(
+            check(&state),
+            vec![Inconsistency::UnmarkedBlock {
+                ino_id: file,
+                blk_no
+            }]
+        );
+        repair(&mut state);
+        assert!(state.blk_bitmap.map[blk_no as usize]);
+        assert_eq!(check(&state), vec![]);
+    }
+
+    #[test]
+    fn test_leaked_block_is_freed() {
+        let (mut state, _, _) = state_with_files();
+        let blk_no = state.alloc_blk().unwrap();
+
+        assert_eq!(check(&state), vec![Inconsistency::LeakedBlock { blk_no }]);
+        let free_blks = state.metadata.free_blk_count;
+        repair(&mut state);
+        assert_eq!(state.metadata.free_blk_count, free_blks + 1);
+        assert!(state.blk_data(blk_no).is_none());
+        assert_eq!(check(&state), vec![]);
+    }
+
+    #[test]
+    fn test_duplicate_and_bad_pointers_are_cleared() {
+        let (mut state, dir, file) = state_with_files();
+        let shared = state.inode(file).unwrap().direct_blks[0];
+        let other = state
+            .make_node(dir, OsStr::new("other"), FileType::RegularFile, 0o644)
+            .unwrap();
+        let inode = state.inode_mut(other).unwrap();
+        inode.direct_blks[0] = shared;
+        inode.direct_blks[1] = 1 << 30;
+        inode.blocks = 2;
+
+        let problems = check(&state);
+        assert!(problems.contains(&Inconsistency::DuplicateBlock {
+            ino_id: other,
+            blk_no: shared,
+            owner: file,
+        }));
+        assert!(problems.contains(&Inconsistency::BadBlockPointer {
+            ino_id: other,
+            blk_no: 1 << 30,
+        }));
+
+        repair(&mut state);
+        let inode = state.inode(other).unwrap();
+        assert_eq!(inode.direct_blks[..2], [INVALID_PTR; 2]);
+        assert_eq!(inode.blocks, 0);
+        assert_eq!(state.inode(file).unwrap().direct_blks[0], shared);
+        assert_eq!(check(&state), vec![]);
+    }
+
+    #[test]
+    fn test_indirect_pointers_are_walked() {
+        let (mut state, _, file) = state_with_files();
+        let ptr_blk = state.alloc_blk().unwrap();
+        let data_blk = state.alloc_blk().unwrap();
+        state.blk_data_mut(ptr_blk).unwrap()[8..12].copy_from_slice(&data_blk.to_le_bytes());
+        let blocks = state.inode(file).unwrap().blocks;
+        state.inode_mut(file).unwrap().indirect_blk = ptr_blk;
+
+        assert_eq!(
+            check(&state),
+            vec![Inconsistency::BlockCount {
+                ino_id: file,
+                recorded: blocks,
+                actual: blocks + 2,
+            }]
+        );
+
+        // A bad pointer inside the indirect block is cleared in place
+        state.blk_data_mut(ptr_blk).unwrap()[12..16].copy_from_slice(&u32::MAX.to_le_bytes());
+        repair(&mut state);
+        assert_eq!(state.inode(file).unwrap().blocks, blocks + 2);
+        assert_eq!(state.blk_data(ptr_blk).unwrap()[12..16], [0; 4]);
+        assert_eq!(check(&state), vec![]);
+    }
+
+    #[test]
+    fn test_bad_extents_are_dropped() {
+        let (mut state, dir, file) = state_with_files();
+        state.set_features(crate::image::FEATURE_EXTENTS);
+        let (a, b) = (
+            make_node(&mut state, dir, "a", FileType::RegularFile),
+            make_node(&mut state, dir, "b", FileType::RegularFile),
+        );
+        state.write_at(a, 0, &[1; 3000]).unwrap();
+        // b claims a's extent, file gets a tree whose only node is garbage
+        let extents = state.inode(a).unwrap().ptr_area();
+        let inode = state.inode_mut(b).unwrap();
+        inode.set_ptr_area(&extents);
+        inode.blocks = 3;
+        let node = state.alloc_blk().unwrap();
+        state.blk_data_mut(node).unwrap().fill(0xff);
+        let inode = state.inode_mut(file).unwrap();
+        inode.flags |= crate::INODE_FLAG_EXTENTS;
+        inode.set_extent_root(&ExtentNode {
+            depth: 1,
+            entries: vec![Extent {
+                lblk: 0,
+                start: node,
+                len: 0,
+            }],
+        });
+
+        let problems = check(&state);
+        let blk_no = state.extents(state.inode(a).unwrap())[0].start;
+        assert!(problems.contains(&Inconsistency::DuplicateBlock {
+            ino_id: b,
+            blk_no,
+            owner: a,
+        }));
+        assert!(problems.contains(&Inconsistency::BadExtentNode {
+            ino_id: file,
+            blk_no: node,
+        }));
+
+        repair(&mut state);
+        assert_eq!(state.extents(state.inode(b).unwrap()), []);
+        assert_eq!(state.inode(b).unwrap().blocks, 0);
+        assert_eq!(
+            state.inode(file).unwrap().extent_root(),
+            ExtentNode::default()
+        );
+        assert_eq!(state.read_at(a, 0, &mut [0; 3000]), Ok(3000));
+        assert_eq!(check(&state), vec![]);
+    }
+
+    #[test]
+    fn test_inode_bitmap_and_id_mismatch() {
+        let (mut state, _, file) = state_with_files();
+        state.inode_bitmap.map.set(file as usize, false);
+        state.inode_bitmap.map.set(20, true);
+        state.inode_mut(file).unwrap().ino_id = 9;
+
+        let problems = check(&state);
+        assert!(problems.contains(&Inconsistency::InodeBitmap {
+            ino_id: file,
+            in_use: true,
+        }));
+        assert!(problems.contains(&Inconsistency::InodeBitmap {
+            ino_id: 20,
+            in_use: false,
+        }));
+        assert!(problems.contains(&Inconsistency::InodeIdMismatch {
+            slot: file,
+            ino_id: 9,
+        }));
+
+        repair(&mut state);
+        assert_eq!(state.inode(file).unwrap().ino_id, file);
+        assert_eq!(check(&state), vec![]);
+    }
+
+    #[test]
+    fn test_dangling_entry_is_removed() {
+        let (mut state, dir, file) = state_with_files();
+        state.truncate(file, 0).unwrap();
+        state.free_inode(file).unwrap();
+
+        assert_eq!(
+            check(&state),
+            vec![Inconsistency::DanglingEntry {
+                dir,
+                name: OsString::from("file"),
+                ino_id: file,
+            }]
+        );
+        repair(&mut state);
+        assert!(state.dir_is_empty(dir).unwrap());
+        assert_eq!(check(&state), vec![]);
+    }
+
+    #[test]
+    fn test_bad_dot_entries_are_rewritten() {
+        let (mut state, dir, file) = state_with_files();
+        state.set_entry(dir, OsStr::new(".."), file).unwrap();
+        state.remove_entry(dir, OsStr::new(".")).unwrap();
+
+        // The counts follow the entries as they are, the dots are fixed up before recounting
+        let link_count = |ino_id, recorded, actual| Inconsistency::LinkCount {
+            ino_id,
+            recorded,
+            actual,
+        };
+        assert_eq!(
+            check(&state),
+            vec![
+                Inconsistency::BadDotEntries {
+                    dir,
+                    parent: ROOT_INO
+                },
+                link_count(ROOT_INO, 3, 2),
+                link_count(dir, 2, 1),
+                link_count(file, 1, 2),
+            ]
+        );
+        repair(&mut state);
+        assert_eq!(state.lookup_entry(dir, OsStr::new(".")), Ok(dir));
+        assert_eq!(state.lookup_entry(dir, OsStr::new("..")), Ok(ROOT_INO));
+        assert_eq!(check(&state), vec![]);
+    }
+
+    #[test]
+    fn test_orphans_move_to_lost_found() {
+        let (mut state, dir, file) = state_with_files();
+        state.remove_entry(ROOT_INO, OsStr::new("dir")).unwrap();
+
+        let problems = check(&state);
+        assert_eq!(
+            problems,
+            vec![
+                Inconsistency::OrphanInode { ino_id: dir },
+                Inconsistency::OrphanInode { ino_id: file },
+            ]
+        );
+
+        repair(&mut state);
+        let lost_found = state
+            .lookup_entry(ROOT_INO, OsStr::new(LOST_FOUND))
+            .unwrap();
+        // Only the top of the lost subtree is linked, the file comes back with its directory
+        assert_eq!(
+            state.lookup_entry(lost_found, OsStr::new(&format!("#{dir}"))),
+            Ok(dir)
+        );
+        assert_eq!(state.read_dir(lost_found).unwrap().len(), 3);
+        assert_eq!(state.lookup_entry(dir, OsStr::new("file")), Ok(file));
+        assert_eq!(state.lookup_entry(dir, OsStr::new("..")), Ok(lost_found));
+        assert_eq!(check(&state), vec![]);
+    }
+
+    #[test]
+    fn test_missing_root_is_recreated() {
+        let (mut state, dir, _) = state_with_files();
+        state.inodes[ROOT_INO as usize] = None;
+
+        let problems = check(&state);
+        assert!(problems.contains(&Inconsistency::BadRoot));
+        assert!(problems.contains(&Inconsistency::OrphanInode { ino_id: dir }));
+
+        repair(&mut state);
+        assert_eq!(state.inode(ROOT_INO).unwrap().kind, FileType::Directory);
+        assert_eq!(check(&state), vec![]);
+    }
+
+    #[test]
+    fn test_link_counts_are_recounted() {
+        let (mut state, dir, file) = state_with_files();
+        state.link_node(file, ROOT_INO, OsStr::new("link")).unwrap();
+        state.inode_mut(file).unwrap().nlink = 1;
+        state.inode_mut(dir).unwrap().nlink = 5;
+
+        assert_eq!(
+            check(&state),
+            vec![
+                Inconsistency::LinkCount {
+                    ino_id: dir,
+                    recorded: 5,
+                    actual: 2,
+                },
+                Inconsistency::LinkCount {
+                    ino_id: file,
+                    recorded: 1,
+                    actual: 2,
+                },
+            ]
+        );
+        repair(&mut state);
+        assert_eq!(state.inode(file).unwrap().nlink, 2);
+        assert_eq!(check(&state), vec![]);
+    }
+
+    #[test]
+    fn test_unlinked_inode_is_freed() {
+        let (mut state, dir, file) = state_with_files();
+        let free_blks = state.metadata.free_blk_count;
+        state.open_inode(file).unwrap();
+        state.remove_node(dir, OsStr::new("file"), false).unwrap();
+
+        assert_eq!(
+            check(&state),
+            vec![Inconsistency::UnlinkedInode { ino_id: file }]
+        );
+        repair(&mut state);
+        assert!(state.inode(file).is_err());
+        assert_eq!(state.metadata.free_blk_count, free_blks + 3);
+        assert!(state
+            .lookup_entry(ROOT_INO, OsStr::new(LOST_FOUND))
+            .is_err());
+        assert_eq!(check(&state), vec![]);
+    }
+}
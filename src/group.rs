@@ -0,0 +1,341 @@
+use crate::image::{Cursor, Geometry, Writer};
+use crate::{FSState, INODE_BMAP_BLK_NO, INODE_SIZE_BYTES};
+use bitvec::prelude::*;
+use fuser::FileType;
+use std::cmp::Reverse;
+use std::ops::Range;
+
+// The volume is split into block groups of Geometry::blks_per_group blocks, with the inodes
+// spread evenly between them. Each group has a block of the inode bitmap and a block of the
+// block bitmap to itself, and a descriptor with its free counts in the table after the bitmaps.
+// Allocation goes by those counts: a file's blocks go to the group of its inode, a new inode to
+// the group of its directory, and a new directory to a group with plenty of room left, so
+// unrelated trees do not end up interleaved. Unlike ext2 all of it sits at the start of the
+// image, ahead of the inode table.
+pub(crate) const GROUP_DESC_LEN: usize = 32;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct GroupDesc {
+    pub(crate) free_blks: u32,
+    pub(crate) free_inos: u32,
+    pub(crate) dirs: u32,
+}
+
+impl GroupDesc {
+    // blk_bitmap | inode_bitmap | inode_table | free_blks | free_inos | dirs. Where the bitmaps
+    // and the group's first inode are follows from the geometry, they are only recorded for
+    // tools reading the image.
+    fn serialize(&self, geometry: Geometry, group: u32, buf: &mut [u8]) {
+        let per_blk = geometry.blk_size / INODE_SIZE_BYTES as u32;
+        let first_ino = geometry.group_inos(group).start;
+        let mut w = Writer::new(buf);
+        w.put(&(geometry.blk_bmap_blk_no() + group).to_le_bytes());
+        w.put(&(INODE_BMAP_BLK_NO + group).to_le_bytes());
+        w.put(&(geometry.inode_table_blk_no() + first_ino / per_blk).to_le_bytes());
+        w.put(&self.free_blks.to_le_bytes());
+        w.put(&self.free_inos.to_le_bytes());
+        w.put(&self.dirs.to_le_bytes());
+    }
+
+    fn deserialize(buf: &[u8]) -> Self {
+        let mut c = Cursor::new(buf);
+        c.take::<12>();
+        Self {
+            free_blks: c.u32(),
+            free_inos: c.u32(),
+            dirs: c.u32(),
+        }
+    }
+}
+
+impl Geometry {
+    pub(crate) fn blk_group(&self, blk_no: u32) -> u32 {
+        blk_no / self.blks_per_group
+    }
+
+    pub(crate) fn ino_group(&self, ino_id: u32) -> u32 {
+        ino_id / self.inos_per_group()
+    }
+
+    pub(crate) fn group_blks(&self, group: u32) -> Range<u32> {
+        let start = group * self.blks_per_group;
+        start..(start + self.blks_per_group).min(self.blk_count)
+    }
+
+    // Empty for the last groups when there are fewer inodes than groups to spread them over
+    pub(crate) fn group_inos(&self, group: u32) -> Range<u32> {
+        let start = (group * self.inos_per_group()).min(self.ino_count);
+        start..(start + self.inos_per_group()).min(self.ino_count)
+    }
+}
+
+// A bitmap is stored one block per group, each starting with the group's first entry
+pub(crate) fn serialize_group_bits(map: &BitSlice<u8, Lsb0>, range: Range<u32>, buf: &mut [u8]) {
+    let range = range.start as usize..range.end as usize;
+    buf.view_bits_mut::<Lsb0>()[..range.len()].copy_from_bitslice(&map[range]);
+}
+
+pub(crate) fn deserialize_group_bits(map: &mut BitSlice<u8, Lsb0>, range: Range<u32>, buf: &[u8]) {
+    let range = range.start as usize..range.end as usize;
+    let len = range.len();
+    map[range].copy_from_bitslice(&buf.view_bits::<Lsb0>()[..len]);
+}
+
+impl FSState {
+    // Every group's counts as the bitmaps and the inode table have them
+    pub(crate) fn count_groups(&self) -> Vec<GroupDesc> {
+        let geometry = self.geometry();
+        (0..geometry.group_count())
+            .map(|group| {
+                let blks = geometry.group_blks(group);
+                let inos = geometry.group_inos(group);
+                let inos = inos.start as usize..inos.end as usize;
+                GroupDesc {
+                    free_blks: self.blk_bitmap.map[blks.start as usize..blks.end as usize]
+                        .count_zeros() as u32,
+                    free_inos: self.inode_bitmap.map[inos.clone()].count_zeros() as u32,
+                    dirs: self.inodes[inos]
+                        .iter()
+                        .flatten()
+                        .filter(|inode| inode.kind == FileType::Directory)
+                        .count() as u32,
+                }
+            })
+            .collect()
+    }
+
+    // After the bitmaps or the inode table were rebuilt wholesale
+    pub(crate) fn recount_groups(&mut self) {
+        self.groups = self.count_groups();
+        for group in 0..self.groups.len() as u32 {
+            self.mark_group_dirty(group);
+        }
+    }
+
+    pub(crate) fn read_groups(&mut self, table: &[u8]) {
+        let count = self.geometry().group_count() as usize;
+        self.groups = table
+            .chunks_exact(GROUP_DESC_LEN)
+            .take(count)
+            .map(GroupDesc::deserialize)
+            .collect();
+    }
+
+    // Block idx of the descriptor table
+    pub(crate) fn serialize_groups(&self, idx: u32, buf: &mut [u8]) {
+        let geometry = self.geometry();
+        let per_blk = geometry.blk_size / GROUP_DESC_LEN as u32;
+        let slots = buf.chunks_exact_mut(GROUP_DESC_LEN);
+        for ((group, desc), slot) in self
+            .groups
+            .iter()
+            .enumerate()
+            .skip((idx * per_blk) as usize)
+            .zip(slots)
+        {
+            desc.serialize(geometry, group as u32, slot);
+        }
+    }
+
+    pub(crate) fn mark_group_dirty(&mut self, group: u32) {
+        let geometry = self.geometry();
+        let per_blk = geometry.blk_size / GROUP_DESC_LEN as u32;
+        self.mark_dirty(geometry.group_desc_blk_no() + group / per_blk);
+    }
+
+    // Counts are kept from wrapping on an image with wrong descriptors, fsck puts them right
+    pub(crate) fn count_group_blk(&mut self, blk_no: u32, alloced: bool) {
+        let group = self.geometry().blk_group(blk_no);
+        let desc = &mut self.groups[group as usize];
+        desc.free_blks = match alloced {
+            true => desc.free_blks.saturating_sub(1),
+            false => desc.free_blks + 1,
+        };
+        self.mark_group_dirty(group);
+    }
+
+    pub(crate) fn count_group_ino(&mut self, ino_id: u32, kind: FileType, alloced: bool) {
+        let group = self.geometry().ino_group(ino_id);
+        let desc = &mut self.groups[group as usize];
+        let dirs = (kind == FileType::Directory) as u32;
+        (desc.free_inos, desc.dirs) = match alloced {
+            true => (desc.free_inos.saturating_sub(1), desc.dirs + dirs),
+            false => (desc.free_inos + 1, desc.dirs.saturating_sub(dirs)),
+        };
+        self.mark_group_dirty(group);
+    }
+
+    // The group for a new inode in directory parent. A directory goes to the group with the most
+    // free blocks among those with at least their share of the free inodes, fewer directories
+    // breaking ties, anything else to the group of parent or the first one after it with room
+    // for both an inode and blocks.
+    pub(crate) fn inode_group(&self, parent: u32, kind: FileType) -> Option<u32> {
+        let count = self.groups.len() as u32;
+        let with_inos = |group: &u32| self.groups[*group as usize].free_inos > 0;
+        if kind == FileType::Directory {
+            let share = self.metadata.free_ino_count / count;
+            return (0..count)
+                .filter(with_inos)
+                .filter(|&group| self.groups[group as usize].free_inos >= share)
+                .min_by_key(|&group| {
+                    let desc = &self.groups[group as usize];
+                    (Reverse(desc.free_blks), desc.dirs)
+                });
+        }
+        let first = self.geometry().ino_group(parent).min(count - 1);
+        let mut order = (first..count).chain(0..first);
+        order
+            .clone()
+            .find(|&group| with_inos(&group) && self.groups[group as usize].free_blks > 0)
+            .or_else(|| order.find(with_inos))
+    }
+
+    // Where blocks for ino_id go when nothing else in the file says where: the first block of
+    // its group past the reserved ones
+    pub(crate) fn inode_goal(&self, ino_id: u32) -> u32 {
+        let geometry = self.geometry();
+        let first = geometry.group_blks(geometry.ino_group(ino_id)).start;
+        first.max(geometry.reserved_blks())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crash::CrashDevice;
+    use crate::image::{self, Geometry};
+    use crate::test_util::{make_node, state_with_root};
+    use crate::{fsck, ROOT_INO};
+    use std::ffi::OsStr;
+
+    // 4 groups of 1024 blocks and 16 inodes
+    fn geometry() -> Geometry {
+        Geometry::new(2 << 20, 512, 64)
+            .unwrap()
+            .with_blks_per_group(1024)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_layout() {
+        let geometry = geometry();
+        assert_eq!(geometry.group_count(), 4);
+        assert_eq!(geometry.inos_per_group(), 16);
+        assert_eq!(geometry.blk_bmap_blk_no(), 5);
+        assert_eq!(geometry.group_desc_blk_no(), 9);
+        assert_eq!(geometry.inode_table_blk_no(), 10);
+        assert_eq!(geometry.group_inos(3), 48..64);
+        assert_eq!(geometry.group_blks(3), 3072..4096);
+
+        // The last group is cut short by the end of the image
+        let geometry = Geometry::new(3 << 19, 512, 64)
+            .unwrap()
+            .with_blks_per_group(1024)
+            .unwrap();
+        assert_eq!(geometry.group_count(), 3);
+        assert_eq!(geometry.inos_per_group(), 22);
+        assert_eq!(geometry.group_inos(2), 44..64);
+        assert_eq!(geometry.group_blks(2), 2048..3072);
+    }
+
+    #[test]
+    fn test_directories_spread_and_files_follow() {
+        let mut state = state_with_root(geometry());
+        let dirs: Vec<u32> = (0..3)
+            .map(|idx| {
+                make_node(
+                    &mut state,
+                    ROOT_INO,
+                    &format!("dir{idx}"),
+                    FileType::Directory,
+                )
+            })
+            .collect();
+        let groups: Vec<u32> = dirs
+            .iter()
+            .map(|&dir| state.geometry().ino_group(dir))
+            .collect();
+        // Group 0 holds the metadata and the root, the others are empty and taken in turn
+        assert_eq!(groups, [1, 2, 3]);
+
+        for (&dir, &group) in dirs.iter().zip(&groups) {
+            let file = make_node(&mut state, dir, "file", FileType::RegularFile);
+            assert_eq!(state.geometry().ino_group(file), group);
+            state.write_at(file, 0, &[1; 3000]).unwrap();
+            let inode = *state.inode(file).unwrap();
+            let blk_no = state.bmap(&inode, 0).unwrap();
+            assert_eq!(state.geometry().blk_group(blk_no), group);
+            // The directory's own block went to its group too
+            let blk_no = state.bmap(state.inode(dir).unwrap(), 0).unwrap();
+            assert_eq!(state.geometry().blk_group(blk_no), group);
+        }
+        assert_eq!(state.groups, state.count_groups());
+        assert_eq!(fsck::check(&state), vec![]);
+    }
+
+    #[test]
+    fn test_full_groups_spill_over() {
+        let mut state = state_with_root(geometry());
+        let dir = make_node(&mut state, ROOT_INO, "dir", FileType::Directory);
+        let group = state.geometry().ino_group(dir);
+        // More inodes than the group has, and more blocks
+        let files: Vec<u32> = (0..20)
+            .map(|idx| make_node(&mut state, dir, &format!("f{idx}"), FileType::RegularFile))
+            .collect();
+        assert_eq!(state.groups[group as usize].free_inos, 0);
+        assert_eq!(state.geometry().ino_group(files[19]), group + 1);
+        state.write_at(files[0], 0, &vec![2; 1100 * 512]).unwrap();
+        let inode = *state.inode(files[0]).unwrap();
+        let blk_no = state.bmap(&inode, 1099).unwrap();
+        assert!(state.geometry().blk_group(blk_no) > group);
+        assert_eq!(state.groups, state.count_groups());
+
+        for idx in 0..20 {
+            let name = format!("f{idx}");
+            state.remove_node(dir, OsStr::new(&name), false).unwrap();
+        }
+        assert_eq!(state.groups, state.count_groups());
+        assert_eq!(fsck::check(&state), vec![]);
+    }
+
+    #[test]
+    fn test_groups_survive_commit_and_load() {
+        for geometry in [
+            geometry().with_journal(64).unwrap(),
+            geometry().with_cow().unwrap(),
+        ] {
+            let device = CrashDevice::new(Vec::new(), None, false);
+            image::format(&device, geometry).unwrap();
+            let mut state = FSState::load(&device).unwrap();
+            let dir = make_node(&mut state, ROOT_INO, "dir", FileType::Directory);
+            let file = make_node(&mut state, dir, "file", FileType::RegularFile);
+            state.write_at(file, 0, &[3; 5000]).unwrap();
+            state.commit(&device).unwrap();
+
+            let state = FSState::load(&device).unwrap();
+            assert_eq!(state.groups, state.count_groups());
+            assert_eq!(
+                state.groups[state.geometry().ino_group(dir) as usize].dirs,
+                1
+            );
+            assert_eq!(fsck::check(&state), vec![]);
+        }
+    }
+
+    #[test]
+    fn test_fsck_fixes_group_counts() {
+        let mut state = state_with_root(geometry());
+        make_node(&mut state, ROOT_INO, "dir", FileType::Directory);
+        state.groups[2].free_blks -= 5;
+        state.groups[0].dirs = 7;
+        assert_eq!(
+            fsck::check(&state),
+            vec![
+                fsck::Inconsistency::GroupCounts { group: 0 },
+                fsck::Inconsistency::GroupCounts { group: 2 },
+            ]
+        );
+        fsck::repair(&mut state);
+        assert_eq!(fsck::check(&state), vec![]);
+    }
+}
@@ -0,0 +1,451 @@
+use crate::dir::{decode_records, encode_record, DIRENT_HEADER_LEN};
+use crate::image::file_type_from_code;
+use crate::{blk_errno, inode_errno, FSState, Inode, INODE_FLAG_INDEX, INVALID_PTR};
+use fuser::FileType;
+use libc::{c_int, EIO, ENOSPC};
+use log::error;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::sync::Arc;
+
+// Directories that outgrow one block get a hashed index, a simplified take on ext3's htree.
+// Block 0 keeps "." and ".." with the index root hidden in the free space of "..". The other
+// blocks are either leaves holding ordinary records, or index nodes that look like a single
+// unused record to anything reading the directory linearly, so readdir and fsck need no
+// changes. Index entries map a name hash to the logical block covering it, sorted by hash with
+// the first entry at hash 0. Names with the same hash always share a leaf.
+//
+// Index header: limit (u16 LE) | count (u16 LE) | levels (u8, root only) | 3 bytes padding,
+// followed by count entries of hash (u32 LE) | lblk (u32 LE).
+const DX_ROOT_OFFSET: usize = 24; // after the 12 byte "." and ".." records
+const DX_NODE_OFFSET: usize = DIRENT_HEADER_LEN;
+const DX_HEADER_LEN: usize = 8;
+const DX_ENTRY_LEN: usize = 8;
+// The root can point at leaves directly or at one level of index nodes
+const MAX_DX_LEVELS: u8 = 1;
+
+// 32-bit FNV-1a, stable across runs since it is stored on disk
+pub(crate) fn name_hash(name: &[u8]) -> u32 {
+    name.iter().fold(0x811c_9dc5, |hash: u32, &byte| {
+        (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+    })
+}
+
+#[derive(Clone, Copy)]
+struct DxEntry {
+    hash: u32,
+    lblk: u32,
+}
+
+// One index block on the way from the root to a leaf, pos is the entry that was followed
+struct DxFrame {
+    blk_no: u32,
+    offset: usize,
+    levels: u8,
+    limit: usize,
+    entries: Vec<DxEntry>,
+    pos: usize,
+}
+
+fn dx_limit(blk_size: usize, offset: usize) -> usize {
+    (blk_size - offset - DX_HEADER_LEN) / DX_ENTRY_LEN
+}
+
+fn read_dx(data: &[u8], offset: usize) -> (u8, Vec<DxEntry>) {
+    let header = &data[offset..offset + DX_HEADER_LEN];
+    let count = u16::from_le_bytes(header[2..4].try_into().unwrap()) as usize;
+    let count = count.min(dx_limit(data.len(), offset));
+    let entries = data[offset + DX_HEADER_LEN..]
+        .chunks_exact(DX_ENTRY_LEN)
+        .take(count)
+        .map(|entry| DxEntry {
+            hash: u32::from_le_bytes(entry[0..4].try_into().unwrap()),
+            lblk: u32::from_le_bytes(entry[4..8].try_into().unwrap()),
+        })
+        .collect();
+    (header[4], entries)
+}
+
+fn write_dx(data: &mut [u8], offset: usize, levels: u8, entries: &[DxEntry]) {
+    let limit = dx_limit(data.len(), offset);
+    let header = &mut data[offset..offset + DX_HEADER_LEN];
+    header[0..2].copy_from_slice(&(limit as u16).to_le_bytes());
+    header[2..4].copy_from_slice(&(entries.len() as u16).to_le_bytes());
+    header[4] = levels;
+    let mut pos = offset + DX_HEADER_LEN;
+    for entry in entries {
+        data[pos..pos + 4].copy_from_slice(&entry.hash.to_le_bytes());
+        data[pos + 4..pos + 8].copy_from_slice(&entry.lblk.to_le_bytes());
+        pos += DX_ENTRY_LEN;
+    }
+}
+
+// An index node starts with an unused record covering the whole block
+fn init_dx_node(data: &mut [u8], entries: &[DxEntry]) {
+    data.fill(0);
+    let rec_len = data.len() as u16;
+    data[4..6].copy_from_slice(&rec_len.to_le_bytes());
+    write_dx(data, DX_NODE_OFFSET, 0, entries);
+}
+
+// ino_id, kind and name of a record being moved between blocks
+type LeafEntry = (u32, FileType, Vec<u8>);
+
+// Packs entries into a leaf, the last record takes the rest of the block
+fn write_leaf(data: &mut [u8], entries: &[LeafEntry]) {
+    data.fill(0);
+    let mut offset = 0;
+    for (idx, (ino_id, kind, name)) in entries.iter().enumerate() {
+        let rec_len = match idx + 1 == entries.len() {
+            true => data.len() - offset,
+            false => (DIRENT_HEADER_LEN + name.len()).next_multiple_of(4),
+        };
+        encode_record(data, offset, rec_len, (*ino_id, *kind, name));
+        offset += rec_len;
+    }
+    if entries.is_empty() {
+        let rec_len = data.len() as u16;
+        data[4..6].copy_from_slice(&rec_len.to_le_bytes());
+    }
+}
+
+// Where to split hash sorted entries in two without separating equal hashes
+fn split_point(hashes: &[u32]) -> Option<usize> {
+    let mid = hashes.len() / 2;
+    (mid.max(1)..hashes.len())
+        .chain((1..mid).rev())
+        .find(|&idx| hashes[idx] != hashes[idx - 1])
+}
+
+impl FSState {
+    fn dx_blk(&self, inode: &Inode, lblk: u64) -> Result<u32, c_int> {
+        let blk_no = self.bmap(inode, lblk).unwrap_or(INVALID_PTR);
+        match self.blk_data(blk_no) {
+            Some(_) => Ok(blk_no),
+            None => {
+                error!("Index of directory {} points at a hole", inode.ino_id);
+                Err(EIO)
+            }
+        }
+    }
+
+    // A block dx_blk found, which the block cache may have to read back from the image
+    fn dx_data(&self, blk_no: u32) -> Result<Arc<[u8]>, c_int> {
+        self.blk_data(blk_no).ok_or(EIO)
+    }
+
+    // Walks the index from the root down to the leaf covering hash
+    fn dx_path(&self, inode: &Inode, hash: u32) -> Result<(Vec<DxFrame>, u32), c_int> {
+        let mut blk_no = self.dx_blk(inode, 0)?;
+        let mut offset = DX_ROOT_OFFSET;
+        let (levels, _) = read_dx(&self.dx_data(blk_no)?, offset);
+        if levels > MAX_DX_LEVELS {
+            error!("Index of directory {} has {levels} levels", inode.ino_id);
+            return Err(EIO);
+        }
+        let mut frames: Vec<DxFrame> = Vec::new();
+        loop {
+            let data = self.dx_data(blk_no)?;
+            let (_, entries) = read_dx(&data, offset);
+            if entries.is_empty() {
+                error!("Empty index block {blk_no} in directory {}", inode.ino_id);
+                return Err(EIO);
+            }
+            let pos = entries.partition_point(|entry| entry.hash <= hash).max(1) - 1;
+            let next = self.dx_blk(inode, entries[pos].lblk as u64)?;
+            frames.push(DxFrame {
+                blk_no,
+                offset,
+                levels,
+                limit: dx_limit(data.len(), offset),
+                entries,
+                pos,
+            });
+            if frames.len() > levels as usize {
+                return Ok((frames, next));
+            }
+            blk_no = next;
+            offset = DX_NODE_OFFSET;
+        }
+    }
+
+    // Blocks that can hold name: block 0 for the dot entries and the leaf its hash maps to.
+    // Falls back to every block when the index is damaged.
+    pub(crate) fn dx_lookup_blks(&self, inode: &Inode, name: &OsStr) -> Vec<u32> {
+        let path = self.dx_path(inode, name_hash(name.as_bytes()));
+        match (self.dx_blk(inode, 0), path) {
+            (Ok(root), Ok((_, leaf))) => vec![root, leaf],
+            _ => self.dir_blks(inode),
+        }
+    }
+
+    pub(crate) fn dx_add_entry(
+        &mut self,
+        dir: u32,
+        entry: (u32, FileType, &[u8]),
+    ) -> Result<(), c_int> {
+        let hash = name_hash(entry.2);
+        loop {
+            let inode = *self.dir_inode(dir)?;
+            let (frames, leaf) = self.dx_path(&inode, hash)?;
+            if self.insert_record(leaf, entry)? {
+                break;
+            }
+            // Make room one step at a time, then walk the index again
+            let parent = frames.last().unwrap();
+            if parent.entries.len() < parent.limit {
+                self.dx_split_leaf(dir, parent, leaf)?;
+            } else if frames.len() == 1 {
+                self.dx_add_level(dir, parent)?;
+            } else if frames[0].entries.len() < frames[0].limit {
+                self.dx_split_node(dir, &frames[0], parent)?;
+            } else {
+                error!("Index of directory {dir} is full");
+                return Err(ENOSPC);
+            }
+        }
+        self.inode_mut(dir).map_err(inode_errno)?.update_mtime();
+        Ok(())
+    }
+
+    // Moves the upper half of a full leaf, by hash, into a new block
+    fn dx_split_leaf(&mut self, dir: u32, parent: &DxFrame, leaf: u32) -> Result<(), c_int> {
+        let data = &self.dx_data(leaf)?;
+        let mut entries: Vec<(u32, LeafEntry)> = decode_records(data)
+            .into_iter()
+            .filter(|record| record.ino_id != INVALID_PTR)
+            .filter_map(|record| {
+                let kind = file_type_from_code(record.kind)?;
+                let name = record.name(data).to_vec();
+                Some((name_hash(&name), (record.ino_id, kind, name)))
+            })
+            .collect();
+        entries.sort_by_key(|(hash, _)| *hash);
+        let hashes: Vec<u32> = entries.iter().map(|(hash, _)| *hash).collect();
+        let Some(mid) = split_point(&hashes) else {
+            error!("Leaf {leaf} of directory {dir} cannot be split");
+            return Err(ENOSPC);
+        };
+        let entries: Vec<_> = entries.into_iter().map(|(_, entry)| entry).collect();
+
+        let (lblk, new_leaf) = self.append_dir_blk(dir)?;
+        write_leaf(self.blk_data_mut(leaf).map_err(blk_errno)?, &entries[..mid]);
+        write_leaf(
+            self.blk_data_mut(new_leaf).map_err(blk_errno)?,
+            &entries[mid..],
+        );
+        self.dx_insert(parent, hashes[mid], lblk as u32)
+    }
+
+    // The root is full: its entries move to a new index node it then points at
+    fn dx_add_level(&mut self, dir: u32, root: &DxFrame) -> Result<(), c_int> {
+        let (lblk, node) = self.append_dir_blk(dir)?;
+        init_dx_node(self.blk_data_mut(node).map_err(blk_errno)?, &root.entries);
+        let data = self.blk_data_mut(root.blk_no).map_err(blk_errno)?;
+        let entry = DxEntry {
+            hash: 0,
+            lblk: lblk as u32,
+        };
+        write_dx(data, root.offset, root.levels + 1, &[entry]);
+        Ok(())
+    }
+
+    fn dx_split_node(&mut self, dir: u32, root: &DxFrame, node: &DxFrame) -> Result<(), c_int> {
+        let (lower, upper) = node.entries.split_at(node.entries.len() / 2);
+        let (lblk, new_node) = self.append_dir_blk(dir)?;
+        init_dx_node(self.blk_data_mut(new_node).map_err(blk_errno)?, upper);
+        let data = self.blk_data_mut(node.blk_no).map_err(blk_errno)?;
+        write_dx(data, node.offset, 0, lower);
+        self.dx_insert(root, upper[0].hash, lblk as u32)
+    }
+
+    // Adds an entry right after the one frame followed
+    fn dx_insert(&mut self, frame: &DxFrame, hash: u32, lblk: u32) -> Result<(), c_int> {
+        let mut entries = frame.entries.clone();
+        entries.insert(frame.pos + 1, DxEntry { hash, lblk });
+        let data = self.blk_data_mut(frame.blk_no).map_err(blk_errno)?;
+        write_dx(data, frame.offset, frame.levels, &entries);
+        Ok(())
+    }
+
+    // Turns a full single block directory into an indexed one: everything but "." and ".."
+    // moves to a new leaf and block 0 becomes the index root
+    pub(crate) fn dx_convert(&mut self, dir: u32) -> Result<(), c_int> {
+        let inode = *self.dir_inode(dir)?;
+        let root = self.dx_blk(&inode, 0)?;
+        let data = &self.dx_data(root)?;
+        let mut dots = [dir, dir];
+        let mut entries = Vec::new();
+        for record in decode_records(data) {
+            let name = record.name(data);
+            match name {
+                _ if record.ino_id == INVALID_PTR => {}
+                b"." => dots[0] = record.ino_id,
+                b".." => dots[1] = record.ino_id,
+                _ => {
+                    let Some(kind) = file_type_from_code(record.kind) else {
+                        continue;
+                    };
+                    entries.push((record.ino_id, kind, name.to_vec()));
+                }
+            }
+        }
+
+        let (lblk, leaf) = self.append_dir_blk(dir)?;
+        write_leaf(self.blk_data_mut(leaf).map_err(blk_errno)?, &entries);
+        let data = self.blk_data_mut(root).map_err(blk_errno)?;
+        data.fill(0);
+        encode_record(data, 0, 12, (dots[0], FileType::Directory, b"."));
+        let rest = data.len() - 12;
+        encode_record(data, 12, rest, (dots[1], FileType::Directory, b".."));
+        let entry = DxEntry {
+            hash: 0,
+            lblk: lblk as u32,
+        };
+        write_dx(data, DX_ROOT_OFFSET, 0, &[entry]);
+        self.inode_mut(dir).map_err(inode_errno)?.flags |= INODE_FLAG_INDEX;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fsck;
+    use crate::image::{Geometry, FEATURE_DIR_INDEX};
+    use crate::test_util::state_with_features;
+    use crate::ROOT_INO;
+    use std::collections::HashSet;
+    use std::ffi::OsString;
+
+    fn state(blk_size: u32, dir_index: bool) -> FSState {
+        let geometry = Geometry::new(16 << 20, blk_size, 8192).unwrap();
+        state_with_features(geometry, if dir_index { FEATURE_DIR_INDEX } else { 0 })
+    }
+
+    fn create(state: &mut FSState, count: usize) -> Vec<(OsString, u32)> {
+        (0..count)
+            .map(|i| {
+                let name = OsString::from(format!("artifact-{i:05}.o"));
+                let ino_id = state
+                    .make_node(ROOT_INO, &name, FileType::RegularFile, 0o644)
+                    .unwrap();
+                (name, ino_id)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_hash_is_stable() {
+        assert_eq!(name_hash(b""), 0x811c_9dc5);
+        assert_eq!(name_hash(b"a"), 0xe40c_292c);
+    }
+
+    #[test]
+    fn test_split_point_keeps_equal_hashes_together() {
+        assert_eq!(split_point(&[1, 2, 3, 4]), Some(2));
+        assert_eq!(split_point(&[1, 2, 2, 2]), Some(1));
+        assert_eq!(split_point(&[1, 1, 1, 2]), Some(3));
+        assert_eq!(split_point(&[5, 5, 5]), None);
+        assert_eq!(split_point(&[5]), None);
+    }
+
+    #[test]
+    fn test_small_directories_stay_linear() {
+        let mut state = state(1024, true);
+        create(&mut state, 10);
+        let root = state.inode(ROOT_INO).unwrap();
+        assert_eq!(root.flags & INODE_FLAG_INDEX, 0);
+        assert_eq!(root.size, 1024);
+    }
+
+    #[test]
+    fn test_directory_is_indexed_past_one_block() {
+        let mut state = state(1024, true);
+        let files = create(&mut state, 60);
+        let root = *state.inode(ROOT_INO).unwrap();
+        assert_ne!(root.flags & INODE_FLAG_INDEX, 0);
+
+        for (name, ino_id) in &files {
+            assert_eq!(state.lookup_entry(ROOT_INO, name), Ok(*ino_id));
+            // Only block 0 and a single leaf are searched
+            assert_eq!(state.dx_lookup_blks(&root, name).len(), 2);
+        }
+        assert_eq!(state.lookup_entry(ROOT_INO, OsStr::new("..")), Ok(ROOT_INO));
+
+        // Reading the directory linearly sees every entry exactly once
+        let names: HashSet<OsString> = state
+            .read_dir(ROOT_INO)
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect();
+        assert_eq!(names.len(), files.len() + 2);
+        assert_eq!(fsck::check(&state), vec![]);
+    }
+
+    #[test]
+    fn test_index_grows_a_level() {
+        // 1 KiB blocks hold 124 root entries, which is not enough for this many leaves
+        let mut state = state(1024, true);
+        let files = create(&mut state, 6000);
+        let root = *state.inode(ROOT_INO).unwrap();
+        let root_blk = state.bmap(&root, 0).unwrap();
+        let (levels, _) = read_dx(&state.blk_data(root_blk).unwrap(), DX_ROOT_OFFSET);
+        assert_eq!(levels, 1);
+
+        for (name, ino_id) in files.iter().step_by(7) {
+            assert_eq!(state.lookup_entry(ROOT_INO, name), Ok(*ino_id));
+        }
+        assert_eq!(state.read_dir(ROOT_INO).unwrap().len(), files.len() + 2);
+        assert_eq!(fsck::check(&state), vec![]);
+    }
+
+    #[test]
+    fn test_remove_and_reuse_in_indexed_directory() {
+        let mut state = state(1024, true);
+        let files = create(&mut state, 200);
+        for (name, _) in files.iter().take(100) {
+            state.remove_node(ROOT_INO, name, false).unwrap();
+        }
+        let size = state.inode(ROOT_INO).unwrap().size;
+        for (name, ino_id) in &files[100..] {
+            assert_eq!(state.lookup_entry(ROOT_INO, name), Ok(*ino_id));
+        }
+        assert!(state.lookup_entry(ROOT_INO, &files[0].0).is_err());
+
+        create(&mut state, 50);
+        assert_eq!(state.inode(ROOT_INO).unwrap().size, size);
+        assert_eq!(fsck::check(&state), vec![]);
+    }
+
+    #[test]
+    fn test_feature_off_keeps_linear_layout() {
+        let mut state = state(1024, false);
+        let files = create(&mut state, 200);
+        let root = state.inode(ROOT_INO).unwrap();
+        assert_eq!(root.flags & INODE_FLAG_INDEX, 0);
+        assert!(root.size > 1024);
+        for (name, ino_id) in &files {
+            assert_eq!(state.lookup_entry(ROOT_INO, name), Ok(*ino_id));
+        }
+    }
+
+    #[test]
+    fn test_damaged_index_falls_back_to_linear_lookup() {
+        let mut state = state(1024, true);
+        let files = create(&mut state, 60);
+        let root = *state.inode(ROOT_INO).unwrap();
+        let root_blk = state.bmap(&root, 0).unwrap();
+        write_dx(
+            state.blk_data_mut(root_blk).unwrap(),
+            DX_ROOT_OFFSET,
+            0,
+            &[],
+        );
+
+        for (name, ino_id) in &files {
+            assert_eq!(state.lookup_entry(ROOT_INO, name), Ok(*ino_id));
+        }
+    }
+}
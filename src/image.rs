@@ -0,0 +1,1147 @@
+use crate::attr::Timestamp;
+use crate::cache::{BlockCache, CacheStats};
+use crate::group::{deserialize_group_bits, serialize_group_bits, GROUP_DESC_LEN};
+use crate::journal::{self, checksum};
+use crate::{
+    secs_from_unix_epoch, FSMetadata, FSState, FreeBlockBitmap, FreeInodeBitmap, Inode,
+    BLK_SIZE_BYTES, INODE_BMAP_BLK_NO, INODE_SIZE_BYTES, MAX_NUM_INODES, NUM_DATA_BLKS,
+    NUM_INO_DIRECT_PTR, RESERVED_INODES, SUPER_BLK_NO,
+};
+use bitvec::prelude::*;
+use fuser::FileType;
+use log::{debug, info};
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::{error, fmt, io, iter};
+
+// "RSFS" in little-endian byte order
+pub const FS_MAGIC: u32 = 0x5346_5352;
+
+// Optional features recorded in the superblock
+pub const FEATURE_DIR_INDEX: u32 = 0x1; // Directories past one block get a hashed index
+pub const FEATURE_EXTENTS: u32 = 0x2; // New inodes map their blocks with extents
+pub const DEFAULT_FEATURES: u32 = FEATURE_DIR_INDEX;
+const MIN_BLK_SIZE_BYTES: u32 = 512;
+const MAX_BLK_SIZE_BYTES: u32 = 65536;
+
+// The superblock is kept to the smallest block size so it is written in one sector. With
+// copy-on-write it ends with one selector bit per block before the journal, see cow.rs, and a
+// checksum of everything before it.
+const SUPER_LEN: usize = MIN_BLK_SIZE_BYTES as usize;
+const SHADOW_OFFSET: usize = 72;
+const SUPER_CHECKSUM_OFFSET: usize = SUPER_LEN - 8;
+pub(crate) const MAX_SHADOW_BLKS: u32 = ((SUPER_CHECKSUM_OFFSET - SHADOW_OFFSET) * 8) as u32;
+
+#[derive(Debug)]
+pub enum ImageError {
+    Io(io::Error),
+    BadMagic(u32),
+    InvalidGeometry(&'static str),
+    CorruptInode(u32),
+    CorruptJournal,
+    CorruptSuperblock,
+    CorruptSnapshots,
+    NoSpace,
+    TransactionTooBig(usize),
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageError::Io(err) => write!(f, "I/O error: {err}"),
+            ImageError::BadMagic(magic) => write!(f, "not an image, bad magic {magic:#010x}"),
+            ImageError::InvalidGeometry(reason) => write!(f, "invalid geometry: {reason}"),
+            ImageError::CorruptInode(ino_id) => write!(f, "inode {ino_id} is corrupt"),
+            ImageError::CorruptJournal => write!(f, "journal superblock is corrupt"),
+            ImageError::CorruptSuperblock => write!(f, "no intact superblock"),
+            ImageError::CorruptSnapshots => write!(f, "snapshot list is corrupt"),
+            ImageError::NoSpace => write!(f, "no free blocks left to copy on write"),
+            ImageError::TransactionTooBig(count) => {
+                write!(
+                    f,
+                    "transaction of {count} blocks does not fit in the journal"
+                )
+            }
+        }
+    }
+}
+
+impl error::Error for ImageError {}
+
+impl From<io::Error> for ImageError {
+    fn from(err: io::Error) -> Self {
+        ImageError::Io(err)
+    }
+}
+
+// Where an image is stored. Files are the real thing, tests use in-memory devices that can
+// simulate a crash at any write.
+pub trait Device {
+    fn read_bytes(&self, buf: &mut [u8], offset: u64) -> io::Result<()>;
+    fn write_bytes(&self, buf: &[u8], offset: u64) -> io::Result<()>;
+    fn size(&self) -> io::Result<u64>;
+    fn resize(&self, size: u64) -> io::Result<()>;
+    // Returns once everything written before it is durable
+    fn sync(&self) -> io::Result<()>;
+}
+
+impl Device for File {
+    fn read_bytes(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        self.read_exact_at(buf, offset)
+    }
+
+    fn write_bytes(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        self.write_all_at(buf, offset)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn resize(&self, size: u64) -> io::Result<()> {
+        self.set_len(size)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.sync_all()
+    }
+}
+
+// Everything needed to work out where each region of an image lives. It is recorded in
+// FSMetadata so the layout can be recomputed from block 0 alone.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Geometry {
+    pub blk_size: u32,
+    pub blk_count: u32,
+    pub ino_count: u32,
+    pub journal_blks: u32,   // 0 for an image without a journal
+    pub cow: bool,           // Copy-on-write updates instead of a journal, see cow.rs
+    pub blks_per_group: u32, // See group.rs
+}
+
+impl Default for Geometry {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+impl Geometry {
+    pub const DEFAULT: Self = Self {
+        blk_size: BLK_SIZE_BYTES as u32,
+        blk_count: NUM_DATA_BLKS,
+        ino_count: MAX_NUM_INODES,
+        journal_blks: 0,
+        cow: false,
+        blks_per_group: BLK_SIZE_BYTES as u32 * 8,
+    };
+
+    pub fn new(fs_size_bytes: u64, blk_size: u32, ino_count: u32) -> Result<Self, ImageError> {
+        let blk_count = u32::try_from(fs_size_bytes / blk_size.max(1) as u64)
+            .map_err(|_| ImageError::InvalidGeometry("too many blocks for 32-bit pointers"))?;
+        let geometry = Self {
+            blk_size,
+            blk_count,
+            ino_count,
+            journal_blks: 0,
+            cow: false,
+            blks_per_group: blk_size.saturating_mul(8),
+        };
+        geometry.validate()?;
+        Ok(geometry)
+    }
+
+    pub fn with_journal(mut self, journal_blks: u32) -> Result<Self, ImageError> {
+        self.journal_blks = journal_blks;
+        self.validate()?;
+        Ok(self)
+    }
+
+    pub fn with_cow(mut self) -> Result<Self, ImageError> {
+        self.cow = true;
+        self.validate()?;
+        Ok(self)
+    }
+
+    // Smaller groups than the one bitmap block each gets can describe
+    pub fn with_blks_per_group(mut self, blks_per_group: u32) -> Result<Self, ImageError> {
+        self.blks_per_group = blks_per_group;
+        self.validate()?;
+        Ok(self)
+    }
+
+    // What mkfs gives an image by default: 1/32 of its blocks, between 32 and 1024, and never
+    // less than the largest request needs
+    pub fn default_journal_blks(&self) -> u32 {
+        let blks = (self.blk_count / 32).clamp(32, 1024);
+        blks.max(journal::min_journal_blks(self) as u32)
+    }
+
+    // Also run on the geometry read back from an image, before anything is sized from it
+    pub fn validate(&self) -> Result<(), ImageError> {
+        if !self.blk_size.is_power_of_two()
+            || !(MIN_BLK_SIZE_BYTES..=MAX_BLK_SIZE_BYTES).contains(&self.blk_size)
+        {
+            return Err(ImageError::InvalidGeometry(
+                "block size must be a power of two between 512 and 65536",
+            ));
+        }
+        if self.ino_count <= RESERVED_INODES {
+            return Err(ImageError::InvalidGeometry(
+                "need at least one inode besides the reserved ones",
+            ));
+        }
+        if self.blks_per_group == 0
+            || !self.blks_per_group.is_multiple_of(8)
+            || self.blks_per_group as u64 > self.blk_size as u64 * 8
+        {
+            return Err(ImageError::InvalidGeometry(
+                "blocks per group must be a multiple of 8 up to 8 times the block size",
+            ));
+        }
+        // Before anything is worked out per group, which takes at least one group
+        if self.blk_count == 0 {
+            return Err(ImageError::InvalidGeometry(
+                "size leaves no room for data blocks",
+            ));
+        }
+        if self.inos_per_group() as u64 > self.blk_size as u64 * 8 {
+            return Err(ImageError::InvalidGeometry(
+                "each group's inode bitmap must fit in a single block",
+            ));
+        }
+        if self.journal_blks != 0 && (self.journal_blks as u64) < journal::min_journal_blks(self) {
+            return Err(ImageError::InvalidGeometry(
+                "journal too small to hold the largest single request",
+            ));
+        }
+        if self.cow && self.journal_blks != 0 {
+            return Err(ImageError::InvalidGeometry(
+                "copy-on-write images have no journal",
+            ));
+        }
+        if self.cow && self.journal_blk_no() > MAX_SHADOW_BLKS {
+            return Err(ImageError::InvalidGeometry(
+                "too many metadata blocks for copy-on-write",
+            ));
+        }
+        if self.journal_blks >= self.blk_count || self.blk_count <= self.reserved_blks() {
+            return Err(ImageError::InvalidGeometry(
+                "size leaves no room for data blocks",
+            ));
+        }
+        Ok(())
+    }
+
+    pub const fn size_bytes(&self) -> u64 {
+        self.blk_count as u64 * self.blk_size as u64
+    }
+
+    pub const fn group_count(&self) -> u32 {
+        self.blk_count.div_ceil(self.blks_per_group)
+    }
+
+    pub const fn inos_per_group(&self) -> u32 {
+        self.ino_count.div_ceil(self.group_count())
+    }
+
+    // Each group has a block of the inode bitmap and one of the block bitmap to itself
+    pub const fn inode_bmap_num_blks(&self) -> u32 {
+        self.group_count()
+    }
+
+    pub const fn blk_bmap_blk_no(&self) -> u32 {
+        INODE_BMAP_BLK_NO + self.inode_bmap_num_blks()
+    }
+
+    pub const fn blk_bmap_num_blks(&self) -> u32 {
+        self.group_count()
+    }
+
+    pub const fn group_desc_blk_no(&self) -> u32 {
+        self.blk_bmap_blk_no() + self.blk_bmap_num_blks()
+    }
+
+    pub const fn group_desc_num_blks(&self) -> u32 {
+        (self.group_count() as u64 * GROUP_DESC_LEN as u64).div_ceil(self.blk_size as u64) as u32
+    }
+
+    pub const fn inode_table_blk_no(&self) -> u32 {
+        self.group_desc_blk_no() + self.group_desc_num_blks()
+    }
+
+    pub const fn inode_table_num_blks(&self) -> u32 {
+        (self.ino_count as u64 * INODE_SIZE_BYTES as u64).div_ceil(self.blk_size as u64) as u32
+    }
+
+    pub const fn journal_blk_no(&self) -> u32 {
+        self.inode_table_blk_no() + self.inode_table_num_blks()
+    }
+
+    // With copy-on-write, a second copy of every block before the journal. The copy of block 0
+    // is the second superblock slot.
+    pub const fn shadow_blk_no(&self) -> u32 {
+        self.journal_blk_no() + self.journal_blks
+    }
+
+    // Blocks 0 up to the first data block
+    pub const fn reserved_blks(&self) -> u32 {
+        if self.cow {
+            self.shadow_blk_no() + self.journal_blk_no()
+        } else {
+            self.shadow_blk_no()
+        }
+    }
+}
+
+// Same codes as ext2 directory entries, 0 marks an unused inode slot
+pub(crate) fn file_type_to_code(kind: FileType) -> u8 {
+    match kind {
+        FileType::RegularFile => 1,
+        FileType::Directory => 2,
+        FileType::CharDevice => 3,
+        FileType::BlockDevice => 4,
+        FileType::NamedPipe => 5,
+        FileType::Socket => 6,
+        FileType::Symlink => 7,
+    }
+}
+
+pub(crate) fn file_type_from_code(code: u8) -> Option<FileType> {
+    match code {
+        1 => Some(FileType::RegularFile),
+        2 => Some(FileType::Directory),
+        3 => Some(FileType::CharDevice),
+        4 => Some(FileType::BlockDevice),
+        5 => Some(FileType::NamedPipe),
+        6 => Some(FileType::Socket),
+        7 => Some(FileType::Symlink),
+        _ => None,
+    }
+}
+
+// Little-endian cursor over a byte slice, fields are read and written in declaration order
+pub(crate) struct Cursor<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    pub(crate) fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    pub(crate) fn take<const N: usize>(&mut self) -> [u8; N] {
+        let bytes = self.buf[self.pos..self.pos + N].try_into().unwrap();
+        self.pos += N;
+        bytes
+    }
+
+    pub(crate) fn u8(&mut self) -> u8 {
+        u8::from_le_bytes(self.take())
+    }
+
+    fn u16(&mut self) -> u16 {
+        u16::from_le_bytes(self.take())
+    }
+
+    pub(crate) fn u32(&mut self) -> u32 {
+        u32::from_le_bytes(self.take())
+    }
+
+    fn u64(&mut self) -> u64 {
+        u64::from_le_bytes(self.take())
+    }
+
+    pub(crate) fn i64(&mut self) -> i64 {
+        i64::from_le_bytes(self.take())
+    }
+}
+
+pub(crate) struct Writer<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl<'a> Writer<'a> {
+    pub(crate) fn new(buf: &'a mut [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    pub(crate) fn put(&mut self, bytes: &[u8]) {
+        self.buf[self.pos..self.pos + bytes.len()].copy_from_slice(bytes);
+        self.pos += bytes.len();
+    }
+}
+
+impl FSMetadata {
+    // magic | blk_size | ino_count | blk_count | free_blk_count | free_ino_count | super_blk_no
+    // | mtime | wtime | features | journal_blks | cow (u8) | generation (u64) | snap_blk
+    // | blks_per_group, then the shadow selectors at SHADOW_OFFSET and the checksum at
+    // SUPER_CHECKSUM_OFFSET
+    pub fn serialize(&self, buf: &mut [u8]) {
+        buf[..SUPER_LEN].fill(0);
+        let mut w = Writer::new(buf);
+        w.put(&FS_MAGIC.to_le_bytes());
+        w.put(&self.blk_size.to_le_bytes());
+        w.put(&self.ino_count.to_le_bytes());
+        w.put(&self.blk_count.to_le_bytes());
+        w.put(&self.free_blk_count.to_le_bytes());
+        w.put(&self.free_ino_count.to_le_bytes());
+        w.put(&self.super_blk_no.to_le_bytes());
+        w.put(&self.mtime.to_le_bytes());
+        w.put(&self.wtime.to_le_bytes());
+        w.put(&self.features.to_le_bytes());
+        w.put(&self.journal_blks.to_le_bytes());
+        w.put(&[self.cow as u8]);
+        w.put(&self.generation.to_le_bytes());
+        w.put(&self.snap_blk.to_le_bytes());
+        w.put(&self.blks_per_group.to_le_bytes());
+        w.pos = SHADOW_OFFSET;
+        w.put(self.shadow.as_raw_slice());
+        let sum = checksum(iter::once(&buf[..SUPER_CHECKSUM_OFFSET]));
+        buf[SUPER_CHECKSUM_OFFSET..SUPER_LEN].copy_from_slice(&sum.to_le_bytes());
+    }
+
+    pub fn deserialize(buf: &[u8]) -> Result<Self, ImageError> {
+        let mut c = Cursor::new(buf);
+        let magic = c.u32();
+        if magic != FS_MAGIC {
+            return Err(ImageError::BadMagic(magic));
+        }
+        Ok(Self {
+            blk_size: c.u32(),
+            ino_count: c.u32(),
+            blk_count: c.u32(),
+            free_blk_count: c.u32(),
+            free_ino_count: c.u32(),
+            super_blk_no: c.u32(),
+            mtime: c.u64(),
+            wtime: c.u64(),
+            features: c.u32(),
+            journal_blks: c.u32(),
+            cow: c.u8() != 0,
+            generation: c.u64(),
+            snap_blk: c.u32(),
+            blks_per_group: c.u32(),
+            shadow: deserialize_map(&buf[SHADOW_OFFSET..], MAX_SHADOW_BLKS as usize),
+        })
+    }
+
+    // Whether buf holds a superblock that was written out whole
+    fn intact(buf: &[u8]) -> bool {
+        let sum = u64::from_le_bytes(buf[SUPER_CHECKSUM_OFFSET..SUPER_LEN].try_into().unwrap());
+        sum == checksum(iter::once(&buf[..SUPER_CHECKSUM_OFFSET]))
+    }
+
+    pub fn geometry(&self) -> Geometry {
+        Geometry {
+            blk_size: self.blk_size,
+            blk_count: self.blk_count,
+            ino_count: self.ino_count,
+            journal_blks: self.journal_blks,
+            cow: self.cow,
+            blks_per_group: self.blks_per_group,
+        }
+    }
+}
+
+// One bit per object, least significant bit first, padded with zeros to a whole byte
+fn deserialize_map(buf: &[u8], len: usize) -> BitVec<u8, Lsb0> {
+    let mut map = BitVec::from_slice(&buf[..len.div_ceil(8)]);
+    map.truncate(len);
+    map
+}
+
+// Both bitmaps take one block per group, see group.rs
+impl FreeInodeBitmap {
+    pub fn serialize_group(&self, geometry: Geometry, group: u32, buf: &mut [u8]) {
+        serialize_group_bits(&self.map, geometry.group_inos(group), buf);
+    }
+
+    pub fn deserialize(buf: &[u8], geometry: Geometry) -> Self {
+        let mut bitmap = Self::new(geometry);
+        let blk_size = geometry.blk_size as usize;
+        for (group, blk) in (0..geometry.group_count()).zip(buf.chunks_exact(blk_size)) {
+            deserialize_group_bits(&mut bitmap.map, geometry.group_inos(group), blk);
+        }
+        bitmap
+    }
+}
+
+impl FreeBlockBitmap {
+    pub fn serialize_group(&self, geometry: Geometry, group: u32, buf: &mut [u8]) {
+        serialize_group_bits(&self.map, geometry.group_blks(group), buf);
+    }
+
+    pub fn deserialize(buf: &[u8], geometry: Geometry) -> Self {
+        let mut bitmap = Self::new(geometry);
+        let blk_size = geometry.blk_size as usize;
+        for (group, blk) in (0..geometry.group_count()).zip(buf.chunks_exact(blk_size)) {
+            deserialize_group_bits(&mut bitmap.map, geometry.group_blks(group), blk);
+        }
+        bitmap
+    }
+}
+
+impl Inode {
+    // ino_id | size | blocks | uid | gid | atime | mtime | ctime | crtime | kind | perm
+    // | direct_blks | indirect | dbl | tri | flags | nlink | xattr_blk | xattr_inline
+    // The rest of the INODE_SIZE_BYTES slot is zero padding left for future fields.
+    pub fn serialize(&self, buf: &mut [u8]) {
+        buf[..INODE_SIZE_BYTES].fill(0);
+        let mut w = Writer::new(buf);
+        w.put(&self.ino_id.to_le_bytes());
+        w.put(&self.size.to_le_bytes());
+        w.put(&self.blocks.to_le_bytes());
+        w.put(&self.uid.to_le_bytes());
+        w.put(&self.gid.to_le_bytes());
+        for time in [self.atime, self.mtime, self.ctime, self.crtime] {
+            w.put(&time.0.to_le_bytes());
+        }
+        w.put(&[file_type_to_code(self.kind)]);
+        w.put(&self.perm.to_le_bytes());
+        for ptr in self.direct_blks {
+            w.put(&ptr.to_le_bytes());
+        }
+        w.put(&self.indirect_blk.to_le_bytes());
+        w.put(&self.dbl_indirect_blk.to_le_bytes());
+        w.put(&self.tri_indirect_blk.to_le_bytes());
+        w.put(&self.flags.to_le_bytes());
+        w.put(&self.nlink.to_le_bytes());
+        w.put(&self.xattr_blk.to_le_bytes());
+        w.put(&self.xattr_inline);
+    }
+
+    // Returns None for an unused slot
+    pub fn deserialize(buf: &[u8]) -> Result<Option<Self>, ImageError> {
+        let mut c = Cursor::new(buf);
+        let ino_id = c.u32();
+        let size = c.u64();
+        let blocks = c.u32();
+        let uid = c.u32();
+        let gid = c.u32();
+        let [atime, mtime, ctime, crtime] = [(); 4].map(|()| Timestamp(c.i64()));
+        let code = c.u8();
+        if code == 0 {
+            return Ok(None);
+        }
+        let kind = file_type_from_code(code).ok_or(ImageError::CorruptInode(ino_id))?;
+        let perm = c.u16();
+        let mut direct_blks = [0; NUM_INO_DIRECT_PTR];
+        for ptr in direct_blks.iter_mut() {
+            *ptr = c.u32();
+        }
+        let indirect_blk = c.u32();
+        let dbl_indirect_blk = c.u32();
+        let tri_indirect_blk = c.u32();
+        let flags = c.u32();
+        let nlink = c.u32();
+        let xattr_blk = c.u32();
+        let xattr_inline = c.take();
+        Ok(Some(Self {
+            ino_id,
+            size,
+            blocks,
+            uid,
+            gid,
+            atime,
+            mtime,
+            ctime,
+            crtime,
+            kind,
+            perm,
+            nlink,
+            direct_blks,
+            indirect_blk,
+            dbl_indirect_blk,
+            tri_indirect_blk,
+            flags,
+            xattr_blk,
+            xattr_inline,
+        }))
+    }
+}
+
+// Reads `count` blocks starting at `start`
+pub(crate) fn read_blks<D: Device>(
+    image: &D,
+    geometry: Geometry,
+    start: u32,
+    count: u32,
+) -> io::Result<Vec<u8>> {
+    let blk_size = geometry.blk_size as u64;
+    let mut buf = vec![0; (count as u64 * blk_size) as usize];
+    image.read_bytes(&mut buf, start as u64 * blk_size)?;
+    Ok(buf)
+}
+
+pub(crate) fn write_blk<D: Device>(
+    image: &D,
+    geometry: Geometry,
+    blk_no: u32,
+    buf: &[u8],
+) -> io::Result<()> {
+    image.write_bytes(buf, blk_no as u64 * geometry.blk_size as u64)
+}
+
+// The block size is not known yet, but the superblock always fits in the smallest one. With
+// copy-on-write, either slot can be torn by a crash while it was written and the newest intact
+// one wins. Both record the same geometry, so it is taken from the first slot either way.
+pub(crate) fn read_metadata<D: Device>(image: &D) -> Result<FSMetadata, ImageError> {
+    let mut buf = vec![0; SUPER_LEN];
+    image.read_bytes(&mut buf, 0)?;
+    let metadata = FSMetadata::deserialize(&buf)?;
+    let geometry = metadata.geometry();
+    geometry.validate()?;
+    if !geometry.cow {
+        return Ok(metadata);
+    }
+    let mut other = vec![0; SUPER_LEN];
+    image.read_bytes(
+        &mut other,
+        geometry.shadow_blk_no() as u64 * geometry.blk_size as u64,
+    )?;
+    [buf, other]
+        .iter()
+        .filter(|buf| FSMetadata::intact(buf))
+        .filter_map(|buf| FSMetadata::deserialize(buf).ok())
+        .filter(|metadata| metadata.geometry() == geometry)
+        .max_by_key(|metadata| metadata.generation)
+        .ok_or(ImageError::CorruptSuperblock)
+}
+
+// Reads count blocks from start, each from whichever of its copies is current
+fn read_meta_blks<D: Device>(
+    image: &D,
+    metadata: &FSMetadata,
+    start: u32,
+    count: u32,
+) -> io::Result<Vec<u8>> {
+    let geometry = metadata.geometry();
+    let mut buf = Vec::with_capacity((count * geometry.blk_size) as usize);
+    for blk_no in start..start + count {
+        buf.extend(read_blks(
+            image,
+            geometry,
+            metadata.current_blk_no(blk_no),
+            1,
+        )?);
+    }
+    Ok(buf)
+}
+
+// Writes an empty filesystem with the given geometry: the superblock, both bitmaps with the
+// reserved entries taken, and an inode table holding only the root directory.
+pub fn format<D: Device>(image: &D, geometry: Geometry) -> Result<(), ImageError> {
+    format_with_features(image, geometry, DEFAULT_FEATURES)
+}
+
+pub fn format_with_features<D: Device>(
+    image: &D,
+    geometry: Geometry,
+    features: u32,
+) -> Result<(), ImageError> {
+    geometry.validate()?;
+    image.resize(0)?;
+    image.resize(geometry.size_bytes())?;
+
+    let mut state = FSState::new(geometry);
+    state.set_features(features);
+    state.metadata.mtime = secs_from_unix_epoch() as u64;
+    state.init_root();
+    state.save(image)?;
+
+    info!(
+        "Formatted image: {} blocks of {} bytes, {} inodes, {} reserved blocks",
+        geometry.blk_count,
+        geometry.blk_size,
+        geometry.ino_count,
+        geometry.reserved_blks()
+    );
+    Ok(())
+}
+
+impl FSState {
+    // Rebuilds the in-memory state from an image written by `save`, sized by the geometry
+    // recorded in its superblock
+    pub fn load<D: Device>(image: &D) -> Result<Self, ImageError> {
+        Self::load_blks(image, false)
+    }
+
+    // Like load, but also reads data blocks the bitmap marks free so fsck can follow pointers
+    // the bitmap lost track of. Free blocks that are all zeros are left unloaded.
+    pub fn load_all_blks<D: Device>(image: &D) -> Result<Self, ImageError> {
+        Self::load_blks(image, true)
+    }
+
+    // Like load, but only the metadata before the journal is read up front. The other blocks
+    // are read from the image when needed, and at most cache_size bytes of them are kept in
+    // memory, see cache.rs. Every commit and save must go to this same image.
+    pub fn open<D: Device + Send + 'static>(image: D, cache_size: u64) -> Result<Self, ImageError> {
+        let mut state = Self::load_meta(&image)?;
+        let blk_size = state.blk_size();
+        let cap = (cache_size / blk_size) as usize;
+        state.blks = BlockCache::with_device(blk_size as usize, Box::new(image), cap);
+        state.load_snapshots()?;
+        Ok(state)
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        self.blks.stats()
+    }
+
+    // Whether the blocks changed since the last commit or save outnumber what the block cache
+    // holds, which calls for writing them back
+    pub fn cache_over_cap(&self) -> bool {
+        self.blks.over_cap()
+    }
+
+    fn load_blks<D: Device>(image: &D, all_blks: bool) -> Result<Self, ImageError> {
+        let mut state = Self::load_meta(image)?;
+        let geometry = state.geometry();
+        let blk_size = geometry.blk_size as usize;
+        let mut loaded = 0;
+        for idx in geometry.reserved_blks() as usize..geometry.blk_count as usize {
+            let alloced = state.blk_bitmap.map[idx];
+            if alloced || all_blks {
+                let mut data = vec![0; blk_size];
+                image.read_bytes(&mut data, (idx * blk_size) as u64)?;
+                if alloced || data.iter().any(|&byte| byte != 0) {
+                    state.blks.insert(idx as u32, data.into(), false);
+                    loaded += 1;
+                }
+            }
+        }
+        state.load_snapshots()?;
+        info!("Loaded image with {loaded} data blocks");
+        Ok(state)
+    }
+
+    // Everything but the blocks past the reserved ones and the snapshots, which are read from
+    // those
+    fn load_meta<D: Device>(image: &D) -> Result<Self, ImageError> {
+        let metadata = read_metadata(image)?;
+        let geometry = metadata.geometry();
+
+        let mut state = Self::new(geometry);
+        state.inode_bitmap = FreeInodeBitmap::deserialize(
+            &read_meta_blks(
+                image,
+                &metadata,
+                INODE_BMAP_BLK_NO,
+                geometry.inode_bmap_num_blks(),
+            )?,
+            geometry,
+        );
+        state.blk_bitmap = FreeBlockBitmap::deserialize(
+            &read_meta_blks(
+                image,
+                &metadata,
+                geometry.blk_bmap_blk_no(),
+                geometry.blk_bmap_num_blks(),
+            )?,
+            geometry,
+        );
+        state.read_groups(&read_meta_blks(
+            image,
+            &metadata,
+            geometry.group_desc_blk_no(),
+            geometry.group_desc_num_blks(),
+        )?);
+
+        let table = read_meta_blks(
+            image,
+            &metadata,
+            geometry.inode_table_blk_no(),
+            geometry.inode_table_num_blks(),
+        )?;
+        state.metadata = metadata;
+        for (inode, slot) in state
+            .inodes
+            .iter_mut()
+            .zip(table.chunks_exact(INODE_SIZE_BYTES))
+        {
+            *inode = Inode::deserialize(slot)?;
+        }
+        if geometry.journal_blks > 0 {
+            state.journal_seq = journal::read_seq(image, geometry)?;
+        }
+        if geometry.cow {
+            state.committed = state.blk_bitmap.map.clone();
+        }
+        Ok(state)
+    }
+
+    // Writes the whole state back to the image, data blocks are only written while allocated.
+    // Blocks the cache does not hold are copied over from the image it reads them from.
+    // Anything left in the journal is invalidated, so only use this on a recovered image. With
+    // copy-on-write everything goes to the first copy and slot, nothing here is crash safe.
+    pub fn save<D: Device>(&mut self, image: &D) -> Result<(), ImageError> {
+        let geometry = self.geometry();
+        let blk_size = geometry.blk_size as usize;
+        let offset = |blk_no: u32| blk_no as u64 * blk_size as u64;
+        if image.size()? < geometry.size_bytes() {
+            image.resize(geometry.size_bytes())?;
+        }
+        self.flush_delayed().map_err(|_| ImageError::NoSpace)?;
+        self.flush_snapshots().map_err(|_| ImageError::NoSpace)?;
+        self.metadata.wtime = secs_from_unix_epoch() as u64;
+        if geometry.cow {
+            self.metadata.generation += 1;
+            self.metadata.super_blk_no = SUPER_BLK_NO;
+            self.metadata.shadow.fill(false);
+        }
+
+        // The superblock, the bitmaps and the group descriptors
+        for blk_no in SUPER_BLK_NO..geometry.inode_table_blk_no() {
+            image.write_bytes(&self.blk_image(blk_no), offset(blk_no))?;
+        }
+
+        let mut table = vec![0; geometry.inode_table_num_blks() as usize * blk_size];
+        for (inode, slot) in self
+            .inodes
+            .iter()
+            .zip(table.chunks_exact_mut(INODE_SIZE_BYTES))
+        {
+            if let Some(inode) = inode {
+                inode.serialize(slot);
+            }
+        }
+        image.write_bytes(&table, offset(geometry.inode_table_blk_no()))?;
+
+        let mut written = 0;
+        for blk_no in geometry.reserved_blks()..geometry.blk_count {
+            if let Some(data) = self.blks.get_uncached(blk_no, self.blk_on_disk(blk_no)) {
+                image.write_bytes(&data, offset(blk_no))?;
+                written += 1;
+            }
+        }
+        if geometry.journal_blks > 0 {
+            self.journal_seq += 1;
+            journal::write_seq(image, geometry, self.journal_seq)?;
+        }
+        image.sync()?;
+        if geometry.cow {
+            self.committed = self.blk_bitmap.map.clone();
+        }
+        self.dirty.clear();
+        self.dirty_data.clear();
+        self.alloced.clear();
+        self.freed.clear();
+        self.blks.mark_clean();
+        debug!("Saved image with {written} allocated data blocks");
+        Ok(())
+    }
+
+    // What block blk_no holds on disk in the current state, for writing back single blocks.
+    // Blocks in the journal or the shadow copies are never part of the state and come out as
+    // zeros.
+    pub(crate) fn blk_image(&self, blk_no: u32) -> Vec<u8> {
+        let geometry = self.geometry();
+        let blk_size = geometry.blk_size as usize;
+        let mut buf = vec![0; blk_size];
+        match blk_no {
+            SUPER_BLK_NO => self.metadata.serialize(&mut buf),
+            _ if blk_no < geometry.blk_bmap_blk_no() => {
+                let group = blk_no - INODE_BMAP_BLK_NO;
+                self.inode_bitmap.serialize_group(geometry, group, &mut buf);
+            }
+            _ if blk_no < geometry.group_desc_blk_no() => {
+                let group = blk_no - geometry.blk_bmap_blk_no();
+                self.blk_bitmap.serialize_group(geometry, group, &mut buf);
+            }
+            _ if blk_no < geometry.inode_table_blk_no() => {
+                self.serialize_groups(blk_no - geometry.group_desc_blk_no(), &mut buf);
+            }
+            _ if blk_no < geometry.journal_blk_no() => {
+                let first =
+                    (blk_no - geometry.inode_table_blk_no()) as usize * blk_size / INODE_SIZE_BYTES;
+                let slots = buf.chunks_exact_mut(INODE_SIZE_BYTES);
+                for (inode, slot) in self.inodes.iter().skip(first).zip(slots) {
+                    if let Some(inode) = inode {
+                        inode.serialize(slot);
+                    }
+                }
+            }
+            _ if blk_no < geometry.reserved_blks() => {}
+            _ => {
+                if let Some(data) = self.blk_data(blk_no) {
+                    buf.copy_from_slice(&data);
+                }
+            }
+        }
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::xattr::INLINE_XATTR_LEN;
+    use crate::ROOT_INO;
+    use std::ffi::OsStr;
+    use std::fs::OpenOptions;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_metadata_roundtrip() {
+        let mut metadata = FSMetadata::default();
+        metadata.free_blk_count -= 5;
+        metadata.mtime = 1234;
+        metadata.wtime = 5678;
+        let mut buf = [0u8; SUPER_LEN];
+        metadata.serialize(&mut buf);
+        assert_eq!(&buf[..4], b"RSFS");
+
+        let decoded = FSMetadata::deserialize(&buf).unwrap();
+        assert_eq!(decoded.ino_count, metadata.ino_count);
+        assert_eq!(decoded.blk_count, metadata.blk_count);
+        assert_eq!(decoded.free_blk_count, metadata.free_blk_count);
+        assert_eq!(decoded.free_ino_count, metadata.free_ino_count);
+        assert_eq!(decoded.mtime, 1234);
+        assert_eq!(decoded.wtime, 5678);
+    }
+
+    #[test]
+    fn test_metadata_rejects_bad_magic() {
+        let buf = [0u8; SUPER_LEN];
+        assert!(matches!(
+            FSMetadata::deserialize(&buf),
+            Err(ImageError::BadMagic(0))
+        ));
+    }
+
+    #[test]
+    fn test_inode_roundtrip() {
+        let mut inode = Inode::new(5, FileType::Symlink, 0o777);
+        inode.size = 1 << 40;
+        inode.blocks = 3;
+        inode.nlink = 7;
+        inode.xattr_blk = 77;
+        inode.xattr_inline[INLINE_XATTR_LEN - 1] = 9;
+        inode.uid = 1000;
+        inode.gid = 100;
+        inode.atime = Timestamp(-1);
+        inode.crtime = Timestamp(1_700_000_000_123_456_789);
+        inode.direct_blks[0] = 42;
+        inode.direct_blks[11] = 43;
+        inode.tri_indirect_blk = 99;
+        let mut buf = [0xffu8; INODE_SIZE_BYTES];
+        inode.serialize(&mut buf);
+        assert_eq!(Inode::deserialize(&buf).unwrap(), Some(inode));
+    }
+
+    #[test]
+    fn test_empty_inode_slot_is_none() {
+        let buf = [0u8; INODE_SIZE_BYTES];
+        assert_eq!(Inode::deserialize(&buf).unwrap(), None);
+    }
+
+    #[test]
+    fn test_inode_bitmap_layout_is_lsb_first() {
+        // 2 groups of 10 inodes, each with a block of the bitmap
+        let geometry = Geometry::new(1 << 20, 512, 20)
+            .unwrap()
+            .with_blks_per_group(1024)
+            .unwrap();
+        let mut bitmap = FreeInodeBitmap::new(geometry);
+        bitmap.map.set(3, true);
+        bitmap.map.set(13, true);
+        let mut buf = vec![0u8; 1024];
+        bitmap.serialize_group(geometry, 0, &mut buf[..512]);
+        bitmap.serialize_group(geometry, 1, &mut buf[512..]);
+        assert_eq!(buf[..2], [0b0000_1011, 0]);
+        assert_eq!(buf[512..514], [0b0000_1000, 0]);
+
+        let decoded = FreeInodeBitmap::deserialize(&buf, geometry);
+        assert_eq!(decoded.map, bitmap.map);
+    }
+
+    fn open_image(tmp_dir: &TempDir) -> File {
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(tmp_dir.path().join("fs.img"))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_default_geometry_layout() {
+        let geometry = Geometry::default();
+        assert_eq!(geometry.size_bytes(), crate::FS_SIZE_BYTES);
+        // 262144 blocks make 8 groups, each with a block of either bitmap, and 10 inodes fit
+        // in one block
+        assert_eq!(geometry.group_count(), 8);
+        assert_eq!(geometry.blk_bmap_blk_no(), 9);
+        assert_eq!(geometry.group_desc_blk_no(), 17);
+        assert_eq!(geometry.inode_table_blk_no(), 18);
+        assert_eq!(geometry.reserved_blks(), 19);
+    }
+
+    #[test]
+    fn test_geometry_validation() {
+        assert!(Geometry::new(1 << 20, 1024, 64).is_ok());
+        assert!(matches!(
+            Geometry::new(1 << 20, 1000, 64),
+            Err(ImageError::InvalidGeometry(_))
+        ));
+        assert!(matches!(
+            Geometry::new(1 << 20, 512, RESERVED_INODES),
+            Err(ImageError::InvalidGeometry(_))
+        ));
+        assert!(matches!(
+            Geometry::new(1 << 20, 512, 512 * 8 + 1),
+            Err(ImageError::InvalidGeometry(_))
+        ));
+        assert!(matches!(
+            Geometry::new(4 * 4096, 4096, 64),
+            Err(ImageError::InvalidGeometry(_))
+        ));
+        // Smaller groups give each group's inode bitmap fewer inodes to hold
+        let geometry = Geometry::new(4 << 20, 512, 512 * 8 + 1).unwrap();
+        assert!(geometry.with_blks_per_group(2048).is_ok());
+        assert!(matches!(
+            geometry.with_blks_per_group(1020),
+            Err(ImageError::InvalidGeometry(_))
+        ));
+        assert!(matches!(
+            geometry.with_blks_per_group(512 * 8 + 8),
+            Err(ImageError::InvalidGeometry(_))
+        ));
+    }
+
+    #[test]
+    fn test_geometry_smaller_than_a_block_is_rejected() {
+        assert!(matches!(
+            Geometry::new(100, 512, 10),
+            Err(ImageError::InvalidGeometry(_))
+        ));
+        assert!(matches!(
+            Geometry::new(0, 1024, 16),
+            Err(ImageError::InvalidGeometry(_))
+        ));
+    }
+
+    #[test]
+    fn test_format_default_geometry_loads_with_root() {
+        let tmp_dir = TempDir::new("image").unwrap();
+        let image = open_image(&tmp_dir);
+        format(&image, Geometry::default()).unwrap();
+        assert_eq!(image.metadata().unwrap().len(), crate::FS_SIZE_BYTES);
+
+        let state = FSState::load(&image).unwrap();
+        let root = state.inode(ROOT_INO).unwrap();
+        assert_eq!(root.kind, FileType::Directory);
+        assert_eq!(root.perm, 0o755);
+        assert_eq!(
+            state.metadata.free_ino_count,
+            MAX_NUM_INODES - RESERVED_INODES
+        );
+        // The root directory block holding "." and ".." is the first data block
+        assert_eq!(
+            state.metadata.free_blk_count,
+            NUM_DATA_BLKS - Geometry::DEFAULT.reserved_blks() - 1
+        );
+        assert_eq!(
+            state.blk_bitmap.map.first_zero(),
+            Some(Geometry::DEFAULT.reserved_blks() as usize + 1)
+        );
+        assert_eq!(state.lookup_entry(ROOT_INO, OsStr::new("..")), Ok(ROOT_INO));
+    }
+
+    #[test]
+    fn test_format_and_load_custom_geometry() {
+        let tmp_dir = TempDir::new("image").unwrap();
+        let image = open_image(&tmp_dir);
+        let geometry = Geometry::new(8 << 20, 1024, 256).unwrap();
+        format(&image, geometry).unwrap();
+        assert_eq!(image.metadata().unwrap().len(), 8 << 20);
+
+        let mut state = FSState::load(&image).unwrap();
+        assert_eq!(state.geometry(), geometry);
+        assert_eq!(state.inodes.len(), 256);
+        assert_eq!(state.metadata.blk_count, 8192);
+        assert_eq!(state.metadata.free_ino_count, 256 - RESERVED_INODES);
+        assert_eq!(
+            state.metadata.free_blk_count,
+            geometry.blk_count - geometry.reserved_blks() - 1
+        );
+        assert!(state.inode(ROOT_INO).is_ok());
+
+        let blk_no = state.alloc_blk().unwrap();
+        assert_eq!(blk_no, geometry.reserved_blks() + 1);
+        assert_eq!(state.blk_data(blk_no).unwrap().len(), 1024);
+        let ino_id = state
+            .alloc_inode(ROOT_INO, FileType::RegularFile, 0o644)
+            .unwrap();
+        state.save(&image).unwrap();
+
+        let reloaded = FSState::load(&image).unwrap();
+        assert!(reloaded.inode(ino_id).is_ok());
+        assert!(reloaded.blk_bitmap.map[blk_no as usize]);
+        assert_eq!(
+            reloaded.metadata.free_blk_count,
+            state.metadata.free_blk_count
+        );
+    }
+
+    #[test]
+    fn test_load_rejects_invalid_geometry() {
+        let tmp_dir = TempDir::new("image").unwrap();
+        let image = open_image(&tmp_dir);
+        let mut metadata = FSMetadata::new(Geometry::new(1 << 20, 1024, 16).unwrap());
+        metadata.blk_size = 1000;
+        let mut buf = vec![0; 1024];
+        metadata.serialize(&mut buf);
+        image.write_all_at(&buf, 0).unwrap();
+        assert!(matches!(
+            FSState::load(&image),
+            Err(ImageError::InvalidGeometry(_))
+        ));
+
+        metadata.blk_size = 1024;
+        metadata.blk_count = 0;
+        metadata.serialize(&mut buf);
+        image.write_all_at(&buf, 0).unwrap();
+        assert!(matches!(
+            FSState::load(&image),
+            Err(ImageError::InvalidGeometry(_))
+        ));
+    }
+
+    #[test]
+    fn test_save_and_load_roundtrip() {
+        let tmp_dir = TempDir::new("image").unwrap();
+        let path = tmp_dir.path().join("fs.img");
+        let image = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+
+        let mut state = FSState::default();
+        state.init_root();
+        let ino_id = state
+            .alloc_inode(ROOT_INO, FileType::RegularFile, 0o644)
+            .unwrap();
+        let blk_no = state.alloc_blk().unwrap();
+        state.blk_data_mut(blk_no).unwrap()[..5].copy_from_slice(b"hello");
+        state.inode_mut(ino_id).unwrap().direct_blks[0] = blk_no;
+        state.save(&image).unwrap();
+
+        let loaded = FSState::load(&image).unwrap();
+        assert_eq!(loaded.inodes[..], state.inodes[..]);
+        assert_eq!(loaded.inode_bitmap.map, state.inode_bitmap.map);
+        assert_eq!(loaded.blk_bitmap.map, state.blk_bitmap.map);
+        assert_eq!(
+            loaded.metadata.free_blk_count,
+            state.metadata.free_blk_count
+        );
+        assert_eq!(&loaded.blk_data(blk_no).unwrap()[..5], b"hello");
+        assert!(loaded.blk_data(blk_no + 1).is_none());
+    }
+}
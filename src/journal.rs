@@ -0,0 +1,569 @@
+use crate::image::{read_blks, read_metadata, write_blk, Device, Geometry, ImageError};
+use crate::{FSState, INODE_BMAP_BLK_NO, INODE_SIZE_BYTES, SUPER_BLK_NO};
+use log::{error, info};
+use std::collections::BTreeSet;
+use std::io;
+use std::iter;
+
+// The journal sits between the inode table and the data blocks, see image::Geometry. Like ext3
+// it logs whole blocks, but it only ever holds one transaction: a commit is written back to the
+// home blocks before the next one starts, so nothing logged can go stale and need revoking.
+//   block 0: magic | seq (u64), the sequence number of the next transaction
+//   block 1..: descriptor, magic | seq | count (u32) | count home block numbers (u32), over as
+//   many blocks as it takes, then the count logged blocks, then the commit block,
+//   magic | seq | checksum (u64) of the descriptor and logged blocks
+// Only a transaction whose descriptor and commit block both carry the seq of block 0 and whose
+// checksum matches is replayed, anything else never committed.
+const JOURNAL_MAGIC: u32 = 0x534a_5352; // "RSJS"
+const DESC_MAGIC: u32 = 0x444a_5352; // "RSJD"
+const COMMIT_MAGIC: u32 = 0x434a_5352; // "RSJC"
+const DESC_HEADER_LEN: usize = 16;
+
+// Blocks already in use on disk that a single request may change besides the superblock,
+// bitmaps and group descriptors: inode table, directory, pointer, extent and xattr blocks. New
+// blocks do not count, they go home before the transaction instead of being logged.
+const MAX_OP_BLKS: u32 = 32;
+
+// Home block numbers and what goes there
+type Transaction = Vec<(u32, Vec<u8>)>;
+
+// How the contents of regular files are committed, picked with the data= mount option like ext4
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum DataMode {
+    // Written home after the transaction, a crash in between leaves files with stale blocks
+    Writeback,
+    // Written home before the transaction that points at them commits
+    #[default]
+    Ordered,
+    // Logged in the transaction along with the metadata, or written home first like Ordered when
+    // that is too big for the journal
+    Journal,
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+fn desc_blks(geometry: Geometry, count: usize) -> u32 {
+    (DESC_HEADER_LEN + count * 4).div_ceil(geometry.blk_size as usize) as u32
+}
+
+// Besides the descriptor and logged blocks, the journal superblock and commit block need room
+fn fits(geometry: Geometry, count: usize) -> bool {
+    desc_blks(geometry, count) as u64 + count as u64 + 1 < geometry.journal_blks as u64
+}
+
+// A truncate can free blocks in every group, so the largest request may touch every block
+// before the inode table along with MAX_OP_BLKS others. The journal must hold it in one piece.
+pub(crate) fn min_journal_blks(geometry: &Geometry) -> u64 {
+    let count = (geometry.inode_table_blk_no() + MAX_OP_BLKS) as usize;
+    desc_blks(*geometry, count) as u64 + count as u64 + 2
+}
+
+// FNV-1a. With writes reordered around the sync before the commit block, it tells a complete
+// transaction from one whose logged blocks never made it to disk.
+pub(crate) fn checksum<'a>(bufs: impl Iterator<Item = &'a [u8]>) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    for &byte in bufs.flatten() {
+        hash = (hash ^ byte as u64).wrapping_mul(0x100_0000_01b3);
+    }
+    hash
+}
+
+pub(crate) fn read_seq<D: Device>(image: &D, geometry: Geometry) -> Result<u64, ImageError> {
+    let buf = read_blks(image, geometry, geometry.journal_blk_no(), 1)?;
+    if u32_at(&buf, 0) != JOURNAL_MAGIC {
+        return Err(ImageError::CorruptJournal);
+    }
+    Ok(u64_at(&buf, 4))
+}
+
+pub(crate) fn write_seq<D: Device>(image: &D, geometry: Geometry, seq: u64) -> io::Result<()> {
+    let mut buf = vec![0; geometry.blk_size as usize];
+    buf[..4].copy_from_slice(&JOURNAL_MAGIC.to_le_bytes());
+    buf[4..12].copy_from_slice(&seq.to_le_bytes());
+    write_blk(image, geometry, geometry.journal_blk_no(), &buf)
+}
+
+fn write_home<D: Device>(image: &D, geometry: Geometry, blks: &Transaction) -> io::Result<()> {
+    for (blk_no, data) in blks {
+        write_blk(image, geometry, *blk_no, data)?;
+    }
+    Ok(())
+}
+
+fn log_transaction<D: Device>(
+    image: &D,
+    geometry: Geometry,
+    seq: u64,
+    blks: &Transaction,
+) -> io::Result<()> {
+    let blk_size = geometry.blk_size as usize;
+    let mut desc = vec![0; desc_blks(geometry, blks.len()) as usize * blk_size];
+    desc[..4].copy_from_slice(&DESC_MAGIC.to_le_bytes());
+    desc[4..12].copy_from_slice(&seq.to_le_bytes());
+    desc[12..16].copy_from_slice(&(blks.len() as u32).to_le_bytes());
+    for (tag, (blk_no, _)) in desc[DESC_HEADER_LEN..].chunks_exact_mut(4).zip(blks) {
+        tag.copy_from_slice(&blk_no.to_le_bytes());
+    }
+
+    let mut pos = geometry.journal_blk_no() + 1;
+    write_blk(image, geometry, pos, &desc)?;
+    pos += desc_blks(geometry, blks.len());
+    for (_, data) in blks {
+        write_blk(image, geometry, pos, data)?;
+        pos += 1;
+    }
+    // The commit block must not reach the disk before what it vouches for
+    image.sync()?;
+
+    let logged = blks.iter().map(|(_, data)| &data[..]);
+    let mut commit = vec![0; blk_size];
+    commit[..4].copy_from_slice(&COMMIT_MAGIC.to_le_bytes());
+    commit[4..12].copy_from_slice(&seq.to_le_bytes());
+    commit[12..20].copy_from_slice(&checksum(iter::once(&desc[..]).chain(logged)).to_le_bytes());
+    write_blk(image, geometry, pos, &commit)?;
+    image.sync()
+}
+
+// The transaction with sequence number seq, None when it never committed
+fn read_transaction<D: Device>(
+    image: &D,
+    geometry: Geometry,
+    seq: u64,
+) -> Result<Option<Transaction>, ImageError> {
+    let start = geometry.journal_blk_no() + 1;
+    let first = read_blks(image, geometry, start, 1)?;
+    let count = u32_at(&first, 12) as usize;
+    if u32_at(&first, 0) != DESC_MAGIC || u64_at(&first, 4) != seq || !fits(geometry, count) {
+        return Ok(None);
+    }
+    let desc_len = desc_blks(geometry, count);
+    let desc = read_blks(image, geometry, start, desc_len)?;
+    let logged = read_blks(image, geometry, start + desc_len, count as u32)?;
+    let commit = read_blks(image, geometry, start + desc_len + count as u32, 1)?;
+    let blk_size = geometry.blk_size as usize;
+    let sum = checksum(iter::once(&desc[..]).chain(logged.chunks_exact(blk_size)));
+    if u32_at(&commit, 0) != COMMIT_MAGIC || u64_at(&commit, 4) != seq || u64_at(&commit, 12) != sum
+    {
+        return Ok(None);
+    }
+
+    let blk_nos = desc[DESC_HEADER_LEN..][..count * 4]
+        .chunks_exact(4)
+        .map(|tag| u32_at(tag, 0));
+    let mut blks = Vec::with_capacity(count);
+    for (blk_no, data) in blk_nos.zip(logged.chunks_exact(blk_size)) {
+        let in_journal = (geometry.journal_blk_no()..geometry.reserved_blks()).contains(&blk_no);
+        if blk_no >= geometry.blk_count || in_journal {
+            error!("Journal transaction {seq} logs block {blk_no} outside the filesystem");
+            return Err(ImageError::CorruptJournal);
+        }
+        blks.push((blk_no, data.to_vec()));
+    }
+    Ok(Some(blks))
+}
+
+// Whether a transaction committed but did not make it home before a crash
+pub fn needs_recovery<D: Device>(image: &D) -> Result<bool, ImageError> {
+    let geometry = read_metadata(image)?.geometry();
+    if geometry.journal_blks == 0 {
+        return Ok(false);
+    }
+    let seq = read_seq(image, geometry)?;
+    Ok(read_transaction(image, geometry, seq)?.is_some())
+}
+
+// Writes home a transaction that committed before a crash. Run before loading an image that
+// may not have been unmounted cleanly, returns whether there was anything to replay.
+pub fn recover<D: Device>(image: &D) -> Result<bool, ImageError> {
+    let geometry = read_metadata(image)?.geometry();
+    if geometry.journal_blks == 0 {
+        return Ok(false);
+    }
+    let seq = read_seq(image, geometry)?;
+    let Some(blks) = read_transaction(image, geometry, seq)? else {
+        return Ok(false);
+    };
+    write_home(image, geometry, &blks)?;
+    image.sync()?;
+    write_seq(image, geometry, seq + 1)?;
+    image.sync()?;
+    info!(
+        "Replayed journal transaction {seq} of {} blocks",
+        blks.len()
+    );
+    Ok(true)
+}
+
+impl FSState {
+    pub fn set_data_mode(&mut self, data_mode: DataMode) {
+        self.data_mode = data_mode;
+    }
+
+    pub(crate) fn mark_dirty(&mut self, blk_no: u32) {
+        self.dirty.insert(blk_no);
+    }
+
+    pub(crate) fn mark_inode_dirty(&mut self, ino_id: u32) {
+        let geometry = self.geometry();
+        let per_blk = geometry.blk_size / INODE_SIZE_BYTES as u32;
+        self.mark_dirty(geometry.inode_table_blk_no() + ino_id / per_blk);
+    }
+
+    // An inode was allocated or freed: its bit, the free count and its slot change. The group
+    // descriptor is marked along with its count, see group.rs.
+    pub(crate) fn mark_ino_alloc_dirty(&mut self, ino_id: u32) {
+        let group = self.geometry().ino_group(ino_id);
+        self.mark_dirty(SUPER_BLK_NO);
+        self.mark_dirty(INODE_BMAP_BLK_NO + group);
+        self.mark_inode_dirty(ino_id);
+    }
+
+    pub(crate) fn mark_blk_alloc_dirty(&mut self, blk_no: u32) {
+        let geometry = self.geometry();
+        self.mark_dirty(SUPER_BLK_NO);
+        self.mark_dirty(geometry.blk_bmap_blk_no() + geometry.blk_group(blk_no));
+    }
+
+    // Whether the transaction built up since the last commit logs as many blocks past the ones
+    // before the inode table as half of what a request may change. Work too big for a single
+    // transaction stops there to be committed, see prepare_snapshot and flush_delayed_some.
+    // Without a journal there is no limit.
+    pub(crate) fn transaction_full(&self) -> bool {
+        let geometry = self.geometry();
+        if geometry.journal_blks == 0 {
+            return false;
+        }
+        let logged = self
+            .dirty
+            .range(geometry.inode_table_blk_no()..)
+            .filter(|blk_no| !self.dirty_data.contains(blk_no))
+            .filter(|blk_no| !self.alloced.contains(blk_no) || self.freed.contains(blk_no))
+            .count();
+        logged >= (MAX_OP_BLKS / 2) as usize
+    }
+
+    // Writes every block changed since the last commit or save to the image as one transaction:
+    // logged to the journal first, then written home. File contents are only logged with
+    // DataMode::Journal, the other modes write them in place around the transaction. Blocks
+    // allocated since the last commit are free on disk, so nothing there points at them yet:
+    // like ordered data they go home before the transaction rather than being logged, and only
+    // blocks the image already uses take room in the journal. Without a journal the blocks go
+    // straight home and a crash can tear them. A transaction too big for the journal is refused
+    // and stays in memory, writing it in place would lose its atomicity. Copy-on-write images
+    // commit without the journal, see cow.rs.
+    pub fn commit<D: Device>(&mut self, image: &D) -> Result<(), ImageError> {
+        if self.metadata.cow {
+            return self.commit_cow(image);
+        }
+        self.flush_snapshots().map_err(|_| ImageError::NoSpace)?;
+        if self.dirty.is_empty() {
+            return Ok(());
+        }
+        let geometry = self.geometry();
+        let logged = geometry.journal_blks > 0;
+        // A block freed since the last commit may still be in use by what is on disk, so new
+        // contents for it wait for the transaction like metadata does
+        let fresh: BTreeSet<u32> = self
+            .dirty
+            .intersection(&self.alloced)
+            .filter(|blk_no| !self.freed.contains(blk_no) && !self.dirty_data.contains(blk_no))
+            .copied()
+            .collect();
+        // File contents that cannot be logged along with the metadata are written home first,
+        // as in ordered mode, so nothing can point at blocks that do not have them yet
+        let mut data_mode = self.data_mode;
+        let logged_len = self.dirty.len() - fresh.len();
+        if data_mode == DataMode::Journal && !(logged && fits(geometry, logged_len)) {
+            data_mode = DataMode::Ordered;
+        }
+        let in_place: BTreeSet<u32> = match data_mode {
+            DataMode::Journal => BTreeSet::new(),
+            _ => self.dirty_data.difference(&self.freed).copied().collect(),
+        };
+        let image_of = |&blk_no: &u32| (blk_no, self.blk_image(blk_no));
+        let data: Transaction = in_place.iter().map(image_of).collect();
+        let first: Transaction = fresh.iter().map(image_of).collect();
+        let meta: Transaction = self
+            .dirty
+            .iter()
+            .filter(|blk_no| !in_place.contains(blk_no) && !fresh.contains(blk_no))
+            .map(image_of)
+            .collect();
+
+        if logged && !fits(geometry, meta.len()) {
+            return Err(ImageError::TransactionTooBig(meta.len()));
+        }
+        write_home(image, geometry, &first)?;
+        if data_mode == DataMode::Ordered {
+            write_home(image, geometry, &data)?;
+        }
+        // The sync before the commit block covers these as well, without a journal nothing else
+        // would keep them ahead of the metadata
+        if !logged {
+            image.sync()?;
+        }
+        if logged {
+            log_transaction(image, geometry, self.journal_seq, &meta)?;
+        }
+        write_home(image, geometry, &meta)?;
+        if data_mode == DataMode::Writeback {
+            write_home(image, geometry, &data)?;
+        }
+        image.sync()?;
+        if logged {
+            self.journal_seq += 1;
+            write_seq(image, geometry, self.journal_seq)?;
+            image.sync()?;
+        }
+        self.dirty.clear();
+        self.dirty_data.clear();
+        self.alloced.clear();
+        self.freed.clear();
+        self.blks.mark_clean();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crash::{self, CrashDevice};
+    use crate::test_util::{self, make_file, make_node};
+    use crate::ROOT_INO;
+    use fuser::FileType;
+    use std::ffi::OsStr;
+
+    // 256 blocks of 1 KiB with a 64 block journal
+    fn geometry() -> Geometry {
+        Geometry::new(256 << 10, 1024, 32)
+            .unwrap()
+            .with_journal(64)
+            .unwrap()
+    }
+
+    fn image_with(setup: impl Fn(&mut FSState)) -> Vec<u8> {
+        crash::image_with(geometry(), setup)
+    }
+
+    fn crash_outcomes(
+        before: Vec<u8>,
+        data_mode: DataMode,
+        op: impl Fn(&mut FSState),
+    ) -> Vec<FSState> {
+        crash::crash_outcomes(before, |state| {
+            state.set_data_mode(data_mode);
+            op(state);
+        })
+    }
+
+    fn check_crash_points(before: Vec<u8>, op: impl Fn(&mut FSState)) {
+        crash_outcomes(before, DataMode::default(), op);
+    }
+
+    #[test]
+    fn test_mkdir_is_atomic() {
+        check_crash_points(image_with(|_| {}), |state| {
+            state
+                .make_node(ROOT_INO, OsStr::new("dir"), FileType::Directory, 0o755)
+                .unwrap();
+        });
+    }
+
+    #[test]
+    fn test_unlink_is_atomic() {
+        let before = image_with(|state| {
+            make_file(state, "file", &vec![0xab; 3000]);
+        });
+        check_crash_points(before, |state| {
+            state
+                .remove_node(ROOT_INO, OsStr::new("file"), false)
+                .unwrap();
+        });
+    }
+
+    #[test]
+    fn test_committed_transaction_is_replayed() {
+        let before = image_with(|_| {});
+        // A commit writes the descriptor, the 7 logged blocks and the commit block, then the
+        // home blocks. Power goes out right after the commit block.
+        let device = CrashDevice::new(before, Some(9), false);
+        let mut state = FSState::load(&device).unwrap();
+        make_file(&mut state, "file", &[0xab; 10]);
+        assert_eq!(state.dirty.len(), 7);
+        assert!(state.commit(&device).is_err());
+
+        let device = CrashDevice::new(device.synced.into_inner(), None, false);
+        assert!(needs_recovery(&device).unwrap());
+        assert!(FSState::load(&device)
+            .unwrap()
+            .lookup_entry(ROOT_INO, OsStr::new("file"))
+            .is_err());
+        assert!(recover(&device).unwrap());
+        assert!(!needs_recovery(&device).unwrap());
+        assert!(!recover(&device).unwrap());
+
+        let state = FSState::load(&device).unwrap();
+        let ino_id = state.lookup_entry(ROOT_INO, OsStr::new("file")).unwrap();
+        let mut buf = [0; 10];
+        assert_eq!(state.read_at(ino_id, 0, &mut buf), Ok(10));
+        assert_eq!(buf, [0xab; 10]);
+    }
+
+    #[test]
+    fn test_journal_holds_the_largest_request() {
+        let geometry = Geometry::new(256 << 10, 1024, 32).unwrap();
+        let min = min_journal_blks(&geometry) as u32;
+        assert!(geometry.with_journal(min).is_ok());
+        assert!(matches!(
+            geometry.with_journal(min - 1),
+            Err(ImageError::InvalidGeometry(_))
+        ));
+        assert!(geometry.default_journal_blks() >= min);
+    }
+
+    #[test]
+    fn test_transaction_too_big_for_journal_is_refused() {
+        let geometry = Geometry::new(256 << 10, 1024, 64).unwrap();
+        let geometry = geometry.with_journal(min_journal_blks(&geometry) as u32);
+        let before = crash::image_with(geometry.unwrap(), |state| {
+            for i in 0..30 {
+                make_node(state, ROOT_INO, &format!("dir{i}"), FileType::Directory);
+            }
+        });
+        let device = CrashDevice::new(before.clone(), None, false);
+        let mut state = FSState::load(&device).unwrap();
+        let seq = state.journal_seq;
+        // Every directory block is already on disk, so each has to be logged
+        for i in 0..30 {
+            let dir = state
+                .lookup_entry(ROOT_INO, OsStr::new(&format!("dir{i}")))
+                .unwrap();
+            make_node(&mut state, dir, "file", FileType::RegularFile);
+        }
+        assert!(matches!(
+            state.commit(&device),
+            Err(ImageError::TransactionTooBig(_))
+        ));
+        // Nothing was written and the changes are still there to commit
+        assert_eq!(device.data.into_inner(), before);
+        assert_eq!(state.journal_seq, seq);
+        let dir = state.lookup_entry(ROOT_INO, OsStr::new("dir29")).unwrap();
+        assert!(state.lookup_entry(dir, OsStr::new("file")).is_ok());
+        assert!(!state.dirty.is_empty());
+    }
+
+    #[test]
+    fn test_large_write_leaves_the_journal_usable() {
+        let geometry = Geometry::new(12 << 20, 1024, 32).unwrap();
+        let geometry = geometry.with_journal(min_journal_blks(&geometry) as u32);
+        let device = CrashDevice::new(crash::image_with(geometry.unwrap(), |_| {}), None, false);
+        let mut state = FSState::load(&device).unwrap();
+        // Some 40 new pointer blocks, more than the journal could log next to the rest
+        let data: Vec<u8> = (0..8 << 20).map(|i: u32| (i % 251) as u8).collect();
+        let ino_id = make_file(&mut state, "big", &data);
+        state.commit(&device).unwrap();
+
+        make_node(&mut state, ROOT_INO, "dir", FileType::Directory);
+        state.commit(&device).unwrap();
+        assert!(state.dirty.is_empty());
+
+        let state = FSState::load(&device).unwrap();
+        assert!(state.lookup_entry(ROOT_INO, OsStr::new("dir")).is_ok());
+        assert_eq!(test_util::contents(&state, ino_id), data);
+        assert_eq!(crate::fsck::check(&state), vec![]);
+    }
+
+    #[test]
+    fn test_save_invalidates_logged_transaction() {
+        let device = CrashDevice::new(image_with(|_| {}), Some(8), false);
+        let mut state = FSState::load(&device).unwrap();
+        make_file(&mut state, "file", &[0xab; 10]);
+        assert!(state.commit(&device).is_err());
+
+        // Loaded without recovering, the transaction is lost and saving must not bring it back
+        let device = CrashDevice::new(device.synced.into_inner(), None, false);
+        let mut state = FSState::load(&device).unwrap();
+        state.save(&device).unwrap();
+        assert!(!recover(&device).unwrap());
+    }
+
+    // An image whose free blocks are full of 0xcd, left behind by an unlinked file
+    fn image_with_stale_blks() -> Vec<u8> {
+        let device = CrashDevice::new(
+            image_with(|state| {
+                make_file(state, "old", &vec![0xcd; 3000]);
+            }),
+            None,
+            false,
+        );
+        let mut state = FSState::load(&device).unwrap();
+        state
+            .remove_node(ROOT_INO, OsStr::new("old"), false)
+            .unwrap();
+        state.commit(&device).unwrap();
+        device.data.into_inner()
+    }
+
+    // Whether a crash while writing a new file of len bytes over stale blocks can leave it
+    // showing them
+    fn exposes_stale_blks(data_mode: DataMode, len: usize) -> bool {
+        let outcomes = crash_outcomes(image_with_stale_blks(), data_mode, |state| {
+            make_file(state, "new", &vec![0xab; len]);
+        });
+        outcomes.iter().any(|state| {
+            let Ok(ino_id) = state.lookup_entry(ROOT_INO, OsStr::new("new")) else {
+                return false;
+            };
+            let mut buf = vec![0; len];
+            let read = state.read_at(ino_id, 0, &mut buf).unwrap();
+            buf[..read].iter().any(|&byte| byte != 0xab)
+        })
+    }
+
+    #[test]
+    fn test_only_writeback_exposes_stale_blocks() {
+        assert!(exposes_stale_blks(DataMode::Writeback, 3000));
+        assert!(!exposes_stale_blks(DataMode::Ordered, 3000));
+        assert!(!exposes_stale_blks(DataMode::Journal, 3000));
+    }
+
+    #[test]
+    fn test_journaled_data_too_big_to_log_is_written_first() {
+        let device = CrashDevice::new(image_with(|_| {}), None, false);
+        let mut state = FSState::load(&device).unwrap();
+        state.set_data_mode(DataMode::Journal);
+        let seq = state.journal_seq;
+        make_file(&mut state, "big", &vec![0xab; 80 * 1024]);
+        state.commit(&device).unwrap();
+        // The metadata was still logged
+        assert_eq!(state.journal_seq, seq + 1);
+        let state = FSState::load(&device).unwrap();
+        let ino_id = state.lookup_entry(ROOT_INO, OsStr::new("big")).unwrap();
+        assert_eq!(state.inode(ino_id).unwrap().size, 80 * 1024);
+
+        assert!(!exposes_stale_blks(DataMode::Journal, 80 * 1024));
+    }
+
+    #[test]
+    fn test_ordered_data_is_not_logged() {
+        let device = CrashDevice::new(image_with(|_| {}), None, false);
+        let mut state = FSState::load(&device).unwrap();
+        make_file(&mut state, "file", &[0xab; 10]);
+        state.commit(&device).unwrap();
+        let writes = device.writes.get();
+
+        let device = CrashDevice::new(image_with(|_| {}), None, false);
+        let mut state = FSState::load(&device).unwrap();
+        state.set_data_mode(DataMode::Journal);
+        make_file(&mut state, "file", &[0xab; 10]);
+        state.commit(&device).unwrap();
+        // The data block is written home either way, only here is it logged as well
+        assert_eq!(device.writes.get(), writes + 1);
+    }
+}
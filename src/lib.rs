@@ -0,0 +1,1360 @@
+mod acl;
+mod attr;
+mod bmap;
+mod cache;
+mod cow;
+#[cfg(test)]
+mod crash;
+mod delalloc;
+mod dir;
+mod extent;
+mod file;
+pub mod fs;
+pub mod fsck;
+mod group;
+mod htree;
+pub mod image;
+pub mod journal;
+mod perm;
+mod reflink;
+mod snapshot;
+mod symlink;
+mod xattr;
+
+pub use attr::SetAttr;
+pub use cache::CacheStats;
+pub use perm::Credentials;
+
+use attr::Timestamp;
+use bitvec::prelude::*;
+use cache::BlockCache;
+use delalloc::DelayedPages;
+use fuser::FileType;
+use group::GroupDesc;
+use image::Geometry;
+use journal::DataMode;
+use libc::{c_int, EFBIG, EIO, ENOENT, ENOSPC};
+use log::error;
+use snapshot::Snapshot;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::time::{SystemTime, UNIX_EPOCH};
+use xattr::INLINE_XATTR_LEN;
+
+// Default geometry, used for in-memory mounts and by mkfs when nothing else is asked for.
+// This is the total capacity of the backing storage for the file system
+// this includes the space used for the FSMetadata, free object bitmaps, and file data and metadata
+const FS_SIZE_BYTES: u64 = 1u64 << 30; // 1 GB
+const BLK_SIZE_BYTES: u64 = 4096u64;
+const NUM_DATA_BLKS: u32 = (FS_SIZE_BYTES / BLK_SIZE_BYTES) as u32;
+
+// On-disk layout, in blocks:
+// 0 -> FSMetadata, 1.. -> InodeBitmap, then the Freeblock bitmap, the group descriptors, the
+// inode table, the journal if there is one, the shadow copies of everything so far with
+// copy-on-write and data blocks.
+// Where each region ends depends on the geometry, see image::Geometry.
+const SUPER_BLK_NO: u32 = 0;
+const INODE_BMAP_BLK_NO: u32 = 1;
+
+// Inodes
+const MAX_NUM_INODES: u32 = 10;
+const RESERVED_INODES: u32 = 2; // 0: null inode, 1: root
+const ROOT_INO: u32 = 1;
+const NUM_INO_DIRECT_PTR: usize = 12;
+const INVALID_PTR: u32 = 0;
+// The most blocks of a write run_goal looks for in one piece
+const MAX_ALLOC_RUN: u64 = 1024;
+const INODE_SIZE_BYTES: usize = 256;
+const MAX_LINKS: u32 = 65000;
+// Inode.flags
+const INODE_FLAG_INDEX: u32 = 0x1000; // Directory with a hashed index, see htree.rs
+const INODE_FLAG_EXTENTS: u32 = 0x8_0000; // Blocks mapped by an extent tree, see extent.rs
+const INODE_FLAG_INLINE_DATA: u32 = 0x1000_0000; // Data kept in the block pointers, see symlink.rs
+
+// Stored in block 0, see image.rs for the serialized layout
+struct FSMetadata {
+    blk_size: u32,
+    ino_count: u32,
+    blk_count: u32,
+    free_blk_count: u32,
+    free_ino_count: u32,
+    super_blk_no: u32,
+    mtime: u64,
+    wtime: u64,
+    features: u32,
+    journal_blks: u32,
+    cow: bool,
+    generation: u64, // Bumped by every copy-on-write commit
+    snap_blk: u32,   // Snapshot list, see snapshot.rs
+    blks_per_group: u32,
+    shadow: BitVec<u8, Lsb0>, // Which copy of each block before the journal is current
+}
+
+impl Default for FSMetadata {
+    fn default() -> Self {
+        Self::new(Geometry::default())
+    }
+}
+
+#[derive(Debug)]
+enum FSMetadataError {
+    InoCountExceedingMax,
+    InoCountBelowReserved,
+    BlkCountExceedingMax,
+    BlkCountBelowReserved,
+}
+impl FSMetadata {
+    fn new(geometry: Geometry) -> Self {
+        Self {
+            blk_size: geometry.blk_size,
+            ino_count: geometry.ino_count,
+            blk_count: geometry.blk_count,
+            free_blk_count: geometry.blk_count - geometry.reserved_blks(),
+            free_ino_count: geometry.ino_count - RESERVED_INODES,
+            super_blk_no: SUPER_BLK_NO,
+            mtime: 0,
+            wtime: 0,
+            features: image::DEFAULT_FEATURES,
+            journal_blks: geometry.journal_blks,
+            cow: geometry.cow,
+            generation: 0,
+            snap_blk: INVALID_PTR,
+            blks_per_group: geometry.blks_per_group,
+            shadow: bitvec![u8, Lsb0; 0; image::MAX_SHADOW_BLKS as usize],
+        }
+    }
+
+    fn dec_free_ino_count(&mut self) -> Result<(), FSMetadataError> {
+        if self.free_ino_count == 0 {
+            error!(
+                "Attempted to decrease the inode count below reserved: {}",
+                { RESERVED_INODES }
+            );
+            Err(FSMetadataError::InoCountBelowReserved)
+        } else {
+            self.free_ino_count -= 1;
+            self.mtime = secs_from_unix_epoch() as u64;
+            Ok(())
+        }
+    }
+
+    fn inc_free_ino_count(&mut self) -> Result<(), FSMetadataError> {
+        if self.free_ino_count >= (self.ino_count - RESERVED_INODES) {
+            error!(
+                "Attempted to increase the inode count above max: {}",
+                self.ino_count - RESERVED_INODES
+            );
+            Err(FSMetadataError::InoCountExceedingMax)
+        } else {
+            self.free_ino_count += 1;
+            self.mtime = secs_from_unix_epoch() as u64;
+            Ok(())
+        }
+    }
+
+    fn dec_free_blk_count(&mut self) -> Result<(), FSMetadataError> {
+        if self.free_blk_count == 0 {
+            error!(
+                "Attempted to decrease the block count below reserved: {}",
+                self.geometry().reserved_blks()
+            );
+            Err(FSMetadataError::BlkCountBelowReserved)
+        } else {
+            self.free_blk_count -= 1;
+            self.mtime = secs_from_unix_epoch() as u64;
+            Ok(())
+        }
+    }
+
+    fn inc_free_blk_count(&mut self) -> Result<(), FSMetadataError> {
+        let max_free = self.blk_count - self.geometry().reserved_blks();
+        if self.free_blk_count >= max_free {
+            error!("Attempted to increase the block count above max: {max_free}");
+            Err(FSMetadataError::BlkCountExceedingMax)
+        } else {
+            self.free_blk_count += 1;
+            self.mtime = secs_from_unix_epoch() as u64;
+            Ok(())
+        }
+    }
+}
+
+#[derive(Debug)]
+enum BitMapError {
+    RestrictedEntry,
+    AlreadyAlloced,
+    AlreadyFree,
+}
+
+// Bitmaps are sized from the geometry in the superblock, entries below reserved() and at or
+// above max() can never change state.
+trait FreeObjectBitmap {
+    fn reserved(&self) -> usize;
+    fn max(&self) -> usize;
+    fn map(&mut self) -> &mut BitSlice<u8, Lsb0>;
+    fn raw(&self) -> &[u8];
+    // Where the last allocation ended, find_free_run starts looking there
+    fn cursor(&self) -> usize;
+    fn set_cursor(&mut self, idx: usize);
+
+    fn find_first_free(&mut self) -> Option<usize> {
+        self.find_free_from(0, &[])
+    }
+
+    // The first free entry at or after start. Entries set in any of taken count as allocated
+    // too, here and in the searches below.
+    fn find_free_from(&self, start: usize, taken: &[&[u8]]) -> Option<usize> {
+        let (start, max) = (start.max(self.reserved()), self.max());
+        let idx = next_entry(&[&[self.raw()], taken].concat(), start, max, false);
+        (idx < max).then_some(idx)
+    }
+
+    // The start of len free entries in a row, next-fit: the first run after the cursor, or
+    // else the first one before it
+    fn find_free_run(&self, len: usize, taken: &[&[u8]]) -> Option<usize> {
+        self.find_free_run_from(self.cursor(), len, taken)
+    }
+
+    // The first run of len free entries at or after start, wrapping around to the first one
+    // before it
+    fn find_free_run_from(&self, start: usize, len: usize, taken: &[&[u8]]) -> Option<usize> {
+        let (reserved, max) = (self.reserved(), self.max());
+        let start = start.clamp(reserved, max);
+        let maps = [&[self.raw()], taken].concat();
+        find_run(&maps, start, max, len)
+            .or_else(|| find_run(&maps, reserved, (start + len).min(max), len))
+    }
+
+    // Marks start..start + len allocated, or nothing when any of them is restricted or taken
+    fn alloc_range(&mut self, start: usize, len: usize) -> Result<(), BitMapError> {
+        self.check_range(start, len)?;
+        if self.map()[start..start + len].any() {
+            error!(
+                "An index in {start}..{} is already alloced, no change",
+                start + len
+            );
+            return Err(BitMapError::AlreadyAlloced);
+        }
+        self.map()[start..start + len].fill(true);
+        self.set_cursor(start + len);
+        Ok(())
+    }
+
+    // Marks start..start + len free, or nothing when any of them is restricted or free already
+    fn free_range(&mut self, start: usize, len: usize) -> Result<(), BitMapError> {
+        self.check_range(start, len)?;
+        if !self.map()[start..start + len].all() {
+            error!(
+                "An index in {start}..{} is already free, no change",
+                start + len
+            );
+            return Err(BitMapError::AlreadyFree);
+        }
+        self.map()[start..start + len].fill(false);
+        Ok(())
+    }
+
+    fn check_range(&self, start: usize, len: usize) -> Result<(), BitMapError> {
+        let end = start.saturating_add(len);
+        if start < self.reserved() || end > self.max() {
+            error!("Tried to acces restricted index: {start}..{end}");
+            return Err(BitMapError::RestrictedEntry);
+        }
+        Ok(())
+    }
+
+    fn set_alloc(&mut self, idx: usize) -> Result<(), BitMapError> {
+        self.alloc_range(idx, 1)
+    }
+
+    fn set_free(&mut self, idx: usize) -> Result<(), BitMapError> {
+        self.free_range(idx, 1)
+    }
+}
+
+// The first entry at or after from that is set in any of maps, or for set == false clear in
+// all of them, max when there is none before it. Goes through the maps 64 entries at a time.
+fn next_entry(maps: &[&[u8]], from: usize, max: usize, set: bool) -> usize {
+    let mut idx = from;
+    while idx < max {
+        let byte = idx / 8;
+        let mut word = 0;
+        for raw in maps {
+            let mut bytes = [0; 8];
+            let chunk = raw.get(byte..).unwrap_or_default();
+            let len = chunk.len().min(8);
+            bytes[..len].copy_from_slice(&chunk[..len]);
+            word |= u64::from_le_bytes(bytes);
+        }
+        if !set {
+            word = !word;
+        }
+        word &= u64::MAX << (idx % 8);
+        if word != 0 {
+            return max.min(byte * 8 + word.trailing_zeros() as usize);
+        }
+        idx = byte * 8 + 64;
+    }
+    max
+}
+
+// The start of the first run of len entries clear in all of maps between from and max
+fn find_run(maps: &[&[u8]], from: usize, max: usize, len: usize) -> Option<usize> {
+    let mut idx = from;
+    while idx < max {
+        let start = next_entry(maps, idx, max, false);
+        let end = next_entry(maps, start, max, true);
+        if end - start >= len && start < max {
+            return Some(start);
+        }
+        idx = end;
+    }
+    None
+}
+
+struct FreeBlockBitmap {
+    map: BitVec<u8, Lsb0>,
+    reserved: usize,
+    cursor: usize,
+}
+
+impl FreeBlockBitmap {
+    fn new(geometry: Geometry) -> Self {
+        let reserved = geometry.reserved_blks() as usize;
+        let mut map = bitvec![u8, Lsb0; 0; geometry.blk_count as usize];
+        map[0..reserved].fill(true);
+        Self {
+            map,
+            reserved,
+            cursor: reserved,
+        }
+    }
+}
+
+impl Default for FreeBlockBitmap {
+    fn default() -> Self {
+        Self::new(Geometry::default())
+    }
+}
+
+impl FreeObjectBitmap for FreeBlockBitmap {
+    fn reserved(&self) -> usize {
+        self.reserved
+    }
+    fn max(&self) -> usize {
+        self.map.len()
+    }
+    fn map(&mut self) -> &mut BitSlice<u8, Lsb0> {
+        &mut self.map
+    }
+    fn raw(&self) -> &[u8] {
+        self.map.as_raw_slice()
+    }
+    fn cursor(&self) -> usize {
+        self.cursor
+    }
+    fn set_cursor(&mut self, idx: usize) {
+        self.cursor = idx;
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+// Because of Copy, re-assignment of variable is copied; ownership is not transferred.
+// Use references here.
+struct Inode {
+    ino_id: u32, // inode number
+    size: u64,   // file size
+    blocks: u32, // num blocks allocated
+    uid: u32,
+    gid: u32,
+    atime: Timestamp,
+    mtime: Timestamp,
+    ctime: Timestamp, // last change to the inode itself, including any mtime update
+    crtime: Timestamp,
+    kind: FileType,
+    perm: u16,  // permission bits along with setuid, setgid and sticky
+    nlink: u32, // directory entries pointing here, a directory's own "." and its children's ".." included
+    direct_blks: [u32; NUM_INO_DIRECT_PTR],
+    indirect_blk: u32,
+    dbl_indirect_blk: u32,
+    tri_indirect_blk: u32,
+    flags: u32,
+    xattr_blk: u32, // extended attributes that do not fit in xattr_inline, see xattr.rs
+    xattr_inline: [u8; INLINE_XATTR_LEN],
+}
+
+fn secs_from_unix_epoch() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+impl Inode {
+    fn new(ino_id: u32, kind: FileType, perm: u16) -> Self {
+        let now = Timestamp::now();
+        Self {
+            ino_id,
+            size: 0,
+            blocks: 0,
+            uid: 0,
+            gid: 0,
+            atime: now,
+            mtime: now,
+            ctime: now,
+            crtime: now,
+            kind,
+            perm,
+            // The links a new inode has once it is entered in its parent
+            nlink: if kind == FileType::Directory { 2 } else { 1 },
+            direct_blks: [INVALID_PTR; NUM_INO_DIRECT_PTR],
+            indirect_blk: INVALID_PTR,
+            dbl_indirect_blk: INVALID_PTR,
+            tri_indirect_blk: INVALID_PTR,
+            flags: 0,
+            xattr_blk: INVALID_PTR,
+            xattr_inline: [0; INLINE_XATTR_LEN],
+        }
+    }
+
+    // For changes to the contents, which change the inode too
+    fn update_mtime(&mut self) {
+        self.mtime = Timestamp::now();
+        self.ctime = self.mtime;
+    }
+
+    fn update_ctime(&mut self) {
+        self.ctime = Timestamp::now();
+    }
+}
+
+struct FreeInodeBitmap {
+    map: BitVec<u8, Lsb0>,
+    cursor: usize,
+}
+
+impl FreeInodeBitmap {
+    fn new(geometry: Geometry) -> Self {
+        let mut map = bitvec![u8, Lsb0; 0; geometry.ino_count as usize];
+        map[0..(RESERVED_INODES as usize)].fill(true);
+        Self {
+            map,
+            cursor: RESERVED_INODES as usize,
+        }
+    }
+}
+
+impl Default for FreeInodeBitmap {
+    fn default() -> Self {
+        Self::new(Geometry::default())
+    }
+}
+
+impl FreeObjectBitmap for FreeInodeBitmap {
+    fn reserved(&self) -> usize {
+        RESERVED_INODES as usize
+    }
+    fn max(&self) -> usize {
+        self.map.len()
+    }
+    fn map(&mut self) -> &mut BitSlice<u8, Lsb0> {
+        &mut self.map
+    }
+    fn raw(&self) -> &[u8] {
+        self.map.as_raw_slice()
+    }
+    fn cursor(&self) -> usize {
+        self.cursor
+    }
+    fn set_cursor(&mut self, idx: usize) {
+        self.cursor = idx;
+    }
+}
+
+pub struct FSState {
+    metadata: FSMetadata,
+    inode_bitmap: FreeInodeBitmap,
+    inodes: Box<[Option<Inode>]>,
+    blk_bitmap: FreeBlockBitmap,
+    blks: BlockCache,       // The blocks past the reserved ones, see cache.rs
+    groups: Vec<GroupDesc>, // See group.rs
+    // Open file handles per inode, an unlinked inode is kept until its count drops to 0
+    open_handles: HashMap<u32, u32>,
+    // Blocks changed since the last commit or save, the ones among them holding file contents,
+    // and the blocks allocated and freed since then. See journal.rs.
+    dirty: BTreeSet<u32>,
+    dirty_data: BTreeSet<u32>,
+    alloced: BTreeSet<u32>,
+    freed: BTreeSet<u32>,
+    journal_seq: u64, // Sequence number of the next journal transaction
+    data_mode: DataMode,
+    // With copy-on-write, the blocks allocated on disk, which must not be written until the
+    // next commit stops using them. See cow.rs.
+    committed: BitVec<u8, Lsb0>,
+    // Blocks referenced from more than one place and how many times, see snapshot.rs
+    refcounts: BTreeMap<u32, u32>,
+    snapshots: Vec<Snapshot>,
+    snap_dirty: bool, // The snapshot list or refcounts changed since they were last written
+    delayed: DelayedPages, // Writes still waiting for blocks, see delalloc.rs
+}
+
+#[derive(Debug)]
+enum InodeError {
+    NoFreeInodesOnAlloc,
+    InodeNotFound,
+    InvalidInoId,
+    BitmapError(BitMapError),
+}
+
+#[derive(Debug)]
+enum BlockError {
+    NoFreeBlksOnAlloc,
+    InvalidBlkNo,
+    FileTooLarge,
+    ReadFailed, // The block cache could not read the block from the image
+    BitmapError(BitMapError),
+}
+
+fn inode_errno(err: InodeError) -> c_int {
+    match err {
+        InodeError::NoFreeInodesOnAlloc => ENOSPC,
+        InodeError::InodeNotFound | InodeError::InvalidInoId => ENOENT,
+        InodeError::BitmapError(err) => {
+            error!("Inode bitmap error: {err:?}");
+            EIO
+        }
+    }
+}
+
+fn blk_errno(err: BlockError) -> c_int {
+    match err {
+        BlockError::NoFreeBlksOnAlloc => ENOSPC,
+        BlockError::FileTooLarge => EFBIG,
+        BlockError::ReadFailed => EIO,
+        BlockError::InvalidBlkNo => {
+            error!("Invalid block number");
+            EIO
+        }
+        BlockError::BitmapError(err) => {
+            error!("Block bitmap error: {err:?}");
+            EIO
+        }
+    }
+}
+
+impl Default for FSState {
+    fn default() -> Self {
+        Self::new(Geometry::default())
+    }
+}
+
+impl FSState {
+    // An empty filesystem laid out according to geometry
+    pub fn new(geometry: Geometry) -> Self {
+        let metadata = FSMetadata::new(geometry);
+        let inode_bitmap = FreeInodeBitmap::new(geometry);
+        let inodes = vec![None; geometry.ino_count as usize].into_boxed_slice();
+        let blk_bitmap = FreeBlockBitmap::new(geometry);
+        let blks = BlockCache::new(geometry.blk_size as usize);
+
+        let mut state = Self {
+            metadata,
+            inode_bitmap,
+            inodes,
+            blk_bitmap,
+            blks,
+            groups: Vec::new(),
+            open_handles: HashMap::new(),
+            dirty: BTreeSet::new(),
+            dirty_data: BTreeSet::new(),
+            alloced: BTreeSet::new(),
+            freed: BTreeSet::new(),
+            journal_seq: 0,
+            data_mode: DataMode::default(),
+            committed: match geometry.cow {
+                true => bitvec![u8, Lsb0; 0; geometry.blk_count as usize],
+                false => BitVec::new(),
+            },
+            refcounts: BTreeMap::new(),
+            snapshots: Vec::new(),
+            snap_dirty: false,
+            delayed: DelayedPages::default(),
+        };
+        state.groups = state.count_groups();
+        state
+    }
+
+    fn geometry(&self) -> Geometry {
+        self.metadata.geometry()
+    }
+
+    fn blk_size(&self) -> u64 {
+        self.metadata.blk_size as u64
+    }
+
+    // Optional features, see image::FEATURE_*
+    pub fn set_features(&mut self, features: u32) {
+        self.metadata.features = features;
+        self.mark_dirty(SUPER_BLK_NO);
+    }
+
+    // For a new entry in directory parent, in the group inode_group picks
+    fn alloc_inode(&mut self, parent: u32, kind: FileType, perm: u16) -> Result<u32, InodeError> {
+        let start = self
+            .inode_group(parent, kind)
+            .map_or(0, |group| self.geometry().group_inos(group).start);
+        let idx = self
+            .inode_bitmap
+            .find_free_from(start as usize, &[])
+            .or_else(|| self.inode_bitmap.find_first_free())
+            .ok_or(InodeError::NoFreeInodesOnAlloc)?;
+
+        self.inode_bitmap
+            .set_alloc(idx)
+            .map_err(|_| InodeError::NoFreeInodesOnAlloc)?;
+
+        self.metadata
+            .dec_free_ino_count()
+            .map_err(|_| InodeError::NoFreeInodesOnAlloc)?;
+
+        let mut inode = Inode::new(idx as u32, kind, perm);
+        if self.metadata.features & image::FEATURE_EXTENTS != 0 {
+            inode.flags |= INODE_FLAG_EXTENTS;
+        }
+        self.inodes[idx] = Some(inode);
+        self.count_group_ino(idx as u32, kind, true);
+        self.mark_ino_alloc_dirty(idx as u32);
+        Ok(idx as u32)
+    }
+
+    fn free_inode(&mut self, ino_id: u32) -> Result<(), InodeError> {
+        let idx = ino_id as usize;
+
+        self.inode_bitmap.set_free(idx).map_err(|err| match err {
+            BitMapError::RestrictedEntry => InodeError::InvalidInoId,
+            BitMapError::AlreadyFree => InodeError::BitmapError(BitMapError::AlreadyFree),
+            BitMapError::AlreadyAlloced => InodeError::BitmapError(BitMapError::AlreadyAlloced),
+        })?;
+
+        self.metadata
+            .inc_free_ino_count()
+            .map_err(|_| InodeError::InvalidInoId)?;
+
+        if let Some(inode) = self.inodes[idx].take() {
+            self.count_group_ino(ino_id, inode.kind, false);
+        }
+        self.mark_ino_alloc_dirty(ino_id);
+        Ok(())
+    }
+
+    // The root inode is reserved in the bitmap, so it is never handed out by alloc_inode.
+    // Its ".." points back at itself. Like mke2fs, it belongs to whoever creates it.
+    fn init_root(&mut self) {
+        if self.inodes[ROOT_INO as usize].is_none() {
+            let mut root = Inode::new(ROOT_INO, FileType::Directory, 0o755);
+            (root.uid, root.gid) = unsafe { (libc::getuid(), libc::getgid()) };
+            self.inodes[ROOT_INO as usize] = Some(root);
+            self.groups[0].dirs += 1;
+            self.mark_group_dirty(0);
+            self.mark_inode_dirty(ROOT_INO);
+            if let Err(err) = self.init_dir(ROOT_INO, ROOT_INO) {
+                error!("Failed to create the root directory entries: errno {err}");
+            }
+        }
+    }
+
+    fn inode(&self, ino_id: u32) -> Result<&Inode, InodeError> {
+        self.inodes
+            .get(ino_id as usize)
+            .ok_or(InodeError::InvalidInoId)?
+            .as_ref()
+            .ok_or(InodeError::InodeNotFound)
+    }
+
+    fn inode_mut(&mut self, ino_id: u32) -> Result<&mut Inode, InodeError> {
+        self.mark_inode_dirty(ino_id);
+        self.inodes
+            .get_mut(ino_id as usize)
+            .ok_or(InodeError::InvalidInoId)?
+            .as_mut()
+            .ok_or(InodeError::InodeNotFound)
+    }
+
+    // Next-fit: the first free block after the last one allocated
+    fn alloc_blk(&mut self) -> Result<u32, BlockError> {
+        self.alloc_blk_near(self.blk_bitmap.cursor as u32)
+    }
+
+    // Allocates goal, or else the first free block after it, so a file written in order gets
+    // consecutive blocks. Wraps around to the start when nothing after goal is free.
+    fn alloc_blk_near(&mut self, goal: u32) -> Result<u32, BlockError> {
+        let idx = self
+            .find_free_blk(goal as usize)
+            .or_else(|| self.find_free_blk(0))
+            .ok_or(BlockError::NoFreeBlksOnAlloc)?;
+        self.blk_bitmap
+            .set_alloc(idx)
+            .map_err(|_| BlockError::NoFreeBlksOnAlloc)?;
+
+        self.metadata
+            .dec_free_blk_count()
+            .map_err(|_| BlockError::NoFreeBlksOnAlloc)?;
+
+        self.count_group_blk(idx as u32, true);
+        self.blks.insert_zeroed(idx as u32);
+        // The block on disk still holds whatever was there before
+        self.mark_dirty(idx as u32);
+        self.mark_blk_alloc_dirty(idx as u32);
+        self.alloced.insert(idx as u32);
+        Ok(idx as u32)
+    }
+
+    // Allocates the first of want blocks about to be written, see run_goal
+    fn alloc_blk_for(&mut self, goal: u32, want: u64) -> Result<u32, BlockError> {
+        let goal = self.run_goal(goal, want);
+        self.alloc_blk_near(goal)
+    }
+
+    fn find_free_blk(&self, from: usize) -> Option<usize> {
+        let taken = self.taken_on_disk();
+        self.blk_bitmap.find_free_from(from, &taken)
+    }
+
+    // Where the first of want blocks about to be written goes when the block before them is at
+    // goal - 1, INVALID_PTR when there is none: goal itself when the want blocks from there
+    // are all free, or else the next run of that many free blocks, so a large write ends up in
+    // one piece. Without a goal the search starts at the cursor.
+    fn run_goal(&self, goal: u32, want: u64) -> u32 {
+        let want = want.min(MAX_ALLOC_RUN) as usize;
+        let taken = self.taken_on_disk();
+        let maps = [&[self.blk_bitmap.raw()], &taken[..]].concat();
+        let (cursor, max) = (self.blk_bitmap.cursor, self.blk_bitmap.max());
+        let start = match goal {
+            INVALID_PTR => cursor,
+            goal => goal as usize,
+        };
+        if next_entry(&maps, start, max, true) >= (start + want).min(max) {
+            return start as u32;
+        }
+        let run = match goal {
+            INVALID_PTR => self.blk_bitmap.find_free_run(want, &taken),
+            goal => self
+                .blk_bitmap
+                .find_free_run_from(goal as usize, want, &taken),
+        };
+        run.unwrap_or(start) as u32
+    }
+
+    // Drops a reference to the block, which is only freed along with the last one
+    fn free_blk(&mut self, blk_no: u32) -> Result<(), BlockError> {
+        if self.drop_shared_ref(blk_no) {
+            return Ok(());
+        }
+        let idx = blk_no as usize;
+
+        self.blk_bitmap.set_free(idx).map_err(|err| match err {
+            BitMapError::RestrictedEntry => BlockError::InvalidBlkNo,
+            err => BlockError::BitmapError(err),
+        })?;
+
+        self.metadata
+            .inc_free_blk_count()
+            .map_err(|_| BlockError::InvalidBlkNo)?;
+
+        self.count_group_blk(blk_no, false);
+        self.blks.remove(blk_no);
+        self.dirty.remove(&blk_no);
+        self.dirty_data.remove(&blk_no);
+        self.freed.insert(blk_no);
+        self.mark_blk_alloc_dirty(blk_no);
+        Ok(())
+    }
+}
+
+// Setup shared by the tests of the other modules
+#[cfg(test)]
+pub(crate) mod test_util {
+    use super::*;
+    use std::ffi::OsStr;
+
+    // A state of geometry with nothing but its root directory
+    pub(crate) fn state_with_root(geometry: Geometry) -> FSState {
+        let mut state = FSState::new(geometry);
+        state.init_root();
+        state
+    }
+
+    // Features are set before the root directory is made, which some of them change
+    pub(crate) fn state_with_features(geometry: Geometry, features: u32) -> FSState {
+        let mut state = FSState::new(geometry);
+        state.set_features(features);
+        state.init_root();
+        state
+    }
+
+    // Along with an empty regular file named "file" in the root directory
+    pub(crate) fn state_with_file(geometry: Geometry) -> (FSState, u32) {
+        let mut state = state_with_root(geometry);
+        let ino_id = make_file(&mut state, "file", &[]);
+        (state, ino_id)
+    }
+
+    // A default state with a node named "node" in the root directory, owned by uid and gid
+    pub(crate) fn state_with_node(kind: FileType, perm: u16, uid: u32, gid: u32) -> (FSState, u32) {
+        let mut state = state_with_root(Geometry::default());
+        let ino_id = state
+            .make_node_as(ROOT_INO, OsStr::new("node"), kind, perm, uid, gid)
+            .unwrap();
+        (state, ino_id)
+    }
+
+    // Directories get 0o755, anything else 0o644
+    pub(crate) fn make_node(state: &mut FSState, parent: u32, name: &str, kind: FileType) -> u32 {
+        let perm = match kind {
+            FileType::Directory => 0o755,
+            _ => 0o644,
+        };
+        state
+            .make_node(parent, OsStr::new(name), kind, perm)
+            .unwrap()
+    }
+
+    // A regular file in the root directory holding data
+    pub(crate) fn make_file(state: &mut FSState, name: &str, data: &[u8]) -> u32 {
+        let ino_id = make_node(state, ROOT_INO, name, FileType::RegularFile);
+        if !data.is_empty() {
+            state.write_at(ino_id, 0, data).unwrap();
+        }
+        ino_id
+    }
+
+    pub(crate) fn contents(state: &FSState, ino_id: u32) -> Vec<u8> {
+        let mut buf = vec![0; state.inode(ino_id).unwrap().size as usize];
+        state.read_at(ino_id, 0, &mut buf).unwrap();
+        buf
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
+mod tests {
+    use super::*;
+    use std::ffi::OsStr;
+
+    const RESERVED_DATA_BLKS: u32 = Geometry::DEFAULT.reserved_blks();
+
+    // Test find_first_free
+    #[test]
+    fn test_find_first_free_returns_first_unreserved_index() {
+        let mut bitmap = FreeInodeBitmap::default();
+        assert_eq!(bitmap.find_first_free(), Some(RESERVED_INODES as usize));
+    }
+
+    #[test]
+    fn test_find_first_free_skips_allocated_indices() {
+        let mut bitmap = FreeInodeBitmap::default();
+        bitmap.map.set(2, true);
+        assert_eq!(bitmap.find_first_free(), Some(3));
+    }
+
+    #[test]
+    fn test_find_first_free_returns_none_when_full() {
+        let mut bitmap = FreeInodeBitmap::default();
+        bitmap.map.fill(true);
+        assert_eq!(bitmap.find_first_free(), None);
+    }
+
+    // Test set_alloc
+    #[test]
+    fn test_set_alloc_succeeds_for_valid_free_index() {
+        let mut bitmap = FreeInodeBitmap::default();
+        let idx = RESERVED_INODES as usize;
+        assert!(bitmap.set_alloc(idx).is_ok());
+        assert_eq!(bitmap.map[idx], true);
+    }
+
+    #[test]
+    fn test_set_alloc_fails_for_reserved_index() {
+        let mut bitmap = FreeInodeBitmap::default();
+        let result = bitmap.set_alloc(0);
+        assert!(matches!(result, Err(BitMapError::RestrictedEntry)));
+    }
+
+    #[test]
+    fn test_set_alloc_fails_for_index_beyond_max() {
+        let mut bitmap = FreeInodeBitmap::default();
+        let result = bitmap.set_alloc(MAX_NUM_INODES as usize + 1);
+        assert!(matches!(result, Err(BitMapError::RestrictedEntry)));
+    }
+
+    #[test]
+    fn test_set_alloc_fails_for_already_allocated_index() {
+        let mut bitmap = FreeInodeBitmap::default();
+        let idx = RESERVED_INODES as usize;
+        bitmap.map.set(idx, true);
+        let result = bitmap.set_alloc(idx);
+        assert!(matches!(result, Err(BitMapError::AlreadyAlloced)));
+    }
+
+    // Test set_free
+    #[test]
+    fn test_set_free_succeeds_for_valid_allocated_index() {
+        let mut bitmap = FreeInodeBitmap::default();
+        let idx = RESERVED_INODES as usize;
+        bitmap.map.set(idx, true); // First allocate it
+        assert!(bitmap.set_free(idx).is_ok());
+        assert_eq!(bitmap.map[idx], false);
+    }
+
+    #[test]
+    fn test_set_free_fails_for_reserved_index() {
+        let mut bitmap = FreeInodeBitmap::default();
+        let result = bitmap.set_free(0);
+        assert!(matches!(result, Err(BitMapError::RestrictedEntry)));
+        assert_eq!(bitmap.map[0], true)
+    }
+
+    #[test]
+    fn test_set_free_fails_for_index_beyond_max() {
+        let mut bitmap = FreeInodeBitmap::default();
+        let result = bitmap.set_free(MAX_NUM_INODES as usize + 1);
+        assert!(matches!(result, Err(BitMapError::RestrictedEntry)));
+    }
+
+    #[test]
+    fn test_set_free_fails_for_already_free_index() {
+        let mut bitmap = FreeInodeBitmap::default();
+        let idx = RESERVED_INODES as usize;
+        let result = bitmap.set_free(idx);
+        assert!(matches!(result, Err(BitMapError::AlreadyFree)));
+    }
+
+    // Test with FreeBlockBitmap to ensure trait works for both implementations
+    #[test]
+    fn test_free_block_bitmap_find_first_free() {
+        let mut bitmap = FreeBlockBitmap::default();
+        assert_eq!(bitmap.find_first_free(), Some(RESERVED_DATA_BLKS as usize));
+    }
+
+    #[test]
+    fn test_free_block_bitmap_set_alloc_and_free() {
+        let mut bitmap = FreeBlockBitmap::default();
+        let idx = RESERVED_DATA_BLKS as usize;
+
+        // Allocate
+        assert!(bitmap.set_alloc(idx).is_ok());
+        assert_eq!(bitmap.map[idx], true);
+
+        // Free
+        assert!(bitmap.set_free(idx).is_ok());
+        assert_eq!(bitmap.map[idx], false);
+    }
+
+    #[test]
+    fn test_free_block_bitmap_max() {
+        let mut bitmap = FreeBlockBitmap::default();
+        let idx = NUM_DATA_BLKS as usize;
+        let idx2 = RESERVED_DATA_BLKS as usize + 1;
+        assert!(bitmap.set_alloc(idx2).is_ok());
+        assert_eq!(bitmap.map[idx2], true);
+
+        let result = bitmap.set_alloc(idx);
+        assert!(matches!(result, Err(BitMapError::RestrictedEntry)));
+    }
+
+    #[test]
+    fn test_bitmaps_sized_from_geometry() {
+        let geometry = Geometry::new(1 << 20, 1024, 40).unwrap();
+        let reserved = geometry.reserved_blks() as usize;
+
+        let mut blk_bitmap = FreeBlockBitmap::new(geometry);
+        assert_eq!(blk_bitmap.max(), 1024);
+        assert_eq!(blk_bitmap.find_first_free(), Some(reserved));
+        assert!(matches!(
+            blk_bitmap.set_alloc(reserved - 1),
+            Err(BitMapError::RestrictedEntry)
+        ));
+        assert!(matches!(
+            blk_bitmap.set_alloc(1024),
+            Err(BitMapError::RestrictedEntry)
+        ));
+        assert!(blk_bitmap.set_alloc(1023).is_ok());
+
+        let mut inode_bitmap = FreeInodeBitmap::new(geometry);
+        for idx in RESERVED_INODES as usize..40 {
+            inode_bitmap.set_alloc(idx).unwrap();
+        }
+        assert_eq!(inode_bitmap.find_first_free(), None);
+    }
+
+    // Test the range allocation
+    #[test]
+    fn test_next_entry_crosses_words() {
+        let mut map = bitvec![u8, Lsb0; 1; 300];
+        map.set(70, false);
+        map.set(299, false);
+        let raw = map.as_raw_slice();
+        assert_eq!(next_entry(&[raw], 0, 300, false), 70);
+        assert_eq!(next_entry(&[raw], 71, 300, false), 299);
+        assert_eq!(next_entry(&[raw], 71, 299, false), 299);
+        assert_eq!(next_entry(&[raw], 70, 300, true), 71);
+
+        // Set in either map counts as set
+        let other = bitvec![u8, Lsb0; 0; 300];
+        let mut busy = other.clone();
+        busy.set(70, true);
+        assert_eq!(next_entry(&[raw, busy.as_raw_slice()], 0, 300, false), 299);
+        assert_eq!(next_entry(&[other.as_raw_slice()], 5, 300, false), 5);
+    }
+
+    #[test]
+    fn test_find_free_run_is_next_fit() {
+        let mut bitmap = FreeBlockBitmap::default();
+        let reserved = RESERVED_DATA_BLKS as usize;
+        bitmap.alloc_range(reserved, 100).unwrap();
+        assert_eq!(bitmap.cursor, reserved + 100);
+        bitmap.free_range(reserved + 10, 20).unwrap();
+
+        // A run of 20 is free before the cursor, but the search starts at the cursor
+        assert_eq!(bitmap.find_free_run(20, &[]), Some(reserved + 100));
+        assert_eq!(
+            bitmap.find_free_run_from(reserved, 20, &[]),
+            Some(reserved + 10)
+        );
+        assert_eq!(
+            bitmap.find_free_run_from(reserved, 21, &[]),
+            Some(reserved + 100)
+        );
+
+        // Past the end it wraps around to the start
+        let max = bitmap.max();
+        bitmap
+            .alloc_range(reserved + 100, max - reserved - 100)
+            .unwrap();
+        assert_eq!(bitmap.find_free_run(20, &[]), Some(reserved + 10));
+        assert_eq!(bitmap.find_free_run(21, &[]), None);
+
+        // Entries taken elsewhere are skipped too
+        let mut taken = bitvec![u8, Lsb0; 0; max];
+        taken.set(reserved + 15, true);
+        assert_eq!(bitmap.find_free_run(15, &[taken.as_raw_slice()]), None);
+        assert_eq!(
+            bitmap.find_free_run(10, &[taken.as_raw_slice()]),
+            Some(reserved + 16)
+        );
+        assert_eq!(
+            bitmap.find_free_run(5, &[taken.as_raw_slice()]),
+            Some(reserved + 10)
+        );
+    }
+
+    #[test]
+    fn test_cursor_starts_at_first_unreserved_entry() {
+        assert_eq!(
+            FreeBlockBitmap::default().cursor,
+            RESERVED_DATA_BLKS as usize
+        );
+        assert_eq!(FreeInodeBitmap::default().cursor, RESERVED_INODES as usize);
+    }
+
+    #[test]
+    fn test_cursor_follows_allocations_not_frees() {
+        let mut bitmap = FreeBlockBitmap::default();
+        let reserved = RESERVED_DATA_BLKS as usize;
+        bitmap.set_alloc(reserved + 5).unwrap();
+        assert_eq!(bitmap.cursor, reserved + 6);
+        bitmap.set_free(reserved + 5).unwrap();
+        assert_eq!(bitmap.cursor, reserved + 6);
+
+        // First fit still finds the lowest free entry, next fit starts at the cursor
+        assert_eq!(bitmap.find_first_free(), Some(reserved));
+        assert_eq!(bitmap.find_free_run(1, &[]), Some(reserved + 6));
+    }
+
+    #[test]
+    fn test_cursor_at_the_end_wraps_to_the_start() {
+        let mut bitmap = FreeInodeBitmap::default();
+        let max = bitmap.max();
+        bitmap.set_alloc(max - 1).unwrap();
+        assert_eq!(bitmap.cursor, max);
+        assert_eq!(bitmap.find_free_run(1, &[]), Some(RESERVED_INODES as usize));
+        assert_eq!(bitmap.find_free_run(max, &[]), None);
+    }
+
+    #[test]
+    fn test_alloc_and_free_range_are_all_or_nothing() {
+        let mut bitmap = FreeInodeBitmap::new(Geometry::new(1 << 20, 1024, 40).unwrap());
+        bitmap.set_alloc(10).unwrap();
+        assert!(matches!(
+            bitmap.alloc_range(5, 10),
+            Err(BitMapError::AlreadyAlloced)
+        ));
+        assert_eq!(bitmap.map.count_ones(), RESERVED_INODES as usize + 1);
+        assert!(matches!(
+            bitmap.alloc_range(1, 3),
+            Err(BitMapError::RestrictedEntry)
+        ));
+        assert!(matches!(
+            bitmap.alloc_range(30, 11),
+            Err(BitMapError::RestrictedEntry)
+        ));
+
+        bitmap.alloc_range(11, 29).unwrap();
+        assert!(matches!(
+            bitmap.free_range(9, 5),
+            Err(BitMapError::AlreadyFree)
+        ));
+        bitmap.free_range(10, 30).unwrap();
+        assert_eq!(bitmap.map.count_ones(), RESERVED_INODES as usize);
+    }
+
+    #[test]
+    fn test_large_writes_get_one_run() {
+        let mut state = FSState::new(Geometry::new(1 << 20, 1024, 32).unwrap());
+        state.set_features(image::FEATURE_EXTENTS);
+        state.init_root();
+        // Leave free blocks only in ones and twos at the start
+        let first = state.alloc_blk().unwrap();
+        for blk_no in first + 1..first + 60 {
+            state.alloc_blk_near(blk_no).unwrap();
+        }
+        for blk_no in (first..first + 60).step_by(3) {
+            state.free_blk(blk_no).unwrap();
+        }
+        state.blk_bitmap.cursor = 0;
+
+        let ino_id = state
+            .make_node(ROOT_INO, OsStr::new("file"), FileType::RegularFile, 0o644)
+            .unwrap();
+        state.write_at(ino_id, 0, &[1; 50 * 1024]).unwrap();
+        let extents = state.extents(state.inode(ino_id).unwrap());
+        assert_eq!(extents.len(), 1);
+        assert!(extents[0].start >= first + 60);
+    }
+
+    #[test]
+    fn test_basic_innode_alloc_and_free_no_errors() {
+        let fsstate = &mut FSState::default();
+        let result = fsstate.alloc_inode(ROOT_INO, FileType::RegularFile, 0);
+        let expected_idx = RESERVED_INODES;
+
+        assert!(result.is_ok());
+        let ino_id = result.unwrap();
+        assert_eq!(ino_id, expected_idx);
+
+        let free_res = fsstate.free_inode(ino_id);
+        assert!(free_res.is_ok());
+
+        assert_eq!(fsstate.inodes[ino_id as usize], None);
+    }
+
+    #[test]
+    fn test_free_inode_once_succeeds_twice_fails() {
+        let fsstate = &mut FSState::default();
+        let ino_id = fsstate
+            .alloc_inode(ROOT_INO, FileType::RegularFile, 0)
+            .unwrap();
+
+        // First free should succeed
+        assert!(fsstate.free_inode(ino_id).is_ok());
+
+        // Second free should fail
+        let result = fsstate.free_inode(ino_id);
+        assert!(result.is_err());
+        assert!(matches!(
+            result.unwrap_err(),
+            InodeError::BitmapError(BitMapError::AlreadyFree)
+        ));
+    }
+
+    #[test]
+    fn test_sequential_allocation_indices() {
+        let fsstate = &mut FSState::default();
+
+        let ino1 = fsstate
+            .alloc_inode(ROOT_INO, FileType::RegularFile, 0)
+            .unwrap();
+        let ino2 = fsstate
+            .alloc_inode(ROOT_INO, FileType::RegularFile, 0)
+            .unwrap();
+        let ino3 = fsstate
+            .alloc_inode(ROOT_INO, FileType::RegularFile, 0)
+            .unwrap();
+
+        assert_eq!(ino1, RESERVED_INODES);
+        assert_eq!(ino2, RESERVED_INODES + 1);
+        assert_eq!(ino3, RESERVED_INODES + 2);
+    }
+
+    #[test]
+    fn test_free_both_and_reallocate_with_bitmap_verification() {
+        let fsstate = &mut FSState::default();
+
+        // Allocate two inodes
+        let ino1 = fsstate
+            .alloc_inode(ROOT_INO, FileType::RegularFile, 0)
+            .unwrap();
+        let ino2 = fsstate
+            .alloc_inode(ROOT_INO, FileType::RegularFile, 0)
+            .unwrap();
+
+        // Verify bitmap is set
+        assert_eq!(fsstate.inode_bitmap.map[ino1 as usize], true);
+        assert_eq!(fsstate.inode_bitmap.map[ino2 as usize], true);
+
+        // Free both
+        fsstate.free_inode(ino1).unwrap();
+        fsstate.free_inode(ino2).unwrap();
+
+        // Verify bitmap is cleared
+        assert_eq!(fsstate.inode_bitmap.map[ino1 as usize], false);
+        assert_eq!(fsstate.inode_bitmap.map[ino2 as usize], false);
+        assert_eq!(fsstate.inodes[ino1 as usize], None);
+        assert_eq!(fsstate.inodes[ino2 as usize], None);
+
+        // Reallocate and verify bitmap is set again
+        let ino_new = fsstate
+            .alloc_inode(ROOT_INO, FileType::RegularFile, 0)
+            .unwrap();
+        assert_eq!(ino_new, RESERVED_INODES);
+        assert_eq!(fsstate.inode_bitmap.map[ino_new as usize], true);
+        assert!(fsstate.inodes[ino_new as usize].is_some());
+    }
+
+    #[test]
+    fn test_allocate_all_inodes_to_max() {
+        let fsstate = &mut FSState::default();
+        let mut allocated_inodes = Vec::new();
+
+        // Allocate all available inodes (MAX - RESERVED)
+        for _ in 0..(MAX_NUM_INODES - RESERVED_INODES) {
+            let ino = fsstate.alloc_inode(ROOT_INO, FileType::RegularFile, 0);
+            assert!(ino.is_ok());
+            allocated_inodes.push(ino.unwrap());
+        }
+
+        // Verify we allocated the expected number
+        assert_eq!(
+            allocated_inodes.len(),
+            (MAX_NUM_INODES - RESERVED_INODES) as usize
+        );
+
+        assert_eq!(fsstate.metadata.ino_count, MAX_NUM_INODES);
+        assert_eq!(fsstate.metadata.free_ino_count, 0);
+
+        // Try to allocate one more - should fail
+        let result = fsstate.alloc_inode(ROOT_INO, FileType::RegularFile, 0);
+        assert!(result.is_err());
+        assert!(matches!(
+            result.unwrap_err(),
+            InodeError::NoFreeInodesOnAlloc
+        ));
+    }
+
+    #[test]
+    fn test_free_reserved_inode_0_fails() {
+        let fsstate = &mut FSState::default();
+
+        let result = fsstate.free_inode(0);
+        assert!(result.is_err());
+        assert!(matches!(result.unwrap_err(), InodeError::InvalidInoId));
+    }
+
+    #[test]
+    fn test_free_reserved_inode_1_fails() {
+        let fsstate = &mut FSState::default();
+
+        let result = fsstate.free_inode(1);
+        assert!(result.is_err());
+        assert!(matches!(result.unwrap_err(), InodeError::InvalidInoId));
+    }
+
+    #[test]
+    fn test_metadata_counts_during_alloc_and_free() {
+        let fsstate = &mut FSState::default();
+
+        let initial_free_count = fsstate.metadata.free_ino_count;
+
+        // Allocate
+        let ino = fsstate
+            .alloc_inode(ROOT_INO, FileType::RegularFile, 0)
+            .unwrap();
+        assert_eq!(fsstate.metadata.ino_count, MAX_NUM_INODES);
+        assert_eq!(fsstate.metadata.free_ino_count, initial_free_count - 1);
+
+        // Free
+        fsstate.free_inode(ino).unwrap();
+        assert_eq!(fsstate.metadata.ino_count, MAX_NUM_INODES);
+        assert_eq!(fsstate.metadata.free_ino_count, initial_free_count);
+    }
+
+    #[test]
+    fn test_alloc_free_alloc_reuses_same_index() {
+        let fsstate = &mut FSState::default();
+
+        let ino1 = fsstate
+            .alloc_inode(ROOT_INO, FileType::RegularFile, 0)
+            .unwrap();
+        fsstate.free_inode(ino1).unwrap();
+        let ino2 = fsstate
+            .alloc_inode(ROOT_INO, FileType::RegularFile, 0)
+            .unwrap();
+
+        // Should reuse the same index
+        assert_eq!(ino1, ino2);
+    }
+
+    #[test]
+    fn test_inode_properties_set_correctly() {
+        let fsstate = &mut FSState::default();
+
+        let ino_id = fsstate
+            .alloc_inode(ROOT_INO, FileType::Directory, 0o755)
+            .unwrap();
+        let inode = fsstate.inodes[ino_id as usize].as_ref().unwrap();
+
+        assert_eq!(inode.ino_id, ino_id);
+        assert_eq!(inode.kind, FileType::Directory);
+        assert_eq!(inode.perm, 0o755);
+        assert_eq!(inode.size, 0);
+        assert_eq!(inode.blocks, 0);
+        assert!(inode.mtime > Timestamp(0));
+    }
+
+    #[test]
+    fn test_new_inode_is_born_when_last_modified() {
+        let fsstate = &mut FSState::default();
+        let ino_id = fsstate
+            .alloc_inode(ROOT_INO, FileType::RegularFile, 0)
+            .unwrap();
+        let inode = fsstate.inodes[ino_id as usize].as_ref().unwrap();
+        assert_eq!(inode.crtime, inode.mtime);
+    }
+
+    #[test]
+    fn test_free_middle_inode_and_reallocate() {
+        let fsstate = &mut FSState::default();
+
+        let ino1 = fsstate
+            .alloc_inode(ROOT_INO, FileType::RegularFile, 0)
+            .unwrap();
+        let ino2 = fsstate
+            .alloc_inode(ROOT_INO, FileType::RegularFile, 0)
+            .unwrap();
+        let ino3 = fsstate
+            .alloc_inode(ROOT_INO, FileType::RegularFile, 0)
+            .unwrap();
+
+        // Free middle inode
+        fsstate.free_inode(ino2).unwrap();
+
+        // Allocate again - should get ino2 back (first free slot)
+        let ino_new = fsstate
+            .alloc_inode(ROOT_INO, FileType::RegularFile, 0)
+            .unwrap();
+        assert_eq!(ino_new, ino2);
+
+        // ino1 and ino3 should still be allocated
+        assert!(fsstate.inodes[ino1 as usize].is_some());
+        assert!(fsstate.inodes[ino3 as usize].is_some());
+    }
+}
@@ -0,0 +1,418 @@
+use crate::{inode_errno, FSState, Inode, SetAttr};
+use fuser::{FileType, TimeOrNow};
+use libc::{c_int, EACCES, ENODATA, EPERM, R_OK, W_OK, X_OK};
+use std::ffi::OsStr;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+
+const S_ISUID: u16 = libc::S_ISUID as u16;
+const S_ISGID: u16 = libc::S_ISGID as u16;
+const S_ISVTX: u16 = libc::S_ISVTX as u16;
+
+// Who an operation is done on behalf of
+#[derive(Clone, Debug, PartialEq)]
+pub struct Credentials {
+    pub uid: u32,
+    pub gid: u32,
+    // Supplementary groups
+    pub groups: Vec<u32>,
+}
+
+impl Credentials {
+    pub fn new(uid: u32, gid: u32) -> Self {
+        Self {
+            uid,
+            gid,
+            groups: Vec::new(),
+        }
+    }
+
+    // Supplementary groups are not part of a FUSE request, they are read from the process.
+    // A process that has already exited only gets its primary group.
+    pub fn of_process(uid: u32, gid: u32, pid: u32) -> Self {
+        let groups = fs::read_to_string(format!("/proc/{pid}/status"))
+            .ok()
+            .and_then(|status| {
+                let line = status.lines().find(|line| line.starts_with("Groups:"))?;
+                Some(
+                    line[7..]
+                        .split_whitespace()
+                        .filter_map(|g| g.parse().ok())
+                        .collect(),
+                )
+            })
+            .unwrap_or_default();
+        Self { uid, gid, groups }
+    }
+
+    fn is_root(&self) -> bool {
+        self.uid == 0
+    }
+
+    pub(crate) fn in_group(&self, gid: u32) -> bool {
+        self.gid == gid || self.groups.contains(&gid)
+    }
+}
+
+impl Inode {
+    // The rwx bits that apply to creds, shifted down to the bottom three
+    fn perm_bits(&self, creds: &Credentials) -> u16 {
+        if creds.uid == self.uid {
+            (self.perm >> 6) & 0o7
+        } else if creds.in_group(self.gid) {
+            (self.perm >> 3) & 0o7
+        } else {
+            self.perm & 0o7
+        }
+    }
+}
+
+impl FSState {
+    // Checks mask, a combination of R_OK, W_OK and X_OK, the way access(2) does. Root may read
+    // and write anything and may execute a file if anyone can.
+    pub fn check_access(&self, ino_id: u32, creds: &Credentials, mask: c_int) -> Result<(), c_int> {
+        let inode = self.inode(ino_id).map_err(inode_errno)?;
+        if creds.is_root() {
+            let any_exec = inode.perm & 0o111 != 0 || inode.kind == FileType::Directory;
+            return match mask & X_OK == 0 || any_exec {
+                true => Ok(()),
+                false => Err(EACCES),
+            };
+        }
+        // R_OK, W_OK and X_OK are the same bits as rwx
+        let want = (mask & (R_OK | W_OK | X_OK)) as u16;
+        let granted = match self.access_acl(ino_id) {
+            Some(acl) => acl.permits(inode, creds, want),
+            None => inode.perm_bits(creds) & want == want,
+        };
+        match granted {
+            true => Ok(()),
+            false => Err(EACCES),
+        }
+    }
+
+    // Removing or renaming an entry needs write and search on dir. In a sticky directory only
+    // the owner of the directory or of the entry may do it.
+    pub fn check_remove(&self, dir: u32, ino_id: u32, creds: &Credentials) -> Result<(), c_int> {
+        self.check_access(dir, creds, W_OK | X_OK)?;
+        let dir = self.inode(dir).map_err(inode_errno)?;
+        let inode = self.inode(ino_id).map_err(inode_errno)?;
+        if dir.perm & S_ISVTX != 0
+            && !creds.is_root()
+            && creds.uid != dir.uid
+            && creds.uid != inode.uid
+        {
+            return Err(EPERM);
+        }
+        Ok(())
+    }
+
+    // user.* follows the file's read and write bits and only exists on regular files and
+    // directories, trusted.* is for root only, and setting security.* or system.* needs
+    // ownership, the same as chmod
+    pub fn check_xattr(
+        &self,
+        ino_id: u32,
+        creds: &Credentials,
+        name: &OsStr,
+        write: bool,
+    ) -> Result<(), c_int> {
+        let inode = self.inode(ino_id).map_err(inode_errno)?;
+        let name = name.as_bytes();
+        let owner = creds.is_root() || creds.uid == inode.uid;
+        if name.starts_with(b"user.") {
+            if !matches!(inode.kind, FileType::RegularFile | FileType::Directory) {
+                return Err(if write { EPERM } else { ENODATA });
+            }
+            if write && inode.kind == FileType::Directory && inode.perm & S_ISVTX != 0 && !owner {
+                return Err(EPERM);
+            }
+            let mask = if write { W_OK } else { R_OK };
+            return self.check_access(ino_id, creds, mask);
+        }
+        if name.starts_with(b"trusted.") && !creds.is_root() {
+            return Err(EPERM);
+        }
+        if write && !owner {
+            return Err(EPERM);
+        }
+        Ok(())
+    }
+
+    // Checks a setattr request against the rules for chmod, chown, truncate and utimens, and
+    // returns it with the setuid and setgid bits dropped where those calls would drop them
+    pub fn check_set_attr(
+        &self,
+        ino_id: u32,
+        creds: &Credentials,
+        attr: SetAttr,
+    ) -> Result<SetAttr, c_int> {
+        let inode = *self.inode(ino_id).map_err(inode_errno)?;
+        let owner = creds.is_root() || creds.uid == inode.uid;
+        let mut attr = attr;
+
+        if let Some(mode) = attr.mode.as_mut() {
+            if !owner {
+                return Err(EPERM);
+            }
+            // Only members of the group may leave the setgid bit on a file
+            let gid = attr.gid.unwrap_or(inode.gid);
+            if !creds.is_root() && inode.kind != FileType::Directory && !creds.in_group(gid) {
+                *mode &= !(S_ISGID as u32);
+            }
+        }
+
+        let new_uid = attr.uid.filter(|&uid| uid != inode.uid);
+        let new_gid = attr.gid.filter(|&gid| gid != inode.gid);
+        if new_uid.is_some() && !creds.is_root() {
+            return Err(EPERM);
+        }
+        if new_gid.is_some_and(|gid| !creds.is_root() && (!owner || !creds.in_group(gid))) {
+            return Err(EPERM);
+        }
+        // A file changing hands loses its setuid and setgid bits
+        if (attr.uid.is_some() || attr.gid.is_some()) && inode.kind != FileType::Directory {
+            let perm = attr.mode.map_or(inode.perm, |mode| mode as u16);
+            if perm & (S_ISUID | S_ISGID) != 0 {
+                attr.mode = Some((perm & !(S_ISUID | S_ISGID)) as u32);
+            }
+        }
+
+        if attr.size.is_some() {
+            self.check_access(ino_id, creds, W_OK)?;
+        }
+
+        // Setting the times to now only needs write access, anything else needs ownership
+        let times = [attr.atime, attr.mtime];
+        if times.iter().any(Option::is_some) && !owner {
+            if times.iter().flatten().any(|time| *time != TimeOrNow::Now) {
+                return Err(EPERM);
+            }
+            self.check_access(ino_id, creds, W_OK)?;
+        }
+        if attr.crtime.is_some() && !owner {
+            return Err(EPERM);
+        }
+        Ok(attr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::state_with_node;
+    use crate::ROOT_INO;
+    use std::time::SystemTime;
+
+    const OWNER: u32 = 1000;
+    const GROUP: u32 = 100;
+
+    fn creds(uid: u32, gid: u32) -> Credentials {
+        Credentials::new(uid, gid)
+    }
+
+    #[test]
+    fn test_owner_group_and_other_bits() {
+        let (state, file) = state_with_node(FileType::RegularFile, 0o640, OWNER, GROUP);
+        let owner = creds(OWNER, 1);
+        let member = Credentials {
+            groups: vec![7, GROUP],
+            ..creds(2000, 1)
+        };
+        let other = creds(3000, 3000);
+
+        assert_eq!(state.check_access(file, &owner, R_OK | W_OK), Ok(()));
+        assert_eq!(state.check_access(file, &owner, X_OK), Err(EACCES));
+        assert_eq!(state.check_access(file, &member, R_OK), Ok(()));
+        assert_eq!(state.check_access(file, &member, W_OK), Err(EACCES));
+        assert_eq!(state.check_access(file, &other, R_OK), Err(EACCES));
+        assert_eq!(state.check_access(file, &other, 0), Ok(()));
+    }
+
+    #[test]
+    fn test_owner_class_is_used_even_when_others_may_do_more() {
+        let (state, file) = state_with_node(FileType::RegularFile, 0o047, OWNER, GROUP);
+        assert_eq!(
+            state.check_access(file, &creds(OWNER, GROUP), R_OK),
+            Err(EACCES)
+        );
+        assert_eq!(state.check_access(file, &creds(1, GROUP), R_OK), Ok(()));
+    }
+
+    #[test]
+    fn test_root_needs_an_exec_bit_to_execute_files() {
+        let (mut state, file) = state_with_node(FileType::RegularFile, 0o600, OWNER, GROUP);
+        let root = creds(0, 0);
+        assert_eq!(state.check_access(file, &root, R_OK | W_OK), Ok(()));
+        assert_eq!(state.check_access(file, &root, X_OK), Err(EACCES));
+        state.inode_mut(file).unwrap().perm = 0o610;
+        assert_eq!(state.check_access(file, &root, X_OK), Ok(()));
+
+        let (state, dir) = state_with_node(FileType::Directory, 0o000, OWNER, GROUP);
+        assert_eq!(state.check_access(dir, &root, R_OK | X_OK), Ok(()));
+    }
+
+    #[test]
+    fn test_creating_entries_needs_write_and_search_on_parent() {
+        let (mut state, dir) = state_with_node(FileType::Directory, 0o755, 0, 0);
+        let mask = W_OK | X_OK;
+        assert_eq!(
+            state.check_access(dir, &creds(OWNER, GROUP), mask),
+            Err(EACCES)
+        );
+        assert_eq!(state.check_access(dir, &creds(0, 0), mask), Ok(()));
+
+        state.inode_mut(dir).unwrap().perm = 0o757;
+        assert_eq!(state.check_access(dir, &creds(OWNER, GROUP), mask), Ok(()));
+        state.inode_mut(dir).unwrap().perm = 0o756;
+        assert_eq!(
+            state.check_access(dir, &creds(OWNER, GROUP), mask),
+            Err(EACCES)
+        );
+    }
+
+    #[test]
+    fn test_sticky_directory_limits_removal() {
+        let (mut state, file) = state_with_node(FileType::RegularFile, 0o666, OWNER, GROUP);
+        state.inode_mut(ROOT_INO).unwrap().perm = 0o1777;
+        let root_owner = state.inode(ROOT_INO).unwrap().uid;
+        assert_eq!(state.check_remove(ROOT_INO, file, &creds(OWNER, 1)), Ok(()));
+        assert_eq!(state.check_remove(ROOT_INO, file, &creds(0, 0)), Ok(()));
+        assert_eq!(
+            state.check_remove(ROOT_INO, file, &creds(root_owner, 1)),
+            Ok(())
+        );
+        assert_eq!(
+            state.check_remove(ROOT_INO, file, &creds(4000, 1)),
+            Err(EPERM)
+        );
+
+        state.inode_mut(ROOT_INO).unwrap().perm = 0o777;
+        assert_eq!(state.check_remove(ROOT_INO, file, &creds(4000, 1)), Ok(()));
+        state.inode_mut(ROOT_INO).unwrap().perm = 0o755;
+        assert_eq!(
+            state.check_remove(ROOT_INO, file, &creds(4000, 1)),
+            Err(EACCES)
+        );
+    }
+
+    #[test]
+    fn test_chmod_and_chown_rules() {
+        let (state, file) = state_with_node(FileType::RegularFile, 0o644, OWNER, GROUP);
+        let chmod = |mode| SetAttr {
+            mode: Some(mode),
+            ..Default::default()
+        };
+        let chgrp = |gid| SetAttr {
+            gid: Some(gid),
+            ..Default::default()
+        };
+        let owner = Credentials {
+            groups: vec![200],
+            ..creds(OWNER, 1)
+        };
+
+        assert_eq!(
+            state.check_set_attr(file, &creds(1, 1), chmod(0o600)).err(),
+            Some(EPERM)
+        );
+        // Not in the file's group, so the setgid bit is dropped
+        let attr = state.check_set_attr(file, &owner, chmod(0o2755)).unwrap();
+        assert_eq!(attr.mode, Some(0o755));
+        let attr = state
+            .check_set_attr(file, &creds(0, 0), chmod(0o2755))
+            .unwrap();
+        assert_eq!(attr.mode, Some(0o2755));
+
+        assert!(state.check_set_attr(file, &owner, chgrp(200)).is_ok());
+        assert_eq!(
+            state.check_set_attr(file, &owner, chgrp(300)).err(),
+            Some(EPERM)
+        );
+        let chown = SetAttr {
+            uid: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(state.check_set_attr(file, &owner, chown).err(), Some(EPERM));
+        // Unchanged ids are fine, as with chown(path, getuid(), -1)
+        let chown = SetAttr {
+            uid: Some(OWNER),
+            ..Default::default()
+        };
+        assert!(state.check_set_attr(file, &owner, chown).is_ok());
+    }
+
+    #[test]
+    fn test_chown_drops_setuid_and_setgid() {
+        let (state, file) = state_with_node(FileType::RegularFile, 0o6755, OWNER, GROUP);
+        let chown = SetAttr {
+            uid: Some(1),
+            ..Default::default()
+        };
+        let attr = state.check_set_attr(file, &creds(0, 0), chown).unwrap();
+        assert_eq!(attr.mode, Some(0o755));
+    }
+
+    #[test]
+    fn test_utimens_and_truncate_rules() {
+        let (state, file) = state_with_node(FileType::RegularFile, 0o646, OWNER, GROUP);
+        let touch = |time| SetAttr {
+            atime: Some(time),
+            mtime: Some(time),
+            ..Default::default()
+        };
+        let writer = creds(2000, 1);
+        let reader = creds(2000, GROUP);
+        let specific = TimeOrNow::SpecificTime(SystemTime::UNIX_EPOCH);
+
+        assert!(state
+            .check_set_attr(file, &writer, touch(TimeOrNow::Now))
+            .is_ok());
+        assert_eq!(
+            state
+                .check_set_attr(file, &reader, touch(TimeOrNow::Now))
+                .err(),
+            Some(EACCES)
+        );
+        assert_eq!(
+            state.check_set_attr(file, &writer, touch(specific)).err(),
+            Some(EPERM)
+        );
+        assert!(state
+            .check_set_attr(file, &creds(OWNER, 1), touch(specific))
+            .is_ok());
+
+        let truncate = || SetAttr {
+            size: Some(0),
+            ..Default::default()
+        };
+        assert!(state.check_set_attr(file, &writer, truncate()).is_ok());
+        assert_eq!(
+            state.check_set_attr(file, &reader, truncate()).err(),
+            Some(EACCES)
+        );
+    }
+
+    #[test]
+    fn test_xattr_namespaces() {
+        let (state, file) = state_with_node(FileType::RegularFile, 0o640, OWNER, GROUP);
+        let owner = creds(OWNER, 1);
+        let member = creds(2000, GROUP);
+        let user = OsStr::new("user.tag");
+        let trusted = OsStr::new("trusted.tag");
+        let security = OsStr::new("security.selinux");
+
+        assert_eq!(state.check_xattr(file, &owner, user, true), Ok(()));
+        assert_eq!(state.check_xattr(file, &member, user, false), Ok(()));
+        assert_eq!(state.check_xattr(file, &member, user, true), Err(EACCES));
+        assert_eq!(state.check_xattr(file, &owner, trusted, false), Err(EPERM));
+        assert_eq!(state.check_xattr(file, &creds(0, 0), trusted, true), Ok(()));
+        assert_eq!(state.check_xattr(file, &member, security, false), Ok(()));
+        assert_eq!(state.check_xattr(file, &member, security, true), Err(EPERM));
+        assert_eq!(state.check_xattr(file, &owner, security, true), Ok(()));
+
+        let (state, link) = state_with_node(FileType::Symlink, 0o777, OWNER, GROUP);
+        assert_eq!(state.check_xattr(link, &owner, user, true), Err(EPERM));
+        assert_eq!(state.check_xattr(link, &owner, user, false), Err(ENODATA));
+    }
+}
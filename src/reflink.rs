@@ -0,0 +1,266 @@
+use crate::{blk_errno, inode_errno, FSState, INVALID_PTR};
+use fuser::FileType;
+use libc::{c_int, EINVAL, EISDIR};
+use std::cmp::min;
+
+// Files can share data blocks the same way snapshots do, see snapshot.rs: a clone points at the
+// blocks of the file it was made from, each of which gains a reference, and whichever of them
+// is written first gets a copy of its own.
+impl FSState {
+    fn check_clone(&self, src: u32, dst: u32) -> Result<(), c_int> {
+        for ino_id in [src, dst] {
+            match self.inode(ino_id).map_err(inode_errno)?.kind {
+                FileType::RegularFile => {}
+                FileType::Directory => return Err(EISDIR),
+                _ => return Err(EINVAL),
+            }
+        }
+        Ok(())
+    }
+
+    // Makes dst a copy of src sharing all of its blocks, like the FICLONE ioctl. Whatever dst
+    // held before is dropped, its xattrs stay.
+    pub fn clone_file(&mut self, src: u32, dst: u32) -> Result<(), c_int> {
+        self.check_clone(src, dst)?;
+        if src == dst {
+            return Err(EINVAL);
+        }
+        self.flush_delayed_inode(src)?;
+        self.settle_before_sharing().map_err(blk_errno)?;
+        self.truncate(dst, 0)?;
+        let mut from = *self.inode(src).map_err(inode_errno)?;
+        if from.has_inline_data() {
+            let mut buf = vec![0; from.size as usize];
+            self.read_at(src, 0, &mut buf)?;
+            return self.write_at(dst, 0, &buf).map(|_| ());
+        }
+        let mut inode = *self.inode(dst).map_err(inode_errno)?;
+        self.share_tree(&mut from, &mut inode).map_err(blk_errno)?;
+        *self.inode_mut(src).map_err(inode_errno)? = from;
+        inode.size = from.size;
+        inode.update_mtime();
+        *self.inode_mut(dst).map_err(inode_errno)? = inode;
+        Ok(())
+    }
+
+    // Copies len bytes from src at off_in to dst at off_out, like copy_file_range(2), and
+    // returns how many were copied. Where a whole block lines up in both files it is shared
+    // instead, the rest goes byte by byte. The copy stops at the end of src, and comes up short
+    // when the filesystem fills up part way.
+    pub fn copy_range(
+        &mut self,
+        src: u32,
+        off_in: u64,
+        dst: u32,
+        off_out: u64,
+        len: u64,
+    ) -> Result<usize, c_int> {
+        self.check_clone(src, dst)?;
+        if src == dst && off_in < off_out + len && off_out < off_in + len {
+            return Err(EINVAL);
+        }
+        let src_size = self.inode(src).map_err(inode_errno)?.size;
+        let len = min(len, src_size.saturating_sub(off_in));
+        // Only blocks can be shared, and pages of dst would cover whatever is shared under them
+        self.flush_delayed_inode(src)?;
+        self.flush_delayed_inode(dst)?;
+        self.settle_before_sharing().map_err(blk_errno)?;
+        let blk_size = self.blk_size();
+        let mut done = 0;
+        while done < len {
+            let (pos_in, pos_out, rest) = (off_in + done, off_out + done, len - done);
+            let copied = match self.share_blk_at(src, pos_in, dst, pos_out, rest) {
+                Ok(0) => {
+                    let n = min(blk_size - pos_in % blk_size, blk_size - pos_out % blk_size);
+                    let mut buf = vec![0; min(n, rest) as usize];
+                    self.read_at(src, pos_in, &mut buf)?;
+                    self.write_at(dst, pos_out, &buf)
+                }
+                Ok(n) => Ok(n as usize),
+                Err(err) => Err(err),
+            };
+            match copied {
+                Ok(n) => done += n as u64,
+                Err(err) if done == 0 => return Err(err),
+                Err(_) => break,
+            }
+        }
+        Ok(done as usize)
+    }
+
+    // Shares the block of src at pos_in with dst at pos_out when both start a block and either
+    // the whole block is copied or everything left of src. Returns how many bytes that covered,
+    // 0 when the block has to be copied instead.
+    fn share_blk_at(
+        &mut self,
+        src: u32,
+        pos_in: u64,
+        dst: u32,
+        pos_out: u64,
+        rest: u64,
+    ) -> Result<u64, c_int> {
+        let blk_size = self.blk_size();
+        let from = *self.inode(src).map_err(inode_errno)?;
+        let mut inode = *self.inode(dst).map_err(inode_errno)?;
+        // A short last block is only shared when nothing of dst comes after it, since the
+        // block continues with zeros past the end of src
+        let whole = rest >= blk_size || pos_out + rest >= inode.size;
+        if !pos_in.is_multiple_of(blk_size) || !pos_out.is_multiple_of(blk_size) || !whole {
+            return Ok(0);
+        }
+        let blk_no = self.bmap(&from, pos_in / blk_size).map_err(blk_errno)?;
+        if blk_no == INVALID_PTR {
+            return Ok(0);
+        }
+        let n = min(blk_size, rest);
+        let result = self.bmap_share(&mut inode, pos_out / blk_size, blk_no);
+        // Pointer blocks allocated before running out of space still belong to dst
+        if result.is_ok() {
+            inode.size = inode.size.max(pos_out + n);
+        }
+        inode.update_mtime();
+        *self.inode_mut(dst).map_err(inode_errno)? = inode;
+        result.map(|()| n).map_err(blk_errno)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crash::CrashDevice;
+    use crate::image::{self, Geometry};
+    use crate::test_util::{contents, make_file, state_with_root};
+    use crate::{fsck, ROOT_INO};
+    use std::ffi::OsStr;
+
+    const BLK: usize = 1024;
+
+    fn state() -> FSState {
+        state_with_root(Geometry::new(1 << 20, 1024, 32).unwrap())
+    }
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|idx| (idx % 251) as u8).collect()
+    }
+
+    #[test]
+    fn test_clone_shares_every_block() {
+        let mut state = state();
+        let data = pattern(300 * BLK + 10);
+        let src = make_file(&mut state, "src", &data);
+        let dst = make_file(&mut state, "dst", &[1; 3000]);
+        let free = state.metadata.free_blk_count;
+
+        state.clone_file(src, dst).unwrap();
+        // dst's own 3 blocks are freed and nothing new is allocated
+        assert_eq!(state.metadata.free_blk_count, free + 3);
+        assert_eq!(contents(&state, dst), data);
+        assert_eq!(
+            state.inode(dst).unwrap().blocks,
+            state.inode(src).unwrap().blocks
+        );
+        assert_eq!(fsck::check(&state), vec![]);
+
+        // Writing either side copies the path to the block written and nothing else
+        state.write_at(dst, 280 * BLK as u64, &[0xcd; 10]).unwrap();
+        assert_eq!(state.metadata.free_blk_count, free);
+        assert_eq!(contents(&state, src), data);
+        let mut expected = data.clone();
+        expected[280 * BLK..280 * BLK + 10].fill(0xcd);
+        assert_eq!(contents(&state, dst), expected);
+        assert_eq!(fsck::check(&state), vec![]);
+
+        // The blocks go with the last file using them
+        state
+            .remove_node(ROOT_INO, OsStr::new("src"), false)
+            .unwrap();
+        assert_eq!(contents(&state, dst), expected);
+        state
+            .remove_node(ROOT_INO, OsStr::new("dst"), false)
+            .unwrap();
+        assert!(state.refcounts.is_empty());
+        assert_eq!(fsck::check(&state), vec![]);
+    }
+
+    #[test]
+    fn test_copy_range_shares_aligned_blocks() {
+        let mut state = state();
+        let data = pattern(10 * BLK + 100);
+        let src = make_file(&mut state, "src", &data);
+        let dst = make_file(&mut state, "dst", &[]);
+        let free = state.metadata.free_blk_count;
+
+        // Blocks 2..=10 of src, the short last one included, to blocks 1..=9 of dst
+        let copied = state
+            .copy_range(src, 2 * BLK as u64, dst, BLK as u64, 100 * BLK as u64)
+            .unwrap();
+        assert_eq!(copied, 8 * BLK + 100);
+        assert_eq!(state.metadata.free_blk_count, free);
+        let got = contents(&state, dst);
+        assert_eq!(got[..BLK], [0; BLK]);
+        assert_eq!(got[BLK..], data[2 * BLK..]);
+        assert_eq!(fsck::check(&state), vec![]);
+    }
+
+    #[test]
+    fn test_copy_range_copies_unaligned_ranges() {
+        let mut state = state();
+        let data = pattern(4 * BLK);
+        let src = make_file(&mut state, "src", &data);
+        let dst = make_file(&mut state, "dst", &[7; 4 * BLK]);
+        let free = state.metadata.free_blk_count;
+
+        let copied = state.copy_range(src, 10, dst, 20, 2 * BLK as u64).unwrap();
+        assert_eq!(copied, 2 * BLK);
+        assert_eq!(state.metadata.free_blk_count, free);
+        assert!(state.refcounts.is_empty());
+        let mut expected = vec![7; 4 * BLK];
+        expected[20..20 + 2 * BLK].copy_from_slice(&data[10..10 + 2 * BLK]);
+        assert_eq!(contents(&state, dst), expected);
+    }
+
+    #[test]
+    fn test_copy_range_errors() {
+        let mut state = state();
+        let src = make_file(&mut state, "src", &pattern(4 * BLK));
+        let dir = state
+            .make_node(ROOT_INO, OsStr::new("dir"), FileType::Directory, 0o755)
+            .unwrap();
+        assert_eq!(state.copy_range(src, 0, dir, 0, 10), Err(EISDIR));
+        assert_eq!(state.clone_file(dir, src), Err(EISDIR));
+        assert_eq!(state.copy_range(src, 0, src, 100, 200), Err(EINVAL));
+        assert_eq!(
+            state.copy_range(src, 0, src, 2 * BLK as u64, 2 * BLK as u64),
+            Ok(2 * BLK)
+        );
+        assert_eq!(state.copy_range(src, 5 * BLK as u64, src, 0, 10), Ok(0));
+    }
+
+    #[test]
+    fn test_clones_survive_commit_and_load() {
+        let geometry = Geometry::new(256 << 10, 1024, 32).unwrap();
+        for geometry in [
+            geometry.with_journal(64).unwrap(),
+            geometry.with_cow().unwrap(),
+        ] {
+            let device = CrashDevice::new(Vec::new(), None, false);
+            image::format(&device, geometry).unwrap();
+            let mut state = FSState::load(&device).unwrap();
+            let data = pattern(20 * BLK);
+            let src = make_file(&mut state, "src", &data);
+            let dst = make_file(&mut state, "dst", &[]);
+            state.clone_file(src, dst).unwrap();
+            state.commit(&device).unwrap();
+
+            let mut state = FSState::load(&device).unwrap();
+            assert_eq!(fsck::check(&state), vec![]);
+            state.write_at(src, 0, &[0xcd; 10]).unwrap();
+            state.commit(&device).unwrap();
+
+            let state = FSState::load(&device).unwrap();
+            assert_eq!(contents(&state, dst), data);
+            assert_eq!(contents(&state, src)[..10], [0xcd; 10]);
+            assert_eq!(fsck::check(&state), vec![]);
+        }
+    }
+}
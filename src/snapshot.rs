@@ -0,0 +1,734 @@
+use crate::attr::Timestamp;
+use crate::bmap::tree_roots;
+use crate::image::{Cursor, ImageError, Writer};
+use crate::{
+    blk_errno, inode_errno, BlockError, FSState, Inode, INODE_SIZE_BYTES, INVALID_PTR,
+    RESERVED_INODES, SUPER_BLK_NO,
+};
+use libc::{c_int, EEXIST, EINVAL, ENAMETOOLONG, ENOENT, ENOSPC};
+use std::ffi::{OsStr, OsString};
+use std::mem;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::time::SystemTime;
+
+// A snapshot is a frozen copy of the inode table. Its inodes point at the same blocks the live
+// ones did when it was taken, so those blocks are shared instead of copied. A shared block
+// counts its references in FSState.refcounts, the bitmap bit only says that it is in use, and
+// it is freed along with the last reference. Like btrfs, a reference is taken for every pointer
+// to a block, not for every inode that reaches it: taking a snapshot shares the blocks its
+// inodes point at directly, and copying a shared pointer block shares its children in turn.
+// Whatever is about to change a shared block gets a copy of its own first, see unshare_blk.
+//
+// On disk, metadata.snap_blk points at the snapshot list:
+// magic | count | refcount chain | 0, then count entries of SNAP_ENTRY_LEN bytes:
+// id | created (i64) | inode table chain | name_len (u8) | name
+// A chain is a list of blocks holding next | len | len bytes of payload. An inode table is the
+// serialized inodes in use when the snapshot was taken, the refcounts (blk_no, refs) pairs.
+const SNAP_MAGIC: u32 = 0x4c53_5352; // "RSSL"
+const SNAP_LIST_HEADER_LEN: usize = 16;
+const SNAP_ENTRY_LEN: usize = 64;
+const MAX_SNAP_NAME_LEN: usize = SNAP_ENTRY_LEN - 17;
+const CHAIN_HEADER_LEN: usize = 8;
+
+pub(crate) struct Snapshot {
+    pub(crate) id: u32,
+    pub(crate) name: OsString,
+    pub(crate) created: Timestamp,
+    pub(crate) inodes: Box<[Option<Inode>]>,
+    // Where the inode table is stored, empty until the next commit writes it
+    pub(crate) table_blks: Vec<u32>,
+}
+
+fn check_snapshot_name(name: &OsStr) -> Result<(), c_int> {
+    if name.is_empty() || name == "." || name == ".." || name.as_bytes().contains(&b'/') {
+        return Err(EINVAL);
+    }
+    match name.len() > MAX_SNAP_NAME_LEN {
+        true => Err(ENAMETOOLONG),
+        false => Ok(()),
+    }
+}
+
+// The blocks an inode points at directly, each of which holds a reference
+fn root_blks(inode: &Inode) -> Vec<u32> {
+    let mut blks = tree_roots(inode);
+    if inode.xattr_blk != INVALID_PTR {
+        blks.push(inode.xattr_blk);
+    }
+    blks
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+impl FSState {
+    // Blocks referenced once have no refcount entry and count 1 while they are in use
+    pub(crate) fn blk_refs(&self, blk_no: u32) -> u32 {
+        match self.refcounts.get(&blk_no) {
+            Some(&refs) => refs,
+            None => self
+                .blk_bitmap
+                .map
+                .get(blk_no as usize)
+                .is_some_and(|in_use| *in_use) as u32,
+        }
+    }
+
+    pub(crate) fn share_blk(&mut self, blk_no: u32) {
+        let refs = self.blk_refs(blk_no);
+        self.refcounts.insert(blk_no, refs + 1);
+        self.snap_dirty = true;
+    }
+
+    // Takes a reference away from a shared block. Returns false when blk_no has a single one,
+    // which frees it.
+    pub(crate) fn drop_shared_ref(&mut self, blk_no: u32) -> bool {
+        let Some(refs) = self.refcounts.get_mut(&blk_no) else {
+            return false;
+        };
+        *refs -= 1;
+        if *refs < 2 {
+            self.refcounts.remove(&blk_no);
+        }
+        self.snap_dirty = true;
+        true
+    }
+
+    // Returns a block the caller can change in place of blk_no, which is a copy when blk_no is
+    // shared. depth is the number of pointer levels below blk_no, a pointer block's copy takes
+    // a reference to everything it points at.
+    pub(crate) fn unshare_blk(&mut self, blk_no: u32, depth: u32) -> Result<u32, BlockError> {
+        if blk_no == INVALID_PTR || self.blk_refs(blk_no) < 2 {
+            return Ok(blk_no);
+        }
+        let copy = self.alloc_blk()?;
+        if let Some(data) = self.blk_data(blk_no) {
+            self.blk_data_mut(copy)?.copy_from_slice(&data);
+            if depth > 0 {
+                for ptr in data.chunks_exact(4) {
+                    let ptr = u32::from_le_bytes(ptr.try_into().unwrap());
+                    if ptr != INVALID_PTR {
+                        self.share_blk(ptr);
+                    }
+                }
+            }
+        }
+        self.free_blk(blk_no)?;
+        Ok(copy)
+    }
+
+    // A block the next copy-on-write commit would move must not be shared, or whatever else
+    // points at it is left pointing at where it was. Those blocks move now instead.
+    pub(crate) fn settle_before_sharing(&mut self) -> Result<(), BlockError> {
+        match self.metadata.cow {
+            true => self.copy_on_write(),
+            false => Ok(()),
+        }
+    }
+
+    fn share_inode(&mut self, inode: &Inode) {
+        for blk_no in root_blks(inode) {
+            self.share_blk(blk_no);
+        }
+    }
+
+    // Drops inode's references, freeing whatever nothing else uses
+    fn release_blks(&mut self, inode: &Inode) -> Result<(), BlockError> {
+        let mut inode = *inode;
+        if !inode.has_inline_data() {
+            self.free_blks_from(&mut inode, 0)?;
+        }
+        if inode.xattr_blk != INVALID_PTR {
+            self.free_blk(inode.xattr_blk)?;
+        }
+        Ok(())
+    }
+
+    fn max_snapshots(&self) -> usize {
+        (self.blk_size() as usize - SNAP_LIST_HEADER_LEN) / SNAP_ENTRY_LEN
+    }
+
+    fn snapshot_idx(&self, name: &OsStr) -> Result<usize, c_int> {
+        self.snapshots
+            .iter()
+            .position(|snapshot| snapshot.name == name)
+            .ok_or(ENOENT)
+    }
+
+    pub fn snapshot_id(&self, name: &OsStr) -> Result<u32, c_int> {
+        Ok(self.snapshots[self.snapshot_idx(name)?].id)
+    }
+
+    // Every snapshot with its id and when it was taken, oldest first
+    pub fn list_snapshots(&self) -> Vec<(u32, OsString, SystemTime)> {
+        self.snapshots
+            .iter()
+            .map(|snapshot| (snapshot.id, snapshot.name.clone(), snapshot.created.into()))
+            .collect()
+    }
+
+    fn push_down_inode(&mut self, ino_id: usize) -> Result<(), c_int> {
+        let Some(mut inode) = self.inodes[ino_id].filter(Inode::has_extents) else {
+            return Ok(());
+        };
+        self.push_down_extents(&mut inode).map_err(blk_errno)?;
+        if Some(inode) != self.inodes[ino_id] {
+            *self.inode_mut(ino_id as u32).map_err(inode_errno)? = inode;
+        }
+        Ok(())
+    }
+
+    // What create_snapshot does to the inodes before sharing their blocks, which on a large
+    // filesystem changes more of them than one transaction holds. Stops once the transaction is
+    // full and returns true, a commit then makes room for the rest.
+    pub fn prepare_snapshot(&mut self) -> Result<bool, c_int> {
+        for ino_id in 0..self.inodes.len() {
+            if self.transaction_full() {
+                return Ok(true);
+            }
+            self.push_down_inode(ino_id)?;
+        }
+        Ok(false)
+    }
+
+    // Freezes the current inode table under name and returns the new snapshot's id. Inodes
+    // unlinked while still open are left out.
+    pub fn create_snapshot(&mut self, name: &OsStr) -> Result<u32, c_int> {
+        check_snapshot_name(name)?;
+        if self.snapshot_idx(name).is_ok() {
+            return Err(EEXIST);
+        }
+        if self.snapshots.len() >= self.max_snapshots() {
+            return Err(ENOSPC);
+        }
+        self.flush_delayed()?;
+        for ino_id in 0..self.inodes.len() {
+            self.push_down_inode(ino_id)?;
+        }
+        self.settle_before_sharing().map_err(blk_errno)?;
+        let inodes: Box<[Option<Inode>]> = self
+            .inodes
+            .iter()
+            .map(|inode| inode.filter(|inode| inode.nlink > 0))
+            .collect();
+        for inode in inodes.iter().flatten() {
+            self.share_inode(inode);
+        }
+        let id = self.snapshots.iter().map(|s| s.id).max().unwrap_or(0) + 1;
+        self.snapshots.push(Snapshot {
+            id,
+            name: name.to_owned(),
+            created: Timestamp::now(),
+            inodes,
+            table_blks: Vec::new(),
+        });
+        self.snap_dirty = true;
+        self.mark_dirty(SUPER_BLK_NO);
+        Ok(id)
+    }
+
+    // Frees the snapshot and every block only it still uses
+    pub fn delete_snapshot(&mut self, name: &OsStr) -> Result<(), c_int> {
+        let snapshot = self.snapshots.remove(self.snapshot_idx(name)?);
+        for inode in snapshot.inodes.iter().flatten() {
+            self.release_blks(inode).map_err(blk_errno)?;
+        }
+        for blk_no in snapshot.table_blks {
+            self.free_blk(blk_no).map_err(blk_errno)?;
+        }
+        self.snap_dirty = true;
+        self.mark_dirty(SUPER_BLK_NO);
+        Ok(())
+    }
+
+    // Puts the filesystem back the way it was when the snapshot was taken. The snapshot stays,
+    // and everything changed since then is lost. Only for unmounted images, since nothing
+    // open survives this.
+    pub fn rollback_snapshot(&mut self, name: &OsStr) -> Result<(), c_int> {
+        let inodes = self.snapshots[self.snapshot_idx(name)?].inodes.clone();
+        // The snapshot's references go first, so nothing it shares is freed along with the
+        // live inodes
+        for inode in inodes.iter().flatten() {
+            self.share_inode(inode);
+        }
+        let live = mem::replace(&mut self.inodes, inodes);
+        for inode in live.iter().flatten() {
+            self.release_blks(inode).map_err(blk_errno)?;
+        }
+        self.open_handles.clear();
+        self.delayed.drop_all();
+        for slot in RESERVED_INODES as usize..self.inodes.len() {
+            let in_use = self.inodes[slot].is_some();
+            self.inode_bitmap.map.set(slot, in_use);
+        }
+        let metadata = &mut self.metadata;
+        metadata.free_ino_count = metadata.ino_count - self.inode_bitmap.map.count_ones() as u32;
+        for ino_id in 0..self.metadata.ino_count {
+            self.mark_ino_alloc_dirty(ino_id);
+        }
+        self.recount_groups();
+        Ok(())
+    }
+
+    // Runs f with the snapshot's inodes in place of the live ones. Their blocks are shared with
+    // the live filesystem or belong to the snapshot alone, so reads find the contents as they
+    // were.
+    pub fn with_snapshot<R>(&mut self, id: u32, f: impl FnOnce(&FSState) -> R) -> Result<R, c_int> {
+        let idx = self
+            .snapshots
+            .iter()
+            .position(|snapshot| snapshot.id == id)
+            .ok_or(ENOENT)?;
+        // Pages held for live files are not part of the snapshot
+        let pages = self.delayed.take_all();
+        mem::swap(&mut self.inodes, &mut self.snapshots[idx].inodes);
+        let result = f(self);
+        mem::swap(&mut self.inodes, &mut self.snapshots[idx].inodes);
+        self.delayed.put_all(pages);
+        Ok(result)
+    }
+
+    // Writes payload to newly allocated blocks and returns them in chain order
+    fn write_chain(&mut self, payload: &[u8]) -> Result<Vec<u32>, BlockError> {
+        let room = self.blk_size() as usize - CHAIN_HEADER_LEN;
+        let mut blks = Vec::new();
+        for _ in payload.chunks(room) {
+            match self.alloc_blk() {
+                Ok(blk_no) => blks.push(blk_no),
+                Err(err) => {
+                    for blk_no in blks {
+                        self.free_blk(blk_no)?;
+                    }
+                    return Err(err);
+                }
+            }
+        }
+        for (idx, chunk) in payload.chunks(room).enumerate() {
+            let next = blks.get(idx + 1).copied().unwrap_or(INVALID_PTR);
+            let data = self.blk_data_mut(blks[idx])?;
+            let mut w = Writer::new(data);
+            w.put(&next.to_le_bytes());
+            w.put(&(chunk.len() as u32).to_le_bytes());
+            w.put(chunk);
+        }
+        Ok(blks)
+    }
+
+    // The payload of the chain starting at head and the blocks it is kept in, None when the
+    // chain is broken
+    pub(crate) fn read_chain(&self, head: u32) -> Option<(Vec<u8>, Vec<u32>)> {
+        let room = self.blk_size() as usize - CHAIN_HEADER_LEN;
+        let (mut payload, mut blks) = (Vec::new(), Vec::new());
+        let mut blk_no = head;
+        while blk_no != INVALID_PTR {
+            if blks.len() >= self.metadata.blk_count as usize
+                || blk_no < self.geometry().reserved_blks()
+            {
+                return None;
+            }
+            let data = &self.blk_data(blk_no)?;
+            let len = read_u32(data, 4) as usize;
+            if len > room {
+                return None;
+            }
+            payload.extend(&data[CHAIN_HEADER_LEN..CHAIN_HEADER_LEN + len]);
+            blks.push(blk_no);
+            blk_no = read_u32(data, 0);
+        }
+        Some((payload, blks))
+    }
+
+    // The snapshot list and the refcount chain, which are written anew whenever they change
+    fn snapshot_list_blks(&self) -> Vec<u32> {
+        let list = self.metadata.snap_blk;
+        let Some(data) = &self.blk_data(list) else {
+            return Vec::new();
+        };
+        let mut blks = vec![list];
+        if let Some((_, chain)) = self.read_chain(read_u32(data, 8)) {
+            blks.extend(chain);
+        }
+        blks
+    }
+
+    // Every block holding snapshot metadata rather than file data, for fsck
+    pub(crate) fn snapshot_meta_blks(&self) -> Vec<u32> {
+        let mut blks = self.snapshot_list_blks();
+        for snapshot in &self.snapshots {
+            blks.extend(&snapshot.table_blks);
+        }
+        blks
+    }
+
+    // Writes out the snapshot list and refcounts when they changed, along with the inode
+    // tables of new snapshots. Nothing already written is changed in place: the list and
+    // refcounts go to new blocks and the superblock switches to them in the same commit. The
+    // old ones are only freed after, so the new blocks are never ones the image on disk still
+    // uses and the commit can write them home ahead of the journal, however many there are.
+    pub(crate) fn flush_snapshots(&mut self) -> Result<(), BlockError> {
+        if !self.snap_dirty {
+            return Ok(());
+        }
+        let old = self.snapshot_list_blks();
+        self.mark_dirty(SUPER_BLK_NO);
+
+        for idx in 0..self.snapshots.len() {
+            if !self.snapshots[idx].table_blks.is_empty() {
+                continue;
+            }
+            let mut table = Vec::new();
+            for inode in self.snapshots[idx].inodes.iter().flatten() {
+                let mut slot = [0; INODE_SIZE_BYTES];
+                inode.serialize(&mut slot);
+                table.extend(slot);
+            }
+            self.snapshots[idx].table_blks = self.write_chain(&table)?;
+        }
+        if !self.snapshots.is_empty() || !self.refcounts.is_empty() {
+            let refs: Vec<u8> = self
+                .refcounts
+                .iter()
+                .flat_map(|(&blk_no, &refs)| [blk_no.to_le_bytes(), refs.to_le_bytes()])
+                .flatten()
+                .collect();
+            let refs_head = self.write_chain(&refs)?.first().copied();
+            let list = self.alloc_blk()?;
+            let blk_size = self.blk_size() as usize;
+            let mut buf = vec![0; blk_size];
+            let mut w = Writer::new(&mut buf);
+            w.put(&SNAP_MAGIC.to_le_bytes());
+            w.put(&(self.snapshots.len() as u32).to_le_bytes());
+            w.put(&refs_head.unwrap_or(INVALID_PTR).to_le_bytes());
+            let entries = buf[SNAP_LIST_HEADER_LEN..].chunks_exact_mut(SNAP_ENTRY_LEN);
+            for (snapshot, entry) in self.snapshots.iter().zip(entries) {
+                let mut w = Writer::new(entry);
+                w.put(&snapshot.id.to_le_bytes());
+                w.put(&snapshot.created.0.to_le_bytes());
+                w.put(&snapshot.table_blks[0].to_le_bytes());
+                w.put(&[snapshot.name.len() as u8]);
+                w.put(snapshot.name.as_bytes());
+            }
+            self.blk_data_mut(list)?.copy_from_slice(&buf);
+            self.metadata.snap_blk = list;
+        } else {
+            self.metadata.snap_blk = INVALID_PTR;
+        }
+        for blk_no in old {
+            self.free_blk(blk_no)?;
+        }
+        self.snap_dirty = false;
+        Ok(())
+    }
+
+    // Reads back what flush_snapshots wrote, once the blocks are loaded
+    pub(crate) fn load_snapshots(&mut self) -> Result<(), ImageError> {
+        let list = self.metadata.snap_blk;
+        if list == INVALID_PTR {
+            return Ok(());
+        }
+        let data = self
+            .blk_data(list)
+            .ok_or(ImageError::CorruptSnapshots)?
+            .to_vec();
+        let mut c = Cursor::new(&data);
+        let (magic, count, refs_head) = (c.u32(), c.u32() as usize, c.u32());
+        if magic != SNAP_MAGIC || count > self.max_snapshots() {
+            return Err(ImageError::CorruptSnapshots);
+        }
+        let (refs, _) = self
+            .read_chain(refs_head)
+            .ok_or(ImageError::CorruptSnapshots)?;
+        for pair in refs.chunks_exact(8) {
+            self.refcounts.insert(read_u32(pair, 0), read_u32(pair, 4));
+        }
+
+        let entries = data[SNAP_LIST_HEADER_LEN..].chunks_exact(SNAP_ENTRY_LEN);
+        for entry in entries.take(count) {
+            let mut c = Cursor::new(entry);
+            let (id, created, head) = (c.u32(), Timestamp(c.i64()), c.u32());
+            let name_len = c.u8() as usize;
+            if name_len > MAX_SNAP_NAME_LEN {
+                return Err(ImageError::CorruptSnapshots);
+            }
+            let name = OsString::from_vec(entry[17..17 + name_len].to_vec());
+            let (table, table_blks) = self.read_chain(head).ok_or(ImageError::CorruptSnapshots)?;
+            let mut inodes = vec![None; self.inodes.len()].into_boxed_slice();
+            for slot in table.chunks_exact(INODE_SIZE_BYTES) {
+                let Some(inode) = Inode::deserialize(slot)? else {
+                    continue;
+                };
+                *inodes
+                    .get_mut(inode.ino_id as usize)
+                    .ok_or(ImageError::CorruptInode(inode.ino_id))? = Some(inode);
+            }
+            self.snapshots.push(Snapshot {
+                id,
+                name,
+                created,
+                inodes,
+                table_blks,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crash::{self, CrashDevice};
+    use crate::image::{self, Geometry, DEFAULT_FEATURES, FEATURE_EXTENTS};
+    use crate::journal::min_journal_blks;
+    use crate::test_util::{self, make_file, state_with_root};
+    use crate::{fsck, ROOT_INO};
+    use fuser::FileType;
+
+    fn geometry() -> Geometry {
+        Geometry::new(1 << 20, 1024, 32).unwrap()
+    }
+
+    fn contents(state: &FSState, name: &str) -> Option<Vec<u8>> {
+        let ino_id = state.lookup_entry(ROOT_INO, OsStr::new(name)).ok()?;
+        Some(test_util::contents(state, ino_id))
+    }
+
+    fn snapshot_contents(state: &mut FSState, snap: &str, name: &str) -> Option<Vec<u8>> {
+        let id = state.snapshot_id(OsStr::new(snap)).unwrap();
+        state
+            .with_snapshot(id, |state| contents(state, name))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_snapshot_keeps_old_contents() {
+        let mut state = state_with_root(geometry());
+        // 300 blocks, so the file has an indirect and a double indirect block to share
+        let ino_id = make_file(&mut state, "file", &vec![0xab; 300 * 1024]);
+        let free = state.metadata.free_blk_count;
+        state.create_snapshot(OsStr::new("snap")).unwrap();
+        assert_eq!(state.metadata.free_blk_count, free);
+
+        state.write_at(ino_id, 280 * 1024, &[0xcd; 1024]).unwrap();
+        make_file(&mut state, "new", &[1; 10]);
+        state
+            .remove_node(ROOT_INO, OsStr::new("file"), false)
+            .unwrap();
+        assert_eq!(fsck::check(&state), vec![]);
+
+        assert_eq!(
+            snapshot_contents(&mut state, "snap", "file"),
+            Some(vec![0xab; 300 * 1024])
+        );
+        assert_eq!(snapshot_contents(&mut state, "snap", "new"), None);
+        assert_eq!(contents(&state, "new"), Some(vec![1; 10]));
+        assert_eq!(contents(&state, "file"), None);
+    }
+
+    #[test]
+    fn test_changing_a_shared_file_copies_only_its_path() {
+        let mut state = state_with_root(geometry());
+        let ino_id = make_file(&mut state, "file", &vec![0xab; 300 * 1024]);
+        state.create_snapshot(OsStr::new("snap")).unwrap();
+        let free = state.metadata.free_blk_count;
+
+        // The data block, the double indirect block and the indirect block below it
+        state.write_at(ino_id, 280 * 1024, &[0xcd; 1024]).unwrap();
+        assert_eq!(state.metadata.free_blk_count, free - 3);
+        let mut expected = vec![0xab; 300 * 1024];
+        expected[280 * 1024..281 * 1024].fill(0xcd);
+        assert_eq!(contents(&state, "file"), Some(expected));
+        assert_eq!(fsck::check(&state), vec![]);
+    }
+
+    #[test]
+    fn test_delete_frees_what_only_the_snapshot_uses() {
+        let mut state = state_with_root(geometry());
+        make_file(&mut state, "file", &vec![0xab; 20 * 1024]);
+        let free = state.metadata.free_blk_count;
+        state.create_snapshot(OsStr::new("snap")).unwrap();
+        state
+            .remove_node(ROOT_INO, OsStr::new("file"), false)
+            .unwrap();
+        // Only the root directory's block is copied, to drop the entry
+        assert_eq!(state.metadata.free_blk_count, free - 1);
+
+        // The file's 20 blocks and indirect block, and the old root directory block
+        state.delete_snapshot(OsStr::new("snap")).unwrap();
+        assert_eq!(state.metadata.free_blk_count, free + 21);
+        assert!(state.refcounts.is_empty());
+        assert!(state.list_snapshots().is_empty());
+        assert_eq!(fsck::check(&state), vec![]);
+    }
+
+    #[test]
+    fn test_rollback_restores_the_snapshot() {
+        let mut state = state_with_root(geometry());
+        make_file(&mut state, "file", &vec![0xab; 5000]);
+        state.create_snapshot(OsStr::new("snap")).unwrap();
+        let free = state.metadata.free_blk_count;
+
+        let ino_id = state.lookup_entry(ROOT_INO, OsStr::new("file")).unwrap();
+        state.write_at(ino_id, 0, &[0xcd; 100]).unwrap();
+        make_file(&mut state, "new", &vec![1; 3000]);
+        state
+            .make_node(ROOT_INO, OsStr::new("dir"), FileType::Directory, 0o755)
+            .unwrap();
+
+        state.rollback_snapshot(OsStr::new("snap")).unwrap();
+        assert_eq!(contents(&state, "file"), Some(vec![0xab; 5000]));
+        assert_eq!(contents(&state, "new"), None);
+        assert_eq!(state.metadata.free_blk_count, free);
+        assert_eq!(state.list_snapshots().len(), 1);
+        assert_eq!(fsck::check(&state), vec![]);
+
+        // Changing the rolled back files leaves the snapshot as it was
+        state.write_at(ino_id, 0, &[0xcd; 100]).unwrap();
+        assert_eq!(
+            snapshot_contents(&mut state, "snap", "file"),
+            Some(vec![0xab; 5000])
+        );
+    }
+
+    #[test]
+    fn test_snapshots_survive_save_and_load() {
+        let device = CrashDevice::new(Vec::new(), None, false);
+        image::format(&device, geometry()).unwrap();
+        let mut state = FSState::load(&device).unwrap();
+        let ino_id = make_file(&mut state, "file", &vec![0xab; 3000]);
+        state.create_snapshot(OsStr::new("one")).unwrap();
+        state.write_at(ino_id, 0, &[0xcd; 3000]).unwrap();
+        state.create_snapshot(OsStr::new("two")).unwrap();
+        state.save(&device).unwrap();
+
+        let mut state = FSState::load(&device).unwrap();
+        let names: Vec<_> = state
+            .list_snapshots()
+            .into_iter()
+            .map(|(id, name, _)| (id, name))
+            .collect();
+        assert_eq!(names, [(1, "one".into()), (2, "two".into())]);
+        assert_eq!(
+            snapshot_contents(&mut state, "one", "file"),
+            Some(vec![0xab; 3000])
+        );
+        assert_eq!(
+            snapshot_contents(&mut state, "two", "file"),
+            Some(vec![0xcd; 3000])
+        );
+        assert_eq!(fsck::check(&state), vec![]);
+
+        state.delete_snapshot(OsStr::new("one")).unwrap();
+        state.delete_snapshot(OsStr::new("two")).unwrap();
+        state.save(&device).unwrap();
+        let state = FSState::load(&device).unwrap();
+        assert_eq!(state.metadata.snap_blk, INVALID_PTR);
+        assert_eq!(fsck::check(&state), vec![]);
+    }
+
+    #[test]
+    fn test_snapshot_names() {
+        let mut state = state_with_root(geometry());
+        state.create_snapshot(OsStr::new("snap")).unwrap();
+        assert_eq!(state.create_snapshot(OsStr::new("snap")), Err(EEXIST));
+        for name in ["", ".", "..", "a/b"] {
+            assert_eq!(state.create_snapshot(OsStr::new(name)), Err(EINVAL));
+        }
+        let long = "x".repeat(MAX_SNAP_NAME_LEN + 1);
+        assert_eq!(state.create_snapshot(OsStr::new(&long)), Err(ENAMETOOLONG));
+        assert_eq!(state.delete_snapshot(OsStr::new("none")), Err(ENOENT));
+        assert_eq!(state.rollback_snapshot(OsStr::new("none")), Err(ENOENT));
+
+        // One list block of 1 KiB holds 15 of them
+        for idx in 1..15 {
+            state.create_snapshot(OsStr::new(&idx.to_string())).unwrap();
+        }
+        assert_eq!(state.create_snapshot(OsStr::new("full")), Err(ENOSPC));
+    }
+
+    #[test]
+    fn test_wrong_refcount_is_repaired() {
+        let mut state = state_with_root(geometry());
+        let ino_id = make_file(&mut state, "file", &vec![0xab; 3000]);
+        state.create_snapshot(OsStr::new("snap")).unwrap();
+        let blk_no = state.inode(ino_id).unwrap().direct_blks[0];
+        state.refcounts.insert(blk_no, 3);
+
+        assert_eq!(
+            fsck::check(&state),
+            vec![fsck::Inconsistency::RefCount {
+                blk_no,
+                recorded: 3,
+                actual: 2
+            }]
+        );
+        fsck::repair(&mut state);
+        assert_eq!(state.refcounts[&blk_no], 2);
+        assert_eq!(fsck::check(&state), vec![]);
+    }
+
+    fn cow_geometry() -> Geometry {
+        Geometry::new(256 << 10, 1024, 32)
+            .unwrap()
+            .with_cow()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_snapshot_is_atomic() {
+        let before = crash::image_with(cow_geometry(), |state| {
+            make_file(state, "file", &vec![0xab; 3000]);
+        });
+        let outcomes = crash::crash_outcomes(before, |state| {
+            let ino_id = state.lookup_entry(ROOT_INO, OsStr::new("file")).unwrap();
+            state.write_at(ino_id, 0, &[0xcd; 100]).unwrap();
+            state.create_snapshot(OsStr::new("snap")).unwrap();
+        });
+        for mut state in outcomes {
+            let file = contents(&state, "file").unwrap();
+            match state.snapshot_id(OsStr::new("snap")) {
+                Ok(_) => {
+                    assert_eq!(file[..100], [0xcd; 100]);
+                    let snap = snapshot_contents(&mut state, "snap", "file").unwrap();
+                    assert_eq!(snap, file);
+                }
+                Err(_) => assert_eq!(file, vec![0xab; 3000]),
+            }
+        }
+    }
+
+    #[test]
+    fn test_snapshot_of_many_inodes_fits_the_journal() {
+        let geometry = Geometry::new(8 << 20, 1024, 1024).unwrap();
+        let geometry = geometry
+            .with_journal(min_journal_blks(&geometry) as u32)
+            .unwrap();
+        for features in [DEFAULT_FEATURES, DEFAULT_FEATURES | FEATURE_EXTENTS] {
+            let device = CrashDevice::new(Vec::new(), None, false);
+            image::format_with_features(&device, geometry, features).unwrap();
+            let mut state = FSState::load(&device).unwrap();
+            // Their inode table alone takes some 150 blocks
+            for idx in 0..600 {
+                make_file(&mut state, &format!("file{idx}"), &[idx as u8; 2000]);
+            }
+            state.save(&device).unwrap();
+
+            let mut state = FSState::load(&device).unwrap();
+            while state.prepare_snapshot().unwrap() {
+                state.commit(&device).unwrap();
+            }
+            state.create_snapshot(OsStr::new("snap")).unwrap();
+            state.commit(&device).unwrap();
+            make_file(&mut state, "new", &[1; 10]);
+            state.commit(&device).unwrap();
+
+            let mut state = FSState::load(&device).unwrap();
+            assert_eq!(fsck::check(&state), vec![]);
+            assert_eq!(
+                snapshot_contents(&mut state, "snap", "file599"),
+                Some(vec![599_u32 as u8; 2000])
+            );
+            assert_eq!(snapshot_contents(&mut state, "snap", "new"), None);
+        }
+    }
+}
@@ -0,0 +1,175 @@
+use crate::{inode_errno, FSState, Inode, INODE_FLAG_INLINE_DATA, NUM_INO_DIRECT_PTR};
+use fuser::FileType;
+use libc::{c_int, EINVAL, ENAMETOOLONG, ENOENT, ENOSPC};
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+
+// Like ext2 fast symlinks, targets shorter than the block pointer area (direct_blks and the
+// three indirect pointers) are stored in it instead of in a data block
+pub(crate) const INLINE_DATA_LEN: usize = (NUM_INO_DIRECT_PTR + 3) * 4;
+
+impl Inode {
+    pub(crate) fn has_inline_data(&self) -> bool {
+        self.flags & INODE_FLAG_INLINE_DATA != 0
+    }
+
+    pub(crate) fn inline_data(&self) -> [u8; INLINE_DATA_LEN] {
+        self.ptr_area()
+    }
+
+    // Replaces the block pointers with data, which must fit in INLINE_DATA_LEN bytes
+    pub(crate) fn set_inline_data(&mut self, data: &[u8]) {
+        self.set_ptr_area(data);
+        self.flags |= INODE_FLAG_INLINE_DATA;
+    }
+
+    // The block pointers as bytes, for whatever is kept there in their place
+    pub(crate) fn ptr_area(&self) -> [u8; INLINE_DATA_LEN] {
+        let mut data = [0; INLINE_DATA_LEN];
+        let ptrs = self.direct_blks.iter().chain([
+            &self.indirect_blk,
+            &self.dbl_indirect_blk,
+            &self.tri_indirect_blk,
+        ]);
+        for (chunk, ptr) in data.chunks_exact_mut(4).zip(ptrs) {
+            chunk.copy_from_slice(&ptr.to_le_bytes());
+        }
+        data
+    }
+
+    pub(crate) fn set_ptr_area(&mut self, data: &[u8]) {
+        let mut buf = [0; INLINE_DATA_LEN];
+        buf[..data.len()].copy_from_slice(data);
+        let mut ptrs = buf
+            .chunks_exact(4)
+            .map(|chunk| u32::from_le_bytes(chunk.try_into().unwrap()));
+        for ptr in self.direct_blks.iter_mut() {
+            *ptr = ptrs.next().unwrap();
+        }
+        self.indirect_blk = ptrs.next().unwrap();
+        self.dbl_indirect_blk = ptrs.next().unwrap();
+        self.tri_indirect_blk = ptrs.next().unwrap();
+    }
+}
+
+impl FSState {
+    // Creates a symlink in parent pointing at target. Targets are limited to one block, the
+    // same as ext2.
+    pub fn make_symlink(
+        &mut self,
+        parent: u32,
+        name: &OsStr,
+        target: &OsStr,
+        uid: u32,
+        gid: u32,
+    ) -> Result<u32, c_int> {
+        let target = target.as_bytes();
+        if target.is_empty() {
+            return Err(ENOENT);
+        }
+        if target.len() as u64 > self.blk_size() {
+            return Err(ENAMETOOLONG);
+        }
+        let ino_id = self.make_node_as(parent, name, FileType::Symlink, 0o777, uid, gid)?;
+        if target.len() < INLINE_DATA_LEN {
+            let inode = self.inode_mut(ino_id).map_err(inode_errno)?;
+            inode.set_inline_data(target);
+            inode.size = target.len() as u64;
+            return Ok(ino_id);
+        }
+        match self.write_at(ino_id, 0, target) {
+            Ok(written) if written == target.len() => Ok(ino_id),
+            result => {
+                let _ = self.remove_node(parent, name, false);
+                Err(result.err().unwrap_or(ENOSPC))
+            }
+        }
+    }
+
+    pub fn read_link(&self, ino_id: u32) -> Result<Vec<u8>, c_int> {
+        let inode = self.inode(ino_id).map_err(inode_errno)?;
+        if inode.kind != FileType::Symlink {
+            return Err(EINVAL);
+        }
+        let mut target = vec![0; inode.size as usize];
+        self.read_inode(inode, 0, &mut target);
+        Ok(target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::Geometry;
+    use crate::test_util::state_with_root;
+    use crate::{fsck, INVALID_PTR, ROOT_INO};
+
+    #[test]
+    fn test_inline_data_roundtrip() {
+        let mut inode = Inode::new(5, FileType::Symlink, 0o777);
+        let data: Vec<u8> = (1..=INLINE_DATA_LEN as u8).collect();
+        inode.set_inline_data(&data);
+        assert!(inode.has_inline_data());
+        assert_eq!(inode.inline_data().to_vec(), data);
+        assert_eq!(inode.tri_indirect_blk, u32::from_le_bytes([57, 58, 59, 60]));
+    }
+
+    #[test]
+    fn test_short_target_is_inline() {
+        let state = &mut state_with_root(Geometry::new(1 << 20, 1024, 32).unwrap());
+        let free = state.metadata.free_blk_count;
+        let ino_id = state
+            .make_symlink(
+                ROOT_INO,
+                OsStr::new("link"),
+                OsStr::new("../some/target"),
+                0,
+                0,
+            )
+            .unwrap();
+        let inode = *state.inode(ino_id).unwrap();
+        assert!(inode.has_inline_data());
+        assert_eq!(inode.blocks, 0);
+        assert_eq!(inode.size, 14);
+        assert_eq!(state.metadata.free_blk_count, free);
+        assert_eq!(state.read_link(ino_id).unwrap(), b"../some/target");
+        assert_eq!(fsck::check(state), vec![]);
+
+        state
+            .remove_node(ROOT_INO, OsStr::new("link"), false)
+            .unwrap();
+        assert_eq!(state.metadata.free_blk_count, free);
+        assert_eq!(fsck::check(state), vec![]);
+    }
+
+    #[test]
+    fn test_long_target_uses_a_block() {
+        let state = &mut state_with_root(Geometry::new(1 << 20, 1024, 32).unwrap());
+        let target = "d/".repeat(INLINE_DATA_LEN / 2);
+        let ino_id = state
+            .make_symlink(ROOT_INO, OsStr::new("link"), OsStr::new(&target), 0, 0)
+            .unwrap();
+        let inode = *state.inode(ino_id).unwrap();
+        assert!(!inode.has_inline_data());
+        assert_eq!(inode.blocks, 1);
+        assert_ne!(inode.direct_blks[0], INVALID_PTR);
+        assert_eq!(state.read_link(ino_id).unwrap(), target.as_bytes());
+        assert_eq!(fsck::check(state), vec![]);
+    }
+
+    #[test]
+    fn test_bad_targets() {
+        let state = &mut state_with_root(Geometry::new(1 << 20, 1024, 32).unwrap());
+        assert_eq!(
+            state.make_symlink(ROOT_INO, OsStr::new("a"), OsStr::new(""), 0, 0),
+            Err(ENOENT)
+        );
+        let long = "x".repeat(1025);
+        assert_eq!(
+            state.make_symlink(ROOT_INO, OsStr::new("a"), OsStr::new(&long), 0, 0),
+            Err(ENAMETOOLONG)
+        );
+        assert_eq!(state.read_link(ROOT_INO), Err(EINVAL));
+        assert_eq!(state.lookup_entry(ROOT_INO, OsStr::new("a")), Err(ENOENT));
+    }
+}
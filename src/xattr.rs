@@ -0,0 +1,425 @@
+use crate::acl::{ACL_ACCESS, ACL_DEFAULT};
+use crate::{blk_errno, inode_errno, FSState, Inode, INVALID_PTR};
+use libc::{c_int, E2BIG, EEXIST, EINVAL, EIO, ENODATA, ENOSPC, EOPNOTSUPP, ERANGE};
+use libc::{XATTR_CREATE, XATTR_REPLACE};
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+
+// Space for attributes in the inode slot itself, see image.rs. Whatever does not fit goes to
+// the inode's xattr block.
+pub(crate) const INLINE_XATTR_LEN: usize = 124;
+
+// Xattr block: magic | refcount | entries. Both areas hold entries back to back,
+// name_index u8 | name_len u8 | value_len u16 | name | value, ending at a zero name_index or
+// when there is no room for another header.
+const XATTR_MAGIC: u32 = 0xea02_0000;
+const XATTR_BLK_HEADER_LEN: usize = 8;
+const ENTRY_HEADER_LEN: usize = 4;
+const MAX_NAME_LEN: usize = 255;
+const MAX_VALUE_LEN: usize = 65536;
+
+// Names are stored without their namespace prefix, like ext2. The ACL names are whole names
+// and come before the "system." namespace they are in.
+const PREFIXES: [(u8, &str); 6] = [
+    (1, "user."),
+    (2, "system.posix_acl_access"),
+    (3, "system.posix_acl_default"),
+    (4, "trusted."),
+    (6, "security."),
+    (7, "system."),
+];
+
+#[derive(Clone, Debug, PartialEq)]
+struct Xattr {
+    index: u8,
+    suffix: Vec<u8>,
+    value: Vec<u8>,
+}
+
+impl Xattr {
+    fn name(&self) -> Vec<u8> {
+        let (_, prefix) = PREFIXES
+            .iter()
+            .find(|(index, _)| *index == self.index)
+            .unwrap();
+        [prefix.as_bytes(), &self.suffix].concat()
+    }
+
+    fn len(&self) -> usize {
+        ENTRY_HEADER_LEN + self.suffix.len() + self.value.len()
+    }
+}
+
+// Splits a name into its namespace index and the rest
+fn split_name(name: &OsStr) -> Result<(u8, &[u8]), c_int> {
+    let name = name.as_bytes();
+    if name.len() > MAX_NAME_LEN {
+        return Err(ERANGE);
+    }
+    let (index, prefix) = PREFIXES
+        .iter()
+        .find(|(_, prefix)| name.starts_with(prefix.as_bytes()))
+        .ok_or(EOPNOTSUPP)?;
+    let suffix = &name[prefix.len()..];
+    if suffix.is_empty() && prefix.ends_with('.') {
+        return Err(EINVAL);
+    }
+    Ok((*index, suffix))
+}
+
+fn decode_entries(mut buf: &[u8]) -> Vec<Xattr> {
+    let mut entries = Vec::new();
+    while buf.len() >= ENTRY_HEADER_LEN && buf[0] != 0 {
+        let name_len = buf[1] as usize;
+        let value_len = u16::from_le_bytes([buf[2], buf[3]]) as usize;
+        let Some(rest) = buf.get(ENTRY_HEADER_LEN..ENTRY_HEADER_LEN + name_len + value_len) else {
+            break;
+        };
+        entries.push(Xattr {
+            index: buf[0],
+            suffix: rest[..name_len].to_vec(),
+            value: rest[name_len..].to_vec(),
+        });
+        buf = &buf[ENTRY_HEADER_LEN + name_len + value_len..];
+    }
+    entries
+}
+
+// Fills buf with entries followed by zeros. The entries have to fit.
+fn encode_entries(entries: &[&Xattr], buf: &mut [u8]) {
+    buf.fill(0);
+    let mut pos = 0;
+    for entry in entries {
+        buf[pos] = entry.index;
+        buf[pos + 1] = entry.suffix.len() as u8;
+        buf[pos + 2..pos + 4].copy_from_slice(&(entry.value.len() as u16).to_le_bytes());
+        pos += ENTRY_HEADER_LEN;
+        buf[pos..pos + entry.suffix.len()].copy_from_slice(&entry.suffix);
+        pos += entry.suffix.len();
+        buf[pos..pos + entry.value.len()].copy_from_slice(&entry.value);
+        pos += entry.value.len();
+    }
+}
+
+impl FSState {
+    fn xattr_blk_capacity(&self) -> usize {
+        self.blk_size() as usize - XATTR_BLK_HEADER_LEN
+    }
+
+    // Inline attributes first, then the ones in the block
+    fn xattrs(&self, inode: &Inode) -> Result<Vec<Xattr>, c_int> {
+        let mut entries = decode_entries(&inode.xattr_inline);
+        if inode.xattr_blk != INVALID_PTR {
+            let data = self.blk_data(inode.xattr_blk).ok_or(EIO)?;
+            if data[..4] != XATTR_MAGIC.to_le_bytes() {
+                return Err(EIO);
+            }
+            entries.extend(decode_entries(&data[XATTR_BLK_HEADER_LEN..]));
+        }
+        Ok(entries)
+    }
+
+    // Lays entries out again from scratch: each one goes inline if it still fits there,
+    // otherwise in the block, which is allocated or freed as needed
+    fn store_xattrs(&mut self, ino_id: u32, entries: &[Xattr]) -> Result<(), c_int> {
+        let mut inode = *self.inode(ino_id).map_err(inode_errno)?;
+        let (mut inline, mut spilled) = (Vec::new(), Vec::new());
+        let mut inline_len = 0;
+        for entry in entries {
+            if inline_len + entry.len() <= INLINE_XATTR_LEN {
+                inline_len += entry.len();
+                inline.push(entry);
+            } else {
+                spilled.push(entry);
+            }
+        }
+        if spilled.iter().map(|entry| entry.len()).sum::<usize>() > self.xattr_blk_capacity() {
+            return Err(ENOSPC);
+        }
+
+        if !spilled.is_empty() && inode.xattr_blk == INVALID_PTR {
+            inode.xattr_blk = self.alloc_blk().map_err(blk_errno)?;
+            inode.blocks += 1;
+        }
+        if spilled.is_empty() {
+            self.free_xattr_blk(&mut inode)?;
+        } else {
+            // A block shared with a snapshot is copied rather than changed, see snapshot.rs
+            inode.xattr_blk = self.unshare_blk(inode.xattr_blk, 0).map_err(blk_errno)?;
+            let data = self.blk_data_mut(inode.xattr_blk).map_err(blk_errno)?;
+            data[..4].copy_from_slice(&XATTR_MAGIC.to_le_bytes());
+            data[4..8].copy_from_slice(&1u32.to_le_bytes());
+            encode_entries(&spilled, &mut data[XATTR_BLK_HEADER_LEN..]);
+        }
+        encode_entries(&inline, &mut inode.xattr_inline);
+        inode.update_ctime();
+        *self.inode_mut(ino_id).map_err(inode_errno)? = inode;
+        Ok(())
+    }
+
+    pub(crate) fn free_xattr_blk(&mut self, inode: &mut Inode) -> Result<(), c_int> {
+        if inode.xattr_blk != INVALID_PTR {
+            self.free_blk(inode.xattr_blk).map_err(blk_errno)?;
+            inode.xattr_blk = INVALID_PTR;
+            inode.blocks -= 1;
+        }
+        Ok(())
+    }
+
+    pub fn get_xattr(&self, ino_id: u32, name: &OsStr) -> Result<Vec<u8>, c_int> {
+        let (index, suffix) = split_name(name)?;
+        let inode = self.inode(ino_id).map_err(inode_errno)?;
+        self.xattrs(inode)?
+            .into_iter()
+            .find(|entry| entry.index == index && entry.suffix == suffix)
+            .map(|entry| entry.value)
+            .ok_or(ENODATA)
+    }
+
+    // Every name followed by a NUL, the format listxattr(2) returns
+    pub fn list_xattr(&self, ino_id: u32) -> Result<Vec<u8>, c_int> {
+        let inode = self.inode(ino_id).map_err(inode_errno)?;
+        let mut names = Vec::new();
+        for entry in self.xattrs(inode)? {
+            names.extend(entry.name());
+            names.push(0);
+        }
+        Ok(names)
+    }
+
+    // flags is 0, XATTR_CREATE or XATTR_REPLACE as for setxattr(2). Values that could never
+    // fit are E2BIG, ones that do not fit next to the attributes already there are ENOSPC.
+    pub fn set_xattr(
+        &mut self,
+        ino_id: u32,
+        name: &OsStr,
+        value: &[u8],
+        flags: i32,
+    ) -> Result<(), c_int> {
+        match name.to_str() {
+            Some(acl @ (ACL_ACCESS | ACL_DEFAULT)) => self.set_acl(ino_id, acl, value, flags),
+            _ => self.store_xattr(ino_id, name, value, flags),
+        }
+    }
+
+    // set_xattr without the checks and side effects of ACLs
+    pub(crate) fn store_xattr(
+        &mut self,
+        ino_id: u32,
+        name: &OsStr,
+        value: &[u8],
+        flags: i32,
+    ) -> Result<(), c_int> {
+        let (index, suffix) = split_name(name)?;
+        let entry = Xattr {
+            index,
+            suffix: suffix.to_vec(),
+            value: value.to_vec(),
+        };
+        let max_len = self.xattr_blk_capacity().max(INLINE_XATTR_LEN);
+        if value.len() > MAX_VALUE_LEN || entry.len() > max_len {
+            return Err(E2BIG);
+        }
+        let inode = self.inode(ino_id).map_err(inode_errno)?;
+        let mut entries = self.xattrs(inode)?;
+        let existing = entries
+            .iter()
+            .position(|old| old.index == index && old.suffix == suffix);
+        match existing {
+            Some(_) if flags & XATTR_CREATE != 0 => return Err(EEXIST),
+            None if flags & XATTR_REPLACE != 0 => return Err(ENODATA),
+            Some(pos) => entries[pos] = entry,
+            None => entries.push(entry),
+        }
+        self.store_xattrs(ino_id, &entries)
+    }
+
+    pub fn remove_xattr(&mut self, ino_id: u32, name: &OsStr) -> Result<(), c_int> {
+        let (index, suffix) = split_name(name)?;
+        let inode = self.inode(ino_id).map_err(inode_errno)?;
+        let mut entries = self.xattrs(inode)?;
+        let pos = entries
+            .iter()
+            .position(|entry| entry.index == index && entry.suffix == suffix)
+            .ok_or(ENODATA)?;
+        entries.remove(pos);
+        self.store_xattrs(ino_id, &entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::{self, Geometry};
+    use crate::test_util::state_with_file;
+    use crate::{fsck, ROOT_INO};
+    use fuser::FileType;
+    use tempdir::TempDir;
+
+    fn name(name: &str) -> &OsStr {
+        OsStr::new(name)
+    }
+
+    #[test]
+    fn test_names_and_namespaces() {
+        assert_eq!(split_name(name("user.a")), Ok((1, &b"a"[..])));
+        assert_eq!(
+            split_name(name("system.posix_acl_access")),
+            Ok((2, &b""[..]))
+        );
+        assert_eq!(split_name(name("system.other")), Ok((7, &b"other"[..])));
+        assert_eq!(
+            split_name(name("security.selinux")),
+            Ok((6, &b"selinux"[..]))
+        );
+        assert_eq!(split_name(name("os2.a")), Err(EOPNOTSUPP));
+        assert_eq!(split_name(name("user.")), Err(EINVAL));
+        assert_eq!(split_name(name(&"u".repeat(256))), Err(ERANGE));
+    }
+
+    #[test]
+    fn test_small_attributes_stay_inline() {
+        let (mut state, ino_id) = state_with_file(Geometry::new(1 << 20, 1024, 32).unwrap());
+        state
+            .set_xattr(ino_id, name("user.tag"), b"blue", 0)
+            .unwrap();
+        state
+            .set_xattr(
+                ino_id,
+                name("security.selinux"),
+                b"system_u:object_r:tmp_t",
+                0,
+            )
+            .unwrap();
+        let inode = *state.inode(ino_id).unwrap();
+        assert_eq!(inode.xattr_blk, INVALID_PTR);
+        assert_eq!(inode.blocks, 0);
+
+        assert_eq!(
+            state.get_xattr(ino_id, name("user.tag")),
+            Ok(b"blue".to_vec())
+        );
+        assert_eq!(
+            state.list_xattr(ino_id),
+            Ok(b"user.tag\0security.selinux\0".to_vec())
+        );
+        assert_eq!(state.get_xattr(ino_id, name("user.other")), Err(ENODATA));
+    }
+
+    #[test]
+    fn test_large_attributes_go_to_the_block() {
+        let (mut state, ino_id) = state_with_file(Geometry::new(1 << 20, 1024, 32).unwrap());
+        state
+            .set_xattr(ino_id, name("user.small"), b"1", 0)
+            .unwrap();
+        state
+            .set_xattr(ino_id, name("user.big"), &[7; 500], 0)
+            .unwrap();
+        state
+            .set_xattr(ino_id, name("user.small2"), b"2", 0)
+            .unwrap();
+        let inode = *state.inode(ino_id).unwrap();
+        assert_ne!(inode.xattr_blk, INVALID_PTR);
+        assert_eq!(inode.blocks, 1);
+        // Only the big one spilled
+        assert_eq!(decode_entries(&inode.xattr_inline).len(), 2);
+        assert_eq!(state.get_xattr(ino_id, name("user.big")), Ok(vec![7; 500]));
+        assert_eq!(fsck::check(&state), vec![]);
+
+        let free = state.metadata.free_blk_count;
+        state.remove_xattr(ino_id, name("user.big")).unwrap();
+        assert_eq!(state.inode(ino_id).unwrap().xattr_blk, INVALID_PTR);
+        assert_eq!(state.metadata.free_blk_count, free + 1);
+        assert_eq!(state.remove_xattr(ino_id, name("user.big")), Err(ENODATA));
+        assert_eq!(
+            state.list_xattr(ino_id),
+            Ok(b"user.small\0user.small2\0".to_vec())
+        );
+        assert_eq!(fsck::check(&state), vec![]);
+    }
+
+    #[test]
+    fn test_create_and_replace_flags() {
+        let (mut state, ino_id) = state_with_file(Geometry::new(1 << 20, 1024, 32).unwrap());
+        assert_eq!(
+            state.set_xattr(ino_id, name("user.a"), b"1", XATTR_REPLACE),
+            Err(ENODATA)
+        );
+        state
+            .set_xattr(ino_id, name("user.a"), b"1", XATTR_CREATE)
+            .unwrap();
+        assert_eq!(
+            state.set_xattr(ino_id, name("user.a"), b"2", XATTR_CREATE),
+            Err(EEXIST)
+        );
+        state
+            .set_xattr(ino_id, name("user.a"), b"22", XATTR_REPLACE)
+            .unwrap();
+        assert_eq!(state.get_xattr(ino_id, name("user.a")), Ok(b"22".to_vec()));
+        state.set_xattr(ino_id, name("user.a"), b"", 0).unwrap();
+        assert_eq!(state.get_xattr(ino_id, name("user.a")), Ok(vec![]));
+    }
+
+    #[test]
+    fn test_size_limits() {
+        let (mut state, ino_id) = state_with_file(Geometry::new(1 << 20, 1024, 32).unwrap());
+        assert_eq!(
+            state.set_xattr(ino_id, name("user.a"), &[0; 1024], 0),
+            Err(E2BIG)
+        );
+        state
+            .set_xattr(ino_id, name("user.a"), &[0; 900], 0)
+            .unwrap();
+        assert_eq!(
+            state.set_xattr(ino_id, name("user.b"), &[0; 200], 0),
+            Err(ENOSPC)
+        );
+        assert_eq!(state.get_xattr(ino_id, name("user.b")), Err(ENODATA));
+    }
+
+    #[test]
+    fn test_freed_with_the_inode() {
+        let (mut state, ino_id) = state_with_file(Geometry::new(1 << 20, 1024, 32).unwrap());
+        let free = state.metadata.free_blk_count;
+        state
+            .set_xattr(ino_id, name("user.big"), &[1; 600], 0)
+            .unwrap();
+        state.remove_node(ROOT_INO, name("file"), false).unwrap();
+        assert_eq!(state.metadata.free_blk_count, free);
+        assert_eq!(fsck::check(&state), vec![]);
+    }
+
+    #[test]
+    fn test_xattrs_persist() {
+        let dir = TempDir::new("xattr").unwrap();
+        let image = std::fs::File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(dir.path().join("fs.img"))
+            .unwrap();
+        image::format(&image, Geometry::new(1 << 20, 1024, 32).unwrap()).unwrap();
+        let mut state = FSState::load(&image).unwrap();
+        let ino_id = state
+            .make_node(ROOT_INO, name("file"), FileType::RegularFile, 0o644)
+            .unwrap();
+        state
+            .set_xattr(ino_id, name("user.small"), b"inline", 0)
+            .unwrap();
+        state
+            .set_xattr(ino_id, name("trusted.big"), &[3; 700], 0)
+            .unwrap();
+        state.save(&image).unwrap();
+
+        let state = FSState::load(&image).unwrap();
+        assert_eq!(
+            state.get_xattr(ino_id, name("user.small")),
+            Ok(b"inline".to_vec())
+        );
+        assert_eq!(
+            state.get_xattr(ino_id, name("trusted.big")),
+            Ok(vec![3; 700])
+        );
+    }
+}
@@ -0,0 +1,65 @@
+use std::fs::{self, OpenOptions};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::process::Command;
+use tempdir::TempDir;
+
+fn run(bin: &str, args: &[&str], image: &Path) -> i32 {
+    Command::new(bin)
+        .args(args)
+        .arg(image)
+        .status()
+        .expect("failed to run")
+        .code()
+        .unwrap()
+}
+
+#[test]
+fn fsck_reports_and_repairs_bad_free_count() {
+    let tmp_dir = TempDir::new("testdir").unwrap();
+    let image = tmp_dir.path().join("fs.img");
+    let mkfs = env!("CARGO_BIN_EXE_mkfs");
+    let fsck = env!("CARGO_BIN_EXE_fsck");
+    assert_eq!(
+        run(mkfs, &["-s", "4M", "-b", "1024", "-i", "64"], &image),
+        0
+    );
+    assert_eq!(run(fsck, &["-n"], &image), 0);
+
+    // free_blk_count lives at byte 16 of the superblock
+    let file = OpenOptions::new().write(true).open(&image).unwrap();
+    file.write_all_at(&7u32.to_le_bytes(), 16).unwrap();
+    let corrupted = fs::read(&image).unwrap();
+
+    assert_eq!(run(fsck, &["-n"], &image), 4);
+    assert_eq!(fs::read(&image).unwrap(), corrupted);
+    assert_eq!(run(fsck, &["-y"], &image), 1);
+    assert_eq!(run(fsck, &["-n"], &image), 0);
+}
+
+#[test]
+fn fsck_fails_on_non_image() {
+    let tmp_dir = TempDir::new("testdir").unwrap();
+    let image = tmp_dir.path().join("fs.img");
+    fs::write(&image, vec![0; 4096]).unwrap();
+    assert_eq!(run(env!("CARGO_BIN_EXE_fsck"), &["-n"], &image), 8);
+}
+
+#[test]
+fn fsck_checks_image_without_journal() {
+    let tmp_dir = TempDir::new("testdir").unwrap();
+    let image = tmp_dir.path().join("fs.img");
+    let args = ["-s", "4M", "-b", "1024", "-i", "64", "-j", "0"];
+    assert_eq!(run(env!("CARGO_BIN_EXE_mkfs"), &args, &image), 0);
+    assert_eq!(run(env!("CARGO_BIN_EXE_fsck"), &["-y"], &image), 0);
+}
+
+#[test]
+fn fsck_checks_copy_on_write_image() {
+    let tmp_dir = TempDir::new("testdir").unwrap();
+    let image = tmp_dir.path().join("fs.img");
+    let args = ["-s", "4M", "-b", "1024", "-i", "64", "-c"];
+    assert_eq!(run(env!("CARGO_BIN_EXE_mkfs"), &args, &image), 0);
+    assert_eq!(run(env!("CARGO_BIN_EXE_fsck"), &["-y"], &image), 0);
+    assert_eq!(run(env!("CARGO_BIN_EXE_fsck"), &["-n"], &image), 0);
+}
@@ -0,0 +1,34 @@
+use std::fs;
+use std::process::Command;
+use tempdir::TempDir;
+
+#[test]
+fn mkfs_writes_image_with_requested_size() {
+    let tmp_dir = TempDir::new("testdir").unwrap();
+    let image = tmp_dir.path().join("fs.img");
+
+    let status = Command::new(env!("CARGO_BIN_EXE_mkfs"))
+        .args(["-s", "4M", "-b", "1024", "-i", "128"])
+        .arg(&image)
+        .status()
+        .expect("failed to run mkfs");
+    assert!(status.success());
+
+    let data = fs::read(&image).unwrap();
+    assert_eq!(data.len(), 4 << 20);
+    assert_eq!(&data[..4], b"RSFS");
+    assert_eq!(u32::from_le_bytes(data[4..8].try_into().unwrap()), 1024);
+}
+
+#[test]
+fn mkfs_rejects_invalid_block_size() {
+    let tmp_dir = TempDir::new("testdir").unwrap();
+    let image = tmp_dir.path().join("fs.img");
+
+    let status = Command::new(env!("CARGO_BIN_EXE_mkfs"))
+        .args(["-b", "1000"])
+        .arg(&image)
+        .status()
+        .expect("failed to run mkfs");
+    assert!(!status.success());
+}
@@ -21,3 +21,23 @@ fn mount() {
     let status = child.wait().unwrap();
     assert!(!status.success(), "filesystem exited unexpectedly");
 }
+
+#[test]
+fn bad_image_is_reported() {
+    let tmp_dir = TempDir::new("testdir").unwrap();
+    let mountpoint = tmp_dir.path();
+    let garbage = tmp_dir.path().join("garbage.img");
+    std::fs::write(&garbage, vec![0xab; 64 << 10]).unwrap();
+
+    for image in [tmp_dir.path().join("missing.img"), garbage] {
+        let output = Command::new(env!("CARGO_BIN_EXE_rusty-file-system"))
+            .arg(mountpoint)
+            .arg(&image)
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(1));
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(stderr.starts_with("rusty-file-system: "), "{stderr}");
+        assert!(!stderr.contains("panicked"), "{stderr}");
+    }
+}
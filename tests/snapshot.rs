@@ -0,0 +1,52 @@
+use std::path::Path;
+use std::process::{Command, Output};
+use tempdir::TempDir;
+
+fn run(bin: &str, args: &[&str], image: &Path) -> Output {
+    Command::new(bin)
+        .args(args)
+        .arg(image)
+        .output()
+        .expect("failed to run")
+}
+
+fn snapshots(image: &Path) -> Vec<String> {
+    let output = run(env!("CARGO_BIN_EXE_snapshot"), &[], image);
+    assert!(output.status.success());
+    String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| line.split('\t').nth(1).unwrap().to_string())
+        .collect()
+}
+
+#[test]
+fn snapshot_creates_rolls_back_and_deletes() {
+    for mkfs_args in [&["-j", "64"][..], &["-c"][..]] {
+        let tmp_dir = TempDir::new("testdir").unwrap();
+        let image = tmp_dir.path().join("fs.img");
+        let mut args = vec!["-s", "4M", "-b", "1024", "-i", "64"];
+        args.extend(mkfs_args);
+        assert!(run(env!("CARGO_BIN_EXE_mkfs"), &args, &image)
+            .status
+            .success());
+
+        let snapshot = env!("CARGO_BIN_EXE_snapshot");
+        for name in ["one", "two"] {
+            assert!(run(snapshot, &["-c", name], &image).status.success());
+        }
+        assert!(!run(snapshot, &["-c", "one"], &image).status.success());
+        assert_eq!(snapshots(&image), ["one", "two"]);
+        assert!(run(env!("CARGO_BIN_EXE_fsck"), &["-n"], &image)
+            .status
+            .success());
+
+        assert!(run(snapshot, &["-r", "one"], &image).status.success());
+        assert!(run(snapshot, &["-d", "one"], &image).status.success());
+        assert!(!run(snapshot, &["-d", "one"], &image).status.success());
+        assert_eq!(snapshots(&image), ["two"]);
+        assert!(run(env!("CARGO_BIN_EXE_fsck"), &["-n"], &image)
+            .status
+            .success());
+    }
+}